@@ -0,0 +1,10 @@
+fn main() {
+    // gRPCのコードは `--features grpc` のときだけ生成する。protocはvendoredのものを使う
+    #[cfg(feature = "grpc")]
+    {
+        let protoc = protoc_bin_vendored::protoc_bin_path().expect("vendored protoc");
+        // SAFETY: ビルドスクリプトは単一スレッド
+        unsafe { std::env::set_var("PROTOC", protoc) };
+        tonic_prost_build::compile_protos("proto/locator.proto").expect("proto/locator.proto");
+    }
+}
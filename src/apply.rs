@@ -0,0 +1,186 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Result, anyhow, bail};
+use serde::Deserialize;
+
+use crate::config::Config;
+use crate::hid::{DeviceDescriptor, IdQuery};
+use crate::util::parse_hex_or_dec_u8;
+
+/// 宣言ファイルに書かれた1台分のLED状態
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DesiredMask {
+    /// `on` / `true`。`--on-value` のマスクを使う
+    On,
+    /// `off` / `false`。`--off-value` のマスクを使う
+    Off,
+    /// マスクを直接指定
+    Mask(u8),
+}
+
+impl DesiredMask {
+    pub fn resolve(self, on_value: u8, off_value: u8) -> u8 {
+        match self {
+            DesiredMask::On => on_value,
+            DesiredMask::Off => off_value,
+            DesiredMask::Mask(mask) => mask,
+        }
+    }
+}
+
+/// YAML/TOML/JSONで書ける値の形 (`0x1f` / `31` / `"0x1f"` / `on` / `off` / `true` / `false`)
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawValue {
+    Bool(bool),
+    Number(i64),
+    Text(String),
+}
+
+impl TryFrom<RawValue> for DesiredMask {
+    type Error = String;
+
+    fn try_from(value: RawValue) -> std::result::Result<Self, Self::Error> {
+        match value {
+            RawValue::Bool(true) => Ok(DesiredMask::On),
+            RawValue::Bool(false) => Ok(DesiredMask::Off),
+            RawValue::Number(n) => u8::try_from(n)
+                .map(DesiredMask::Mask)
+                .map_err(|_| format!("マスクは0〜255で指定してください: {}", n)),
+            RawValue::Text(text) => match text.trim().to_ascii_lowercase().as_str() {
+                "on" => Ok(DesiredMask::On),
+                "off" => Ok(DesiredMask::Off),
+                other => parse_hex_or_dec_u8(other).map(DesiredMask::Mask),
+            },
+        }
+    }
+}
+
+/// locator id → 目標LED状態。キーは `--id` と同じく シリアル番号 or HIDパスの部分文字列、または設定ファイルのグループ名・`[aliases]` の名前
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DesiredState {
+    pub entries: BTreeMap<String, DesiredMask>,
+}
+
+impl DesiredState {
+    /// 拡張子(.yaml/.yml/.toml/.json)で形式を判定して読み込む
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("{} を読み込めません", path.display()))?;
+        let format = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_ascii_lowercase())
+            .unwrap_or_default();
+        Self::parse(&text, &format).with_context(|| format!("{} の解析に失敗", path.display()))
+    }
+
+    pub fn parse(text: &str, format: &str) -> Result<Self> {
+        let raw: BTreeMap<String, RawValue> = match format {
+            "yaml" | "yml" => serde_yaml::from_str(text)?,
+            "toml" => toml::from_str(text)?,
+            "json" => serde_json::from_str(text)?,
+            other => bail!("対応していない形式です (yaml/toml/json): {:?}", other),
+        };
+
+        let mut entries = BTreeMap::new();
+        for (id, value) in raw {
+            let mask = DesiredMask::try_from(value)
+                .map_err(|e| anyhow!("{} の値を解釈できません: {}", id, e))?;
+            entries.insert(id, mask);
+        }
+        Ok(Self { entries })
+    }
+}
+
+/// 宣言と接続中のlocatorを突き合わせた結果
+#[derive(Debug, Default)]
+pub struct Resolution {
+    /// 宣言に一致したlocatorと目標マスク
+    pub targets: Vec<(DeviceDescriptor, u8)>,
+    /// 宣言にあるが接続されていないid
+    pub missing: Vec<String>,
+    /// 複数台に一致してしまったid (一致したlocator id一覧付き)
+    pub ambiguous: Vec<(String, Vec<String>)>,
+    /// 接続されているが宣言に無いlocator
+    pub unknown: Vec<DeviceDescriptor>,
+}
+
+/// 宣言の各idを接続中のlocatorへ割り当てる
+///
+/// - キーが設定ファイルのグループ名ならメンバー全員、`[aliases]` の名前ならその名前を付けたlocatorを指す
+/// - シリアル番号・ポート・HIDパスが完全に一致するlocatorがあればそれを、無ければ部分一致で探す
+/// - 1台に一致したものだけを対象にする
+/// - 同じlocatorに複数のidが一致した場合はエラー
+pub fn resolve_targets(
+    desired: &DesiredState,
+    devices: &[DeviceDescriptor],
+    config: &Config,
+    on_value: u8,
+    off_value: u8,
+) -> Result<Resolution> {
+    let mut resolution = Resolution::default();
+    let mut claimed: BTreeMap<String, String> = BTreeMap::new();
+
+    for (key, mask) in &desired.entries {
+        for query in expand_key(config, key) {
+            let matched = find_devices(devices, &query);
+            // グループのメンバーはどれか分かるように `グループ名/id` で報告する
+            let label = if query == *key { key.clone() } else { format!("{}/{}", key, query) };
+            match matched.as_slice() {
+                [] => resolution.missing.push(label),
+                [device] => {
+                    let locator_id = device.locator_id();
+                    if let Some(previous) = claimed.insert(locator_id.clone(), label.clone()) {
+                        bail!(
+                            "{} と {} が同じlocatorを指しています (id={})",
+                            previous,
+                            label,
+                            locator_id
+                        );
+                    }
+                    resolution
+                        .targets
+                        .push(((*device).clone(), mask.resolve(on_value, off_value)));
+                }
+                many => resolution.ambiguous.push((
+                    label,
+                    many.iter().map(|d| d.locator_id()).collect(),
+                )),
+            }
+        }
+    }
+
+    resolution.unknown = devices
+        .iter()
+        .filter(|d| !claimed.contains_key(&d.locator_id()))
+        .cloned()
+        .collect();
+    Ok(resolution)
+}
+
+/// 宣言のキーを探すidにする。グループ名ならメンバー、`[aliases]` の名前ならそのキー、どちらでもなければそのまま
+fn expand_key(config: &Config, key: &str) -> Vec<String> {
+    if let Some(members) = config.groups.get(key) {
+        return members.clone();
+    }
+    let aliased: Vec<String> = config
+        .aliases
+        .iter()
+        .filter(|(_, alias)| *alias == key)
+        .map(|(id, _)| id.clone())
+        .collect();
+    if aliased.is_empty() {
+        vec![key.to_string()]
+    } else {
+        aliased
+    }
+}
+
+/// 完全に一致するlocatorを優先し、無いときだけ部分一致で探す
+fn find_devices<'a>(devices: &'a [DeviceDescriptor], query: &str) -> Vec<&'a DeviceDescriptor> {
+    let query = IdQuery::resolve(devices, query);
+    devices.iter().filter(|d| query.selects(d)).collect()
+}
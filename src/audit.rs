@@ -0,0 +1,366 @@
+use std::env;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::OnceLock;
+
+use anyhow::{Context, Result, anyhow};
+use chrono::{DateTime, FixedOffset, Local, NaiveDate, TimeZone};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use tracing::field::{Field, Visit};
+use tracing::{Event, Subscriber, info, warn};
+use tracing_subscriber::filter::{LevelFilter, Targets};
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::layer::{Context as LayerContext, Layer};
+use tracing_subscriber::registry::LookupSpan;
+
+use crate::cli::ProtocolArgs;
+use crate::hid::{DeviceDescriptor, HidDeviceIo};
+use crate::profile::DeviceProfile;
+use crate::util::parse_duration;
+
+/// 状態を変えた・断った記録を出すtracingのターゲット (`RUST_LOG=audit=info` などで絞れる)
+///
+/// `--audit-log` にはこのターゲットだけを書き出す
+pub const AUDIT_TARGET: &str = "audit";
+
+/// LEDを変えた経路
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Origin {
+    /// このマシンのコマンドライン
+    Cli,
+    /// `serve` / `grpc-serve` / MQTT の要求
+    Api,
+    /// `daemon` のスケジュール
+    Daemon,
+}
+
+impl Origin {
+    pub fn name(self) -> &'static str {
+        match self {
+            Origin::Cli => "cli",
+            Origin::Api => "api",
+            Origin::Daemon => "daemon",
+        }
+    }
+}
+
+/// LEDの変更1回分の経緯
+#[derive(Clone, Debug)]
+pub struct Change<'a> {
+    pub origin: Origin,
+    /// `on` / `apply` / `SetLeds` など
+    pub command: &'a str,
+    /// API経由ならクライアント名。Noneならこのマシンのユーザー
+    pub user: Option<&'a str>,
+    /// API経由なら接続元。Noneならこのマシンのホスト名
+    pub host: Option<&'a str>,
+    /// 分かっていれば変更前のマスク
+    pub previous: Option<u8>,
+}
+
+impl<'a> Change<'a> {
+    pub fn cli(command: &'a str) -> Self {
+        Self {
+            origin: Origin::Cli,
+            command,
+            user: None,
+            host: None,
+            previous: None,
+        }
+    }
+
+    pub fn api(command: &'a str, user: Option<&'a str>, host: Option<&'a str>) -> Self {
+        Self {
+            origin: Origin::Api,
+            command,
+            user,
+            host,
+            previous: None,
+        }
+    }
+
+    pub fn daemon(command: &'a str) -> Self {
+        Self {
+            origin: Origin::Daemon,
+            ..Self::cli(command)
+        }
+    }
+
+    pub fn previous(mut self, mask: Option<u8>) -> Self {
+        self.previous = mask;
+        self
+    }
+}
+
+/// LEDのマスクを送り、監査ログに記録する
+///
+/// 変更前のマスクは `change.previous` のまま記録する。分からなければ記録しない (問い合わせはしない)
+pub fn set_mask(
+    profile: &DeviceProfile,
+    device: &dyn HidDeviceIo,
+    protocol: &ProtocolArgs,
+    target: &DeviceDescriptor,
+    mask: u8,
+    change: &Change,
+) -> Result<()> {
+    let result = profile.set_mask(device, protocol, mask);
+    let outcome = result.as_ref().map(|_| ()).map_err(|err| format!("{:#}", err));
+    AuditRecord::new(&target.locator_id(), target.serial_number.as_deref(), Some(mask), change, outcome).emit();
+    result
+}
+
+/// 監査ログの1件。状態を変えた・断った要求ごとに1つ出し、`--audit-log` には1行のJSONで書く
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditRecord {
+    pub timestamp: DateTime<FixedOffset>,
+    /// API経由ならクライアント名、それ以外はこのマシンのユーザー
+    pub user: String,
+    /// API経由なら接続元、それ以外はこのマシンのホスト名
+    pub host: String,
+    pub id: String,
+    pub serial: Option<String>,
+    pub previous: Option<u8>,
+    /// 設定したLEDのマスク。LED以外の変更 (EEPROMへの書き込みなど) ならNone
+    pub mask: Option<u8>,
+    /// マスク以外の内容 (`blink=500ms` や `data=[...]` など)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    pub origin: Origin,
+    pub command: String,
+    /// `ok` または `error`
+    pub result: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl AuditRecord {
+    /// 今の時刻の記録を作る。`outcome` のエラーは断った・失敗した理由
+    pub fn new(
+        id: &str,
+        serial: Option<&str>,
+        mask: Option<u8>,
+        change: &Change,
+        outcome: std::result::Result<(), String>,
+    ) -> Self {
+        let (user, host) = local_identity();
+        Self {
+            timestamp: Local::now().fixed_offset(),
+            user: change.user.unwrap_or(user).to_string(),
+            host: change.host.unwrap_or(host).to_string(),
+            id: id.to_string(),
+            serial: serial.map(str::to_string),
+            previous: change.previous,
+            mask,
+            detail: None,
+            origin: change.origin,
+            command: change.command.to_string(),
+            result: if outcome.is_ok() { "ok" } else { "error" }.to_string(),
+            error: outcome.err(),
+        }
+    }
+
+    pub fn detail(mut self, detail: impl Into<String>) -> Self {
+        self.detail = Some(detail.into());
+        self
+    }
+
+    /// `AUDIT_TARGET` のイベントとして出す
+    pub fn emit(&self) {
+        info!(
+            target: AUDIT_TARGET,
+            timestamp = %self.timestamp.to_rfc3339(),
+            user = %self.user,
+            host = %self.host,
+            id = %self.id,
+            serial = self.serial.as_deref(),
+            previous = self.previous,
+            mask = self.mask,
+            detail = self.detail.as_deref(),
+            origin = self.origin.name(),
+            command = %self.command,
+            result = %self.result,
+            error = self.error.as_deref(),
+            "監査"
+        );
+    }
+
+    /// `mask=0x05 blink=500ms` のような変更の内容。何も無ければ `-`
+    pub fn change_text(&self) -> String {
+        let text: Vec<String> = self
+            .mask
+            .map(|mask| format!("mask=0x{:02x}", mask))
+            .into_iter()
+            .chain(self.detail.clone())
+            .collect();
+        if text.is_empty() { "-".to_string() } else { text.join(" ") }
+    }
+
+    /// `audit show` の1行
+    pub fn line(&self) -> String {
+        let previous = self
+            .previous
+            .map_or_else(|| "-".to_string(), |mask| format!("0x{:02x}", mask));
+        let mut line = format!(
+            "{} {:<6} {:<10} id={:<20} user={} host={} previous={} {} result={}",
+            self.timestamp.to_rfc3339(),
+            self.origin.name(),
+            self.command,
+            self.id,
+            self.user,
+            self.host,
+            previous,
+            self.change_text(),
+            self.result
+        );
+        if let Some(error) = &self.error {
+            line.push_str(&format!(" error={}", error));
+        }
+        line
+    }
+}
+
+/// `audit show` の絞り込み
+#[derive(Clone, Debug, Default)]
+pub struct AuditQuery {
+    /// locator id またはシリアル番号の部分文字列
+    pub id: Option<String>,
+    pub since: Option<DateTime<FixedOffset>>,
+    pub until: Option<DateTime<FixedOffset>>,
+}
+
+impl AuditQuery {
+    pub fn matches(&self, record: &AuditRecord) -> bool {
+        self.id.as_deref().is_none_or(|id| {
+            record.id.contains(id) || record.serial.as_deref().is_some_and(|serial| serial.contains(id))
+        }) && self.since.is_none_or(|since| record.timestamp >= since)
+            && self.until.is_none_or(|until| record.timestamp < until)
+    }
+}
+
+/// 監査ログを読み、絞り込みに一致するものを古い順に返す。壊れた行は警告して飛ばす
+pub fn read_audit_log(path: &Path, query: &AuditQuery) -> Result<Vec<AuditRecord>> {
+    let file = File::open(path).with_context(|| format!("監査ログを開けません: {}", path.display()))?;
+    let mut records = Vec::new();
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.with_context(|| format!("{} を読み込めません", path.display()))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str::<AuditRecord>(&line) {
+            Ok(record) if query.matches(&record) => records.push(record),
+            Ok(_) => {}
+            Err(err) => warn!("{}:{} を読み飛ばしました: {}", path.display(), i + 1, err),
+        }
+    }
+    Ok(records)
+}
+
+/// `--since` / `--until` の値
+///
+/// RFC 3339、`YYYY-MM-DD` (その日の0時、ローカル時刻)、または `30m` `24h` のような「今からどれだけ前か」
+pub fn parse_time_bound(text: &str, now: &DateTime<Local>) -> Result<DateTime<FixedOffset>> {
+    if let Ok(at) = DateTime::parse_from_rfc3339(text) {
+        return Ok(at);
+    }
+    if let Ok(date) = NaiveDate::parse_from_str(text, "%Y-%m-%d") {
+        let midnight = date.and_hms_opt(0, 0, 0).expect("0時は常に有効");
+        return Local
+            .from_local_datetime(&midnight)
+            .earliest()
+            .map(|at| at.fixed_offset())
+            .ok_or_else(|| anyhow!("その日の0時はローカル時刻にありません: {}", text));
+    }
+    let ago = parse_duration(text)
+        .map_err(|_| anyhow!("時刻を解釈できません: {} (RFC 3339、YYYY-MM-DD、または 24h のような期間)", text))?;
+    Ok((*now - ago).fixed_offset())
+}
+
+/// このマシンのユーザー名とホスト名 (最初に1回だけ調べる)
+fn local_identity() -> (&'static str, &'static str) {
+    static IDENTITY: OnceLock<(String, String)> = OnceLock::new();
+    let (user, host) = IDENTITY.get_or_init(|| (local_user(), local_host()));
+    (user, host)
+}
+
+/// このマシンのユーザー名
+fn local_user() -> String {
+    env::var("USER")
+        .or_else(|_| env::var("USERNAME"))
+        .unwrap_or_else(|_| "unknown".to_string())
+}
+
+/// このマシンのホスト名
+fn local_host() -> String {
+    env::var("HOSTNAME")
+        .ok()
+        .or_else(|| fs::read_to_string("/etc/hostname").ok())
+        .or_else(|| env::var("COMPUTERNAME").ok())
+        .map(|host| host.trim().to_string())
+        .filter(|host| !host.is_empty())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+/// `AUDIT_TARGET` のイベントだけを `AuditRecord` のJSON Linesで書き出すレイヤー (`--audit-log` 用)
+pub fn audit_layer<S, W>(writer: W) -> impl Layer<S>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    AuditLayer { writer }.with_filter(Targets::new().with_target(AUDIT_TARGET, LevelFilter::INFO))
+}
+
+struct AuditLayer<W> {
+    writer: W,
+}
+
+impl<S, W> Layer<S> for AuditLayer<W>
+where
+    S: Subscriber,
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    fn on_event(&self, event: &Event<'_>, _ctx: LayerContext<'_, S>) {
+        let mut fields = FieldMap::default();
+        event.record(&mut fields);
+        let record: AuditRecord = match serde_json::from_value(Value::Object(fields.0)) {
+            Ok(record) => record,
+            Err(err) => {
+                // レイヤーの中からはtracingへ出せない
+                eprintln!("監査ログの項目が足りません: {}", err);
+                return;
+            }
+        };
+        let line = match serde_json::to_string(&record) {
+            Ok(line) => line,
+            Err(_) => return,
+        };
+        // 他のプロセスと行が混ざらないよう、1行を1回で書く
+        if let Err(err) = self.writer.make_writer().write_all(format!("{}\n", line).as_bytes()) {
+            eprintln!("監査ログを書き込めません: {}", err);
+        }
+    }
+}
+
+#[derive(Default)]
+struct FieldMap(Map<String, Value>);
+
+impl Visit for FieldMap {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0.insert(field.name().to_string(), Value::from(format!("{:?}", value)));
+    }
+}
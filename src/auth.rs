@@ -0,0 +1,394 @@
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use anyhow::{Context, Result, bail};
+
+use crate::config::{ClientConfig, Config};
+use crate::hid::{DeviceDescriptor, PORT_ID_PREFIX};
+
+/// `--rate-limit` の既定値 (1秒あたりの要求数)
+pub const DEFAULT_RATE_LIMIT: f64 = 50.0;
+
+/// `--token` で指定したクライアントの名前
+const TOKEN_CLIENT: &str = "token";
+
+/// クライアントに許す操作
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Permission {
+    /// 一覧とステータスの取得
+    Read,
+    /// LEDの設定やEEPROMへの書き込みなど、状態を変える操作も
+    Control,
+}
+
+impl Permission {
+    pub fn name(self) -> &'static str {
+        match self {
+            Permission::Read => "read",
+            Permission::Control => "control",
+        }
+    }
+
+    pub fn parse(name: &str) -> Result<Self> {
+        match name {
+            "read" => Ok(Permission::Read),
+            "control" => Ok(Permission::Control),
+            other => bail!("不明な権限です: {} (read/control)", other),
+        }
+    }
+}
+
+/// 認証済みのクライアント
+#[derive(Clone, Debug, PartialEq)]
+pub struct Caller {
+    pub name: String,
+    pub permission: Permission,
+    /// グループを展開したlocator id。Noneならすべてのlocator
+    pub targets: Option<Vec<String>>,
+    /// 1秒あたりの要求数の上限。Noneなら `--rate-limit`
+    pub rate_limit: Option<f64>,
+}
+
+impl Caller {
+    /// 操作できるlocatorか。一覧にもこれに一致するものだけを出す
+    ///
+    /// シリアル番号・USBポートの経路 (`port:1-2.3`)・locator id のどれかが対象と完全に一致するか、
+    /// 対象が `*` を含むならそのパターンに一致するものだけ
+    pub fn can_access(&self, device: &DeviceDescriptor) -> bool {
+        let ids: Vec<String> = device
+            .serial_number
+            .iter()
+            .cloned()
+            .chain(device.port.as_ref().map(|port| format!("{}{}", PORT_ID_PREFIX, port)))
+            .chain([device.locator_id()])
+            .collect();
+        self.targets
+            .as_ref()
+            .is_none_or(|targets| targets.iter().any(|target| ids.iter().any(|id| target_matches(target, id))))
+    }
+
+    /// 接続/切断のイベントのようにlocator idしか分からないときの `can_access`
+    pub fn can_access_id(&self, id: &str) -> bool {
+        self.targets
+            .as_ref()
+            .is_none_or(|targets| targets.iter().any(|target| target_matches(target, id)))
+    }
+
+    /// `device` に `needed` の操作をしてよいか
+    pub fn authorize(&self, device: &DeviceDescriptor, needed: Permission) -> std::result::Result<(), Denied> {
+        if !self.can_access(device) {
+            return Err(Denied::Forbidden(format!(
+                "{} は {} を操作できません",
+                self.name,
+                device.locator_id()
+            )));
+        }
+        if self.permission < needed {
+            return Err(Denied::Forbidden(format!("{} は読み取り専用です", self.name)));
+        }
+        Ok(())
+    }
+}
+
+/// 要求を断った理由
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Denied {
+    /// 資格情報が無い・違う
+    Unauthenticated(String),
+    /// 権限が足りない・対象外のlocator
+    Forbidden(String),
+    /// 要求が多すぎる
+    RateLimited(String),
+}
+
+impl fmt::Display for Denied {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Denied::Unauthenticated(message) | Denied::Forbidden(message) | Denied::RateLimited(message) => {
+                f.write_str(message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Denied {}
+
+/// 証明書の指紋を比べられる形にする (`AB:CD:...` も `abcd...` も受け付ける)
+pub fn normalize_fingerprint(fingerprint: &str) -> String {
+    fingerprint
+        .chars()
+        .filter(|c| *c != ':')
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+struct Client {
+    caller: Caller,
+    token: Option<String>,
+    cert_sha256: Option<String>,
+}
+
+/// `RateLimiter` が覚えておくキーの上限。超えたら満タンに戻ったものと、最も古いものから捨てる
+pub const MAX_RATE_BUCKETS: usize = 4096;
+
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+    rate: f64,
+}
+
+impl Bucket {
+    /// 満タンまで戻っていれば、捨てても次の要求で作り直すのと同じ
+    fn is_full(&self, now: Instant) -> bool {
+        self.tokens + now.saturating_duration_since(self.last).as_secs_f64() * self.rate >= self.rate.max(1.0)
+    }
+}
+
+/// 1秒あたり `rate` 回まで (最大 `rate` 回の連続を許す) のトークンバケット
+#[derive(Default)]
+pub struct RateLimiter {
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+    /// `key` の要求を1回数え、上限を超えていればfalse。`rate` が0以下なら制限しない
+    pub fn check(&self, key: &str, rate: f64, now: Instant) -> bool {
+        if rate <= 0.0 {
+            return true;
+        }
+        let burst = rate.max(1.0);
+        let Ok(mut buckets) = self.buckets.lock() else {
+            return true;
+        };
+        if !buckets.contains_key(key) && buckets.len() >= MAX_RATE_BUCKETS {
+            evict(&mut buckets, now);
+        }
+        let bucket = buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: burst,
+            last: now,
+            rate,
+        });
+        let refilled = now.saturating_duration_since(bucket.last).as_secs_f64() * rate;
+        bucket.tokens = (bucket.tokens + refilled).min(burst);
+        bucket.last = now;
+        bucket.rate = rate;
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+
+    /// 覚えているキーの数
+    pub fn len(&self) -> usize {
+        self.buckets.lock().map_or(0, |buckets| buckets.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// 満タンに戻ったバケットを捨て、それでも上限なら最も長く使われていないものを捨てる
+fn evict(buckets: &mut HashMap<String, Bucket>, now: Instant) {
+    buckets.retain(|_, bucket| !bucket.is_full(now));
+    if buckets.len() >= MAX_RATE_BUCKETS
+        && let Some(oldest) = buckets.iter().min_by_key(|(_, bucket)| bucket.last).map(|(key, _)| key.clone())
+    {
+        buckets.remove(&oldest);
+    }
+}
+
+/// `serve` / `grpc-serve` の認証と認可、要求数の制限
+///
+/// - `--token` は全てのlocatorを操作できる1クライアント
+/// - 設定ファイルの `[[clients]]` でクライアントごとの権限と対象を決める
+/// - 要求数はクライアントごと、認証に失敗した要求は接続元ごとに数える
+pub struct AccessControl {
+    clients: Vec<Client>,
+    rate_limit: f64,
+    limiter: RateLimiter,
+}
+
+impl AccessControl {
+    pub fn new(rate_limit: f64) -> Self {
+        Self {
+            clients: Vec::new(),
+            rate_limit,
+            limiter: RateLimiter::default(),
+        }
+    }
+
+    /// 設定ファイルの `clients` と `--token` から作る。どちらも無ければエラー
+    pub fn load(config: &Config, token: Option<&str>, rate_limit: f64) -> Result<Self> {
+        let mut access = Self::new(rate_limit);
+        if let Some(token) = token {
+            access = access.with_token(token)?;
+        }
+        for (i, client) in config.clients.iter().enumerate() {
+            access
+                .add_client(client, config)
+                .with_context(|| format!("clients[{}]", i))?;
+        }
+        if access.clients.is_empty() {
+            bail!("--token か設定ファイルの [[clients]] を指定してください");
+        }
+        Ok(access)
+    }
+
+    /// 全てのlocatorを操作できるトークンを加える
+    pub fn with_token(mut self, token: &str) -> Result<Self> {
+        let caller = Caller {
+            name: TOKEN_CLIENT.to_string(),
+            permission: Permission::Control,
+            targets: None,
+            rate_limit: None,
+        };
+        self.push(caller, Some(token.to_string()), None)?;
+        Ok(self)
+    }
+
+    pub fn add_client(&mut self, client: &ClientConfig, config: &Config) -> Result<()> {
+        if client.name.trim().is_empty() {
+            bail!("name が空です");
+        }
+        let token = match (&client.token, &client.token_env) {
+            (Some(_), Some(_)) => bail!("token と token_env は同時に指定できません"),
+            (Some(token), None) => Some(token.clone()),
+            (None, Some(var)) => Some(env::var(var).with_context(|| format!("環境変数 {} がありません", var))?),
+            (None, None) => None,
+        };
+        let cert_sha256 = client.cert_sha256.as_deref().map(normalize_fingerprint);
+        if token.is_none() && cert_sha256.is_none() {
+            bail!("{} に token / token_env / cert_sha256 のいずれかを指定してください", client.name);
+        }
+        let permission = match client.permission.as_deref() {
+            Some(permission) => Permission::parse(permission)?,
+            None => Permission::Read,
+        };
+        let targets = (!client.targets.is_empty()).then(|| {
+            client
+                .targets
+                .iter()
+                .flat_map(|target| config.expand_target(target))
+                .collect()
+        });
+        let caller = Caller {
+            name: client.name.clone(),
+            permission,
+            targets,
+            rate_limit: client.rate_limit,
+        };
+        self.push(caller, token, cert_sha256)
+    }
+
+    fn push(&mut self, caller: Caller, token: Option<String>, cert_sha256: Option<String>) -> Result<()> {
+        if token.as_ref().is_some_and(String::is_empty) {
+            bail!("{} のトークンが空です", caller.name);
+        }
+        if self.clients.iter().any(|c| c.caller.name == caller.name) {
+            bail!("クライアント名 {} が重複しています", caller.name);
+        }
+        if token
+            .as_ref()
+            .is_some_and(|token| self.clients.iter().any(|c| c.token.as_ref() == Some(token)))
+        {
+            bail!("{} のトークンが他のクライアントと同じです", caller.name);
+        }
+        self.clients.push(Client {
+            caller,
+            token,
+            cert_sha256,
+        });
+        Ok(())
+    }
+
+    /// 提示されたトークン (`Authorization: Bearer`) かクライアント証明書の指紋でクライアントを決める
+    ///
+    /// 両方あればトークンを優先する
+    pub fn authenticate(
+        &self,
+        token: Option<&str>,
+        cert_sha256: Option<&str>,
+    ) -> std::result::Result<&Caller, Denied> {
+        if let Some(token) = token {
+            return self
+                .clients
+                .iter()
+                .find(|c| c.token.as_deref().is_some_and(|expected| same_token(token, expected)))
+                .map(|c| &c.caller)
+                .ok_or_else(|| Denied::Unauthenticated("トークンが違います".to_string()));
+        }
+        if let Some(fingerprint) = cert_sha256 {
+            let fingerprint = normalize_fingerprint(fingerprint);
+            return self
+                .clients
+                .iter()
+                .find(|c| c.cert_sha256.as_deref() == Some(fingerprint.as_str()))
+                .map(|c| &c.caller)
+                .ok_or_else(|| Denied::Unauthenticated(format!("登録されていない証明書です (sha256={})", fingerprint)));
+        }
+        Err(Denied::Unauthenticated("トークンがありません (--remote-token)".to_string()))
+    }
+
+    /// 要求を1回数える。`caller` が無ければ (認証前・失敗) `client` (接続元) のIPアドレスごとに数える
+    ///
+    /// 接続ごとに変わるポート番号は使わない。使うと接続し直すだけで制限を抜けられる
+    pub fn admit(&self, caller: Option<&Caller>, client: &str) -> std::result::Result<(), Denied> {
+        let (key, rate) = match caller {
+            Some(caller) => (format!("caller:{}", caller.name), caller.rate_limit.unwrap_or(self.rate_limit)),
+            None => (format!("client:{}", client_ip(client)), self.rate_limit),
+        };
+        if self.limiter.check(&key, rate, Instant::now()) {
+            Ok(())
+        } else {
+            Err(Denied::RateLimited(format!("要求が多すぎます (1秒あたり{}回まで)", rate)))
+        }
+    }
+}
+
+/// `[[clients]]` の対象が `id` に一致するか。`*` は任意の文字列 (空も含む) に一致する
+fn target_matches(target: &str, id: &str) -> bool {
+    let Some((first, rest)) = target.split_once('*') else {
+        return !target.is_empty() && target == id;
+    };
+    let Some(mut remaining) = id.strip_prefix(first) else {
+        return false;
+    };
+    let mut parts: Vec<&str> = rest.split('*').collect();
+    let last = parts.pop().unwrap_or_default();
+    for part in parts {
+        match remaining.find(part) {
+            Some(at) => remaining = &remaining[at + part.len()..],
+            None => return false,
+        }
+    }
+    remaining.ends_with(last)
+}
+
+/// `10.0.0.2:5000` / `[::1]:5000` のような接続元からIPアドレスを取り出す。解釈できなければそのまま
+fn client_ip(client: &str) -> String {
+    client
+        .parse::<SocketAddr>()
+        .map_or_else(|_| client.to_string(), |addr| addr.ip().to_string())
+}
+
+/// `Authorization` ヘッダー(メタデータ)の値からトークンを取り出す
+pub fn bearer_token(value: &str) -> Option<&str> {
+    value.strip_prefix("Bearer ").filter(|token| !token.is_empty())
+}
+
+/// 長さ以外の情報を応答時間から漏らさないよう、全バイトを比べる
+fn same_token(presented: &str, expected: &str) -> bool {
+    presented.len() == expected.len()
+        && presented
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
@@ -0,0 +1,45 @@
+use anyhow::{Context, Result};
+use hidapi::HidApi;
+
+use crate::cli::FilterArgs;
+use crate::doctor::open_failure;
+use crate::hid::{DeviceDescriptor, HidDeviceIo, snapshot_devices};
+
+/// locatorの列挙とオープンを抽象化するトレイト
+///
+/// 通常は `HidApi` を使い、記録/再生(`--record` / `--replay`)ではラッパーに差し替える
+pub trait LocatorBackend {
+    /// フィルタに一致するlocator一覧
+    fn devices(&self, filter: &FilterArgs) -> Result<Vec<DeviceDescriptor>>;
+
+    /// locatorを開く
+    fn open(&self, device: &DeviceDescriptor) -> Result<Box<dyn HidDeviceIo>>;
+
+    /// 常駐モードで接続状態を取り直す
+    fn refresh(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// 開くときにこのマシンのロックを取るか。リモートのlocatorは相手側でロックする
+    fn locks_locally(&self) -> bool {
+        true
+    }
+}
+
+impl LocatorBackend for HidApi {
+    fn devices(&self, filter: &FilterArgs) -> Result<Vec<DeviceDescriptor>> {
+        Ok(snapshot_devices(self, filter))
+    }
+
+    fn open(&self, device: &DeviceDescriptor) -> Result<Box<dyn HidDeviceIo>> {
+        let handle = self
+            .open_path(device.path.as_c_str())
+            .with_context(|| open_failure(device))?;
+        Ok(Box::new(handle))
+    }
+
+    fn refresh(&mut self) -> Result<()> {
+        self.refresh_devices()
+            .context("HIDデバイス一覧を更新できません")
+    }
+}
@@ -1,6 +1,13 @@
-use clap::{Args, Parser, Subcommand};
+use std::path::PathBuf;
+use std::time::Duration;
 
-use crate::util::{parse_hex_or_dec_u16, parse_hex_or_dec_u8};
+use clap::{ArgAction, Args, Parser, Subcommand};
+
+use crate::auth::DEFAULT_RATE_LIMIT;
+use crate::daemon::ExitAction;
+use crate::logging::LogFormat;
+use crate::mqtt::DEFAULT_MAX_PACKET_SIZE;
+use crate::util::{parse_duration, parse_hex_or_dec_u16, parse_hex_or_dec_u8, parse_port_chain};
 
 #[derive(Parser)]
 #[command(
@@ -9,6 +16,34 @@ use crate::util::{parse_hex_or_dec_u16, parse_hex_or_dec_u8};
     about = "Control locator LEDs over USB HID"
 )]
 pub struct Cli {
+    /// 詳細ログを標準エラーへ出す (-v: 各操作と所要時間, -vv: 送受信バイト列まで)
+    #[arg(short, long, action = ArgAction::Count, global = true)]
+    pub verbose: u8,
+    /// ログの形式
+    #[arg(long, value_enum, default_value_t = LogFormat::Text, global = true)]
+    pub log_format: LogFormat,
+    /// 全てのHID送受信をJSON Linesで追記するファイル (不具合報告用)
+    #[arg(long, global = true)]
+    pub trace_file: Option<PathBuf>,
+    /// LEDの変更(誰が・どこから・前後のマスク・結果)をJSON Linesで追記する監査ログ
+    #[arg(long, env = "CAP_LOCATOR_AUDIT_LOG", global = true)]
+    pub audit_log: Option<PathBuf>,
+    /// 列挙結果と全てのHID送受信をタイミング付きで記録するファイル (`--replay` で再生できる)
+    #[arg(long, global = true, conflicts_with = "replay")]
+    pub record: Option<PathBuf>,
+    /// `--record` で記録したファイルを実機の代わりに使う。記録と異なる要求はエラー
+    #[arg(long, global = true)]
+    pub replay: Option<PathBuf>,
+    /// 別のマシンのlocatorを操作する。`serve` なら `http://host:port`、`grpc-serve` なら `host:port` (list/status/on/off のみ)
+    #[arg(long, env = "CAP_LOCATOR_REMOTE", global = true, conflicts_with_all = ["record", "replay"])]
+    pub remote: Option<String>,
+    /// `serve` / `grpc-serve` の `--token`、または設定ファイルの [[clients]] のトークン
+    #[arg(long, env = "CAP_LOCATOR_REMOTE_TOKEN", hide_env_values = true, global = true)]
+    pub remote_token: Option<String>,
+    #[command(flatten)]
+    pub remote_tls: RemoteTlsArgs,
+    #[command(flatten)]
+    pub profile: ProfileArgs,
     #[command(subcommand)]
     pub command: Commands,
 }
@@ -23,6 +58,37 @@ pub enum Commands {
     On(SetArgs),
     /// 指定locatorの光を消す
     Off(SetArgs),
+    /// 宣言ファイル(YAML/TOML/JSON)に書いたLED状態へ各locatorを揃える
+    Apply(ApplyArgs),
+    /// 全locatorのLED状態を保存/復元
+    Snapshot(SnapshotArgs),
+    /// 設定ファイルのスケジュールに従ってLEDを切り替え続ける(常駐)
+    Daemon(DaemonArgs),
+    /// `daemon` のsystemdユニットと、hidrawを使えるようにするudevルールを書き出す
+    InstallService(InstallServiceArgs),
+    /// hidrawの権限・udevルール・グループを確認し、locatorを開けない原因と対処を表示
+    Doctor(DoctorArgs),
+    /// スケジュールの確認
+    Schedule(ScheduleArgs),
+    /// `--audit-log` に記録したLEDの変更の確認
+    Audit(AuditArgs),
+    /// locatorのUSB情報とファームウェアのバージョン/対応機能を表示
+    Info(InfoArgs),
+    /// Intel HEXのファームウェアをブートローダー経由で書き込む
+    Update(UpdateArgs),
+    /// locatorのEEPROMへシリアル番号/名前を書き込む
+    Provision(ProvisionArgs),
+    /// 電源投入時のLEDマスク(EEPROMに保存)の確認/設定
+    Default(DefaultArgs),
+    /// locatorのボタン押下などのイベントを表示し、フックを実行する (Ctrl-Cで終了)
+    Listen(ListenArgs),
+    /// MQTTブローカーへlocatorの状態を出し、`…/set` トピックでLEDを操作できるようにする (Ctrl-Cで終了)
+    Mqtt(MqttArgs),
+    /// 別のマシンから `--remote http://...` でlocatorを操作できるようにする (Ctrl-Cで終了)
+    Serve(ServeArgs),
+    /// gRPCの LocatorService で他のマシンからlocatorを操作できるようにする (Ctrl-Cで終了)
+    #[cfg(feature = "grpc")]
+    GrpcServe(GrpcServeArgs),
 }
 
 #[derive(Args, Clone, Debug)]
@@ -36,10 +102,74 @@ pub struct StatusArgs {
     /// locator id (シリアル番号 or HIDデバイスパスの部分文字列)。未指定ならフィルタに一致する全てを表示
     #[arg(long)]
     pub id: Option<String>,
+    /// 行末に点灯しているLEDの名前 (`leds=`) と電源投入時のマスク (`default=`) も表示する。電源投入時のマスクの問い合わせが増える
+    #[arg(long)]
+    pub details: bool,
+    #[command(flatten)]
+    pub filter: FilterArgs,
+    #[command(flatten)]
+    pub protocol: ProtocolArgs,
+    #[command(flatten)]
+    pub lock: LockArgs,
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct InfoArgs {
+    /// locator id (シリアル番号 or HIDデバイスパスの部分文字列)。未指定ならフィルタに一致する全てを表示
+    #[arg(long)]
+    pub id: Option<String>,
+    #[command(flatten)]
+    pub filter: FilterArgs,
+    #[command(flatten)]
+    pub protocol: ProtocolArgs,
+    #[command(flatten)]
+    pub lock: LockArgs,
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct UpdateArgs {
+    /// locator id (シリアル番号 or HIDデバイスパスの部分文字列)。未指定ならフィルタで1台に絞れないとエラー
+    #[arg(long)]
+    pub id: Option<String>,
+    /// 書き込むファームウェア(Intel HEX)
+    pub file: PathBuf,
     #[command(flatten)]
     pub filter: FilterArgs,
     #[command(flatten)]
     pub protocol: ProtocolArgs,
+    #[command(flatten)]
+    pub lock: LockArgs,
+    /// 書き込みかベリファイに失敗したブロックを書き直す回数
+    #[arg(long, default_value_t = 3)]
+    pub retries: u32,
+    /// ブートローダー/アプリケーションとして再列挙されるのを待つ最大時間
+    #[arg(long, value_parser = parse_duration, default_value = "10s")]
+    pub reenumerate_timeout: Duration,
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct ProvisionArgs {
+    /// 書き込むlocatorのHIDデバイスパス (`list` の path)
+    #[arg(long, required_unless_present = "id", conflicts_with = "id")]
+    pub path: Option<String>,
+    /// locator id (シリアル番号 or HIDデバイスパスの部分文字列)。シリアル番号を付け替えるとき
+    #[arg(long)]
+    pub id: Option<String>,
+    /// 書き込むシリアル番号 (印字可能なASCII、最大24文字)
+    #[arg(long)]
+    pub serial: String,
+    /// 書き込む名前 (表示用)
+    #[arg(long)]
+    pub name: Option<String>,
+    /// 確認せずに書き込む
+    #[arg(long, short = 'y')]
+    pub yes: bool,
+    #[command(flatten)]
+    pub filter: FilterArgs,
+    #[command(flatten)]
+    pub protocol: ProtocolArgs,
+    #[command(flatten)]
+    pub lock: LockArgs,
 }
 
 #[derive(Args, Clone, Debug)]
@@ -51,15 +181,382 @@ pub struct SetArgs {
     pub filter: FilterArgs,
     #[command(flatten)]
     pub protocol: ProtocolArgs,
+    #[command(flatten)]
+    pub lock: LockArgs,
     /// RC2〜RC5/RA4のビットマスク(1=ON)でLED ON時に送る値
     #[arg(long, value_parser = parse_hex_or_dec_u8, default_value_t = 0x1f)]
     pub on_value: u8,
     /// RC2〜RC5/RA4のビットマスク(1=ON)でLED OFF時に送る値
     #[arg(long, value_parser = parse_hex_or_dec_u8, default_value_t = 0)]
     pub off_value: u8,
+    /// 指定時間(10m, 30s, 1h30mなど)だけ維持し、その後は元のマスクへ戻す。Ctrl-Cで即座に戻す
+    #[arg(long = "for", value_parser = parse_duration)]
+    pub hold_for: Option<Duration>,
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct ApplyArgs {
+    /// locator id → LED状態(`0x1f` / `on` / `off`)を並べたファイル。拡張子で形式を判定
+    pub file: PathBuf,
+    /// 計画を表示するだけで、LEDは変更しない
+    #[arg(long)]
+    pub dry_run: bool,
+    #[command(flatten)]
+    pub filter: FilterArgs,
+    #[command(flatten)]
+    pub protocol: ProtocolArgs,
+    #[command(flatten)]
+    pub lock: LockArgs,
+    /// `on` と書かれたlocatorに送るビットマスク
+    #[arg(long, value_parser = parse_hex_or_dec_u8, default_value_t = 0x1f)]
+    pub on_value: u8,
+    /// `off` と書かれたlocatorに送るビットマスク
+    #[arg(long, value_parser = parse_hex_or_dec_u8, default_value_t = 0)]
+    pub off_value: u8,
+    /// 宣言のキーに使うグループと `[aliases]` の名前
+    #[command(flatten)]
+    pub config: ConfigArgs,
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct SnapshotArgs {
+    #[command(subcommand)]
+    pub action: SnapshotAction,
+}
+
+#[derive(Subcommand, Clone, Debug)]
+pub enum SnapshotAction {
+    /// フィルタに一致する全locatorのLEDマスクをファイルへ保存
+    Save(SnapshotSaveArgs),
+    /// 保存したLEDマスクを各locatorへ書き戻す
+    Restore(SnapshotFileArgs),
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct SnapshotFileArgs {
+    /// スナップショットファイル(JSON)
+    pub file: PathBuf,
+    #[command(flatten)]
+    pub filter: FilterArgs,
+    #[command(flatten)]
+    pub protocol: ProtocolArgs,
+    #[command(flatten)]
+    pub lock: LockArgs,
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct SnapshotSaveArgs {
+    #[command(flatten)]
+    pub snapshot: SnapshotFileArgs,
+    /// 読めなかったlocatorがあっても、読めた分だけ保存する (終了コードは非0のまま)
+    #[arg(long)]
+    pub allow_partial: bool,
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct DaemonArgs {
+    #[command(flatten)]
+    pub config: ConfigArgs,
+    #[command(flatten)]
+    pub filter: FilterArgs,
+    #[command(flatten)]
+    pub protocol: ProtocolArgs,
+    #[command(flatten)]
+    pub lock: LockArgs,
+    /// locatorの列挙とスケジュール評価の間隔 (blinkの間隔以下にしてください)
+    #[arg(long, value_parser = parse_duration, default_value = "1s")]
+    pub interval: Duration,
+    /// 毎周LEDのマスクを読み、他のCLIなどによる変化も changed として扱う
+    #[arg(long)]
+    pub watch_status: bool,
+    /// 同時に実行するフックの上限
+    #[arg(long, default_value_t = 4)]
+    pub max_hooks: usize,
+    /// Prometheusの `/metrics` を出すアドレス (`127.0.0.1:9464` など)。指定するとステータスも毎周読む
+    #[arg(long, env = "CAP_LOCATOR_METRICS_LISTEN")]
+    pub metrics_listen: Option<String>,
+    /// 画面とWebSocket (イベントの配信と制御メッセージ) のアドレス (`127.0.0.1:9465` など)。指定するとステータスも毎周読む
+    #[arg(long, env = "CAP_LOCATOR_WS_LISTEN")]
+    pub ws_listen: Option<String>,
+    /// WebSocketのクライアントが `Authorization: Bearer` か `?token=` で送るトークン。設定ファイルの [[clients]] も使える
+    #[arg(long, env = "CAP_LOCATOR_WS_TOKEN", hide_env_values = true)]
+    pub ws_token: Option<String>,
+    /// WebSocketで `on` を受け取ったときに送るビットマスク
+    #[arg(long, value_parser = parse_hex_or_dec_u8, default_value_t = 0x1f)]
+    pub on_value: u8,
+    /// 停止するとき (Ctrl-C / SIGTERM) にLEDをどうするか
+    #[arg(long, value_enum, default_value_t = ExitAction::Keep)]
+    pub on_exit: ExitAction,
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct InstallServiceArgs {
+    /// ユニット名 (`<name>.service`、`<name>.socket`、`70-<name>.rules`)
+    #[arg(long, default_value = "cap-locator")]
+    pub name: String,
+    #[command(flatten)]
+    pub config: ConfigArgs,
+    /// udevルールとExecStartに使うvendor id/product id (未指定なら .env、それも無ければ各プロファイル)
+    #[command(flatten)]
+    pub filter: FilterArgs,
+    /// `daemon --ws-listen` に渡すアドレス
+    #[arg(long)]
+    pub ws_listen: Option<String>,
+    /// `--ws-listen` のポートを `<name>.socket` で待ち受け、接続が来たら起動する
+    #[arg(long, requires = "ws_listen")]
+    pub socket: bool,
+    /// `daemon --on-exit` に渡す値
+    #[arg(long, value_enum, default_value_t = ExitAction::Restore)]
+    pub on_exit: ExitAction,
+    /// hidrawの読み書きを許可するグループ
+    #[arg(long, default_value = "plugdev")]
+    pub group: String,
+    /// サービスを動かすユーザー。未指定ならroot
+    #[arg(long)]
+    pub user: Option<String>,
+    /// ユニットを書き出すディレクトリ
+    #[arg(long, default_value = "/etc/systemd/system")]
+    pub unit_dir: PathBuf,
+    /// udevルールを書き出すディレクトリ
+    #[arg(long, default_value = "/etc/udev/rules.d")]
+    pub udev_dir: PathBuf,
+    /// 書き出さずに内容を表示する
+    #[arg(long)]
+    pub print: bool,
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct DoctorArgs {
+    /// 確認するlocatorとudevルールのvendor id/product id (未指定なら .env、それも無ければ各プロファイル)
+    #[command(flatten)]
+    pub filter: FilterArgs,
+    /// udevルールで読み書きを許可するグループ
+    #[arg(long, default_value = "plugdev")]
+    pub group: String,
+    /// 確認せずにudevルールを表示する
+    #[arg(long, conflicts_with = "install")]
+    pub print_rule: bool,
+    /// udevルールを `<udev-dir>/70-<name>.rules` に書き出す
+    #[arg(long)]
+    pub install: bool,
+    /// udevルールのファイル名に使う名前 (`install-service --name` と同じ)
+    #[arg(long, default_value = "cap-locator")]
+    pub name: String,
+    /// udevルールを書き出すディレクトリ
+    #[arg(long, default_value = "/etc/udev/rules.d")]
+    pub udev_dir: PathBuf,
 }
 
 #[derive(Args, Clone, Debug)]
+pub struct DefaultArgs {
+    #[command(subcommand)]
+    pub action: DefaultAction,
+}
+
+#[derive(Subcommand, Clone, Debug)]
+pub enum DefaultAction {
+    /// 保存されている電源投入時のマスクを表示
+    Get(StatusArgs),
+    /// 電源投入時のマスクを保存し、読み戻して確認
+    Set(DefaultSetArgs),
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct DefaultSetArgs {
+    /// locator id (シリアル番号 or HIDデバイスパスの部分文字列)。未指定ならフィルタで1台に絞れないとエラー
+    #[arg(long)]
+    pub id: Option<String>,
+    /// 電源投入時に点灯させるRC2〜RC5/RA4のビットマスク
+    #[arg(value_parser = parse_hex_or_dec_u8)]
+    pub mask: u8,
+    #[command(flatten)]
+    pub filter: FilterArgs,
+    #[command(flatten)]
+    pub protocol: ProtocolArgs,
+    #[command(flatten)]
+    pub lock: LockArgs,
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct ListenArgs {
+    /// locator id (シリアル番号 or HIDデバイスパスの部分文字列)。未指定ならフィルタで1台に絞れないとエラー
+    #[arg(long)]
+    pub id: Option<String>,
+    #[command(flatten)]
+    pub filter: FilterArgs,
+    #[command(flatten)]
+    pub protocol: ProtocolArgs,
+    #[command(flatten)]
+    pub lock: LockArgs,
+    /// フックを実行するボタン番号。未指定ならすべてのボタン
+    #[arg(long)]
+    pub button: Option<u8>,
+    /// ボタンが押されたら実行するシェルコマンド (CAP_LOCATOR_ID / CAP_LOCATOR_EVENT / CAP_LOCATOR_BUTTON を渡す)
+    #[arg(long)]
+    pub exec: Option<String>,
+    /// ボタンが押されたらLEDを反転する (消灯中なら --on-value で点灯、点灯中なら消灯)
+    #[arg(long)]
+    pub toggle: bool,
+    /// `--toggle` で点灯するときのビットマスク
+    #[arg(long, value_parser = parse_hex_or_dec_u8, default_value_t = 0x1f)]
+    pub on_value: u8,
+    /// 指定件数のイベントを受け取ったら終了する
+    #[arg(long)]
+    pub count: Option<usize>,
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct MqttArgs {
+    /// ブローカーの `host:port`
+    #[arg(long, env = "CAP_LOCATOR_MQTT_BROKER", default_value = "localhost:1883")]
+    pub broker: String,
+    /// クライアントID。未指定なら `cap-locator-cli-<PID>`
+    #[arg(long)]
+    pub client_id: Option<String>,
+    #[arg(long, env = "CAP_LOCATOR_MQTT_USERNAME")]
+    pub username: Option<String>,
+    #[arg(long, env = "CAP_LOCATOR_MQTT_PASSWORD", hide_env_values = true)]
+    pub password: Option<String>,
+    /// トピックの先頭 (`<prefix>/<id>/state`, `<prefix>/<id>/set`, `<prefix>/availability`)
+    #[arg(long, env = "CAP_LOCATOR_MQTT_PREFIX", default_value = "cap-locator")]
+    pub topic_prefix: String,
+    /// keep alive (秒単位。この間に送信が無ければPINGREQを送る)
+    #[arg(long, value_parser = parse_duration, default_value = "30s")]
+    pub keep_alive: Duration,
+    /// 受信・送信するパケットの最大バイト数。これより大きいパケットが届いたら接続し直す
+    #[arg(long, default_value_t = DEFAULT_MAX_PACKET_SIZE)]
+    pub max_packet_size: usize,
+    /// locatorの列挙と状態の確認の間隔 (点滅中は切り替わりに合わせてこれより短くなる)
+    #[arg(long, value_parser = parse_duration, default_value = "1s")]
+    pub interval: Duration,
+    /// `on` を受け取ったときに送るビットマスク
+    #[arg(long, value_parser = parse_hex_or_dec_u8, default_value_t = 0x1f)]
+    pub on_value: u8,
+    #[command(flatten)]
+    pub filter: FilterArgs,
+    #[command(flatten)]
+    pub protocol: ProtocolArgs,
+    #[command(flatten)]
+    pub lock: LockArgs,
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct ServeArgs {
+    /// 待ち受けるアドレス
+    #[arg(long, env = "CAP_LOCATOR_SERVE_LISTEN", default_value = "127.0.0.1:7070")]
+    pub listen: String,
+    /// クライアントが `--remote-token` で送るトークン (全てのlocatorを操作できる)。設定ファイルの [[clients]] と併用可
+    #[arg(long, env = "CAP_LOCATOR_SERVE_TOKEN", hide_env_values = true)]
+    pub token: Option<String>,
+    /// この間使われなかったlocatorは閉じてロックを解放する (クライアントが異常終了したとき用)
+    #[arg(long, value_parser = parse_duration, default_value = "60s")]
+    pub idle_timeout: Duration,
+    #[command(flatten)]
+    pub auth: AuthArgs,
+    #[command(flatten)]
+    pub filter: FilterArgs,
+    #[command(flatten)]
+    pub lock: LockArgs,
+}
+
+#[cfg(feature = "grpc")]
+#[derive(Args, Clone, Debug)]
+pub struct GrpcServeArgs {
+    /// 待ち受けるアドレス
+    #[arg(long, env = "CAP_LOCATOR_GRPC_LISTEN", default_value = "127.0.0.1:50051")]
+    pub listen: String,
+    /// locatorの列挙と状態の確認の間隔 (StreamEventsで送る変化はこの間隔で検出する)
+    #[arg(long, value_parser = parse_duration, default_value = "1s")]
+    pub interval: Duration,
+    /// クライアントが `--remote-token` で送るトークン (全てのlocatorを操作できる)。設定ファイルの [[clients]] と併用可
+    #[arg(long, env = "CAP_LOCATOR_GRPC_TOKEN", hide_env_values = true)]
+    pub token: Option<String>,
+    /// サーバー証明書(PEM)。指定するとTLSで待ち受ける
+    #[arg(long, requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+    /// サーバー証明書の秘密鍵(PEM)
+    #[arg(long, requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+    /// クライアント証明書を検証するCA証明書(PEM)。指定するとクライアント証明書が必須になる (mTLS)
+    #[arg(long, requires = "tls_cert")]
+    pub client_ca: Option<PathBuf>,
+    #[command(flatten)]
+    pub auth: AuthArgs,
+    #[command(flatten)]
+    pub filter: FilterArgs,
+    #[command(flatten)]
+    pub protocol: ProtocolArgs,
+    #[command(flatten)]
+    pub lock: LockArgs,
+}
+
+/// `serve` / `grpc-serve` のクライアントごとの権限と要求数の制限
+#[derive(Args, Clone, Debug)]
+pub struct AuthArgs {
+    #[command(flatten)]
+    pub config: ConfigArgs,
+    /// クライアントごとの1秒あたりの要求数の上限 (0で制限しない)。[[clients]] の rate_limit が優先
+    #[arg(long, default_value_t = DEFAULT_RATE_LIMIT)]
+    pub rate_limit: f64,
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct ScheduleArgs {
+    #[command(subcommand)]
+    pub action: ScheduleAction,
+}
+
+#[derive(Subcommand, Clone, Debug)]
+pub enum ScheduleAction {
+    /// 今後のルール開始/終了を表示
+    List(ScheduleListArgs),
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct ScheduleListArgs {
+    #[command(flatten)]
+    pub config: ConfigArgs,
+    /// 表示する件数
+    #[arg(long, default_value_t = 10)]
+    pub limit: usize,
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct AuditArgs {
+    #[command(subcommand)]
+    pub action: AuditAction,
+}
+
+#[derive(Subcommand, Clone, Debug)]
+pub enum AuditAction {
+    /// 記録を古い順に表示
+    Show(AuditShowArgs),
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct AuditShowArgs {
+    /// locator id またはシリアル番号の部分文字列
+    #[arg(long)]
+    pub id: Option<String>,
+    /// この時刻以降 (RFC 3339、YYYY-MM-DD、または 24h のような「今からどれだけ前か」)
+    #[arg(long)]
+    pub since: Option<String>,
+    /// この時刻より前 (`--since` と同じ形式)
+    #[arg(long)]
+    pub until: Option<String>,
+    /// 新しいものからこの件数だけ表示
+    #[arg(long)]
+    pub limit: Option<usize>,
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct ConfigArgs {
+    /// 設定ファイル(TOML)。未指定ならカレントの cap-locator.toml を探す
+    #[arg(long, env = "CAP_LOCATOR_CONFIG")]
+    pub config: Option<PathBuf>,
+}
+
+#[derive(Args, Clone, Debug, Default)]
 pub struct FilterArgs {
     /// vendor id (0x1234のような16進 or 10進)
     #[arg(long, value_parser = parse_hex_or_dec_u16)]
@@ -73,14 +570,48 @@ pub struct FilterArgs {
     /// HID usageでのフィルタ
     #[arg(long, value_parser = parse_hex_or_dec_u16)]
     pub usage: Option<u16>,
+    /// 挿さっているUSBポートの経路 (`1-2.3` のようなbus-port.port。Linuxのみ)
+    #[arg(long, value_parser = parse_port_chain)]
+    pub port: Option<String>,
 }
 
 #[derive(Args, Clone, Debug)]
 pub struct ProtocolArgs {
-    /// 入出力レポートの長さ(バイト)。不足分は0埋めで送信します
-    #[arg(long, default_value_t = 64)]
-    pub report_len: usize,
+    /// 入出力レポートの長さ(バイト)。不足分は0埋めで送信します。未指定ならプロファイルの値(組み込みは64)
+    #[arg(long)]
+    pub report_len: Option<usize>,
     /// 入力レポートを待つタイムアウト(ms)
     #[arg(long, default_value_t = 1000)]
     pub read_timeout_ms: i32,
 }
+
+/// `grpc-serve` へTLSで接続するときの証明書
+#[derive(Args, Clone, Debug, Default)]
+pub struct RemoteTlsArgs {
+    /// `grpc-serve` のサーバー証明書を検証するCA証明書(PEM)。指定するとTLSで接続する
+    #[arg(long, env = "CAP_LOCATOR_REMOTE_CA", global = true)]
+    pub remote_ca: Option<PathBuf>,
+    /// mTLSで提示するクライアント証明書(PEM)
+    #[arg(long, env = "CAP_LOCATOR_REMOTE_CERT", global = true, requires = "remote_key")]
+    pub remote_cert: Option<PathBuf>,
+    /// クライアント証明書の秘密鍵(PEM)
+    #[arg(long, env = "CAP_LOCATOR_REMOTE_KEY", global = true, requires = "remote_cert")]
+    pub remote_key: Option<PathBuf>,
+}
+
+#[derive(Args, Clone, Debug, Default)]
+pub struct ProfileArgs {
+    /// 使うデバイスプロファイル(名前 or TOMLファイル)。未指定ならVID/PIDで自動選択
+    #[arg(long, env = "CAP_LOCATOR_PROFILE", global = true)]
+    pub profile: Option<String>,
+    /// 追加のプロファイル(*.toml)を置いたディレクトリ
+    #[arg(long, env = "CAP_LOCATOR_PROFILE_DIR", global = true)]
+    pub profile_dir: Option<PathBuf>,
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct LockArgs {
+    /// 他のCLIが同じlocatorを使用中のとき、ロック解放を待つ最大時間 (0で待たない)
+    #[arg(long, value_parser = parse_duration, default_value = "5s")]
+    pub lock_timeout: Duration,
+}
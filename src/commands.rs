@@ -1,123 +1,1794 @@
-use anyhow::{Context, Result, bail};
-use hidapi::HidApi;
+use std::collections::BTreeSet;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::io::{self, BufRead, Write};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use crate::cli::{ListArgs, SetArgs, StatusArgs};
+use anyhow::{Context, Result, anyhow, bail};
+use chrono::Local;
+use clap::ValueEnum;
+use tracing::{debug, debug_span, info_span, warn};
+
+use crate::apply::{DesiredState, resolve_targets};
+use crate::audit::{self, AuditQuery, AuditRecord, Change, parse_time_bound, read_audit_log};
+use crate::auth::{AccessControl, DEFAULT_RATE_LIMIT, Permission};
+use crate::backend::LocatorBackend;
+#[cfg(feature = "grpc")]
+use crate::cli::GrpcServeArgs;
+use crate::cli::{
+    ApplyArgs, AuditShowArgs, AuthArgs, Commands, DaemonArgs, DefaultSetArgs, DoctorArgs, FilterArgs, InfoArgs, InstallServiceArgs, ListArgs, ListenArgs, LockArgs,
+    MqttArgs, ProtocolArgs, ProvisionArgs, RemoteTlsArgs, ScheduleListArgs, ServeArgs, SetArgs, SnapshotFileArgs,
+    SnapshotSaveArgs, StatusArgs, UpdateArgs,
+};
+use crate::config::{Config, DEFAULT_CONFIG_PATH};
+use crate::daemon::{Daemon, DaemonEvent};
+use crate::doctor::{Access, Accounts, Credentials, NodeOwner, UDEV_RULE_DIRS, find_rules, probe};
 use crate::env_config::{EnvDefaults, merge_filter};
-use crate::hid::{pick_single_device, query_status, set_light, snapshot_devices};
-use crate::util::{format_bytes, format_usage};
+use crate::events::{LocatorEvent, ReceivedEvent, listen};
+use crate::hooks::{HookEvent, HookRunner, run_command};
+use crate::hid::{DeviceDescriptor, HidDeviceIo, IdQuery, LocatorStatus, pick_single_device, retain_id};
+use crate::firmware::{FirmwareImage, FlashProgress, HidBootloader, flash_blocks};
+#[cfg(feature = "grpc")]
+use crate::grpc::{GrpcClient, GrpcServer, ServeOptions, ServerTls};
+use crate::info::{Capability, CapabilityCache, format_release};
+use crate::metrics::{Metrics, serve_metrics};
+use crate::mqtt::{Message, MqttBridge, MqttClient, MqttOptions};
+use crate::lock::{DeviceLock, LockedDevice, lock_dir};
+use crate::profile::{DeviceProfile, IdentityField, ProfileRegistry, validate_identity};
+use crate::remote::{RemoteServer, ServeEvent};
+use crate::schedule::{Edge, Pattern, Scheduler};
+use crate::service::{Notifier, ServiceUnit, activated_listener, socket_unit, udev_rules, usb_ids};
+use crate::snapshot::{RestoreMatch, Snapshot, SnapshotEntry, match_entry};
+use crate::timer::{SystemClock, TimerOutcome, hold_then_restore, wait_for};
+use crate::util::{format_bytes, format_duration, format_usage};
+use crate::websocket::{Control, ControlAction, WS_PATH, WsServer};
 
 /// locator一覧をフィルタ付きで表示する
 ///
 /// - .envとCLI引数をマージして対象デバイスを抽出
 /// - 見つからなければその旨を標準出力に表示
-pub fn handle_list(api: &HidApi, args: &ListArgs, env: &EnvDefaults) -> Result<()> {
-    let filter = merge_filter(&args.filter, env);
-    let devices = snapshot_devices(api, &filter);
+pub fn handle_list(
+    backend: &dyn LocatorBackend,
+    profiles: &ProfileRegistry,
+    args: &ListArgs,
+    env: &EnvDefaults,
+) -> Result<()> {
+    let filter = profiles.fill_filter(merge_filter(&args.filter, env));
+    let devices = backend.devices(&filter)?;
     if devices.is_empty() {
         println!("locatorは見つかりませんでした");
         return Ok(());
     }
 
     for device in devices {
-        let locator_id = device.locator_id();
-        let serial = device
-            .serial_number
-            .clone()
-            .unwrap_or_else(|| "-".to_string());
-        let path = device.path.to_string_lossy();
-        println!(
-            "id={:<20} serial={:<20} vendor=0x{:04x} product=0x{:04x} usage={} path={}",
-            locator_id,
-            serial,
-            device.vendor_id,
-            device.product_id,
-            format_usage(device.usage_page, device.usage),
-            path
-        );
+        print_locator(&device, &profiles.for_device(&device).name);
     }
     Ok(())
 }
 
+/// `list` の1行。`--remote` でも同じ形で表示する
+pub(crate) fn print_locator(device: &DeviceDescriptor, profile_name: &str) {
+    let locator_id = device.locator_id();
+    let serial = device
+        .serial_number
+        .clone()
+        .unwrap_or_else(|| "-".to_string());
+    let path = device.path.to_string_lossy();
+    println!(
+        "id={:<20} serial={:<20} vendor=0x{:04x} product=0x{:04x} usage={} port={} profile={} path={}",
+        locator_id,
+        serial,
+        device.vendor_id,
+        device.product_id,
+        format_usage(device.usage_page, device.usage),
+        device.port.as_deref().unwrap_or("-"),
+        profile_name,
+        path
+    );
+    if !device.has_stable_id() {
+        eprintln!(
+            "warning: {} はシリアル番号もUSBポートの経路も無いため、idが再接続で変わるHIDパスです。`provision --path {} --serial SN-...` で設定してください",
+            locator_id, path
+        );
+    }
+}
+
 /// 対象locatorのLED点灯状態を問い合わせて表示する
 ///
 /// - .env/CLIのフィルタでデバイスを絞り込む
-/// - Output Reportでステータスコマンドを送信し、応答(先頭0xff)のLEDマスクで判定
-pub fn handle_status(api: &HidApi, args: &StatusArgs, env: &EnvDefaults) -> Result<()> {
-    let filter = merge_filter(&args.filter, env);
-    let mut devices = snapshot_devices(api, &filter);
+/// - locatorごとのプロファイルでステータスコマンドを送信し、応答のLEDマスクで判定
+pub fn handle_status(
+    backend: &dyn LocatorBackend,
+    profiles: &ProfileRegistry,
+    args: &StatusArgs,
+    env: &EnvDefaults,
+) -> Result<()> {
+    let filter = profiles.fill_filter(merge_filter(&args.filter, env));
+    let mut devices = backend.devices(&filter)?;
     if let Some(id) = args.id.as_deref().filter(|s| !s.is_empty()) {
-        devices.retain(|d| d.matches_id(id));
+        retain_id(&mut devices, id);
     }
 
     if devices.is_empty() {
         bail!("対象となるlocatorが見つかりませんでした");
     }
 
+    let mut capabilities = CapabilityCache::default();
     for device in devices {
         let locator_id = device.locator_id();
-        let handle = api
-            .open_path(device.path.as_c_str())
-            .with_context(|| format!("open device {}", locator_id))?;
+        let _span = info_span!("locator", id = %locator_id).entered();
+        let profile = profiles.for_device(&device);
+        let handle = open_locked(backend, &device, &args.lock)?;
 
         let status =
-            query_status(&handle, &args.protocol).with_context(|| {
+            profile.query_status(&handle, &args.protocol).with_context(|| {
                 format!(
                     "ステータス取得に失敗しました (id={})",
                     locator_id
                 )
             })?;
 
+        if args.details {
+            let default = stored_default_mask(profile, &device, &handle, &args.protocol, &mut capabilities);
+            println!(
+                "{}",
+                format_status_details(&locator_id, &profile.lit_leds(status.mask), &status, default)
+            );
+        } else {
+            println!("{}", format_status(&locator_id, &status));
+        }
+    }
+
+    Ok(())
+}
+
+/// 旧ファームウェアのタイムアウトで `status` が遅くならないよう、機能の問い合わせはこの時間で打ち切る
+const PROBE_TIMEOUT_MS: i32 = 200;
+
+/// 電源投入時のマスク。ファームウェアが対応していなければNone
+///
+/// - `status` の表示用なので、問い合わせに失敗してもエラーにせずNoneにする
+/// - プロファイルがバージョン問い合わせに対応していなければ問い合わせず、機能は `capabilities` に覚えておく
+pub(crate) fn stored_default_mask(
+    profile: &DeviceProfile,
+    device: &DeviceDescriptor,
+    handle: &dyn HidDeviceIo,
+    protocol: &ProtocolArgs,
+    capabilities: &mut CapabilityCache,
+) -> Option<u8> {
+    profile.commands.default_mask?;
+    profile.commands.info?;
+    let probe = ProtocolArgs {
+        read_timeout_ms: protocol.read_timeout_ms.min(PROBE_TIMEOUT_MS),
+        ..protocol.clone()
+    };
+    let result = capabilities
+        .get_or_query(device, || Ok(profile.query_info(handle, &probe)?.capabilities))
+        .and_then(|capabilities| {
+            if !capabilities.contains(Capability::DefaultMask) {
+                return Ok(None);
+            }
+            profile.query_default_mask(handle, protocol).map(Some)
+        });
+    result.unwrap_or_else(|err| {
+        warn!("電源投入時のマスクを取得できません: {:#}", err);
+        None
+    })
+}
+
+/// 電源投入時のマスクを表示する
+///
+/// - ファームウェアが default-mask に対応していないlocatorはエラー
+pub fn handle_default_get(
+    backend: &dyn LocatorBackend,
+    profiles: &ProfileRegistry,
+    args: &StatusArgs,
+    env: &EnvDefaults,
+) -> Result<()> {
+    let filter = profiles.fill_filter(merge_filter(&args.filter, env));
+    let mut devices = backend.devices(&filter)?;
+    if let Some(id) = args.id.as_deref().filter(|s| !s.is_empty()) {
+        retain_id(&mut devices, id);
+    }
+
+    if devices.is_empty() {
+        bail!("対象となるlocatorが見つかりませんでした");
+    }
+
+    for device in devices {
+        let locator_id = device.locator_id();
+        let _span = info_span!("locator", id = %locator_id).entered();
+        let profile = profiles.for_device(&device);
+        let handle = open_locked(backend, &device, &args.lock)?;
+        let mask = require_capability(profile, &handle, &args.protocol, Capability::DefaultMask)
+            .and_then(|_| profile.query_default_mask(&handle, &args.protocol))
+            .with_context(|| {
+                format!(
+                    "電源投入時のマスクを取得できません (id={})",
+                    locator_id
+                )
+            })?;
+        println!("id={:<20} default=0x{:02x}", locator_id, mask);
+    }
+
+    Ok(())
+}
+
+/// 電源投入時のマスクを保存する
+///
+/// - 1台に絞れないとエラー
+/// - 保存後に読み戻し、一致しなければエラー
+pub fn handle_default_set(
+    backend: &dyn LocatorBackend,
+    profiles: &ProfileRegistry,
+    args: &DefaultSetArgs,
+    env: &EnvDefaults,
+) -> Result<()> {
+    let filter = profiles.fill_filter(merge_filter(&args.filter, env));
+    let device = pick_single_device(backend, &filter, args.id.as_deref())?;
+    let locator_id = device.locator_id();
+    let _span = info_span!("locator", id = %locator_id).entered();
+    let profile = profiles.for_device(&device);
+    let handle = open_locked(backend, &device, &args.lock)?;
+    require_capability(profile, &handle, &args.protocol, Capability::DefaultMask)
+        .and_then(|_| profile.set_default_mask(&handle, &args.protocol, args.mask))
+        .with_context(|| {
+            format!(
+                "電源投入時のマスクを保存できません (id={})",
+                locator_id
+            )
+        })?;
+    println!("id={:<20} default=0x{:02x} (保存しました)", locator_id, args.mask);
+    Ok(())
+}
+
+/// ファームウェアが機能に対応しているか問い合わせ、対応していなければエラーにする
+fn require_capability(
+    profile: &DeviceProfile,
+    handle: &dyn HidDeviceIo,
+    protocol: &ProtocolArgs,
+    capability: Capability,
+) -> Result<()> {
+    profile.query_info(handle, protocol)?.require(capability)
+}
+
+/// locatorのUSB情報とファームウェアのバージョン/対応機能を表示する
+///
+/// - Manufacturer/Product文字列とリリース番号はHIDの列挙結果から表示
+/// - バージョン問い合わせに応答しない旧ファームウェアは version=unknown とし、ステータス取得/LED設定だけに対応とみなす
+pub fn handle_info(
+    backend: &dyn LocatorBackend,
+    profiles: &ProfileRegistry,
+    args: &InfoArgs,
+    env: &EnvDefaults,
+) -> Result<()> {
+    let filter = profiles.fill_filter(merge_filter(&args.filter, env));
+    let mut devices = backend.devices(&filter)?;
+    if let Some(id) = args.id.as_deref().filter(|s| !s.is_empty()) {
+        retain_id(&mut devices, id);
+    }
+
+    if devices.is_empty() {
+        bail!("対象となるlocatorが見つかりませんでした");
+    }
+
+    for device in devices {
+        let locator_id = device.locator_id();
+        let _span = info_span!("locator", id = %locator_id).entered();
+        let profile = profiles.for_device(&device);
+        let handle = open_locked(backend, &device, &args.lock)?;
+        let info = profile.query_info(&handle, &args.protocol).with_context(|| {
+            format!(
+                "バージョン取得に失敗しました (id={})",
+                locator_id
+            )
+        })?;
+
+        println!("id={}", locator_id);
+        println!("  manufacturer={}", device.manufacturer.as_deref().unwrap_or("-"));
+        println!("  product={}", device.product.as_deref().unwrap_or("-"));
         println!(
-            "id={:<20} status={} mask=0x{mask:02x} raw=[{raw}]",
-            locator_id,
-            if status.is_on { "on " } else { "off" },
-            mask = status.mask,
-            raw = format_bytes(&status.raw)
+            "  release={} (0x{:04x})",
+            format_release(device.release_number),
+            device.release_number
         );
+        println!("  vendor=0x{:04x} product_id=0x{:04x}", device.vendor_id, device.product_id);
+        println!("  profile={}", profile.name);
+        match info.version {
+            Some(_) => println!("  firmware={}", info.version_string()),
+            None => println!("  firmware=unknown (バージョン問い合わせに未対応)"),
+        }
+        println!("  capabilities={}", info.capabilities);
+        println!("  commands={}", info.capabilities.commands().join(", "));
     }
 
     Ok(())
 }
 
+/// 再列挙を確認する間隔
+const REENUMERATE_POLL: Duration = Duration::from_millis(250);
+
+/// ファームウェアをブートローダー経由で書き込む
+///
+/// - HEXを解析し、プロファイルの書き込み範囲でブロックに分ける(ブートローダー領域を含むHEXは拒否)
+/// - 対象locatorがブートローダーに対応しているか確認してから切り替え、ブートローダーとして再列挙されるのを待つ
+/// - 各ブロックを書き込んでベリファイし、最後にアプリケーションを起動して新しいバージョンを表示
+/// - 対象が見つからずブートローダーのデバイスだけが居る場合は、中断した更新の続きとして書き込む
+pub fn handle_update(
+    backend: &mut dyn LocatorBackend,
+    profiles: &ProfileRegistry,
+    args: &UpdateArgs,
+    env: &EnvDefaults,
+) -> Result<()> {
+    let image = FirmwareImage::load(&args.file)?;
+    let filter = profiles.fill_filter(merge_filter(&args.filter, env));
+    let mut candidates = backend.devices(&filter)?;
+    if let Some(id) = args.id.as_deref().filter(|s| !s.is_empty()) {
+        retain_id(&mut candidates, id);
+    }
+    if candidates.len() > 1 {
+        bail!("複数のlocatorが見つかりました。idを指定するか、vendor/productやusageで絞り込んでください");
+    }
+
+    // ブートローダーに入る前に、書き込めるイメージか確かめておく
+    let (profile, serial, blocks) = match candidates.pop() {
+        Some(device) => {
+            let locator_id = device.locator_id();
+            let _span = info_span!("locator", id = %locator_id).entered();
+            let profile = profiles.for_device(&device);
+            let layout = profile
+                .bootloader
+                .as_ref()
+                .ok_or_else(|| anyhow!("プロファイル {} はファームウェア更新に対応していません", profile.name))?;
+            let blocks = image.blocks(layout)?;
+
+            let handle = open_locked(&*backend, &device, &args.lock)?;
+            let info = profile.query_info(&handle, &args.protocol).with_context(|| {
+                format!(
+                    "バージョン取得に失敗しました (id={})",
+                    locator_id
+                )
+            })?;
+            info.require(Capability::Bootloader)?;
+            println!("current: id={} firmware={}", locator_id, info.version_string());
+            profile.enter_bootloader(&handle, &args.protocol)?;
+            (profile, device.serial_number.clone(), blocks)
+        }
+        None => {
+            println!("対象のlocatorが見つからないため、ブートローダーのデバイスを探して続きから書き込みます");
+            let device = wait_for_device(backend, Duration::ZERO, "ブートローダー", |d| {
+                profiles.for_bootloader(d).is_some()
+            })?;
+            let profile = profiles.for_bootloader(&device).expect("ブートローダーとして一致済み");
+            let layout = profile.bootloader.as_ref().expect("ブートローダーとして一致済み");
+            (profile, None, image.blocks(layout)?)
+        }
+    };
+    let layout = profile.bootloader.as_ref().expect("ブートローダー対応を確認済み");
+
+    let device = wait_for_device(backend, args.reenumerate_timeout, "ブートローダー", |d| {
+        d.vendor_id == layout.vendor_id && d.product_id == layout.product_id
+    })?;
+    println!(
+        "bootloader: path={} blocks={} range=0x{:08x}-0x{:08x}",
+        device.path.to_string_lossy(),
+        blocks.len(),
+        layout.app_start,
+        layout.app_end
+    );
+    {
+        let handle = open_locked(&*backend, &device, &args.lock)?;
+        let bootloader = HidBootloader::new(
+            &handle,
+            profile.report_len(&args.protocol),
+            profile.response.header,
+            args.protocol.read_timeout_ms,
+        );
+        if layout.block_size > bootloader.max_block_len() {
+            bail!(
+                "block_size {} がレポートに収まりません (--report-len を{}以上にしてください)",
+                layout.block_size,
+                layout.block_size + 6
+            );
+        }
+
+        let mut on_progress = |progress: &FlashProgress| match progress {
+            FlashProgress::Skipped { index, total, address }
+            | FlashProgress::Written { index, total, address } => {
+                eprint!(
+                    "\r  {:>3}% {}/{} 0x{:08x}",
+                    (index + 1) * 100 / total,
+                    index + 1,
+                    total,
+                    address
+                );
+            }
+            FlashProgress::Retry { address, attempt, error } => {
+                eprintln!("\n  retry 0x{:08x} ({}/{}): {}", address, attempt, args.retries, error);
+            }
+        };
+        let report = flash_blocks(&bootloader, &blocks, args.retries, &mut on_progress);
+        eprintln!();
+        let report = report.map_err(|err| {
+            anyhow!(
+                "{}\nデバイスはブートローダーのまま残っています。同じコマンドを再実行すると、書き込み済みのブロックを飛ばして続きから書き込みます",
+                err
+            )
+        })?;
+        println!(
+            "flashed: written={} skipped={} retries={}",
+            report.written, report.skipped, report.retries
+        );
+        bootloader.reset()?;
+    }
+
+    let id = args.id.as_deref().filter(|s| !s.is_empty());
+    let device = wait_for_device(backend, args.reenumerate_timeout, "更新後のlocator", |d| {
+        profile.matches(d)
+            && match (&serial, id) {
+                (Some(serial), _) => d.serial_number.as_ref() == Some(serial),
+                (None, Some(id)) => d.matches_id(id),
+                (None, None) => true,
+            }
+    })?;
+    let locator_id = device.locator_id();
+    let handle = open_locked(&*backend, &device, &args.lock)?;
+    let info = profile.query_info(&handle, &args.protocol).with_context(|| {
+        format!(
+            "更新後のバージョン取得に失敗しました (id={})",
+            locator_id
+        )
+    })?;
+    println!("updated: id={} firmware={}", locator_id, info.version_string());
+    Ok(())
+}
+
+/// 条件に一致するデバイスが1台だけ列挙されるまで待つ
+fn wait_for_device(
+    backend: &mut dyn LocatorBackend,
+    timeout: Duration,
+    what: &str,
+    matches: impl Fn(&DeviceDescriptor) -> bool,
+) -> Result<DeviceDescriptor> {
+    let started = Instant::now();
+    loop {
+        backend.refresh()?;
+        let mut found: Vec<_> = backend
+            .devices(&FilterArgs::default())?
+            .into_iter()
+            .filter(|d| matches(d))
+            .collect();
+        match found.len() {
+            1 => return Ok(found.remove(0)),
+            0 => {}
+            _ => bail!("{}が複数見つかりました。1台ずつ更新してください", what),
+        }
+        if started.elapsed() >= timeout {
+            bail!("{}が{}以内に見つかりませんでした", what, format_duration(timeout));
+        }
+        thread::sleep(REENUMERATE_POLL);
+    }
+}
+
+/// locatorのEEPROMへシリアル番号/名前を書き込む
+///
+/// - `--path` (またはシリアル付け替え時は `--id`) で1台に絞り、ファームウェアが provision に対応しているか確認
+/// - 同じシリアル番号のlocatorが既に接続されていれば書き込まない
+/// - 確認(`--yes` で省略)してから書き込み、読み戻して一致することを確かめる
+pub fn handle_provision(
+    backend: &dyn LocatorBackend,
+    profiles: &ProfileRegistry,
+    args: &ProvisionArgs,
+    env: &EnvDefaults,
+) -> Result<()> {
+    validate_identity(IdentityField::Serial, &args.serial)?;
+    if let Some(name) = &args.name {
+        validate_identity(IdentityField::Name, name)?;
+    }
+
+    let filter = profiles.fill_filter(merge_filter(&args.filter, env));
+    let devices = backend.devices(&filter)?;
+    let query = args.id.as_deref().map(|id| IdQuery::resolve(&devices, id));
+    let mut candidates: Vec<_> = devices
+        .iter()
+        .filter(|d| match (&args.path, &query) {
+            (Some(path), _) => d.path.to_string_lossy() == path.as_str(),
+            (None, Some(query)) => query.selects(d),
+            (None, None) => false,
+        })
+        .cloned()
+        .collect();
+    let device = match candidates.len() {
+        0 => bail!("一致するlocatorがありませんでした: {}", args.path.as_deref().or(args.id.as_deref()).unwrap_or("")),
+        1 => candidates.remove(0),
+        _ => bail!("複数のlocatorが一致しました。--path で指定してください"),
+    };
+    if let Some(other) = devices
+        .iter()
+        .find(|d| d.path != device.path && d.serial_number.as_deref() == Some(args.serial.as_str()))
+    {
+        bail!(
+            "シリアル番号 {} は既に別のlocatorが使っています (path={})",
+            args.serial,
+            other.path.to_string_lossy()
+        );
+    }
+
+    let locator_id = device.locator_id();
+    let _span = info_span!("locator", id = %locator_id).entered();
+    let profile = profiles.for_device(&device);
+    let handle = open_locked(backend, &device, &args.lock)?;
+    let info = profile.query_info(&handle, &args.protocol).with_context(|| {
+        format!(
+            "バージョン取得に失敗しました (id={})",
+            locator_id
+        )
+    })?;
+    info.require(Capability::Provision)?;
+    let current = profile.read_identity(&handle, &args.protocol, IdentityField::Serial)?;
+
+    println!(
+        "provision: path={} serial {} -> {}{}",
+        device.path.to_string_lossy(),
+        if current.is_empty() { "-" } else { current.as_str() },
+        args.serial,
+        args.name
+            .as_deref()
+            .map(|name| format!(" name={}", name))
+            .unwrap_or_default()
+    );
+    if !args.yes && !confirm("EEPROMへ書き込みますか?", &mut io::stdin().lock())? {
+        println!("中止しました");
+        return Ok(());
+    }
+
+    let mut fields = vec![(IdentityField::Serial, args.serial.as_str())];
+    if let Some(name) = &args.name {
+        fields.push((IdentityField::Name, name.as_str()));
+    }
+    for (field, value) in fields {
+        profile
+            .write_identity(&handle, &args.protocol, field, value)
+            .with_context(|| format!("{} の書き込みに失敗しました (id={})", field.name(), locator_id))?;
+        let written = profile.read_identity(&handle, &args.protocol, field)?;
+        if written != value {
+            bail!(
+                "{} の読み戻しが一致しません (書き込み={} 読み戻し={})",
+                field.name(),
+                value,
+                written
+            );
+        }
+        println!("written: {}={}", field.name(), written);
+    }
+    println!("再接続するとUSBのシリアル番号として反映され、`--id {}` で指定できます", args.serial);
+    Ok(())
+}
+
+/// y/N の確認。y/yes 以外は中止
+fn confirm(prompt: &str, input: &mut dyn BufRead) -> Result<bool> {
+    print!("{} [y/N] ", prompt);
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    input.read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_ascii_lowercase().as_str(), "y" | "yes"))
+}
+
 /// 単一のlocatorに対してLED点灯/消灯コマンドを送信する
 ///
 /// - .env/CLIのフィルタでデバイスを検索し、1件に絞れないとエラー
 /// - Output ReportでON/OFF値を送信し、成功したら結果を表示
-pub fn handle_set(api: &HidApi, args: &SetArgs, env: &EnvDefaults, turn_on: bool) -> Result<()> {
-    let filter = merge_filter(&args.filter, env);
-    let device = pick_single_device(api, &filter, args.id.as_deref())?;
+/// - `--for` 指定時は変更前のマスクを覚えておき、時間経過かCtrl-Cで元に戻す
+pub fn handle_set(
+    backend: &dyn LocatorBackend,
+    profiles: &ProfileRegistry,
+    args: &SetArgs,
+    env: &EnvDefaults,
+    turn_on: bool,
+) -> Result<()> {
+    let filter = profiles.fill_filter(merge_filter(&args.filter, env));
+    let device = pick_single_device(backend, &filter, args.id.as_deref())?;
     let locator_id = device.locator_id();
-    let handle = api
-        .open_path(device.path.as_c_str())
-        .with_context(|| format!("open device {}", locator_id))?;
+    let profile = profiles.for_device(&device);
+    let _span = info_span!("locator", id = %locator_id, turn_on).entered();
+    let handle = open_locked(backend, &device, &args.lock)?;
 
-    set_light(
-        &handle,
-        &args.protocol,
-        turn_on,
-        args.on_value,
-        args.off_value,
-    )
-    .with_context(|| {
+    let previous = match args.hold_for {
+        Some(_) => Some(profile.query_status(&handle, &args.protocol).with_context(|| {
+            format!(
+                "変更前のステータス取得に失敗しました (id={})",
+                locator_id
+            )
+        })?),
+        None => None,
+    };
+
+    // 書き込んだ後にハンドラを設定すると、その間のCtrl-Cでは元に戻さずに終了してしまう
+    let cancel = Arc::new(AtomicBool::new(false));
+    if args.hold_for.is_some() {
+        let flag = Arc::clone(&cancel);
+        ctrlc::set_handler(move || flag.store(true, Ordering::SeqCst))
+            .context("Ctrl-Cハンドラを設定できません")?;
+    }
+
+    let mask = if turn_on { args.on_value } else { args.off_value };
+    let change = Change::cli(if turn_on { "on" } else { "off" }).previous(previous.as_ref().map(|status| status.mask));
+    audit::set_mask(profile, &handle, &args.protocol, &device, mask, &change)
+        .with_context(|| {
         format!(
             "LED制御に失敗しました (id={})",
             locator_id
         )
     })?;
 
-    let status = query_status(&handle, &args.protocol).with_context(|| {
+    let status = profile.query_status(&handle, &args.protocol).with_context(|| {
         format!(
             "ステータス取得に失敗しました (id={})",
             locator_id
         )
     })?;
+    print_status(&locator_id, &status);
+
+    // 待っている間は他のCLIが操作できるようにロックを解放する
+    drop(handle);
+    let (Some(duration), Some(previous)) = (args.hold_for, previous) else {
+        return Ok(());
+    };
 
     println!(
+        "{}後に mask=0x{:02x} へ戻します (Ctrl-Cで即座に戻します)",
+        format_duration(duration),
+        previous.mask
+    );
+    let (outcome, restored) = hold_then_restore(
+        || open_locked(backend, &device, &args.lock),
+        profile,
+        &device,
+        &args.protocol,
+        previous.mask,
+        duration,
+        &SystemClock,
+        &cancel,
+    )
+    .with_context(|| {
+        format!(
+            "元の状態へ戻せませんでした (id={} mask=0x{:02x})",
+            locator_id, previous.mask
+        )
+    })?;
+    if outcome == TimerOutcome::Cancelled {
+        println!("中断されたため元の状態へ戻しました");
+    }
+    print_status(&locator_id, &restored);
+    Ok(())
+}
+
+/// locatorのイベントを表示し、ボタンが押されたらフックを実行する
+///
+/// - 待っている間は他のCLIが操作できるようにロックを持たず、`--toggle` のときだけ取得する
+/// - フックが失敗しても表示して待ち続ける
+pub fn handle_listen(
+    backend: &dyn LocatorBackend,
+    profiles: &ProfileRegistry,
+    args: &ListenArgs,
+    env: &EnvDefaults,
+) -> Result<()> {
+    let filter = profiles.fill_filter(merge_filter(&args.filter, env));
+    let device = pick_single_device(backend, &filter, args.id.as_deref())?;
+    let locator_id = device.locator_id();
+    let _span = info_span!("locator", id = %locator_id).entered();
+    let profile = profiles.for_device(&device);
+    if profile.response.event_header.is_none() {
+        bail!("プロファイル {} はイベントに対応していません", profile.name);
+    }
+    {
+        let handle = open_locked(backend, &device, &args.lock)?;
+        require_capability(profile, &handle, &args.protocol, Capability::Events)
+            .with_context(|| format!("イベントを受け取れません (id={})", locator_id))?;
+    }
+
+    let handle = backend.open(&device)?;
+    let cancel = Arc::new(AtomicBool::new(false));
+    let flag = Arc::clone(&cancel);
+    ctrlc::set_handler(move || flag.store(true, Ordering::SeqCst))
+        .context("Ctrl-Cハンドラを設定できません")?;
+
+    println!("id={} のイベントを待っています (Ctrl-Cで終了)", locator_id);
+    listen(
+        profile,
+        &handle,
+        profile.report_len(&args.protocol),
+        &cancel,
+        args.count,
+        &mut |received| {
+            let timestamp = Local::now().to_rfc3339();
+            println!(
+                "[{}] {:<8} id={} button={} raw=[{}]",
+                timestamp,
+                received.event.name(),
+                locator_id,
+                received.event.button().map_or("-".to_string(), |b| b.to_string()),
+                format_bytes(&received.raw)
+            );
+            if let Err(err) = run_button_hooks(backend, &device, profile, args, received) {
+                println!("[{}] hook-failed id={} error={:#}", timestamp, locator_id, err);
+            }
+        },
+    )
+    .with_context(|| format!("イベントの受信に失敗しました (id={})", locator_id))?;
+    Ok(())
+}
+
+/// `--toggle` / `--exec` をボタン押下で実行する
+fn run_button_hooks(
+    backend: &dyn LocatorBackend,
+    device: &DeviceDescriptor,
+    profile: &DeviceProfile,
+    args: &ListenArgs,
+    received: &ReceivedEvent,
+) -> Result<()> {
+    let LocatorEvent::Pressed { button } = received.event else {
+        return Ok(());
+    };
+    if args.button.is_some_and(|wanted| wanted != button) {
+        return Ok(());
+    }
+
+    if args.toggle {
+        let handle = open_locked(backend, device, &args.lock)?;
+        let status = profile.query_status(&handle, &args.protocol)?;
+        let mask = if status.is_on { 0 } else { args.on_value };
+        let change = Change::cli("toggle").previous(Some(status.mask));
+        audit::set_mask(profile, &handle, &args.protocol, device, mask, &change)?;
+        debug!(from = status.mask, to = mask, "ボタン押下でLEDを反転");
+    }
+    if let Some(command) = &args.exec {
+        run_command(
+            command,
+            &[
+                ("CAP_LOCATOR_ID", device.locator_id()),
+                ("CAP_LOCATOR_EVENT", received.event.name().to_string()),
+                ("CAP_LOCATOR_BUTTON", button.to_string()),
+            ],
+            None,
+        )?;
+    }
+    Ok(())
+}
+
+/// MQTTブリッジ
+///
+/// - 接続できない・切断されたときは `--interval` ごとに繋ぎ直す
+/// - 終了時は availability を offline にしてから切断する。異常終了時はlast willで offline になる
+pub fn handle_mqtt(
+    backend: &mut dyn LocatorBackend,
+    profiles: &ProfileRegistry,
+    args: &MqttArgs,
+    env: &EnvDefaults,
+) -> Result<()> {
+    let filter = profiles.fill_filter(merge_filter(&args.filter, env));
+    let mut bridge = MqttBridge::new(&args.topic_prefix, profiles.clone(), args.on_value);
+    let availability = bridge.availability_topic();
+    let options = MqttOptions {
+        broker: args.broker.clone(),
+        client_id: args
+            .client_id
+            .clone()
+            .unwrap_or_else(|| format!("cap-locator-cli-{}", std::process::id())),
+        username: args.username.clone(),
+        password: args.password.clone(),
+        keep_alive: args.keep_alive,
+        will: Some(Message::new(availability.as_str(), "offline", true)),
+        max_packet_size: args.max_packet_size,
+    };
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let flag = Arc::clone(&stop);
+    ctrlc::set_handler(move || flag.store(true, Ordering::SeqCst))
+        .context("Ctrl-Cハンドラを設定できません")?;
+
+    while !stop.load(Ordering::SeqCst) {
+        let mut client = match MqttClient::connect(&options) {
+            Ok(client) => client,
+            Err(err) => {
+                println!("[{}] mqtt-connect-failed error={:#}", Local::now().to_rfc3339(), err);
+                wait_for(&SystemClock, args.interval, &stop);
+                continue;
+            }
+        };
+        println!("[{}] mqtt-connected broker={}", Local::now().to_rfc3339(), args.broker);
+        bridge.forget_published();
+        match run_mqtt_session(backend, &filter, &mut bridge, &mut client, args, &stop) {
+            Ok(()) => {
+                client.publish(&Message::new(availability.as_str(), "offline", true))?;
+                client.disconnect()?;
+            }
+            Err(err) => {
+                println!("[{}] mqtt-disconnected error={:#}", Local::now().to_rfc3339(), err);
+                wait_for(&SystemClock, args.interval, &stop);
+            }
+        }
+    }
+    println!("停止しました");
+    Ok(())
+}
+
+/// 1回の接続の間の処理。`stop` が立ったらOk、接続が切れたらErr
+fn run_mqtt_session(
+    backend: &mut dyn LocatorBackend,
+    filter: &FilterArgs,
+    bridge: &mut MqttBridge,
+    client: &mut MqttClient,
+    args: &MqttArgs,
+    stop: &AtomicBool,
+) -> Result<()> {
+    client.subscribe(&bridge.set_filter())?;
+    client.publish(&Message::new(bridge.availability_topic(), "online", true))?;
+
+    while !stop.load(Ordering::SeqCst) {
+        let tick_started = Instant::now();
+        if let Err(err) = backend.refresh() {
+            warn!("locatorの再列挙に失敗しました: {:#}", err);
+        }
+        match backend.devices(filter) {
+            Ok(devices) => {
+                let messages = bridge.tick(&Local::now(), &devices, &args.protocol, &mut |device| {
+                    Ok(Box::new(open_locked(&*backend, device, &args.lock)?) as Box<dyn HidDeviceIo>)
+                });
+                for message in messages {
+                    client.publish(&message)?;
+                }
+            }
+            Err(err) => warn!("locatorを列挙できません: {:#}", err),
+        }
+
+        // 次の周までは `…/set` を待つ。受け取ったらすぐ反映する
+        // 点滅中は切り替わりを逃さないよう、`--interval` より先に来る切り替わりの時刻で次の周にする
+        let wait = bridge.next_tick(&Local::now(), args.interval.saturating_sub(tick_started.elapsed()));
+        let next = Instant::now() + wait;
+        while !stop.load(Ordering::SeqCst) {
+            let Some(remaining) = next.checked_duration_since(Instant::now()).filter(|left| !left.is_zero()) else {
+                break;
+            };
+            let Some(message) = client.poll(remaining.min(Duration::from_millis(200)))? else {
+                continue;
+            };
+            match bridge.handle_message(&message) {
+                Ok(Some(id)) => {
+                    println!(
+                        "[{}] mqtt-set id={} payload={}",
+                        Local::now().to_rfc3339(),
+                        id,
+                        String::from_utf8_lossy(&message.payload)
+                    );
+                    break;
+                }
+                Ok(None) => {}
+                Err(err) => println!("[{}] mqtt-set-rejected error={:#}", Local::now().to_rfc3339(), err),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// ロックを取得してからlocatorを開く。ハンドルをDropするまで他のCLIは同じlocatorを操作できない
+///
+/// `--remote` のlocatorは `serve` 側がロックするので、このマシンではロックしない
+pub(crate) fn open_locked(
+    backend: &dyn LocatorBackend,
+    device: &DeviceDescriptor,
+    lock: &LockArgs,
+) -> Result<LockedDevice<Box<dyn HidDeviceIo>>> {
+    let locator_id = device.locator_id();
+    let _span = debug_span!("open", id = %locator_id).entered();
+    let started = Instant::now();
+    if !backend.locks_locally() {
+        let handle = backend.open(device)?;
+        debug!(elapsed_us = started.elapsed().as_micros() as u64, "オープン完了 (ロックはリモート側)");
+        return Ok(LockedDevice::remote(handle));
+    }
+    let guard = DeviceLock::acquire(&lock_dir()?, device, lock.lock_timeout)?;
+    debug!(lock = %guard.path().display(), waited_us = started.elapsed().as_micros() as u64, "ロック取得");
+    let handle = backend.open(device)?;
+    debug!(elapsed_us = started.elapsed().as_micros() as u64, "オープン完了");
+    Ok(LockedDevice::new(handle, guard))
+}
+
+fn print_status(locator_id: &str, status: &LocatorStatus) {
+    println!("{}", format_status(locator_id, status));
+}
+
+/// `status` / `on` / `off` の1行
+pub(crate) fn format_status(locator_id: &str, status: &LocatorStatus) -> String {
+    format!(
         "id={:<20} status={} mask=0x{mask:02x} raw=[{raw}]",
         locator_id,
         if status.is_on { "on " } else { "off" },
         mask = status.mask,
         raw = format_bytes(&status.raw)
+    )
+}
+
+/// `status --details` の1行。`format_status` の行末に点灯しているLEDの名前 (`lit`) と電源投入時のマスクを足す
+pub(crate) fn format_status_details(
+    locator_id: &str,
+    lit: &[&str],
+    status: &LocatorStatus,
+    default: Option<u8>,
+) -> String {
+    format!(
+        "{} leds={} default={}",
+        format_status(locator_id, status),
+        if lit.is_empty() { "-".to_string() } else { lit.join(",") },
+        default.map_or("-".to_string(), |mask| format!("0x{:02x}", mask))
+    )
+}
+
+/// 宣言ファイルのLED状態へlocatorを揃える
+///
+/// - 各locatorの現在のマスクを問い合わせて差分(計画)を表示
+/// - `--dry-run` でなければ差分のあるlocatorにだけ点灯/消灯コマンドを送り、結果を再確認
+/// - 接続されていない宣言(missing)と宣言の無いlocator(unknown)は別枠で表示
+pub fn handle_apply(
+    backend: &dyn LocatorBackend,
+    profiles: &ProfileRegistry,
+    args: &ApplyArgs,
+    env: &EnvDefaults,
+) -> Result<()> {
+    let filter = profiles.fill_filter(merge_filter(&args.filter, env));
+    let desired = DesiredState::load(&args.file)?;
+    let config = Config::load(args.config.config.as_deref())?;
+    let devices = backend.devices(&filter)?;
+    let resolution = resolve_targets(&desired, &devices, &config, args.on_value, args.off_value)?;
+
+    let mut failures = 0usize;
+    let mut planned = Vec::new();
+    println!("plan:");
+    for (device, desired_mask) in resolution.targets {
+        let locator_id = device.locator_id();
+        let _span = info_span!("locator", id = %locator_id).entered();
+        let profile = profiles.for_device(&device);
+        let current = open_locked(backend, &device, &args.lock)
+            .and_then(|handle| {
+                let status = profile.query_status(&handle, &args.protocol)?;
+                Ok((handle, status))
+            });
+        match current {
+            Ok((handle, status)) => {
+                let action = if status.mask == desired_mask { "keep" } else { "set" };
+                println!(
+                    "  id={:<20} current=0x{:02x} desired=0x{:02x} action={}",
+                    locator_id, status.mask, desired_mask, action
+                );
+                if status.mask != desired_mask {
+                    planned.push((device, profile, handle, desired_mask, status.mask));
+                }
+            }
+            Err(err) => {
+                failures += 1;
+                println!("  id={:<20} error={:#}", locator_id, err);
+            }
+        }
+    }
+
+    for query in &resolution.missing {
+        println!("missing: {} (一致するlocatorが接続されていません)", query);
+    }
+    for (query, matched) in &resolution.ambiguous {
+        failures += 1;
+        println!("ambiguous: {} (複数のlocatorに一致: {})", query, matched.join(", "));
+    }
+    for device in &resolution.unknown {
+        println!("unknown: {} (宣言に含まれていません)", device.locator_id());
+    }
+
+    if !args.dry_run {
+        for (device, profile, handle, desired_mask, current_mask) in planned {
+            let locator_id = device.locator_id();
+            let _span = info_span!("locator", id = %locator_id).entered();
+            let change = Change::cli("apply").previous(Some(current_mask));
+            let result = audit::set_mask(profile, &handle, &args.protocol, &device, desired_mask, &change)
+                .and_then(|_| profile.query_status(&handle, &args.protocol));
+            match result {
+                Ok(status) if status.mask == desired_mask => {
+                    println!(
+                        "applied: id={:<20} mask=0x{:02x} raw=[{}]",
+                        locator_id,
+                        status.mask,
+                        format_bytes(&status.raw)
+                    );
+                }
+                Ok(status) => {
+                    failures += 1;
+                    println!(
+                        "mismatch: id={:<20} desired=0x{:02x} actual=0x{:02x}",
+                        locator_id, desired_mask, status.mask
+                    );
+                }
+                Err(err) => {
+                    failures += 1;
+                    println!("failed: id={:<20} error={:#}", locator_id, err);
+                }
+            }
+        }
+    }
+
+    if failures > 0 {
+        bail!("{}件のlocatorを目標状態にできませんでした", failures);
+    }
+    Ok(())
+}
+
+/// フィルタに一致する全locatorのLEDマスクをスナップショットとして保存する
+///
+/// - ステータス取得に失敗したlocatorは表示してエラー終了し、ファイルは書き込まない
+/// - `--allow-partial` なら読めた分だけ保存してからエラー終了する (1台も読めなければ書き込まない)
+pub fn handle_snapshot_save(
+    backend: &dyn LocatorBackend,
+    profiles: &ProfileRegistry,
+    args: &SnapshotSaveArgs,
+    env: &EnvDefaults,
+) -> Result<()> {
+    let SnapshotSaveArgs { snapshot, allow_partial } = args;
+    let filter = profiles.fill_filter(merge_filter(&snapshot.filter, env));
+    let devices = backend.devices(&filter)?;
+    if devices.is_empty() {
+        bail!("対象となるlocatorが見つかりませんでした");
+    }
+
+    let mut entries = Vec::new();
+    let mut failures = 0usize;
+    for device in devices {
+        let locator_id = device.locator_id();
+        let _span = info_span!("locator", id = %locator_id).entered();
+        let profile = profiles.for_device(&device);
+        let status = open_locked(backend, &device, &snapshot.lock)
+            .and_then(|handle| profile.query_status(&handle, &snapshot.protocol));
+        match status {
+            Ok(status) => {
+                println!("saved: id={:<20} mask=0x{:02x}", locator_id, status.mask);
+                entries.push(SnapshotEntry::new(&device, status.mask));
+            }
+            Err(err) => {
+                failures += 1;
+                println!("failed: id={:<20} error={:#}", locator_id, err);
+            }
+        }
+    }
+
+    // 欠けたスナップショットで上書きすると、restoreで戻せないlocatorが出る
+    if entries.is_empty() {
+        bail!("どのlocatorも読めなかったため {} を書き込みませんでした", snapshot.file.display());
+    }
+    if failures > 0 && !allow_partial {
+        bail!(
+            "{}件のlocatorを読めなかったため {} を書き込みませんでした (読めた分だけ保存するには --allow-partial)",
+            failures,
+            snapshot.file.display()
+        );
+    }
+    Snapshot::new(entries).save(&snapshot.file)?;
+    if failures > 0 {
+        bail!("{}件のlocatorを保存できませんでした", failures);
+    }
+    Ok(())
+}
+
+/// スナップショットのLEDマスクを各locatorへ書き戻す
+///
+/// - シリアル番号で対応付け、シリアルの無いlocatorはパスで対応付ける
+/// - 同じパスに別のlocatorが居る場合(identity changed)や見つからない場合は書き込まずに報告
+pub fn handle_snapshot_restore(
+    backend: &dyn LocatorBackend,
+    profiles: &ProfileRegistry,
+    args: &SnapshotFileArgs,
+    env: &EnvDefaults,
+) -> Result<()> {
+    let filter = profiles.fill_filter(merge_filter(&args.filter, env));
+    let snapshot = Snapshot::load(&args.file)?;
+    let devices = backend.devices(&filter)?;
+    println!("snapshot saved_at={}", snapshot.saved_at.to_rfc3339());
+
+    let mut failures = 0usize;
+    for entry in &snapshot.devices {
+        let device = match match_entry(entry, &devices) {
+            RestoreMatch::BySerial {
+                device,
+                path_changed,
+            }
+            | RestoreMatch::ByPort {
+                device,
+                path_changed,
+            } => {
+                if path_changed {
+                    println!(
+                        "moved: id={:<20} path {} -> {}",
+                        entry.locator_id(),
+                        entry.path,
+                        device.path.to_string_lossy()
+                    );
+                }
+                device
+            }
+            RestoreMatch::ByPath { device } => device,
+            RestoreMatch::IdentityChanged { device } => {
+                failures += 1;
+                println!(
+                    "identity-changed: id={:<20} path={} は現在 id={} (vendor=0x{:04x} product=0x{:04x})",
+                    entry.locator_id(),
+                    entry.path,
+                    device.locator_id(),
+                    device.vendor_id,
+                    device.product_id
+                );
+                continue;
+            }
+            RestoreMatch::Disappeared => {
+                failures += 1;
+                println!("disappeared: id={:<20} (見つかりません)", entry.locator_id());
+                continue;
+            }
+        };
+
+        let locator_id = device.locator_id();
+        let _span = info_span!("locator", id = %locator_id).entered();
+        let profile = profiles.for_device(&device);
+        let result = open_locked(backend, &device, &args.lock)
+            .and_then(|handle| {
+                audit::set_mask(profile, &handle, &args.protocol, &device, entry.mask, &Change::cli("restore"))?;
+                profile.query_status(&handle, &args.protocol)
+            });
+        match result {
+            Ok(status) if status.mask == entry.mask => {
+                println!("restored: id={:<20} mask=0x{:02x}", locator_id, status.mask);
+            }
+            Ok(status) => {
+                failures += 1;
+                println!(
+                    "mismatch: id={:<20} expected=0x{:02x} actual=0x{:02x}",
+                    locator_id, entry.mask, status.mask
+                );
+            }
+            Err(err) => {
+                failures += 1;
+                println!("failed: id={:<20} error={:#}", locator_id, err);
+            }
+        }
+    }
+
+    if failures > 0 {
+        bail!("{}件のlocatorを復元できませんでした", failures);
+    }
+    Ok(())
+}
+
+/// 設定ファイルのスケジュールに従ってLEDを切り替え続ける
+///
+/// - `--interval` ごとにlocatorを列挙し直し、各locatorの目標マスクを評価
+/// - 目標が変わったlocatorと新しく接続されたlocatorにだけ送信
+/// - Ctrl-Cで終了
+pub fn handle_daemon(
+    backend: &mut dyn LocatorBackend,
+    profiles: &ProfileRegistry,
+    args: &DaemonArgs,
+    env: &EnvDefaults,
+) -> Result<()> {
+    let filter = profiles.fill_filter(merge_filter(&args.filter, env));
+    let config = Config::load(args.config.config.as_deref())?;
+    let scheduler = Scheduler::from_config(&config)?;
+    // systemdのソケット活性化で起動されたら、渡されたソケットで画面とWebSocketを出す
+    let activated = activated_listener()?;
+    let ws_enabled = args.ws_listen.is_some() || activated.is_some();
+    if scheduler.rules.is_empty() && !ws_enabled {
+        println!("スケジュールルールがありません。LEDは変更しません");
+    }
+    let hooks = HookRunner::from_config(&config, args.max_hooks)?;
+    if !hooks.is_empty() {
+        println!("フック {} 件 (同時実行 {} 件まで)", hooks.len(), args.max_hooks.max(1));
+    }
+    let mut daemon = Daemon::new(scheduler, profiles.clone())
+        .watch_status(args.watch_status || args.metrics_listen.is_some() || ws_enabled)
+        .with_aliases(config.aliases.clone())
+        .on_exit(args.on_exit);
+    if let Some(listen) = &args.metrics_listen {
+        let metrics = Arc::new(Metrics::new()?);
+        serve_metrics(listen, Arc::clone(&metrics))?;
+        println!("メトリクス: http://{}/metrics", listen);
+        daemon = daemon.with_metrics(metrics);
+    }
+    let ws = if ws_enabled {
+        let access = (args.ws_token.is_some() || !config.clients.is_empty())
+            .then(|| AccessControl::load(&config, args.ws_token.as_deref(), DEFAULT_RATE_LIMIT))
+            .transpose()?;
+        let authenticated = access.is_some();
+        let ws = match (activated, &args.ws_listen) {
+            (Some(listener), _) => WsServer::from_listener(listener, access, args.on_value)?,
+            (None, Some(listen)) => WsServer::bind(listen, access, args.on_value)?,
+            (None, None) => unreachable!(),
+        };
+        println!(
+            "画面: http://{}/ WebSocket: ws://{}{}{}",
+            ws.local_addr(),
+            ws.local_addr(),
+            WS_PATH,
+            if authenticated { "" } else { " (認証なし・読み取り専用)" }
+        );
+        Some(ws)
+    } else {
+        None
+    };
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let flag = Arc::clone(&stop);
+    // ctrlcの termination 機能でSIGTERM (systemctl stop) も同じように扱う
+    ctrlc::set_handler(move || flag.store(true, Ordering::SeqCst))
+        .context("Ctrl-Cハンドラを設定できません")?;
+
+    let mut notifier = Notifier::from_env();
+    let mut ready = false;
+    let mut attached = None;
+    let mut previous: Vec<DeviceDescriptor> = Vec::new();
+    while !stop.load(Ordering::SeqCst) {
+        notifier.watchdog();
+        if let Err(err) = backend.refresh() {
+            println!("[{}] refresh-failed error={}", Local::now().to_rfc3339(), err);
+        }
+        let devices = match backend.devices(&filter) {
+            Ok(devices) => devices,
+            Err(err) => {
+                println!("[{}] enumerate-failed error={:#}", Local::now().to_rfc3339(), err);
+                wait_for(&SystemClock, args.interval, &stop);
+                continue;
+            }
+        };
+        let now = Local::now();
+        let events = daemon.tick(&now, &devices, &args.protocol, &mut |device| {
+            Ok(Box::new(open_locked(&*backend, device, &args.lock)?) as Box<dyn HidDeviceIo>)
+        });
+        let timestamp = now.to_rfc3339();
+        // 切断されたlocatorのイベントもフックの対象を決められるよう、前回列挙したものも含める
+        let known: Vec<DeviceDescriptor> = devices
+            .iter()
+            .chain(previous.iter().filter(|old| !devices.iter().any(|d| d.path == old.path)))
+            .cloned()
+            .collect();
+        for event in events {
+            print_daemon_event(&timestamp, &event);
+            hooks.dispatch(&HookEvent::from_daemon(&event), &known);
+            if let Some(ws) = &ws {
+                ws.publish(&timestamp, &event);
+            }
+        }
+        previous.clone_from(&devices);
+        // 最初の周を終えたら起動完了とし、以降は台数が変わったときだけ状態を知らせる
+        if attached != Some(devices.len()) {
+            let status = format!("locator {}台", devices.len());
+            if ready {
+                notifier.status(&status);
+            } else {
+                notifier.ready(&status);
+                ready = true;
+            }
+            attached = Some(devices.len());
+        }
+        match &ws {
+            Some(ws) => {
+                ws.publish_state(&timestamp, daemon.states());
+                // 制御メッセージを受け取ったら、次の周を待たずにLEDへ反映する
+                if let Some(control) = ws.wait(args.interval, &stop) {
+                    apply_control(&mut daemon, &devices, &control);
+                }
+            }
+            None => {
+                wait_for(&SystemClock, args.interval, &stop);
+            }
+        }
+    }
+
+    notifier.stopping();
+    let events = daemon.shutdown(&args.protocol, &mut |device| {
+        Ok(Box::new(open_locked(&*backend, device, &args.lock)?) as Box<dyn HidDeviceIo>)
+    });
+    let timestamp = Local::now().to_rfc3339();
+    for event in events {
+        print_daemon_event(&timestamp, &event);
+        if let Some(ws) = &ws {
+            ws.publish(&timestamp, &event);
+        }
+    }
+    println!("停止しました");
+    Ok(())
+}
+
+/// `daemon` をsystemdで常駐させるユニットと、hidrawを使えるようにするudevルールを書き出す
+///
+/// - ExecStartには今のcap-locator-cliと、絶対パスにした設定ファイル・VID/PID(.env含む)を書く
+/// - `--socket` なら `--ws-listen` のポートを `<name>.socket` で待ち受ける
+/// - `--print` なら書き出さずに表示する
+pub fn handle_install_service(
+    profiles: &ProfileRegistry,
+    args: &InstallServiceArgs,
+    env: &EnvDefaults,
+) -> Result<()> {
+    let filter = merge_filter(&args.filter, env);
+    let ids = usb_ids(profiles, &filter);
+    if ids.is_empty() {
+        bail!("udevルールに書くvendor id/product idが分かりません。--vendor-id と --product-id を指定してください");
+    }
+
+    let exe = std::env::current_exe().context("実行ファイルのパスが分かりません")?;
+    let mut exec_start = vec![exe.display().to_string(), "daemon".to_string()];
+    let config = match &args.config.config {
+        Some(path) => Some(path.clone()),
+        None => Some(PathBuf::from(DEFAULT_CONFIG_PATH)).filter(|path| path.exists()),
+    };
+    if let Some(config) = config {
+        let config = fs::canonicalize(&config)
+            .with_context(|| format!("設定ファイルが見つかりません: {}", config.display()))?;
+        exec_start.extend(["--config".to_string(), config.display().to_string()]);
+    }
+    if let Some(vendor_id) = filter.vendor_id {
+        exec_start.extend(["--vendor-id".to_string(), format!("0x{:04x}", vendor_id)]);
+    }
+    if let Some(product_id) = filter.product_id {
+        exec_start.extend(["--product-id".to_string(), format!("0x{:04x}", product_id)]);
+    }
+    // ソケット活性化ではsystemdが待ち受けるので、daemonには渡さない
+    if let Some(listen) = args.ws_listen.as_ref().filter(|_| !args.socket) {
+        exec_start.extend(["--ws-listen".to_string(), listen.clone()]);
+    }
+    let on_exit = args.on_exit.to_possible_value().map(|value| value.get_name().to_string());
+    exec_start.extend(["--on-exit".to_string(), on_exit.unwrap_or_default()]);
+
+    let unit = ServiceUnit {
+        name: args.name.clone(),
+        exec_start,
+        user: args.user.clone(),
+        group: args.group.clone(),
+        socket: args.socket,
+    };
+    let mut files = vec![
+        (args.unit_dir.join(format!("{}.service", args.name)), unit.render()),
+        (args.udev_dir.join(format!("70-{}.rules", args.name)), udev_rules(&ids, &args.group)),
+    ];
+    if let Some(listen) = args.ws_listen.as_ref().filter(|_| args.socket) {
+        files.push((args.unit_dir.join(format!("{}.socket", args.name)), socket_unit(&args.name, listen)));
+    }
+
+    if args.print {
+        for (path, content) in &files {
+            println!("# {}\n{}", path.display(), content);
+        }
+        return Ok(());
+    }
+    for (path, content) in &files {
+        fs::write(path, content).with_context(|| format!("{} を書き込めません (rootで実行してください)", path.display()))?;
+        println!("書き込みました: {}", path.display());
+    }
+    let start = if args.socket { "socket" } else { "service" };
+    println!("次のコマンドで有効にしてください:");
+    println!("  sudo udevadm control --reload && sudo udevadm trigger");
+    println!("  sudo systemctl daemon-reload");
+    println!("  sudo systemctl enable --now {}.{}", args.name, start);
+    if args.user.is_some() {
+        println!("  (サービスのユーザーが {} グループに入っているか確認してください)", args.group);
+    }
+    Ok(())
+}
+
+/// locatorを開けない原因 (hidrawの権限・udevルール・グループ) を調べて対処を表示する
+///
+/// - 対象はフィルタ(.env含む)に一致するlocatorと、そのVID/PID向けのudevルール
+/// - `--print-rule` / `--install` では `install-service` と同じudevルールを表示/書き出しする
+/// - 問題があればエラーで終了する
+pub fn handle_doctor(
+    open_backend: impl FnOnce() -> Result<Box<dyn LocatorBackend>>,
+    profiles: &ProfileRegistry,
+    args: &DoctorArgs,
+    env: &EnvDefaults,
+) -> Result<()> {
+    let filter = merge_filter(&args.filter, env);
+    let ids = usb_ids(profiles, &filter);
+    if ids.is_empty() {
+        bail!("udevルールに書くvendor id/product idが分かりません。--vendor-id と --product-id を指定してください");
+    }
+    let rules = udev_rules(&ids, &args.group);
+    if args.print_rule {
+        print!("{}", rules);
+        return Ok(());
+    }
+    if args.install {
+        let path = args.udev_dir.join(format!("70-{}.rules", args.name));
+        fs::write(&path, &rules).with_context(|| format!("{} を書き込めません (rootで実行してください)", path.display()))?;
+        println!("書き込みました: {}", path.display());
+        println!("次のコマンドで反映してください:");
+        println!("  sudo udevadm control --reload && sudo udevadm trigger");
+        return Ok(());
+    }
+    if !cfg!(target_os = "linux") {
+        println!("hidrawの権限を確認するのはLinuxだけです");
+        return Ok(());
+    }
+
+    let accounts = Accounts::load();
+    let credentials = Credentials::current().unwrap_or_default();
+    let user = accounts.user_name(credentials.uid);
+    println!("ユーザー: {} (uid {})", user, credentials.uid);
+    let mut fixes = Vec::new();
+
+    let devices = open_backend()?.devices(&profiles.fill_filter(filter))?;
+    if devices.is_empty() {
+        println!("locatorは見つかりませんでした");
+    }
+    let mut denied = false;
+    // 読み書きできなかったノードのうち、グループに許可が出ているもののグループ
+    let mut node_groups = BTreeSet::new();
+    for device in &devices {
+        let path = device.path.to_string_lossy();
+        let access = probe(Path::new(path.as_ref()));
+        let owner = NodeOwner::read(Path::new(path.as_ref()));
+        let permission = owner
+            .as_ref()
+            .map(|owner| {
+                format!("{:04o} {}:{}", owner.mode, accounts.user_name(owner.uid), accounts.group_name(owner.gid))
+            })
+            .unwrap_or_else(|| "-".to_string());
+        let result = match &access {
+            Access::Granted => "OK".to_string(),
+            Access::Denied => "読み書きできません".to_string(),
+            Access::Missing => "ノードがありません".to_string(),
+            Access::Failed(err) => err.clone(),
+        };
+        println!("{:<20} {} {} {}", device.locator_id(), path, permission, result);
+        if access == Access::Denied {
+            denied = true;
+            if let Some(owner) = owner.filter(|owner| owner.group_can_use() && owner.gid != 0) {
+                node_groups.insert(accounts.group_name(owner.gid));
+            }
+        }
+    }
+
+    let dirs: Vec<PathBuf> = UDEV_RULE_DIRS.iter().map(PathBuf::from).collect();
+    let mut rules_missing = false;
+    for (vendor_id, product_id) in &ids {
+        let found = find_rules(&dirs, *vendor_id, *product_id);
+        if found.is_empty() {
+            println!("udevルール {:04x}:{:04x}: 見つかりません", vendor_id, product_id);
+            rules_missing = true;
+        } else {
+            let files: Vec<String> = found.iter().map(|path| path.display().to_string()).collect();
+            println!("udevルール {:04x}:{:04x}: {}", vendor_id, product_id, files.join(", "));
+        }
+    }
+    if rules_missing {
+        fixes.push(format!(
+            "udevルールを追加する: sudo cap-locator-cli doctor --install --group {}",
+            args.group
+        ));
+    } else if denied && node_groups.is_empty() {
+        // ルールはあるのに、ノードがまだrootだけのもの
+        fixes.push("udevルールを反映する: sudo udevadm control --reload && sudo udevadm trigger (または挿し直す)".to_string());
+    }
+
+    // ノードがrootだけのものなら、これから入れるルールのグループに入る必要がある
+    if denied && node_groups.is_empty() {
+        node_groups.insert(args.group.clone());
+    }
+    if rules_missing && !node_groups.contains(&args.group) && accounts.group_id(&args.group).is_none() {
+        println!("グループ {}: ありません", args.group);
+        fixes.push(format!("グループを作る: sudo groupadd --system {}", args.group));
+    }
+    for group in &node_groups {
+        match accounts.group_id(group) {
+            None => {
+                println!("グループ {}: ありません", group);
+                fixes.push(format!("グループを作る: sudo groupadd --system {}", group));
+                fixes.push(format!("グループに入る: sudo usermod -aG {} {} (ログインし直すと反映されます)", group, user));
+            }
+            Some(gid) if credentials.gids.contains(&gid) => println!("グループ {}: 所属しています", group),
+            Some(_) if accounts.is_member(&user, group) => {
+                println!("グループ {}: 追加済みですが、このログインには反映されていません", group);
+                fixes.push("ログインし直す (または newgrp で反映する)".to_string());
+            }
+            Some(_) => {
+                println!("グループ {}: 所属していません", group);
+                fixes.push(format!("グループに入る: sudo usermod -aG {} {} (ログインし直すと反映されます)", group, user));
+            }
+        }
+    }
+
+    if !denied && fixes.is_empty() {
+        println!("問題は見つかりませんでした");
+        return Ok(());
+    }
+    println!("対処:");
+    for fix in &fixes {
+        println!("  - {}", fix);
+    }
+    bail!("locatorを使うための設定が足りません ({} 件)", fixes.len().max(1))
+}
+
+/// 別のマシンの `--remote` にlocatorを貸し出す
+///
+/// - 公開するのはフィルタ(.env含む)に一致し、いずれかのプロファイルの対象になるデバイスだけ
+/// - クライアントが開いている間はlocatorのロックを持つ
+/// - `--token` と設定ファイルの [[clients]] で認証し、状態を変える要求を監査ログに出す
+/// - Ctrl-Cで終了
+pub fn handle_serve(
+    backend: &mut dyn LocatorBackend,
+    profiles: &ProfileRegistry,
+    args: &ServeArgs,
+    env: &EnvDefaults,
+) -> Result<()> {
+    let filter = profiles.fill_filter(merge_filter(&args.filter, env));
+    let access = load_access(&args.auth, args.token.as_deref())?;
+    let mut server = RemoteServer::bind(&args.listen, access, filter)?
+        .lock_timeout(args.lock.lock_timeout)
+        .idle_timeout(args.idle_timeout);
+    println!("serve: http://{}", server.local_addr()?);
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let flag = Arc::clone(&stop);
+    ctrlc::set_handler(move || flag.store(true, Ordering::SeqCst))
+        .context("Ctrl-Cハンドラを設定できません")?;
+    server.run(backend, profiles, &stop, &mut |event| {
+        let timestamp = Local::now().to_rfc3339();
+        match event {
+            ServeEvent::Opened {
+                handle,
+                id,
+                caller,
+                client,
+            } => println!(
+                "[{}] open     id={} handle={} caller={} client={}",
+                timestamp, id, handle, caller, client
+            ),
+            ServeEvent::Closed { handle, id } => println!("[{}] close    id={} handle={}", timestamp, id, handle),
+            ServeEvent::Expired { handle, id } => println!("[{}] expired  id={} handle={}", timestamp, id, handle),
+            ServeEvent::Rejected { client, reason } => {
+                println!("[{}] rejected client={} reason={}", timestamp, client, reason)
+            }
+            ServeEvent::Audit(record) => print_audit(record),
+        }
+    })?;
+    println!("停止しました");
+    Ok(())
+}
+
+/// gRPCの LocatorService を提供し、他のマシンから `--remote` で操作できるようにする
+///
+/// - 要求ごとにロックを取ってlocatorを操作するので、ローカルのCLIと同時に使える
+/// - `--interval` ごとに列挙とステータス取得を行い、変化を表示して StreamEvents で送る
+/// - `--token` と設定ファイルの [[clients]] (トークンまたはmTLSのクライアント証明書) で認証し、SetLedsを監査ログに出す
+/// - Ctrl-Cで終了
+#[cfg(feature = "grpc")]
+pub fn handle_grpc_serve(
+    backend: &mut dyn LocatorBackend,
+    profiles: &ProfileRegistry,
+    args: &GrpcServeArgs,
+    env: &EnvDefaults,
+) -> Result<()> {
+    let options = ServeOptions {
+        filter: profiles.fill_filter(merge_filter(&args.filter, env)),
+        protocol: args.protocol.clone(),
+        lock: args.lock.clone(),
+        interval: args.interval,
+    };
+    let access = load_access(&args.auth, args.token.as_deref())?;
+    let tls = match (&args.tls_cert, &args.tls_key) {
+        (Some(cert), Some(key)) => Some(ServerTls {
+            cert: cert.clone(),
+            key: key.clone(),
+            client_ca: args.client_ca.clone(),
+        }),
+        _ => None,
+    };
+    let server = GrpcServer::bind(&args.listen, access, tls.as_ref())?;
+    println!(
+        "gRPC: {} ({})",
+        server.local_addr(),
+        match &tls {
+            Some(tls) if tls.client_ca.is_some() => "mTLS",
+            Some(_) => "TLS",
+            None => "平文",
+        }
+    );
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let flag = Arc::clone(&stop);
+    ctrlc::set_handler(move || flag.store(true, Ordering::SeqCst))
+        .context("Ctrl-Cハンドラを設定できません")?;
+    server.run(
+        backend,
+        profiles,
+        &options,
+        &stop,
+        &mut |timestamp, event| print_daemon_event(timestamp, event),
+        &mut |record| print_audit(record),
+    )?;
+    println!("停止しました");
+    Ok(())
+}
+
+/// `--remote host:port` のとき、ローカルのHIDの代わりに `grpc-serve` へ要求して同じ形で表示する
+///
+/// - 対象のフィルタはサーバー側の指定を使う
+/// - 使えるのは list/status/on/off (`--for` 無し) だけ
+#[cfg(feature = "grpc")]
+pub fn handle_remote(remote: &str, token: Option<&str>, tls: &RemoteTlsArgs, command: &Commands) -> Result<()> {
+    let mut client = GrpcClient::connect(remote, token, tls)?;
+    match command {
+        Commands::List(_) => {
+            let locators = client.list()?;
+            if locators.is_empty() {
+                println!("locatorは見つかりませんでした");
+            }
+            for locator in locators {
+                print_locator(&locator.descriptor()?, &locator.profile);
+            }
+        }
+        Commands::Status(args) => {
+            for status in client.status(args.id.as_deref(), args.details)? {
+                if args.details {
+                    let lit: Vec<&str> = status.lit.iter().map(String::as_str).collect();
+                    let default = status.default_mask.map(u8::try_from).transpose()?;
+                    println!("{}", format_status_details(&status.id, &lit, &status.locator_status()?, default));
+                } else {
+                    println!("{}", format_status(&status.id, &status.locator_status()?));
+                }
+            }
+        }
+        Commands::On(args) | Commands::Off(args) => {
+            if args.hold_for.is_some() {
+                bail!("--remote では --for を使えません");
+            }
+            let mask = if matches!(command, Commands::On(_)) { args.on_value } else { args.off_value };
+            let status = client.set_leds(args.id.as_deref(), mask)?;
+            println!("{}", format_status(&status.id, &status.locator_status()?));
+        }
+        _ => bail!("gRPCの --remote で使えるのは list/status/on/off だけです (他は `serve` と --remote http://host:port を使ってください)"),
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "grpc"))]
+pub fn handle_remote(remote: &str, _token: Option<&str>, _tls: &RemoteTlsArgs, _command: &Commands) -> Result<()> {
+    bail!(
+        "--remote {} はgRPCの接続先です。`--features grpc` を付けてビルドするか、`serve` の http://host:port を指定してください",
+        remote
+    )
+}
+
+/// 設定ファイルの [[clients]] と `--token` から `serve` / `grpc-serve` の認証を作る
+fn load_access(args: &AuthArgs, token: Option<&str>) -> Result<AccessControl> {
+    let config = Config::load(args.config.config.as_deref())?;
+    AccessControl::load(&config, token, args.rate_limit)
+}
+
+/// WebSocketの制御メッセージをdaemonへ渡し、監査ログを出して結果を返す
+fn apply_control(daemon: &mut Daemon, devices: &[DeviceDescriptor], control: &Control) {
+    let query = IdQuery::resolve(devices, &control.id);
+    let device = devices.iter().find(|device| query.selects(device));
+    let (action, mask, detail) = match &control.action {
+        ControlAction::Set(leds) => (
+            "set",
+            Some(leds.mask),
+            match leds.pattern {
+                Pattern::Steady => None,
+                Pattern::Blink { interval } => Some(format!("blink={}", format_duration(interval))),
+            },
+        ),
+        ControlAction::Release => ("release", None, None),
+    };
+    let result = match device {
+        Some(device) => control
+            .caller
+            .authorize(device, Permission::Control)
+            .map(|()| match control.action {
+                ControlAction::Set(leds) => {
+                    daemon.override_leds(&device.locator_id(), leds, &control.caller.name, &control.client)
+                }
+                ControlAction::Release => {
+                    daemon.release(&device.locator_id());
+                }
+            })
+            .map_err(|denied| denied.to_string()),
+        None => Err(format!("locatorが見つかりません: {}", control.id)),
+    };
+    let id = device.map_or_else(|| control.id.clone(), |device| device.locator_id());
+    let serial = device.and_then(|device| device.serial_number.as_deref());
+    let change = Change::api(action, Some(&control.caller.name), Some(&control.client));
+    let mut record = AuditRecord::new(&id, serial, mask, &change, result.clone());
+    if let Some(detail) = detail {
+        record = record.detail(detail);
+    }
+    record.emit();
+    print_audit(&record);
+    control.respond(result);
+}
+
+/// `serve` / `grpc-serve` / `daemon --ws-listen` の監査ログを標準出力に出す (tracingへは `AuditRecord::emit` で出す)
+fn print_audit(record: &AuditRecord) {
+    let result = match &record.error {
+        Some(reason) => format!("error reason={}", reason),
+        None => record.result.clone(),
+    };
+    println!(
+        "[{}] audit    caller={} client={} id={} action={} {} result={}",
+        record.timestamp.to_rfc3339(),
+        record.user,
+        record.host,
+        record.id,
+        record.command,
+        record.change_text(),
+        result
     );
+}
+
+fn print_daemon_event(timestamp: &str, event: &DaemonEvent) {
+    match event {
+        DaemonEvent::Attached { id } => println!("[{}] attached id={}", timestamp, id),
+        DaemonEvent::Detached { id } => println!("[{}] detached id={}", timestamp, id),
+        DaemonEvent::Applied { id, mask } => {
+            println!("[{}] applied  id={} mask=0x{:02x}", timestamp, id, mask)
+        }
+        DaemonEvent::Changed { id, mask } => {
+            println!("[{}] changed  id={} mask=0x{:02x}", timestamp, id, mask)
+        }
+        DaemonEvent::Failed { id, error } => {
+            println!("[{}] failed   id={} error={}", timestamp, id, error)
+        }
+    }
+}
+
+/// `--audit-log` の記録を絞り込んで古い順に表示する
+pub fn handle_audit_show(audit_log: Option<&Path>, args: &AuditShowArgs) -> Result<()> {
+    let path = audit_log.context("--audit-log か CAP_LOCATOR_AUDIT_LOG で監査ログを指定してください")?;
+    let now = Local::now();
+    let query = AuditQuery {
+        id: args.id.clone(),
+        since: args.since.as_deref().map(|text| parse_time_bound(text, &now)).transpose()?,
+        until: args.until.as_deref().map(|text| parse_time_bound(text, &now)).transpose()?,
+    };
+    let records = read_audit_log(path, &query)?;
+    if records.is_empty() {
+        println!("一致する記録はありません");
+        return Ok(());
+    }
+
+    let skip = args.limit.map_or(0, |limit| records.len().saturating_sub(limit));
+    for record in &records[skip..] {
+        println!("{}", record.line());
+    }
+    Ok(())
+}
+
+/// 今後のスケジュールの開始/終了を近い順に表示する
+pub fn handle_schedule_list(args: &ScheduleListArgs) -> Result<()> {
+    let config = Config::load(args.config.config.as_deref())?;
+    let scheduler = Scheduler::from_config(&config)?;
+    let transitions = scheduler.next_transitions(&Local::now(), args.limit);
+    if transitions.is_empty() {
+        println!("予定されている切り替えはありません");
+        return Ok(());
+    }
+
+    for transition in transitions {
+        println!(
+            "{} {:<5} rule={:<20} target={:<20} mask=0x{:02x} priority={}",
+            transition.at.to_rfc3339(),
+            match transition.edge {
+                Edge::Start => "start",
+                Edge::End => "end",
+            },
+            transition.rule,
+            transition.target,
+            transition.mask,
+            transition.priority
+        );
+    }
     Ok(())
 }
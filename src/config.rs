@@ -0,0 +1,132 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, bail};
+use serde::Deserialize;
+
+/// 設定ファイルを指定しなかった場合に探すパス
+pub const DEFAULT_CONFIG_PATH: &str = "cap-locator.toml";
+
+/// 常駐モードなどで使う設定ファイル(TOML)
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Config {
+    /// グループ名 → locator id の一覧
+    ///
+    /// 各idは接続中のlocatorに完全に一致するものがあればそれだけ、無ければシリアル番号かHIDパスの部分文字列で選ぶ
+    #[serde(default)]
+    pub groups: BTreeMap<String, Vec<String>>,
+    /// スケジュールルール
+    #[serde(default)]
+    pub rules: Vec<RuleConfig>,
+    /// 接続/切断やLEDの変化で実行するシェルコマンド
+    #[serde(default)]
+    pub hooks: Vec<HookConfig>,
+    /// `serve` / `grpc-serve` に接続を許すクライアント
+    #[serde(default)]
+    pub clients: Vec<ClientConfig>,
+    /// locator id → `daemon` の画面に出す名前 (`"SN-CAP25001" = "3F 会議室"` など)
+    #[serde(default)]
+    pub aliases: BTreeMap<String, String>,
+}
+
+/// 1件分のスケジュールルール (`window` か `cron` のどちらかを指定)
+#[derive(Clone, Debug, Deserialize)]
+pub struct RuleConfig {
+    /// 表示用の名前。省略時は target
+    pub name: Option<String>,
+    /// locator id またはグループ名
+    pub target: String,
+    /// `09:00-18:00` のような時間帯
+    pub window: Option<String>,
+    /// window の曜日 (`mon`〜`sun`)。空なら毎日
+    #[serde(default)]
+    pub days: Vec<String>,
+    /// `sec min hour day month weekday` 形式のcron式。発火時刻から `duration` の間だけ有効
+    pub cron: Option<String>,
+    /// cron の有効期間 (`30m` など)
+    pub duration: Option<String>,
+    /// 有効な間に送るビットマスク
+    pub mask: u8,
+    /// `steady`(デフォルト) または `blink`
+    pub pattern: Option<String>,
+    /// blink の切り替え間隔 (デフォルト500ms)
+    pub interval: Option<String>,
+    /// 重なったときは大きいものを優先
+    #[serde(default)]
+    pub priority: i32,
+}
+
+/// 1件分のフック
+#[derive(Clone, Debug, Deserialize)]
+pub struct HookConfig {
+    /// 実行するイベント (`attached` / `detached` / `changed` / `failed`)。空ならすべて
+    #[serde(default)]
+    pub on: Vec<String>,
+    /// locator id またはグループ名。省略時はすべてのlocator
+    pub target: Option<String>,
+    /// `sh -c` で実行するコマンド。`{id}` `{mask}` `{event}` を置き換える
+    pub command: String,
+    /// これを超えたら強制終了する (デフォルト10s)
+    pub timeout: Option<String>,
+}
+
+/// 1件分のクライアント (`token` / `token_env` / `cert_sha256` のいずれかで識別する)
+#[derive(Clone, Debug, Deserialize)]
+pub struct ClientConfig {
+    /// 監査ログに出す名前
+    pub name: String,
+    /// クライアントが `--remote-token` で送るトークン
+    pub token: Option<String>,
+    /// トークンを読む環境変数 (設定ファイルに書きたくないとき)
+    pub token_env: Option<String>,
+    /// mTLSのクライアント証明書(DER)のSHA-256。`grpc-serve` のみ
+    pub cert_sha256: Option<String>,
+    /// `read` (一覧とステータスのみ) または `control` (LEDの設定なども)。省略時は read
+    pub permission: Option<String>,
+    /// 操作できるlocator id またはグループ名。空ならすべて
+    #[serde(default)]
+    pub targets: Vec<String>,
+    /// 1秒あたりの要求数の上限。省略時は `--rate-limit`
+    pub rate_limit: Option<f64>,
+}
+
+impl Config {
+    pub fn parse(text: &str) -> Result<Self> {
+        Ok(toml::from_str(text)?)
+    }
+
+    /// 設定ファイルを読み込む
+    ///
+    /// - 明示されたパスが無ければエラー
+    /// - 未指定ならカレントの `cap-locator.toml` を探し、無ければ空の設定
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let path = match path {
+            Some(path) => {
+                if !path.exists() {
+                    bail!("設定ファイルが見つかりません: {}", path.display());
+                }
+                path.to_path_buf()
+            }
+            None => {
+                let default = PathBuf::from(DEFAULT_CONFIG_PATH);
+                if !default.exists() {
+                    return Ok(Self::default());
+                }
+                default
+            }
+        };
+
+        let text = fs::read_to_string(&path)
+            .with_context(|| format!("{} を読み込めません", path.display()))?;
+        Self::parse(&text).with_context(|| format!("{} の解析に失敗", path.display()))
+    }
+
+    /// グループ名ならメンバーのid一覧、そうでなければそのidだけを返す
+    pub fn expand_target(&self, target: &str) -> Vec<String> {
+        self.groups
+            .get(target)
+            .cloned()
+            .unwrap_or_else(|| vec![target.to_string()])
+    }
+}
@@ -0,0 +1,375 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+use std::time::Instant;
+
+use anyhow::Result;
+use chrono::{DateTime, TimeZone};
+use clap::ValueEnum;
+use serde::Serialize;
+
+use crate::audit::{self, Change};
+use crate::cli::ProtocolArgs;
+use crate::hid::{DeviceDescriptor, HidDeviceIo, IdQuery};
+use crate::metrics::Metrics;
+use crate::mqtt::DesiredLeds;
+use crate::profile::ProfileRegistry;
+use crate::schedule::{Pattern, Scheduler};
+
+/// 常駐ループの1周で起きたこと
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DaemonEvent {
+    Attached { id: String },
+    Detached { id: String },
+    Applied { id: String, mask: u8 },
+    /// ステータスを監視しているとき、このdaemon以外によってLEDのマスクが変わった
+    Changed { id: String, mask: u8 },
+    Failed { id: String, error: String },
+}
+
+/// 常駐を止めるとき、このdaemonが変えたLEDをどうするか
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum ExitAction {
+    /// 最後に送ったまま残す
+    #[default]
+    Keep,
+    /// 接続中のlocatorをすべて消灯する
+    Off,
+    /// 最初に送る前のマスクに戻す
+    Restore,
+}
+
+/// `Daemon::states` の1台分
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct LocatorState {
+    pub id: String,
+    /// 設定ファイルの `aliases` で付けた名前
+    pub alias: Option<String>,
+    /// 最後に読んだ(または送った)マスク。まだ分からなければNone
+    pub mask: Option<u8>,
+    /// `mask` で光っているLEDの名前
+    pub leds: Vec<String>,
+    /// `override_leds` で点滅させている
+    pub blinking: bool,
+    /// `override_leds` で操作しているクライアント。Noneならスケジュールに従う
+    pub controlled_by: Option<String>,
+}
+
+/// スケジュールの代わりに送り続けるLEDの状態
+#[derive(Clone, Debug)]
+struct Override {
+    leds: DesiredLeds,
+    user: String,
+    host: String,
+}
+
+/// スケジュールに従ってlocatorのLEDを切り替える常駐処理の状態
+///
+/// - 最後に送ったマスクを覚えておき、変化したときだけ送信する
+/// - 新しく接続されたlocatorには必ず送り直す(ホットプラグ対応)
+/// - ステータスを監視するときは毎周マスクを読み、前回と違えば知らせる
+/// - `override_leds` したlocatorは `release` するか切断されるまでスケジュールより優先する
+/// - `on_exit(ExitAction::Restore)` なら最初に送る前のマスクを読んでおき、`shutdown` で戻す
+pub struct Daemon {
+    scheduler: Scheduler,
+    profiles: ProfileRegistry,
+    watch_status: bool,
+    metrics: Option<Arc<Metrics>>,
+    on_exit: ExitAction,
+    present: BTreeMap<String, DeviceDescriptor>,
+    applied: BTreeMap<String, u8>,
+    observed: BTreeMap<String, u8>,
+    overrides: BTreeMap<String, Override>,
+    aliases: BTreeMap<String, String>,
+    initial: BTreeMap<String, u8>,
+}
+
+impl Daemon {
+    pub fn new(scheduler: Scheduler, profiles: ProfileRegistry) -> Self {
+        Self {
+            scheduler,
+            profiles,
+            watch_status: false,
+            metrics: None,
+            on_exit: ExitAction::Keep,
+            present: BTreeMap::new(),
+            applied: BTreeMap::new(),
+            observed: BTreeMap::new(),
+            overrides: BTreeMap::new(),
+            aliases: BTreeMap::new(),
+            initial: BTreeMap::new(),
+        }
+    }
+
+    /// 毎周ステータスを読み、他のCLIなどによるLEDの変化も `Changed` として知らせる
+    pub fn watch_status(mut self, watch: bool) -> Self {
+        self.watch_status = watch;
+        self
+    }
+
+    /// 接続状態・送信数・通信の失敗・ステータス取得の時間を記録する
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// `states` に出す名前 (キーは locator id、シリアル番号、HIDパスの部分文字列のいずれか)
+    pub fn with_aliases(mut self, aliases: BTreeMap<String, String>) -> Self {
+        self.aliases = aliases;
+        self
+    }
+
+    /// `shutdown` で行うこと
+    pub fn on_exit(mut self, action: ExitAction) -> Self {
+        self.on_exit = action;
+        self
+    }
+
+    /// 最後に送ったマスク
+    pub fn applied_mask(&self, locator_id: &str) -> Option<u8> {
+        self.applied.get(locator_id).copied()
+    }
+
+    /// 次の周から `locator_id` にはスケジュールの代わりに `leds` を送る
+    ///
+    /// `user` / `host` は監査ログに出す操作したクライアントと接続元
+    pub fn override_leds(&mut self, locator_id: &str, leds: DesiredLeds, user: &str, host: &str) {
+        self.overrides
+            .insert(locator_id.to_string(), Override {
+                leds,
+                user: user.to_string(),
+                host: host.to_string(),
+            });
+        // 同じマスクでも送り直して、他のCLIが変えた状態を上書きする
+        self.applied.remove(locator_id);
+    }
+
+    /// `override_leds` をやめてスケジュールに戻す。戻したらtrue
+    pub fn release(&mut self, locator_id: &str) -> bool {
+        self.applied.remove(locator_id);
+        self.overrides.remove(locator_id).is_some()
+    }
+
+    /// 接続中のlocatorとLEDの状態 (locator id順)
+    pub fn states(&self) -> Vec<LocatorState> {
+        self.present
+            .iter()
+            .map(|(id, device)| {
+                let mask = self.observed.get(id).copied();
+                let overridden = self.overrides.get(id);
+                LocatorState {
+                    id: id.clone(),
+                    alias: self
+                        .aliases
+                        .iter()
+                        .find(|(key, _)| IdQuery::resolve(self.present.values(), key).selects(device))
+                        .map(|(_, alias)| alias.clone()),
+                    mask,
+                    leds: mask
+                        .map(|mask| {
+                            let profile = self.profiles.for_device(device);
+                            profile.lit_leds(mask).into_iter().map(str::to_string).collect()
+                        })
+                        .unwrap_or_default(),
+                    blinking: overridden.is_some_and(|o| matches!(o.leds.pattern, Pattern::Blink { .. })),
+                    controlled_by: overridden.map(|o| o.user.clone()),
+                }
+            })
+            .collect()
+    }
+
+    /// 1周分の処理。devices は今回列挙したlocator、open はlocatorを開く関数
+    pub fn tick<Tz: TimeZone>(
+        &mut self,
+        now: &DateTime<Tz>,
+        devices: &[DeviceDescriptor],
+        protocol: &ProtocolArgs,
+        open: &mut dyn FnMut(&DeviceDescriptor) -> Result<Box<dyn HidDeviceIo>>,
+    ) -> Vec<DaemonEvent> {
+        let mut events = Vec::new();
+        let current: BTreeMap<String, DeviceDescriptor> = devices
+            .iter()
+            .map(|d| (d.locator_id(), d.clone()))
+            .collect();
+
+        for (id, device) in &self.present {
+            if !current.contains_key(id) {
+                if let Some(metrics) = &self.metrics {
+                    metrics.device_present(device, false);
+                }
+                self.applied.remove(id);
+                self.observed.remove(id);
+                self.overrides.remove(id);
+                self.initial.remove(id);
+                events.push(DaemonEvent::Detached { id: id.clone() });
+            }
+        }
+        for (id, device) in &current {
+            if let Some(metrics) = &self.metrics {
+                metrics.device_present(device, true);
+            }
+            if !self.present.contains_key(id) {
+                events.push(DaemonEvent::Attached { id: id.clone() });
+            }
+        }
+
+        for (id, device) in &current {
+            let overridden = self.overrides.get(id);
+            let desired = match overridden {
+                Some(o) => Some(o.leds.pattern.mask_at(o.leds.mask, now)),
+                None => self.scheduler.desired_mask(device, devices, now),
+            };
+            let Some(mask) = desired else {
+                continue;
+            };
+            if self.applied.get(id) == Some(&mask) {
+                continue;
+            }
+            let profile = self.profiles.for_device(device);
+            let change = match overridden {
+                Some(o) => Change::api("websocket", Some(&o.user), Some(&o.host)),
+                None => Change::daemon("schedule"),
+            }
+            .previous(self.observed.get(id).copied());
+            let remember = self.on_exit == ExitAction::Restore && !self.initial.contains_key(id);
+            let initial = &mut self.initial;
+            let result = open(device).and_then(|handle| {
+                let mut change = change;
+                if remember {
+                    // 戻す先が分からないまま変えないよう、読めなければ送らずに次の周で再試行する
+                    let status = profile.query_status(handle.as_ref(), protocol)?;
+                    initial.insert(id.clone(), status.mask);
+                    change = change.previous(Some(status.mask));
+                }
+                audit::set_mask(profile, handle.as_ref(), protocol, device, mask, &change)
+            });
+            if let Some(metrics) = &self.metrics {
+                metrics.command_sent("set");
+                match &result {
+                    Ok(()) => metrics.led_mask(id, profile, mask),
+                    Err(err) => metrics.protocol_error(err),
+                }
+            }
+            match result {
+                Ok(()) => {
+                    self.applied.insert(id.clone(), mask);
+                    self.observed.insert(id.clone(), mask);
+                    events.push(DaemonEvent::Applied {
+                        id: id.clone(),
+                        mask,
+                    });
+                }
+                Err(err) => {
+                    // 次の周で再送できるよう、送信済みマスクは更新しない
+                    self.applied.remove(id);
+                    events.push(DaemonEvent::Failed {
+                        id: id.clone(),
+                        error: format!("{:#}", err),
+                    });
+                }
+            }
+        }
+
+        if self.watch_status {
+            self.observe(&current, protocol, open, &mut events);
+        }
+
+        self.present = current;
+        events
+    }
+
+    /// 常駐を止める前に `on_exit` に従ってLEDを消す/戻す
+    ///
+    /// 最後の周で接続していたlocatorが対象。戻すのはこのdaemonが一度でも送ったものだけ
+    pub fn shutdown(
+        &mut self,
+        protocol: &ProtocolArgs,
+        open: &mut dyn FnMut(&DeviceDescriptor) -> Result<Box<dyn HidDeviceIo>>,
+    ) -> Vec<DaemonEvent> {
+        let targets: Vec<(String, u8)> = match self.on_exit {
+            ExitAction::Keep => Vec::new(),
+            ExitAction::Off => self.present.keys().map(|id| (id.clone(), 0)).collect(),
+            ExitAction::Restore => self
+                .initial
+                .iter()
+                .filter(|(id, _)| self.present.contains_key(*id))
+                .map(|(id, mask)| (id.clone(), *mask))
+                .collect(),
+        };
+        let mut events = Vec::new();
+        for (id, mask) in targets {
+            let device = &self.present[&id];
+            if self.observed.get(&id) == Some(&mask) {
+                continue;
+            }
+            let profile = self.profiles.for_device(device);
+            let change = Change::daemon("shutdown").previous(self.observed.get(&id).copied());
+            let result = open(device)
+                .and_then(|handle| audit::set_mask(profile, handle.as_ref(), protocol, device, mask, &change));
+            match result {
+                Ok(()) => {
+                    self.applied.insert(id.clone(), mask);
+                    self.observed.insert(id.clone(), mask);
+                    events.push(DaemonEvent::Applied { id, mask });
+                }
+                Err(err) => events.push(DaemonEvent::Failed {
+                    id,
+                    error: format!("{:#}", err),
+                }),
+            }
+        }
+        events
+    }
+
+    /// 各locatorのマスクを読み、前回読んだ(または送った)値と違えば `Changed` を積む
+    ///
+    /// 接続直後の1回目は比較対象が無いので記録だけする
+    fn observe(
+        &mut self,
+        current: &BTreeMap<String, DeviceDescriptor>,
+        protocol: &ProtocolArgs,
+        open: &mut dyn FnMut(&DeviceDescriptor) -> Result<Box<dyn HidDeviceIo>>,
+        events: &mut Vec<DaemonEvent>,
+    ) {
+        // この周で送ったlocatorは読まなくてもマスクが分かっている(失敗したものは次の周に回す)
+        let sent: BTreeSet<String> = events
+            .iter()
+            .filter_map(|event| match event {
+                DaemonEvent::Applied { id, .. } | DaemonEvent::Failed { id, .. } => Some(id.clone()),
+                _ => None,
+            })
+            .collect();
+        for (id, device) in current {
+            if sent.contains(id) {
+                continue;
+            }
+            let profile = self.profiles.for_device(device);
+            let started = Instant::now();
+            let result = open(device).and_then(|handle| profile.query_status(handle.as_ref(), protocol));
+            if let Some(metrics) = &self.metrics {
+                metrics.command_sent("status");
+                match &result {
+                    Ok(status) => {
+                        metrics.observe_status(id, started.elapsed());
+                        metrics.led_mask(id, profile, status.mask);
+                    }
+                    Err(err) => metrics.protocol_error(err),
+                }
+            }
+            match result {
+                Ok(status) => {
+                    let previous = self.observed.insert(id.clone(), status.mask);
+                    if previous.is_some_and(|previous| previous != status.mask) {
+                        events.push(DaemonEvent::Changed {
+                            id: id.clone(),
+                            mask: status.mask,
+                        });
+                    }
+                }
+                Err(err) => events.push(DaemonEvent::Failed {
+                    id: id.clone(),
+                    error: format!("{:#}", err),
+                }),
+            }
+        }
+    }
+}
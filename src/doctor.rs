@@ -0,0 +1,203 @@
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use crate::hid::DeviceDescriptor;
+
+/// udevがルールを読むディレクトリ (後ろほど優先度の低い配布元のもの)
+pub const UDEV_RULE_DIRS: &[&str] = &[
+    "/etc/udev/rules.d",
+    "/run/udev/rules.d",
+    "/usr/lib/udev/rules.d",
+    "/lib/udev/rules.d",
+];
+
+/// hidrawノードを読み書きできるか
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Access {
+    Granted,
+    Denied,
+    /// ノードが無い (hidraw以外のパスや、列挙の後に抜かれた)
+    Missing,
+    Failed(String),
+}
+
+/// 実際に読み書きで開いてみる。ACL (`TAG+="uaccess"`) やrootも含めて判定できる
+pub fn probe(path: &Path) -> Access {
+    match OpenOptions::new().read(true).write(true).open(path) {
+        Ok(_) => Access::Granted,
+        Err(err) => match err.kind() {
+            ErrorKind::PermissionDenied => Access::Denied,
+            ErrorKind::NotFound => Access::Missing,
+            _ => Access::Failed(err.to_string()),
+        },
+    }
+}
+
+/// `open_path` の失敗に付けるメッセージ。権限が無いせいなら対処を書く
+pub fn open_failure(device: &DeviceDescriptor) -> String {
+    let path = device.path.to_string_lossy();
+    if cfg!(target_os = "linux") && probe(Path::new(path.as_ref())) == Access::Denied {
+        return format!(
+            "open device {}: {} を読み書きする権限がありません。`cap-locator-cli doctor` で原因を確認し、udevルールが無ければ `sudo cap-locator-cli doctor --install` で追加してください",
+            device.locator_id(),
+            path
+        );
+    }
+    format!("open device {}", device.locator_id())
+}
+
+/// hidrawノードの所有者とパーミッション
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NodeOwner {
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl NodeOwner {
+    #[cfg(unix)]
+    pub fn read(path: &Path) -> Option<Self> {
+        use std::os::unix::fs::MetadataExt;
+        let metadata = fs::metadata(path).ok()?;
+        Some(Self {
+            mode: metadata.mode() & 0o7777,
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+        })
+    }
+
+    #[cfg(not(unix))]
+    pub fn read(_path: &Path) -> Option<Self> {
+        None
+    }
+
+    /// グループに読み書きを許しているか
+    pub fn group_can_use(&self) -> bool {
+        self.mode & 0o060 == 0o060
+    }
+}
+
+/// `/etc/passwd` と `/etc/group` から引くユーザー名・グループ名とグループのメンバー
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Accounts {
+    users: BTreeMap<u32, String>,
+    groups: BTreeMap<u32, String>,
+    /// グループ名ごとの補助メンバー
+    members: BTreeMap<String, Vec<String>>,
+}
+
+impl Accounts {
+    pub fn load() -> Self {
+        Self::parse(
+            &fs::read_to_string("/etc/passwd").unwrap_or_default(),
+            &fs::read_to_string("/etc/group").unwrap_or_default(),
+        )
+    }
+
+    pub fn parse(passwd: &str, group: &str) -> Self {
+        let mut accounts = Self::default();
+        for fields in passwd.lines().map(|line| line.split(':').collect::<Vec<_>>()) {
+            if let [name, _, uid, ..] = fields[..]
+                && let Ok(uid) = uid.parse()
+            {
+                accounts.users.insert(uid, name.to_string());
+            }
+        }
+        for fields in group.lines().map(|line| line.split(':').collect::<Vec<_>>()) {
+            if let [name, _, gid, members, ..] = fields[..]
+                && let Ok(gid) = gid.parse()
+            {
+                accounts.groups.insert(gid, name.to_string());
+                let members = members.split(',').filter(|m| !m.is_empty()).map(str::to_string);
+                accounts.members.insert(name.to_string(), members.collect());
+            }
+        }
+        accounts
+    }
+
+    pub fn user_name(&self, uid: u32) -> String {
+        self.users.get(&uid).cloned().unwrap_or_else(|| uid.to_string())
+    }
+
+    pub fn group_name(&self, gid: u32) -> String {
+        self.groups.get(&gid).cloned().unwrap_or_else(|| gid.to_string())
+    }
+
+    pub fn group_id(&self, name: &str) -> Option<u32> {
+        self.groups.iter().find(|(_, group)| *group == name).map(|(gid, _)| *gid)
+    }
+
+    /// `/etc/group` 上で `user` が `group` の補助メンバーか (ログインし直す前でもtrue)
+    pub fn is_member(&self, user: &str, group: &str) -> bool {
+        self.members.get(group).is_some_and(|members| members.iter().any(|m| m == user))
+    }
+}
+
+/// このプロセスのuidと所属グループ (`/proc/self/status` の Uid / Gid / Groups)
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Credentials {
+    pub uid: u32,
+    pub gids: Vec<u32>,
+}
+
+impl Credentials {
+    pub fn current() -> Option<Self> {
+        Self::parse(&fs::read_to_string("/proc/self/status").ok()?)
+    }
+
+    pub fn parse(status: &str) -> Option<Self> {
+        let field = |name: &str| {
+            status
+                .lines()
+                .find_map(|line| line.strip_prefix(name))
+                .map(|rest| rest.split_whitespace().filter_map(|id| id.parse::<u32>().ok()).collect::<Vec<_>>())
+        };
+        // Uid / Gid は実・実効・保存・ファイルシステムの順。実効IDを使う
+        let uid = *field("Uid:")?.get(1)?;
+        let mut gids = field("Groups:").unwrap_or_default();
+        gids.extend(field("Gid:")?.get(1));
+        gids.sort();
+        gids.dedup();
+        Some(Self { uid, gids })
+    }
+}
+
+/// udevルールの1行が `vendor_id` / `product_id` のデバイスを対象にしているか
+///
+/// `ATTRS{idVendor}=="04d8", ATTRS{idProduct}=="1455"` の形と、hidrawの親の
+/// `KERNELS=="*:04D8:1455.*"` の形を見る
+pub fn rule_covers(line: &str, vendor_id: u16, product_id: u16) -> bool {
+    let line: String = line.split_whitespace().collect::<String>().to_ascii_lowercase();
+    if line.starts_with('#') {
+        return false;
+    }
+    let vendor = format!("{:04x}", vendor_id);
+    let product = format!("{:04x}", product_id);
+    let by_attrs =
+        line.contains(&format!("{{idvendor}}==\"{}\"", vendor)) && line.contains(&format!("{{idproduct}}==\"{}\"", product));
+    by_attrs || line.contains(&format!(":{}:{}", vendor, product))
+}
+
+/// `dirs` の `*.rules` のうち、`vendor_id` / `product_id` を対象にしたルールがあるファイル
+pub fn find_rules(dirs: &[PathBuf], vendor_id: u16, product_id: u16) -> Vec<PathBuf> {
+    let mut found = Vec::new();
+    for dir in dirs {
+        let Ok(entries) = fs::read_dir(dir) else {
+            continue;
+        };
+        let mut files: Vec<PathBuf> = entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.extension().is_some_and(|ext| ext == "rules"))
+            .collect();
+        files.sort();
+        for path in files {
+            let text = fs::read_to_string(&path).unwrap_or_default();
+            if text.lines().any(|line| rule_covers(line, vendor_id, product_id)) {
+                found.push(path);
+            }
+        }
+    }
+    found
+}
@@ -29,6 +29,7 @@ pub fn merge_filter(cli: &FilterArgs, env: &EnvDefaults) -> FilterArgs {
         product_id: cli.product_id.or(env.product_id),
         usage_page: cli.usage_page.or(env.usage_page),
         usage: cli.usage.or(env.usage),
+        port: cli.port.clone(),
     }
 }
 
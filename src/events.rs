@@ -0,0 +1,101 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use anyhow::{Context, Result};
+use tracing::{debug, trace};
+
+use crate::hid::HidDeviceIo;
+use crate::logging::HID_TRACE_TARGET;
+use crate::profile::DeviceProfile;
+use crate::util::format_bytes;
+
+/// イベントを待つ1回あたりの時間。この間隔で中断(Ctrl-C)を確認する
+pub const LISTEN_POLL_MS: i32 = 200;
+
+/// イベントの種類 (`[event_header, kind, button]` の2バイト目)
+const EVENT_BUTTON_PRESSED: u8 = 0x01;
+const EVENT_BUTTON_RELEASED: u8 = 0x02;
+
+/// locatorが自発的に送るイベント
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LocatorEvent {
+    /// ボタンが押された
+    Pressed { button: u8 },
+    /// ボタンが離された
+    Released { button: u8 },
+    /// このCLIが知らない種類 (新しいファームウェア)
+    Unknown { kind: u8 },
+}
+
+impl LocatorEvent {
+    /// イベントのInput Reportを解釈する。イベントでなければNone
+    ///
+    /// `[event_header, kind, button, ...]`
+    pub fn decode(profile: &DeviceProfile, report: &[u8]) -> Option<Self> {
+        if !profile.is_event(report) {
+            return None;
+        }
+        let kind = report.get(1).copied()?;
+        let button = report.get(2).copied().unwrap_or(0);
+        Some(match kind {
+            EVENT_BUTTON_PRESSED => LocatorEvent::Pressed { button },
+            EVENT_BUTTON_RELEASED => LocatorEvent::Released { button },
+            kind => LocatorEvent::Unknown { kind },
+        })
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            LocatorEvent::Pressed { .. } => "press",
+            LocatorEvent::Released { .. } => "release",
+            LocatorEvent::Unknown { .. } => "unknown",
+        }
+    }
+
+    pub fn button(&self) -> Option<u8> {
+        match self {
+            LocatorEvent::Pressed { button } | LocatorEvent::Released { button } => Some(*button),
+            LocatorEvent::Unknown { .. } => None,
+        }
+    }
+}
+
+/// 受け取ったイベントと元のInput Report
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReceivedEvent {
+    pub event: LocatorEvent,
+    pub raw: Vec<u8>,
+}
+
+/// `cancel` が立つか `limit` 件受け取るまでイベントを読み、受け取るたびに `on_event` を呼ぶ
+///
+/// - 他のCLIの要求への応答(先頭がheader)など、イベント以外のInput Reportは無視する
+/// - `on_event` のエラーで読み取りは止めない (呼び出し側で表示する)
+/// - 受け取ったイベントの件数を返す
+pub fn listen(
+    profile: &DeviceProfile,
+    device: &dyn HidDeviceIo,
+    report_len: usize,
+    cancel: &AtomicBool,
+    limit: Option<usize>,
+    on_event: &mut dyn FnMut(&ReceivedEvent),
+) -> Result<usize> {
+    let mut received = 0;
+    while !cancel.load(Ordering::SeqCst) && limit.is_none_or(|limit| received < limit) {
+        let mut report = vec![0u8; report_len];
+        let len = device
+            .read_timeout(&mut report, LISTEN_POLL_MS)
+            .context("input report受信に失敗")?;
+        if len == 0 {
+            continue;
+        }
+        report.truncate(len);
+        trace!(target: HID_TRACE_TARGET, command = "event", direction = "in", bytes = %format_bytes(&report));
+        let Some(event) = LocatorEvent::decode(profile, &report) else {
+            debug!(bytes = %format_bytes(&report), "イベント以外のInput Reportを無視しました");
+            continue;
+        };
+        received += 1;
+        on_event(&ReceivedEvent { event, raw: report });
+    }
+    Ok(received)
+}
@@ -0,0 +1,454 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::time::Instant;
+
+use anyhow::{Context, Result, anyhow, bail};
+use tracing::{debug, trace, warn};
+
+use crate::hid::HidDeviceIo;
+use crate::logging::HID_TRACE_TARGET;
+use crate::profile::BootloaderLayout;
+use crate::util::format_bytes;
+
+/// ブートローダーのコマンド (先頭バイト)
+pub const BOOTLOADER_WRITE: u8 = 0x11;
+pub const BOOTLOADER_READ: u8 = 0x12;
+pub const BOOTLOADER_RESET: u8 = 0x13;
+
+/// 書き込みコマンドのヘッダ長 `[command, addr(4), len]`
+const BLOCK_HEADER_LEN: usize = 6;
+
+/// Intel HEXから読み込んだファームウェア。アドレス(バイト単位)ごとのデータ
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FirmwareImage {
+    pub data: BTreeMap<u32, u8>,
+}
+
+/// フラッシュに書き込む1ブロック
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FlashBlock {
+    pub address: u32,
+    pub data: Vec<u8>,
+}
+
+impl FirmwareImage {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("ファームウェアを読み込めません: {}", path.display()))?;
+        Self::parse(&text).with_context(|| format!("{} の解析に失敗", path.display()))
+    }
+
+    /// Intel HEXを解析する
+    ///
+    /// - データ(00)、EOF(01)、拡張セグメントアドレス(02)、拡張リニアアドレス(04)に対応
+    /// - 開始アドレス(03/05)は読み飛ばす
+    /// - 各レコードのチェックサムを検証し、同じアドレスへの異なる値はエラー
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut image = Self::default();
+        let mut base = 0u32;
+        let mut ended = false;
+
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let line_no = i + 1;
+            if ended {
+                bail!("{}行目: EOFレコードの後にデータがあります", line_no);
+            }
+            let record = line
+                .strip_prefix(':')
+                .ok_or_else(|| anyhow!("{}行目: ':' で始まっていません", line_no))?;
+            if record.len() % 2 != 0 {
+                bail!("{}行目: 16進数の桁数が奇数です", line_no);
+            }
+            let bytes = (0..record.len())
+                .step_by(2)
+                .map(|j| u8::from_str_radix(&record[j..j + 2], 16))
+                .collect::<std::result::Result<Vec<u8>, _>>()
+                .with_context(|| format!("{}行目: 16進数として読めません", line_no))?;
+            if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5 {
+                bail!("{}行目: レコード長が一致しません", line_no);
+            }
+            let sum = bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+            if sum != 0 {
+                bail!("{}行目: チェックサムが一致しません", line_no);
+            }
+
+            let offset = u16::from_be_bytes([bytes[1], bytes[2]]) as u32;
+            let payload = &bytes[4..bytes.len() - 1];
+            match bytes[3] {
+                0x00 => {
+                    for (j, value) in payload.iter().enumerate() {
+                        let address = base.wrapping_add(offset + j as u32);
+                        if let Some(previous) = image.data.insert(address, *value)
+                            && previous != *value
+                        {
+                            bail!("{}行目: アドレス0x{:08x}に異なる値が重複しています", line_no, address);
+                        }
+                    }
+                }
+                0x01 => ended = true,
+                0x02 if payload.len() == 2 => {
+                    base = (u16::from_be_bytes([payload[0], payload[1]]) as u32) << 4;
+                }
+                0x04 if payload.len() == 2 => {
+                    base = (u16::from_be_bytes([payload[0], payload[1]]) as u32) << 16;
+                }
+                0x03 | 0x05 => {}
+                other => bail!("{}行目: 未対応のレコード種別 0x{:02x}", line_no, other),
+            }
+        }
+
+        if !ended {
+            bail!("EOFレコードがありません");
+        }
+        Ok(image)
+    }
+
+    /// 範囲内のデータをブロック境界で区切る。データの無いブロックは含めず、隙間は0xFF(消去状態)で埋める
+    ///
+    /// 範囲より下(ブートローダー領域)にデータがあればエラー。範囲より上(コンフィグワードなど)は読み飛ばす
+    pub fn blocks(&self, layout: &BootloaderLayout) -> Result<Vec<FlashBlock>> {
+        if let Some((&address, _)) = self.data.range(..layout.app_start).next() {
+            bail!(
+                "アドレス0x{:08x}はブートローダー領域(0x{:08x}未満)です。ブートローダー用にリンクしたHEXを指定してください",
+                address,
+                layout.app_start
+            );
+        }
+        let skipped = self.data.range(layout.app_end..).count();
+        if skipped > 0 {
+            warn!(bytes = skipped, app_end = layout.app_end, "書き込み範囲外のデータを読み飛ばします");
+        }
+
+        let size = layout.block_size as u32;
+        let mut blocks: Vec<FlashBlock> = Vec::new();
+        for (&address, &value) in self.data.range(layout.app_start..layout.app_end) {
+            let start = address - address % size;
+            if blocks.last().is_none_or(|b| b.address != start) {
+                blocks.push(FlashBlock {
+                    address: start,
+                    data: vec![0xff; layout.block_size],
+                });
+            }
+            let block = blocks.last_mut().expect("直前に追加済み");
+            block.data[(address - start) as usize] = value;
+        }
+        Ok(blocks)
+    }
+}
+
+/// ブロックの書き込み/読み出し先
+pub trait FlashTarget {
+    fn write_block(&self, block: &FlashBlock) -> Result<()>;
+    fn read_block(&self, address: u32, len: usize) -> Result<Vec<u8>>;
+}
+
+/// HIDブートローダーとの通信
+///
+/// - 書き込み: `[0x11, addr(LE 4B), len, data...]` → `[header, 0x11, status]` (status 0 で成功)
+/// - 読み出し: `[0x12, addr(LE 4B), len]` → `[header, 0x12, data...]`
+/// - アプリケーションを起動: `[0x13]` (応答なし、再列挙される)
+pub struct HidBootloader<'a> {
+    device: &'a dyn HidDeviceIo,
+    report_len: usize,
+    header: u8,
+    read_timeout_ms: i32,
+}
+
+impl<'a> HidBootloader<'a> {
+    pub fn new(device: &'a dyn HidDeviceIo, report_len: usize, header: u8, read_timeout_ms: i32) -> Self {
+        Self {
+            device,
+            report_len,
+            header,
+            read_timeout_ms,
+        }
+    }
+
+    /// 1回の書き込みで送れる最大ブロック長
+    pub fn max_block_len(&self) -> usize {
+        self.report_len.saturating_sub(BLOCK_HEADER_LEN)
+    }
+
+    fn request(&self, command: u8, address: u32, len: usize, data: &[u8]) -> Result<Vec<u8>> {
+        let mut report = vec![0u8; self.report_len];
+        report[0] = command;
+        report[1..5].copy_from_slice(&address.to_le_bytes());
+        report[5] = len as u8;
+        report[BLOCK_HEADER_LEN..BLOCK_HEADER_LEN + data.len()].copy_from_slice(data);
+        trace!(target: HID_TRACE_TARGET, command, direction = "out", bytes = %format_bytes(&report));
+        self.device
+            .write(&report)
+            .context("output report送信に失敗")?;
+
+        let mut response = vec![0u8; self.report_len];
+        let received = self
+            .device
+            .read_timeout(&mut response, self.read_timeout_ms)
+            .context("input report受信に失敗")?;
+        response.truncate(received);
+        trace!(target: HID_TRACE_TARGET, command, direction = "in", bytes = %format_bytes(&response));
+        if received == 0 {
+            bail!("ブートローダーから応答がありません (アドレス0x{:08x})", address);
+        }
+        if response.len() < 2 || response[0] != self.header || response[1] != command {
+            bail!("ブートローダーの応答が不正です: [{}]", format_bytes(&response));
+        }
+        Ok(response)
+    }
+
+    /// アプリケーションを起動する。デバイスはUSBから切断され、アプリケーションとして再列挙される
+    pub fn reset(&self) -> Result<()> {
+        let mut report = vec![0u8; self.report_len];
+        report[0] = BOOTLOADER_RESET;
+        trace!(target: HID_TRACE_TARGET, command = BOOTLOADER_RESET, direction = "out", bytes = %format_bytes(&report));
+        self.device
+            .write(&report)
+            .context("output report送信に失敗")?;
+        Ok(())
+    }
+}
+
+impl FlashTarget for HidBootloader<'_> {
+    fn write_block(&self, block: &FlashBlock) -> Result<()> {
+        if block.data.len() > self.max_block_len() {
+            bail!(
+                "ブロック長{}がレポートに収まりません (最大{})",
+                block.data.len(),
+                self.max_block_len()
+            );
+        }
+        let response = self.request(BOOTLOADER_WRITE, block.address, block.data.len(), &block.data)?;
+        match response.get(2).copied() {
+            Some(0) => Ok(()),
+            Some(status) => bail!("書き込みエラー (アドレス0x{:08x} status=0x{:02x})", block.address, status),
+            None => bail!("書き込み結果がありません: [{}]", format_bytes(&response)),
+        }
+    }
+
+    fn read_block(&self, address: u32, len: usize) -> Result<Vec<u8>> {
+        let response = self.request(BOOTLOADER_READ, address, len, &[])?;
+        response
+            .get(2..2 + len)
+            .map(|data| data.to_vec())
+            .ok_or_else(|| anyhow!("読み出したデータが不足しています: [{}]", format_bytes(&response)))
+    }
+}
+
+/// 書き込みの進捗
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FlashProgress {
+    /// 既に同じ内容だったので書き込まなかった (中断した更新の再開)
+    Skipped { index: usize, total: usize, address: u32 },
+    /// 書き込んでベリファイした
+    Written { index: usize, total: usize, address: u32 },
+    /// 失敗したので書き直す
+    Retry { address: u32, attempt: u32, error: String },
+}
+
+/// 書き込み結果
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FlashReport {
+    pub written: usize,
+    pub skipped: usize,
+    pub retries: usize,
+}
+
+/// 途中で失敗した書き込み。ここまでのブロックは書き込み済み
+#[derive(Debug)]
+pub struct FlashError {
+    pub address: u32,
+    pub completed: usize,
+    pub total: usize,
+    pub source: anyhow::Error,
+}
+
+impl std::fmt::Display for FlashError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "アドレス0x{:08x}の書き込みに失敗しました ({}/{}ブロック完了): {:#}",
+            self.address, self.completed, self.total, self.source
+        )
+    }
+}
+
+impl std::error::Error for FlashError {}
+
+/// ブロックを順に書き込んでベリファイする
+///
+/// - 書き込む前に読み出し、既に同じ内容のブロックは飛ばす(中断した更新をやり直すと続きから書ける)
+/// - 書き込みかベリファイに失敗したブロックは `retries` 回まで書き直す
+pub fn flash_blocks(
+    target: &dyn FlashTarget,
+    blocks: &[FlashBlock],
+    retries: u32,
+    progress: &mut dyn FnMut(&FlashProgress),
+) -> std::result::Result<FlashReport, FlashError> {
+    let started = Instant::now();
+    let total = blocks.len();
+    let mut report = FlashReport::default();
+
+    for (index, block) in blocks.iter().enumerate() {
+        let fail = |source: anyhow::Error| FlashError {
+            address: block.address,
+            completed: index,
+            total,
+            source,
+        };
+
+        let mut attempt = 0;
+        loop {
+            let result = target.read_block(block.address, block.data.len()).and_then(|current| {
+                if current == block.data {
+                    return Ok(attempt == 0);
+                }
+                target.write_block(block)?;
+                let written = target.read_block(block.address, block.data.len())?;
+                if written != block.data {
+                    bail!(
+                        "ベリファイに失敗しました (アドレス0x{:08x}: [{}])",
+                        block.address,
+                        format_bytes(&written)
+                    );
+                }
+                Ok(false)
+            });
+            match result {
+                Ok(true) => {
+                    report.skipped += 1;
+                    progress(&FlashProgress::Skipped {
+                        index,
+                        total,
+                        address: block.address,
+                    });
+                    break;
+                }
+                Ok(false) => {
+                    report.written += 1;
+                    progress(&FlashProgress::Written {
+                        index,
+                        total,
+                        address: block.address,
+                    });
+                    break;
+                }
+                Err(err) if attempt < retries => {
+                    attempt += 1;
+                    report.retries += 1;
+                    progress(&FlashProgress::Retry {
+                        address: block.address,
+                        attempt,
+                        error: format!("{:#}", err),
+                    });
+                }
+                Err(err) => return Err(fail(err)),
+            }
+        }
+    }
+
+    debug!(
+        written = report.written,
+        skipped = report.skipped,
+        retries = report.retries,
+        elapsed_ms = started.elapsed().as_millis() as u64,
+        "書き込み完了"
+    );
+    Ok(report)
+}
+
+#[cfg(test)]
+pub mod simulated {
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+
+    use hidapi::{HidError, HidResult};
+
+    use super::*;
+
+    /// テスト用のHIDブートローダー。フラッシュを0xFFで初期化し、書き込み/読み出しコマンドに応答する
+    ///
+    /// `fail_writes` のアドレスへの書き込みはエラー応答、`corrupt_writes` のアドレスには化けた値を書く(いずれも1回ずつ)
+    pub struct SimulatedBootloader {
+        pub flash: RefCell<Vec<u8>>,
+        pub writes: RefCell<Vec<u32>>,
+        pub fail_writes: RefCell<Vec<u32>>,
+        pub corrupt_writes: RefCell<Vec<u32>>,
+        pub reset: RefCell<bool>,
+        responses: RefCell<VecDeque<Vec<u8>>>,
+    }
+
+    impl SimulatedBootloader {
+        pub fn new(size: usize) -> Self {
+            Self {
+                flash: RefCell::new(vec![0xff; size]),
+                writes: RefCell::new(Vec::new()),
+                fail_writes: RefCell::new(Vec::new()),
+                corrupt_writes: RefCell::new(Vec::new()),
+                reset: RefCell::new(false),
+                responses: RefCell::new(VecDeque::new()),
+            }
+        }
+
+        fn take(list: &RefCell<Vec<u32>>, address: u32) -> bool {
+            let mut list = list.borrow_mut();
+            match list.iter().position(|a| *a == address) {
+                Some(index) => {
+                    list.remove(index);
+                    true
+                }
+                None => false,
+            }
+        }
+    }
+
+    impl HidDeviceIo for SimulatedBootloader {
+        fn write(&self, data: &[u8]) -> HidResult<usize> {
+            let address = u32::from_le_bytes([data[1], data[2], data[3], data[4]]);
+            let len = data[5] as usize;
+            let range = address as usize..address as usize + len;
+            let response = match data[0] {
+                BOOTLOADER_WRITE => {
+                    self.writes.borrow_mut().push(address);
+                    if Self::take(&self.fail_writes, address) {
+                        vec![0xff, BOOTLOADER_WRITE, 0x01]
+                    } else {
+                        let mut payload = data[BLOCK_HEADER_LEN..BLOCK_HEADER_LEN + len].to_vec();
+                        if Self::take(&self.corrupt_writes, address) {
+                            payload[0] ^= 0xff;
+                        }
+                        self.flash.borrow_mut()[range].copy_from_slice(&payload);
+                        vec![0xff, BOOTLOADER_WRITE, 0x00]
+                    }
+                }
+                BOOTLOADER_READ => {
+                    let mut response = vec![0xff, BOOTLOADER_READ];
+                    response.extend_from_slice(&self.flash.borrow()[range]);
+                    response
+                }
+                BOOTLOADER_RESET => {
+                    *self.reset.borrow_mut() = true;
+                    return Ok(data.len());
+                }
+                other => vec![0x00, other],
+            };
+            self.responses.borrow_mut().push_back(response);
+            Ok(data.len())
+        }
+
+        fn read_timeout(&self, data: &mut [u8], _timeout_ms: i32) -> HidResult<usize> {
+            let response = self.responses.borrow_mut().pop_front().ok_or_else(|| {
+                HidError::HidApiError {
+                    message: "simulated bootloader has no response".to_string(),
+                }
+            })?;
+            let len = data.len().min(response.len());
+            data[..len].copy_from_slice(&response[..len]);
+            Ok(len)
+        }
+    }
+}
@@ -0,0 +1,726 @@
+use std::ffi::CString;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result, anyhow, bail};
+use chrono::Local;
+use tokio::runtime::Runtime;
+use tokio::sync::{broadcast, oneshot};
+use tokio_stream::wrappers::{BroadcastStream, TcpListenerStream};
+use tokio_stream::{Stream, StreamExt};
+use tonic::metadata::{Ascii, MetadataValue};
+use tonic::transport::{Certificate, Channel, ClientTlsConfig, Endpoint, Identity, Server, ServerTlsConfig};
+use tonic::{Request, Response, Status, Streaming};
+use tracing::{debug, warn};
+
+use crate::audit::{AuditRecord, Change};
+use crate::auth::{AccessControl, Caller, Denied, Permission, bearer_token};
+use crate::backend::LocatorBackend;
+use crate::cli::{FilterArgs, LockArgs, ProtocolArgs, RemoteTlsArgs};
+use crate::commands::{open_locked, stored_default_mask};
+use crate::daemon::{Daemon, DaemonEvent};
+use crate::hid::{DeviceDescriptor, HidDeviceIo, LocatorStatus, retain_id};
+use crate::info::CapabilityCache;
+use crate::profile::{DeviceProfile, ProfileRegistry, error_kind};
+use crate::schedule::Scheduler;
+
+/// `proto/locator.proto` から生成したコード
+pub mod proto {
+    tonic::include_proto!("cap_locator.v1");
+}
+
+use proto::locator_event::Kind;
+use proto::locator_service_client::LocatorServiceClient;
+use proto::locator_service_server::{LocatorService, LocatorServiceServer};
+
+/// 要求を待つ1回あたりの時間。この間隔で中断(Ctrl-C)を確認する
+const STOP_POLL: Duration = Duration::from_millis(100);
+
+/// StreamEventsの受信が遅いクライアントのために溜めておくイベントの数。あふれた分は捨てる
+const EVENT_BUFFER: usize = 64;
+
+/// `grpc-serve` がlocatorを操作するときの設定
+#[derive(Clone, Debug)]
+pub struct ServeOptions {
+    /// .envとプロファイルで補完済みのフィルタ
+    pub filter: FilterArgs,
+    pub protocol: ProtocolArgs,
+    pub lock: LockArgs,
+    /// locatorの列挙と状態の確認の間隔
+    pub interval: Duration,
+}
+
+/// `grpc-serve` のTLSの設定 (PEMファイル)
+#[derive(Clone, Debug)]
+pub struct ServerTls {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    /// 指定するとクライアント証明書を必須にする (mTLS)
+    pub client_ca: Option<PathBuf>,
+}
+
+impl ServerTls {
+    fn config(&self) -> Result<ServerTlsConfig> {
+        let identity = Identity::from_pem(read_pem(&self.cert)?, read_pem(&self.key)?);
+        let mut config = ServerTlsConfig::new().identity(identity);
+        if let Some(ca) = &self.client_ca {
+            config = config.client_ca_root(Certificate::from_pem(read_pem(ca)?));
+        }
+        Ok(config)
+    }
+}
+
+fn read_pem(path: &Path) -> Result<Vec<u8>> {
+    fs::read(path).with_context(|| format!("{} を読み込めません", path.display()))
+}
+
+/// 証明書(DER)のSHA-256 (`[[clients]]` の cert_sha256 と比べる)
+pub fn certificate_sha256(der: &[u8]) -> String {
+    ring::digest::digest(&ring::digest::SHA256, der)
+        .as_ref()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// 認証済みの要求の送り手
+struct Requester {
+    caller: Caller,
+    /// 接続元のアドレス
+    client: String,
+}
+
+/// サービスが受け付けた要求。locatorの操作はバックエンドを持つスレッドでまとめて行う
+enum Call {
+    List(Caller, oneshot::Sender<Result<proto::ListResponse, Status>>),
+    GetStatus(Caller, proto::GetStatusRequest, oneshot::Sender<Result<proto::GetStatusResponse, Status>>),
+    SetLeds(Requester, String, u32, oneshot::Sender<Result<proto::SetLedsResponse, Status>>),
+}
+
+struct Service {
+    calls: mpsc::Sender<Call>,
+    events: broadcast::Sender<proto::LocatorEvent>,
+    access: AccessControl,
+}
+
+impl Service {
+    /// トークン (`authorization` メタデータ) かクライアント証明書でクライアントを決め、要求数を数える
+    fn authenticate<T>(&self, request: &Request<T>) -> Result<Requester, Status> {
+        let client = request
+            .remote_addr()
+            .map_or_else(|| "-".to_string(), |addr| addr.to_string());
+        let token = request
+            .metadata()
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(bearer_token);
+        let fingerprint = request
+            .peer_certs()
+            .and_then(|certs| certs.first().map(|cert| certificate_sha256(cert)));
+        let result = match self.access.authenticate(token, fingerprint.as_deref()) {
+            Ok(caller) => self.access.admit(Some(caller), &client).map(|()| caller.clone()),
+            Err(denied) => self.access.admit(None, &client).and(Err(denied)),
+        };
+        match result {
+            Ok(caller) => Ok(Requester { caller, client }),
+            Err(denied) => {
+                warn!(client, "gRPCの要求を断りました: {}", denied);
+                Err(denied_status(&denied))
+            }
+        }
+    }
+
+    async fn call<T>(&self, make: impl FnOnce(oneshot::Sender<Result<T, Status>>) -> Call) -> Result<T, Status> {
+        let (reply, answer) = oneshot::channel();
+        self.calls
+            .send(make(reply))
+            .map_err(|_| Status::unavailable("サーバーを停止しています"))?;
+        answer
+            .await
+            .map_err(|_| Status::unavailable("サーバーを停止しています"))?
+    }
+}
+
+type EventStream = Pin<Box<dyn Stream<Item = Result<proto::LocatorEvent, Status>> + Send>>;
+
+#[tonic::async_trait]
+impl LocatorService for Service {
+    async fn list(&self, request: Request<proto::ListRequest>) -> Result<Response<proto::ListResponse>, Status> {
+        let requester = self.authenticate(&request)?;
+        self.call(|reply| Call::List(requester.caller, reply))
+            .await
+            .map(Response::new)
+    }
+
+    async fn get_status(
+        &self,
+        request: Request<proto::GetStatusRequest>,
+    ) -> Result<Response<proto::GetStatusResponse>, Status> {
+        let requester = self.authenticate(&request)?;
+        let request = request.into_inner();
+        self.call(|reply| Call::GetStatus(requester.caller, request, reply))
+            .await
+            .map(Response::new)
+    }
+
+    async fn set_leds(
+        &self,
+        request: Request<proto::SetLedsRequest>,
+    ) -> Result<Response<proto::SetLedsResponse>, Status> {
+        let requester = self.authenticate(&request)?;
+        let request = request.into_inner();
+        self.call(|reply| Call::SetLeds(requester, request.id, request.mask, reply))
+            .await
+            .map(Response::new)
+    }
+
+    type StreamEventsStream = EventStream;
+
+    async fn stream_events(
+        &self,
+        request: Request<proto::StreamEventsRequest>,
+    ) -> Result<Response<Self::StreamEventsStream>, Status> {
+        let caller = self.authenticate(&request)?.caller;
+        let id = request.into_inner().id;
+        let stream = BroadcastStream::new(self.events.subscribe()).filter_map(move |event| match event {
+            Ok(event) if (id.is_empty() || event.id == id) && caller.can_access_id(&event.id) => Some(Ok(event)),
+            Ok(_) => None,
+            Err(err) => {
+                warn!("StreamEventsの送信が追いつかずイベントを捨てました: {}", err);
+                None
+            }
+        });
+        Ok(Response::new(Box::pin(stream)))
+    }
+}
+
+/// gRPCの LocatorService
+///
+/// - 通信は別スレッドのtokioランタイムで受け、locatorの操作は `run` を呼んだスレッドで順に行う
+/// - クライアントごとに見えるlocatorとLEDを設定できるかを `AccessControl` で決める
+/// - `interval` ごとに列挙とステータス取得を行い、接続/切断とLEDの変化を StreamEvents で送る
+/// - Dropするとサーバーを止める
+pub struct GrpcServer {
+    runtime: Option<Runtime>,
+    local_addr: SocketAddr,
+    calls: Receiver<Call>,
+    events: broadcast::Sender<proto::LocatorEvent>,
+    shutdown: Option<oneshot::Sender<()>>,
+}
+
+impl GrpcServer {
+    /// `listen` (`127.0.0.1:50051` など。ポート0なら空いているポート) で待ち受けを始める
+    pub fn bind(listen: &str, access: AccessControl, tls: Option<&ServerTls>) -> Result<Self> {
+        let mut builder = Server::builder();
+        if let Some(tls) = tls {
+            builder = builder
+                .tls_config(tls.config()?)
+                .context("TLSの設定が不正です")?;
+        }
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .context("tokioランタイムを作れません")?;
+        let listener = runtime
+            .block_on(tokio::net::TcpListener::bind(listen))
+            .with_context(|| format!("gRPC用のポートを開けません: {}", listen))?;
+        let local_addr = listener.local_addr()?;
+        let (calls_tx, calls) = mpsc::channel();
+        let (events, _) = broadcast::channel(EVENT_BUFFER);
+        let service = Service {
+            calls: calls_tx,
+            events: events.clone(),
+            access,
+        };
+        let (shutdown, signal) = oneshot::channel::<()>();
+        runtime.spawn(async move {
+            let result = builder
+                .add_service(LocatorServiceServer::new(service))
+                .serve_with_incoming_shutdown(TcpListenerStream::new(listener), async {
+                    let _ = signal.await;
+                })
+                .await;
+            if let Err(err) = result {
+                warn!("gRPCサーバーが停止しました: {}", err);
+            }
+        });
+        Ok(Self {
+            runtime: Some(runtime),
+            local_addr,
+            calls,
+            events,
+            shutdown: Some(shutdown),
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// `stop` が立つまで要求に答え続ける
+    ///
+    /// `on_event` には検出した変化を時刻(RFC 3339)付きで、`on_audit` にはSetLedsの記録を渡す
+    pub fn run(
+        &self,
+        backend: &mut dyn LocatorBackend,
+        profiles: &ProfileRegistry,
+        options: &ServeOptions,
+        stop: &AtomicBool,
+        on_event: &mut dyn FnMut(&str, &DaemonEvent),
+        on_audit: &mut dyn FnMut(&AuditRecord),
+    ) -> Result<()> {
+        let mut daemon = Daemon::new(Scheduler::default(), profiles.clone()).watch_status(true);
+        let mut capabilities = CapabilityCache::default();
+        let mut next_tick = Instant::now();
+        while !stop.load(Ordering::SeqCst) {
+            if Instant::now() >= next_tick {
+                self.tick(&mut daemon, backend, options, on_event);
+                next_tick = Instant::now() + options.interval;
+            }
+            let wait = next_tick.saturating_duration_since(Instant::now()).min(STOP_POLL);
+            match self.calls.recv_timeout(wait) {
+                Ok(call) => answer(call, backend, profiles, options, &mut capabilities, on_audit),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => bail!("gRPCサーバーが停止しました"),
+            }
+        }
+        Ok(())
+    }
+
+    fn tick(
+        &self,
+        daemon: &mut Daemon,
+        backend: &mut dyn LocatorBackend,
+        options: &ServeOptions,
+        on_event: &mut dyn FnMut(&str, &DaemonEvent),
+    ) {
+        if let Err(err) = backend.refresh() {
+            warn!("HIDデバイス一覧を更新できません: {:#}", err);
+        }
+        let devices = match backend.devices(&options.filter) {
+            Ok(devices) => devices,
+            Err(err) => {
+                warn!("locatorを列挙できません: {:#}", err);
+                return;
+            }
+        };
+        let now = Local::now();
+        let events = daemon.tick(&now, &devices, &options.protocol, &mut |device| {
+            Ok(Box::new(open_locked(&*backend, device, &options.lock)?) as Box<dyn HidDeviceIo>)
+        });
+        let timestamp = now.to_rfc3339();
+        for event in events {
+            on_event(&timestamp, &event);
+            // 購読しているクライアントが居なければ送れないが、それで構わない
+            let _ = self.events.send(event_message(&timestamp, &event));
+        }
+    }
+}
+
+impl Drop for GrpcServer {
+    fn drop(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+        // StreamEventsを購読中のクライアントが居ても待たない
+        if let Some(runtime) = self.runtime.take() {
+            runtime.shutdown_timeout(Duration::from_secs(1));
+        }
+    }
+}
+
+fn answer(
+    call: Call,
+    backend: &dyn LocatorBackend,
+    profiles: &ProfileRegistry,
+    options: &ServeOptions,
+    capabilities: &mut CapabilityCache,
+    on_audit: &mut dyn FnMut(&AuditRecord),
+) {
+    // 応答を待たずに切断したクライアントには送れないが、それで構わない
+    match call {
+        Call::List(caller, reply) => {
+            debug!(caller = caller.name, "List");
+            let _ = reply.send(list(backend, profiles, options, &caller));
+        }
+        Call::GetStatus(caller, request, reply) => {
+            debug!(caller = caller.name, id = request.id, "GetStatus");
+            let _ = reply.send(get_status(backend, profiles, options, capabilities, &caller, &request));
+        }
+        Call::SetLeds(requester, id, mask, reply) => {
+            debug!(caller = requester.caller.name, id, mask, "SetLeds");
+            let _ = reply.send(set_leds(backend, profiles, options, &requester, &id, mask, on_audit));
+        }
+    }
+}
+
+/// フィルタに一致し、クライアントが操作できるlocator
+fn accessible(
+    backend: &dyn LocatorBackend,
+    options: &ServeOptions,
+    caller: &Caller,
+) -> Result<Vec<DeviceDescriptor>, Status> {
+    let mut devices = backend.devices(&options.filter).map_err(to_status)?;
+    devices.retain(|device| caller.can_access(device));
+    Ok(devices)
+}
+
+fn list(
+    backend: &dyn LocatorBackend,
+    profiles: &ProfileRegistry,
+    options: &ServeOptions,
+    caller: &Caller,
+) -> Result<proto::ListResponse, Status> {
+    let devices = accessible(backend, options, caller)?;
+    Ok(proto::ListResponse {
+        locators: devices
+            .iter()
+            .map(|device| locator_message(device, profiles.for_device(device)))
+            .collect(),
+    })
+}
+
+fn get_status(
+    backend: &dyn LocatorBackend,
+    profiles: &ProfileRegistry,
+    options: &ServeOptions,
+    capabilities: &mut CapabilityCache,
+    caller: &Caller,
+    request: &proto::GetStatusRequest,
+) -> Result<proto::GetStatusResponse, Status> {
+    let mut devices = accessible(backend, options, caller)?;
+    if !request.id.is_empty() {
+        retain_id(&mut devices, &request.id);
+    }
+    if devices.is_empty() {
+        return Err(Status::not_found("対象となるlocatorが見つかりませんでした"));
+    }
+
+    let statuses = devices
+        .iter()
+        .map(|device| {
+            let locator_id = device.locator_id();
+            let profile = profiles.for_device(device);
+            let handle = open_locked(backend, device, &options.lock)?;
+            let status = profile
+                .query_status(&handle, &options.protocol)
+                .with_context(|| format!("ステータス取得に失敗しました (id={})", locator_id))?;
+            let default = request
+                .with_default_mask
+                .then(|| stored_default_mask(profile, device, &handle, &options.protocol, capabilities))
+                .flatten();
+            Ok(status_message(&locator_id, profile, &status, default))
+        })
+        .collect::<Result<_>>()
+        .map_err(to_status)?;
+    Ok(proto::GetStatusResponse { statuses })
+}
+
+fn set_leds(
+    backend: &dyn LocatorBackend,
+    profiles: &ProfileRegistry,
+    options: &ServeOptions,
+    requester: &Requester,
+    id: &str,
+    mask: u32,
+    on_audit: &mut dyn FnMut(&AuditRecord),
+) -> Result<proto::SetLedsResponse, Status> {
+    let mask = u8::try_from(mask)
+        .map_err(|_| Status::invalid_argument(format!("maskは0x00〜0xffで指定してください: {}", mask)))?;
+    let device = pick_accessible(backend, options, &requester.caller, id)?;
+    let locator_id = device.locator_id();
+    let result = requester
+        .caller
+        .authorize(&device, Permission::Control)
+        .map_err(|denied| denied_status(&denied))
+        .and_then(|()| apply_mask(backend, profiles, options, &device, mask));
+    let change = Change::api("SetLeds", Some(&requester.caller.name), Some(&requester.client));
+    let outcome = result.as_ref().map(|_| ()).map_err(|status| status.message().to_string());
+    let record = AuditRecord::new(&locator_id, device.serial_number.as_deref(), Some(mask), &change, outcome);
+    record.emit();
+    on_audit(&record);
+    result
+}
+
+/// idに一致し、クライアントが操作できる1台 (idが空ならフィルタに一致する1台)
+fn pick_accessible(
+    backend: &dyn LocatorBackend,
+    options: &ServeOptions,
+    caller: &Caller,
+    id: &str,
+) -> Result<DeviceDescriptor, Status> {
+    let mut candidates = accessible(backend, options, caller)?;
+    if !id.is_empty() {
+        retain_id(&mut candidates, id);
+    }
+    match candidates.len() {
+        0 if id.is_empty() => Err(Status::failed_precondition("フィルタに一致するlocatorがありませんでした")),
+        0 => Err(Status::failed_precondition(format!("一致するlocatorがありませんでした: {}", id))),
+        1 => Ok(candidates.remove(0)),
+        _ => Err(Status::failed_precondition(
+            "複数のlocatorが見つかりました。idを指定するか、vendor/productやusageで絞り込んでください",
+        )),
+    }
+}
+
+fn apply_mask(
+    backend: &dyn LocatorBackend,
+    profiles: &ProfileRegistry,
+    options: &ServeOptions,
+    device: &DeviceDescriptor,
+    mask: u8,
+) -> Result<proto::SetLedsResponse, Status> {
+    let locator_id = device.locator_id();
+    let profile = profiles.for_device(device);
+    let status = open_locked(backend, device, &options.lock)
+        .and_then(|handle| {
+            profile
+                .set_mask(&handle, &options.protocol, mask)
+                .with_context(|| format!("LED制御に失敗しました (id={})", locator_id))?;
+            profile
+                .query_status(&handle, &options.protocol)
+                .with_context(|| format!("ステータス取得に失敗しました (id={})", locator_id))
+        })
+        .map_err(to_status)?;
+    Ok(proto::SetLedsResponse {
+        status: Some(status_message(&locator_id, profile, &status, None)),
+    })
+}
+
+/// 認証できない・権限が無い・要求が多すぎるときのgRPCのステータス
+fn denied_status(denied: &Denied) -> Status {
+    match denied {
+        Denied::Unauthenticated(message) => Status::unauthenticated(message.as_str()),
+        Denied::Forbidden(message) => Status::permission_denied(message.as_str()),
+        Denied::RateLimited(message) => Status::resource_exhausted(message.as_str()),
+    }
+}
+
+/// 応答のタイムアウトは DEADLINE_EXCEEDED、HIDの入出力の失敗は UNAVAILABLE、それ以外は INTERNAL
+fn to_status(err: anyhow::Error) -> Status {
+    let message = format!("{:#}", err);
+    match error_kind(&err) {
+        "timeout" => Status::deadline_exceeded(message),
+        "io" => Status::unavailable(message),
+        _ => Status::internal(message),
+    }
+}
+
+fn locator_message(device: &DeviceDescriptor, profile: &DeviceProfile) -> proto::Locator {
+    proto::Locator {
+        id: device.locator_id(),
+        serial: device.serial_number.clone(),
+        vendor_id: device.vendor_id.into(),
+        product_id: device.product_id.into(),
+        usage_page: device.usage_page.map(u32::from),
+        usage: device.usage.map(u32::from),
+        port: device.port.clone(),
+        profile: profile.name.clone(),
+        path: device.path.to_string_lossy().into_owned(),
+    }
+}
+
+fn status_message(
+    locator_id: &str,
+    profile: &DeviceProfile,
+    status: &LocatorStatus,
+    default: Option<u8>,
+) -> proto::LedStatus {
+    proto::LedStatus {
+        id: locator_id.to_string(),
+        is_on: status.is_on,
+        mask: status.mask.into(),
+        lit: profile.lit_leds(status.mask).into_iter().map(str::to_string).collect(),
+        raw: status.raw.clone(),
+        default_mask: default.map(u32::from),
+    }
+}
+
+fn event_message(timestamp: &str, event: &DaemonEvent) -> proto::LocatorEvent {
+    let (kind, id, mask, error) = match event {
+        DaemonEvent::Attached { id } => (Kind::Attached, id, None, None),
+        DaemonEvent::Detached { id } => (Kind::Detached, id, None, None),
+        DaemonEvent::Applied { id, mask } | DaemonEvent::Changed { id, mask } => {
+            (Kind::Changed, id, Some(u32::from(*mask)), None)
+        }
+        DaemonEvent::Failed { id, error } => (Kind::Failed, id, None, Some(error.clone())),
+    };
+    proto::LocatorEvent {
+        timestamp: timestamp.to_string(),
+        kind: kind.into(),
+        id: id.clone(),
+        mask,
+        error,
+    }
+}
+
+impl proto::Locator {
+    /// `list` の表示に使う列挙結果。Manufacturer/Product文字列とリリース番号は送らないので空
+    pub fn descriptor(&self) -> Result<DeviceDescriptor> {
+        Ok(DeviceDescriptor {
+            path: CString::new(self.path.as_str()).context("HIDパスにNULが含まれています")?,
+            vendor_id: u16::try_from(self.vendor_id)?,
+            product_id: u16::try_from(self.product_id)?,
+            serial_number: self.serial.clone(),
+            usage_page: self.usage_page.map(u16::try_from).transpose()?,
+            usage: self.usage.map(u16::try_from).transpose()?,
+            manufacturer: None,
+            product: None,
+            release_number: 0,
+            port: self.port.clone(),
+        })
+    }
+}
+
+impl proto::LedStatus {
+    pub fn locator_status(&self) -> Result<LocatorStatus> {
+        Ok(LocatorStatus {
+            is_on: self.is_on,
+            mask: u8::try_from(self.mask)?,
+            raw: self.raw.clone(),
+        })
+    }
+}
+
+/// `--remote` の値 (`host:port` または `grpc://host:port`) を接続先のURLにする。TLSなら https
+pub fn remote_endpoint(remote: &str, tls: bool) -> Result<String> {
+    let address = remote.strip_prefix("grpc://").unwrap_or(remote);
+    if address.is_empty() || address.contains("://") {
+        bail!("--remote は host:port の形式で指定してください: {}", remote);
+    }
+    let scheme = if tls { "https" } else { "http" };
+    Ok(format!("{}://{}", scheme, address.trim_end_matches('/')))
+}
+
+/// `grpc-serve` のクライアント。呼び出しは完了するまでブロックする
+pub struct GrpcClient {
+    runtime: Runtime,
+    client: LocatorServiceClient<Channel>,
+    authorization: Option<MetadataValue<Ascii>>,
+}
+
+impl GrpcClient {
+    /// `tls.remote_ca` があればTLSで、さらに `remote_cert` があればクライアント証明書を提示して接続する
+    pub fn connect(remote: &str, token: Option<&str>, tls: &RemoteTlsArgs) -> Result<Self> {
+        let mut endpoint = Endpoint::from_shared(remote_endpoint(remote, tls.remote_ca.is_some())?)
+            .with_context(|| format!("--remote が不正です: {}", remote))?;
+        if let Some(ca) = &tls.remote_ca {
+            let mut config = ClientTlsConfig::new().ca_certificate(Certificate::from_pem(read_pem(ca)?));
+            if let (Some(cert), Some(key)) = (&tls.remote_cert, &tls.remote_key) {
+                config = config.identity(Identity::from_pem(read_pem(cert)?, read_pem(key)?));
+            }
+            endpoint = endpoint.tls_config(config).context("TLSの設定が不正です")?;
+        }
+        let authorization = token
+            .map(|token| MetadataValue::try_from(format!("Bearer {}", token)))
+            .transpose()
+            .context("トークンに使えない文字が含まれています")?;
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .context("tokioランタイムを作れません")?;
+        let channel = runtime
+            .block_on(endpoint.connect())
+            .with_context(|| format!("{} に接続できません", remote))?;
+        Ok(Self {
+            runtime,
+            client: LocatorServiceClient::new(channel),
+            authorization,
+        })
+    }
+
+    /// トークンを付けた要求
+    fn request<T>(&self, message: T) -> Request<T> {
+        let mut request = Request::new(message);
+        if let Some(authorization) = &self.authorization {
+            request.metadata_mut().insert("authorization", authorization.clone());
+        }
+        request
+    }
+
+    pub fn list(&mut self) -> Result<Vec<proto::Locator>> {
+        let request = self.request(proto::ListRequest {});
+        let response = self
+            .runtime
+            .block_on(self.client.list(request))
+            .map_err(remote_error)?;
+        Ok(response.into_inner().locators)
+    }
+
+    /// idが未指定ならサーバー側のフィルタに一致する全て
+    pub fn status(&mut self, id: Option<&str>, with_default_mask: bool) -> Result<Vec<proto::LedStatus>> {
+        let request = self.request(proto::GetStatusRequest {
+            id: id.unwrap_or_default().to_string(),
+            with_default_mask,
+        });
+        let response = self
+            .runtime
+            .block_on(self.client.get_status(request))
+            .map_err(remote_error)?;
+        Ok(response.into_inner().statuses)
+    }
+
+    /// idが未指定ならサーバー側のフィルタに一致する1台。設定後の状態を返す
+    pub fn set_leds(&mut self, id: Option<&str>, mask: u8) -> Result<proto::LedStatus> {
+        let request = self.request(proto::SetLedsRequest {
+            id: id.unwrap_or_default().to_string(),
+            mask: mask.into(),
+        });
+        let response = self
+            .runtime
+            .block_on(self.client.set_leds(request))
+            .map_err(remote_error)?;
+        response
+            .into_inner()
+            .status
+            .ok_or_else(|| anyhow!("SetLedsの応答に状態がありません"))
+    }
+
+    /// 接続/切断とLEDの変化の購読を始める
+    pub fn events(&mut self, id: Option<&str>) -> Result<RemoteEvents<'_>> {
+        let request = self.request(proto::StreamEventsRequest {
+            id: id.unwrap_or_default().to_string(),
+        });
+        let stream = self
+            .runtime
+            .block_on(self.client.stream_events(request))
+            .map_err(remote_error)?
+            .into_inner();
+        Ok(RemoteEvents {
+            runtime: &self.runtime,
+            stream,
+        })
+    }
+}
+
+/// StreamEventsで受け取るイベント
+pub struct RemoteEvents<'a> {
+    runtime: &'a Runtime,
+    stream: Streaming<proto::LocatorEvent>,
+}
+
+impl RemoteEvents<'_> {
+    /// 次のイベント。`timeout` 以内に届かなければNone、サーバーが終了したらエラー
+    pub fn next(&mut self, timeout: Duration) -> Result<Option<proto::LocatorEvent>> {
+        let stream = &mut self.stream;
+        let message = self
+            .runtime
+            .block_on(async { tokio::time::timeout(timeout, stream.message()).await });
+        match message {
+            Err(_) => Ok(None),
+            Ok(Ok(Some(event))) => Ok(Some(event)),
+            Ok(Ok(None)) => bail!("サーバーがStreamEventsを終了しました"),
+            Ok(Err(status)) => Err(remote_error(status)),
+        }
+    }
+}
+
+/// サーバー側のエラーメッセージをローカルで実行したときと同じ形で表示する
+fn remote_error(status: Status) -> anyhow::Error {
+    anyhow!("{}", status.message())
+}
@@ -1,13 +1,16 @@
 use std::ffi::CString;
+use std::time::Instant;
 
-use anyhow::{anyhow, bail, Context, Result};
+use anyhow::{anyhow, Result};
 use hidapi::{HidApi, HidDevice};
+use tracing::{debug, debug_span};
 
-use crate::util::format_bytes;
+use crate::backend::LocatorBackend;
+use crate::profile::DeviceProfile;
+use crate::topology::usb_port;
 
-const COMMAND_STATUS: u8 = 0x01;
-const COMMAND_SET: u8 = 0x02;
-const RESPONSE_HEADER: u8 = 0xFF;
+/// USBポートの経路で表したlocator idの接頭辞
+pub const PORT_ID_PREFIX: &str = "port:";
 
 /// HIDデバイスIOを抽象化するトレイト（テストでモックしやすくするため）
 pub trait HidDeviceIo {
@@ -15,6 +18,16 @@ pub trait HidDeviceIo {
     fn read_timeout(&self, data: &mut [u8], timeout_ms: i32) -> hidapi::HidResult<usize>;
 }
 
+impl<T: HidDeviceIo + ?Sized> HidDeviceIo for Box<T> {
+    fn write(&self, data: &[u8]) -> hidapi::HidResult<usize> {
+        (**self).write(data)
+    }
+
+    fn read_timeout(&self, data: &mut [u8], timeout_ms: i32) -> hidapi::HidResult<usize> {
+        (**self).read_timeout(data, timeout_ms)
+    }
+}
+
 impl HidDeviceIo for HidDevice {
     fn write(&self, data: &[u8]) -> hidapi::HidResult<usize> {
         HidDevice::write(self, data)
@@ -27,7 +40,7 @@ impl HidDeviceIo for HidDevice {
 
 use crate::cli::{FilterArgs, ProtocolArgs};
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct DeviceDescriptor {
     pub path: CString,
     pub vendor_id: u16,
@@ -35,6 +48,12 @@ pub struct DeviceDescriptor {
     pub serial_number: Option<String>,
     pub usage_page: Option<u16>,
     pub usage: Option<u16>,
+    /// USBのManufacturer/Product文字列とリリース番号(bcdDevice)
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    pub release_number: u16,
+    /// 挿さっているUSBポートの経路 (`1-2.3`)。分からなければNone
+    pub port: Option<String>,
 }
 
 impl DeviceDescriptor {
@@ -61,15 +80,32 @@ impl DeviceDescriptor {
             serial_number,
             usage_page,
             usage,
+            manufacturer: info.manufacturer_string().map(|s| s.to_string()),
+            product: info.product_string().map(|s| s.to_string()),
+            release_number: info.release_number(),
+            port: usb_port(&info.path().to_string_lossy()),
         }
     }
 
+    /// シリアル番号、無ければUSBポートの経路(`port:1-2.3`)、それも無ければHIDパス
     pub fn locator_id(&self) -> String {
-        self.serial_number
-            .clone()
-            .unwrap_or_else(|| self.path.to_string_lossy().into_owned())
+        if let Some(serial) = &self.serial_number {
+            return serial.clone();
+        }
+        match &self.port {
+            Some(port) => format!("{}{}", PORT_ID_PREFIX, port),
+            None => self.path.to_string_lossy().into_owned(),
+        }
+    }
+
+    /// idが挿し直しで変わらないか (シリアル番号かUSBポートの経路)
+    pub fn has_stable_id(&self) -> bool {
+        self.serial_number.is_some() || self.port.is_some()
     }
 
+    /// シリアル番号・HIDパスの部分文字列か、USBポートの経路が `query` に一致するか
+    ///
+    /// 複数のlocatorから選ぶときは完全一致を優先する `IdQuery` を使う
     pub fn matches_id(&self, query: &str) -> bool {
         if query.is_empty() {
             return false;
@@ -79,10 +115,56 @@ impl DeviceDescriptor {
             .as_ref()
             .map(|serial| serial == query || serial.contains(query))
             .unwrap_or(false);
-        serial_match || self.path.to_string_lossy().contains(query)
+        let port_match = self.port.as_deref().is_some_and(|port| {
+            port == query || query.strip_prefix(PORT_ID_PREFIX) == Some(port)
+        });
+        serial_match || port_match || self.path.to_string_lossy().contains(query)
     }
+
+    /// シリアル番号・USBポートの経路・HIDパスのどれかが `query` と完全に一致するか
+    pub fn is_exact_id(&self, query: &str) -> bool {
+        !query.is_empty()
+            && (self.serial_number.as_deref() == Some(query)
+                || self.port.as_deref().is_some_and(|port| {
+                    port == query || query.strip_prefix(PORT_ID_PREFIX) == Some(port)
+                })
+                || self.path.to_bytes() == query.as_bytes())
+    }
+}
+
+/// `--id` や設定ファイルに書いたidでlocatorを選ぶときの一致のしかた
+///
+/// 候補の中にidが完全に一致するものがあればそれだけを選び、無ければ部分一致で選ぶ
+/// (`LOC-1` で `LOC-10` まで選ばないように)
+#[derive(Clone, Copy, Debug)]
+pub struct IdQuery<'q> {
+    query: &'q str,
+    exact: bool,
 }
 
+impl<'q> IdQuery<'q> {
+    /// `candidates` (接続中のlocator) を見て、`query` を完全一致で使うか部分一致で使うかを決める
+    pub fn resolve<'a>(candidates: impl IntoIterator<Item = &'a DeviceDescriptor>, query: &'q str) -> Self {
+        let exact = candidates.into_iter().any(|d| d.is_exact_id(query));
+        Self { query, exact }
+    }
+
+    pub fn selects(&self, device: &DeviceDescriptor) -> bool {
+        if self.exact {
+            device.is_exact_id(self.query)
+        } else {
+            device.matches_id(self.query)
+        }
+    }
+}
+
+/// `query` で選ばれるlocatorだけを残す
+pub fn retain_id(devices: &mut Vec<DeviceDescriptor>, query: &str) {
+    let query = IdQuery::resolve(devices.iter(), query);
+    devices.retain(|d| query.selects(d));
+}
+
+#[derive(Clone, Debug)]
 pub struct LocatorStatus {
     pub is_on: bool,
     pub mask: u8,
@@ -90,23 +172,42 @@ pub struct LocatorStatus {
 }
 
 pub fn snapshot_devices(api: &HidApi, filter: &FilterArgs) -> Vec<DeviceDescriptor> {
+    let _span = debug_span!("enumerate", vendor_id = ?filter.vendor_id, product_id = ?filter.product_id).entered();
+    let started = Instant::now();
     // HIDデバイス一覧を取得し、フィルタに合致するものだけ抽出
     let mut devices = Vec::new();
+    let mut total = 0usize;
     for info in api.device_list() {
-        if !matches_filter(&info, filter) {
+        total += 1;
+        if !matches_filter(info, filter) {
+            continue;
+        }
+        let device = DeviceDescriptor::from_info(info);
+        if !matches_port(&device, filter) {
             continue;
         }
-        devices.push(DeviceDescriptor::from_info(&info));
+        debug!(id = %device.locator_id(), path = %device.path.to_string_lossy(), "locatorを検出");
+        devices.push(device);
     }
+    debug!(
+        total,
+        matched = devices.len(),
+        elapsed_us = started.elapsed().as_micros() as u64,
+        "列挙完了"
+    );
     devices
 }
 
-pub fn pick_single_device(api: &HidApi, filter: &FilterArgs, id: Option<&str>) -> Result<DeviceDescriptor> {
-    let mut candidates = snapshot_devices(api, filter);
-    if let Some(id) = id {
-        if !id.is_empty() {
-            candidates.retain(|d| d.matches_id(id));
-        }
+pub fn pick_single_device(
+    backend: &dyn LocatorBackend,
+    filter: &FilterArgs,
+    id: Option<&str>,
+) -> Result<DeviceDescriptor> {
+    let mut candidates = backend.devices(filter)?;
+    if let Some(id) = id
+        && !id.is_empty()
+    {
+        retain_id(&mut candidates, id);
     }
 
     match candidates.len() {
@@ -124,44 +225,15 @@ pub fn pick_single_device(api: &HidApi, filter: &FilterArgs, id: Option<&str>) -
     }
 }
 
+/// 組み込みのCap Locatorプロファイルでステータスを取得する
 pub fn query_status(
     device: &dyn HidDeviceIo,
     protocol: &ProtocolArgs,
 ) -> Result<LocatorStatus> {
-    let report_len = protocol.report_len.max(2);
-
-    let mut request = vec![0u8; report_len];
-    request[0] = COMMAND_STATUS;
-    device
-        .write(&request)
-        .context("output report送信に失敗")?;
-
-    let mut response = vec![0u8; report_len];
-    let received = device
-        .read_timeout(&mut response, protocol.read_timeout_ms)
-        .context("input report受信に失敗")?;
-    response.truncate(received);
-
-    if response.first().copied() != Some(RESPONSE_HEADER) {
-        bail!(
-            "応答先頭が0xFFではありません: [{}]",
-            format_bytes(&response)
-        );
-    }
-
-    let mask = response
-        .get(1)
-        .copied()
-        .ok_or_else(|| anyhow!("LED状態のバイトが不足しています: [{}]", format_bytes(&response)))?;
-    let is_on = mask != 0;
-
-    Ok(LocatorStatus {
-        is_on,
-        mask,
-        raw: response,
-    })
+    DeviceProfile::cap_locator().query_status(device, protocol)
 }
 
+/// 組み込みのCap Locatorプロファイルで点灯/消灯する
 pub fn set_light(
     device: &dyn HidDeviceIo,
     protocol: &ProtocolArgs,
@@ -169,27 +241,38 @@ pub fn set_light(
     on_value: u8,
     off_value: u8,
 ) -> Result<()> {
-    let report_len = protocol.report_len.max(2);
-    let mut report = vec![0u8; report_len];
-    report[0] = COMMAND_SET;
-    report[1] = if turn_on { on_value } else { off_value };
+    let mask = if turn_on { on_value } else { off_value };
+    DeviceProfile::cap_locator().set_mask(device, protocol, mask)
+}
+
+/// 組み込みのCap Locatorプロファイルで電源投入時のマスクを読む
+pub fn query_default_mask(device: &dyn HidDeviceIo, protocol: &ProtocolArgs) -> Result<u8> {
+    DeviceProfile::cap_locator().query_default_mask(device, protocol)
+}
 
-    device
-        .write(&report)
-        .context("output report送信に失敗")?;
-    Ok(())
+/// 組み込みのCap Locatorプロファイルで電源投入時のマスクを保存し、読み戻して確かめる
+pub fn set_default_mask(device: &dyn HidDeviceIo, protocol: &ProtocolArgs, mask: u8) -> Result<()> {
+    DeviceProfile::cap_locator().set_default_mask(device, protocol, mask)
+}
+
+/// `--port` の指定があれば、USBポートの経路が一致するものだけ
+pub fn matches_port(device: &DeviceDescriptor, filter: &FilterArgs) -> bool {
+    filter
+        .port
+        .as_deref()
+        .is_none_or(|port| device.port.as_deref() == Some(port))
 }
 
 fn matches_filter(info: &hidapi::DeviceInfo, filter: &FilterArgs) -> bool {
-    if let Some(vendor_id) = filter.vendor_id {
-        if info.vendor_id() != vendor_id {
-            return false;
-        }
+    if let Some(vendor_id) = filter.vendor_id
+        && info.vendor_id() != vendor_id
+    {
+        return false;
     }
-    if let Some(product_id) = filter.product_id {
-        if info.product_id() != product_id {
-            return false;
-        }
+    if let Some(product_id) = filter.product_id
+        && info.product_id() != product_id
+    {
+        return false;
     }
     #[cfg(not(target_os = "linux"))]
     {
@@ -210,21 +293,29 @@ fn matches_filter(info: &hidapi::DeviceInfo, filter: &FilterArgs) -> bool {
 #[cfg(test)]
 pub mod mock {
     use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::rc::Rc;
 
     use super::*;
     use hidapi::HidError;
 
-    /// テスト用の簡易モックデバイス。送信内容を記録し、あらかじめ設定した応答を返す
+    /// テスト用の簡易モックデバイス。送信内容を記録し、あらかじめ設定した応答を順に返す
+    ///
+    /// `sent` は共有できるので、Boxに入れて渡した後も送信内容を確認できる
     pub struct MockDevice {
-        pub sent: RefCell<Vec<Vec<u8>>>,
-        next_response: RefCell<Option<Vec<u8>>>,
+        pub sent: SentLog,
+        responses: RefCell<VecDeque<Vec<u8>>>,
     }
 
     impl MockDevice {
         pub fn with_response(response: Vec<u8>) -> Self {
+            Self::with_responses(vec![response])
+        }
+
+        pub fn with_responses(responses: Vec<Vec<u8>>) -> Self {
             Self {
-                sent: RefCell::new(Vec::new()),
-                next_response: RefCell::new(Some(response)),
+                sent: Rc::new(RefCell::new(Vec::new())),
+                responses: RefCell::new(responses.into()),
             }
         }
 
@@ -233,6 +324,41 @@ pub mod mock {
         }
     }
 
+    /// MockDeviceの送信ログ
+    pub type SentLog = Rc<RefCell<Vec<Vec<u8>>>>;
+
+    /// テスト用のバックエンド。固定のlocator一覧を返し、開くたびに同じ応答を返すMockDeviceを作る
+    pub struct MockBackend {
+        pub devices: Vec<DeviceDescriptor>,
+        pub response: Vec<u8>,
+        /// 開いたデバイスごとの送信ログ
+        pub opened: RefCell<Vec<(String, SentLog)>>,
+    }
+
+    impl MockBackend {
+        pub fn new(devices: Vec<DeviceDescriptor>, response: Vec<u8>) -> Self {
+            Self {
+                devices,
+                response,
+                opened: RefCell::new(Vec::new()),
+            }
+        }
+    }
+
+    impl crate::backend::LocatorBackend for MockBackend {
+        fn devices(&self, _filter: &FilterArgs) -> Result<Vec<DeviceDescriptor>> {
+            Ok(self.devices.clone())
+        }
+
+        fn open(&self, device: &DeviceDescriptor) -> Result<Box<dyn HidDeviceIo>> {
+            let mock = MockDevice::with_responses(vec![self.response.clone(); 8]);
+            self.opened
+                .borrow_mut()
+                .push((device.locator_id(), Rc::clone(&mock.sent)));
+            Ok(Box::new(mock))
+        }
+    }
+
     impl HidDeviceIo for MockDevice {
         fn write(&self, data: &[u8]) -> hidapi::HidResult<usize> {
             self.sent.borrow_mut().push(data.to_vec());
@@ -240,7 +366,7 @@ pub mod mock {
         }
 
         fn read_timeout(&self, data: &mut [u8], _timeout_ms: i32) -> hidapi::HidResult<usize> {
-            let response = self.next_response.borrow_mut().take().ok_or_else(|| {
+            let response = self.responses.borrow_mut().pop_front().ok_or_else(|| {
                 HidError::HidApiError {
                     message: "mock response not set".to_string(),
                 }
@@ -0,0 +1,322 @@
+use std::collections::BTreeSet;
+use std::process::{Child, Command};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result, anyhow, bail};
+use tracing::{debug, warn};
+
+use crate::config::{Config, HookConfig};
+use crate::daemon::DaemonEvent;
+use crate::hid::{DeviceDescriptor, IdQuery};
+use crate::util::parse_duration;
+
+/// フックの既定のタイムアウト
+pub const DEFAULT_HOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// 実行中のコマンドの終了を確認する間隔
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// フックを実行するきっかけ
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum HookEventKind {
+    /// locatorが接続された
+    Attached,
+    /// locatorが切断された
+    Detached,
+    /// LEDのマスクが変わった
+    Changed,
+    /// LEDの設定やステータス取得に失敗した
+    Failed,
+}
+
+impl HookEventKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            HookEventKind::Attached => "attached",
+            HookEventKind::Detached => "detached",
+            HookEventKind::Changed => "changed",
+            HookEventKind::Failed => "failed",
+        }
+    }
+
+    pub fn parse(name: &str) -> Result<Self> {
+        match name {
+            "attached" => Ok(HookEventKind::Attached),
+            "detached" => Ok(HookEventKind::Detached),
+            "changed" => Ok(HookEventKind::Changed),
+            "failed" => Ok(HookEventKind::Failed),
+            other => bail!("不明なフックのイベントです: {} (attached/detached/changed/failed)", other),
+        }
+    }
+}
+
+/// フックに渡すイベント
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HookEvent {
+    pub kind: HookEventKind,
+    pub id: String,
+    /// changed のときの新しいマスク
+    pub mask: Option<u8>,
+}
+
+impl HookEvent {
+    pub fn from_daemon(event: &DaemonEvent) -> Self {
+        let (kind, id, mask) = match event {
+            DaemonEvent::Attached { id } => (HookEventKind::Attached, id, None),
+            DaemonEvent::Detached { id } => (HookEventKind::Detached, id, None),
+            DaemonEvent::Applied { id, mask } | DaemonEvent::Changed { id, mask } => {
+                (HookEventKind::Changed, id, Some(*mask))
+            }
+            DaemonEvent::Failed { id, .. } => (HookEventKind::Failed, id, None),
+        };
+        Self {
+            kind,
+            id: id.clone(),
+            mask,
+        }
+    }
+
+    fn mask_text(&self) -> String {
+        self.mask.map_or("-".to_string(), |mask| format!("0x{:02x}", mask))
+    }
+
+    /// コマンドに渡す環境変数
+    fn vars(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("CAP_LOCATOR_ID", self.id.clone()),
+            ("CAP_LOCATOR_EVENT", self.kind.name().to_string()),
+            ("CAP_LOCATOR_MASK", self.mask_text()),
+        ]
+    }
+}
+
+/// 設定ファイルの1件分のフック
+#[derive(Clone, Debug)]
+pub struct Hook {
+    /// 空ならすべてのイベント
+    pub on: BTreeSet<HookEventKind>,
+    /// グループを展開したlocator id。Noneならすべてのlocator
+    pub targets: Option<Vec<String>>,
+    pub command: String,
+    pub timeout: Duration,
+}
+
+impl Hook {
+    pub fn from_config(hook: &HookConfig, config: &Config) -> Result<Self> {
+        if hook.command.trim().is_empty() {
+            bail!("フックの command が空です");
+        }
+        let on = hook
+            .on
+            .iter()
+            .map(|name| HookEventKind::parse(name))
+            .collect::<Result<_>>()?;
+        let timeout = match hook.timeout.as_deref() {
+            Some(timeout) => parse_duration(timeout).map_err(|e| anyhow!(e))?,
+            None => DEFAULT_HOOK_TIMEOUT,
+        };
+        Ok(Self {
+            on,
+            targets: hook.target.as_deref().map(|target| config.expand_target(target)),
+            command: hook.command.clone(),
+            timeout,
+        })
+    }
+
+    /// このフックを実行するイベントか
+    ///
+    /// 対象のidは `known` (今回と前回に列挙したlocator) の中で `IdQuery` と同じく完全一致を優先して選ぶ
+    pub fn matches(&self, event: &HookEvent, known: &[DeviceDescriptor]) -> bool {
+        if !(self.on.is_empty() || self.on.contains(&event.kind)) {
+            return false;
+        }
+        let Some(targets) = &self.targets else {
+            return true;
+        };
+        match known.iter().find(|device| device.locator_id() == event.id) {
+            Some(device) => targets
+                .iter()
+                .any(|target| IdQuery::resolve(known, target).selects(device)),
+            None => targets.contains(&event.id),
+        }
+    }
+
+    /// `{id}` `{mask}` `{event}` を置き換えたコマンド
+    ///
+    /// シリアル番号に記号が含まれていてもコマンドが壊れないよう、置き換える値はシングルクォートで囲む
+    pub fn render(&self, event: &HookEvent) -> String {
+        self.command
+            .replace("{id}", &shell_quote(&event.id))
+            .replace("{mask}", &shell_quote(&event.mask_text()))
+            .replace("{event}", &shell_quote(event.kind.name()))
+    }
+}
+
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+/// `sh -c` (Windowsは `cmd /C`) でコマンドを実行し、終了を待つ
+///
+/// - `timeout` を超えたら強制終了してエラー。Unixではコマンドを別のプロセスグループで起動し、
+///   `sh` だけでなくコマンドが起動したプロセスもまとめて止める
+/// - 0以外で終了したらエラー
+pub fn run_command(command: &str, vars: &[(&str, String)], timeout: Option<Duration>) -> Result<()> {
+    let mut shell = if cfg!(windows) {
+        let mut shell = Command::new("cmd");
+        shell.arg("/C");
+        shell
+    } else {
+        let mut shell = Command::new("sh");
+        shell.arg("-c");
+        #[cfg(unix)]
+        std::os::unix::process::CommandExt::process_group(&mut shell, 0);
+        shell
+    };
+    let started = Instant::now();
+    let mut child = shell
+        .arg(command)
+        .envs(vars.iter().map(|(key, value)| (key, value)))
+        .spawn()
+        .with_context(|| format!("コマンドを実行できません: {}", command))?;
+
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if timeout.is_some_and(|timeout| started.elapsed() >= timeout) {
+            // 既に終了していればkillは失敗するが、どちらにしても回収する
+            kill_tree(&mut child);
+            child.wait()?;
+            bail!("{:?} 以内に終了しなかったため停止しました: {}", timeout.unwrap_or_default(), command);
+        }
+        thread::sleep(POLL_INTERVAL);
+    };
+    debug!(command, %status, elapsed_ms = started.elapsed().as_millis() as u64, "コマンド終了");
+    if !status.success() {
+        bail!("コマンドが失敗しました ({}): {}", status, command);
+    }
+    Ok(())
+}
+
+/// `run_command` で起動したコマンドを強制終了する。Unixではプロセスグループごと止める
+fn kill_tree(child: &mut Child) {
+    #[cfg(unix)]
+    {
+        // process_group(0) で起動したので、プロセスグループのidは子プロセスのpidと同じ
+        let pgid = child.id() as libc::pid_t;
+        // SAFETY: シグナルを送るだけで、メモリには触れない
+        if unsafe { libc::kill(-pgid, libc::SIGKILL) } == 0 {
+            return;
+        }
+    }
+    let _ = child.kill();
+}
+
+/// 実行待ちのフック
+struct HookJob {
+    command: String,
+    vars: Vec<(&'static str, String)>,
+    timeout: Duration,
+}
+
+/// フックを常駐ループとは別のスレッドで実行する
+///
+/// - 同時に実行するのは `max_concurrent` 個まで。あふれた分は順番待ちになる
+/// - 失敗やタイムアウトはログに出すだけで、常駐ループは止めない
+/// - Dropすると順番待ちのフックが終わるまで待つ
+pub struct HookRunner {
+    hooks: Vec<Hook>,
+    queue: Option<Sender<HookJob>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl HookRunner {
+    pub fn new(hooks: Vec<Hook>, max_concurrent: usize) -> Self {
+        if hooks.is_empty() {
+            return Self {
+                hooks,
+                queue: None,
+                workers: Vec::new(),
+            };
+        }
+        let (queue, jobs) = mpsc::channel();
+        let jobs = Arc::new(Mutex::new(jobs));
+        let workers = (0..max_concurrent.max(1))
+            .map(|_| {
+                let jobs = Arc::clone(&jobs);
+                thread::spawn(move || run_jobs(&jobs))
+            })
+            .collect();
+        Self {
+            hooks,
+            queue: Some(queue),
+            workers,
+        }
+    }
+
+    pub fn from_config(config: &Config, max_concurrent: usize) -> Result<Self> {
+        let hooks = config
+            .hooks
+            .iter()
+            .enumerate()
+            .map(|(i, hook)| Hook::from_config(hook, config).with_context(|| format!("hooks[{}]", i)))
+            .collect::<Result<_>>()?;
+        Ok(Self::new(hooks, max_concurrent))
+    }
+
+    pub fn len(&self) -> usize {
+        self.hooks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hooks.is_empty()
+    }
+
+    /// イベントに一致するフックを順番待ちに入れ、その数を返す。`known` は `Hook::matches` を参照
+    pub fn dispatch(&self, event: &HookEvent, known: &[DeviceDescriptor]) -> usize {
+        let Some(queue) = &self.queue else {
+            return 0;
+        };
+        let mut queued = 0;
+        for hook in self.hooks.iter().filter(|hook| hook.matches(event, known)) {
+            let job = HookJob {
+                command: hook.render(event),
+                vars: event.vars(),
+                timeout: hook.timeout,
+            };
+            if queue.send(job).is_ok() {
+                queued += 1;
+            }
+        }
+        queued
+    }
+}
+
+impl Drop for HookRunner {
+    fn drop(&mut self) {
+        self.queue.take();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+fn run_jobs(jobs: &Mutex<Receiver<HookJob>>) {
+    loop {
+        // 受け取るまでの間だけロックし、実行中は他のワーカーが次を受け取れるようにする
+        let job = match jobs.lock() {
+            Ok(jobs) => jobs.recv(),
+            Err(_) => return,
+        };
+        let Ok(job) = job else {
+            return;
+        };
+        if let Err(err) = run_command(&job.command, &job.vars, Some(job.timeout)) {
+            warn!(command = %job.command, "フックが失敗しました: {:#}", err);
+        }
+    }
+}
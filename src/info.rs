@@ -0,0 +1,177 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use anyhow::{Result, bail};
+
+use crate::hid::DeviceDescriptor;
+
+/// ファームウェアが対応する機能
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Capability {
+    /// ステータス取得
+    Status,
+    /// LEDマスク設定
+    Set,
+    /// バージョン/機能の問い合わせ
+    Info,
+    /// 電源投入時のマスクをEEPROMに保存
+    DefaultMask,
+    /// シリアル番号/名前をEEPROMに書き込む
+    Provision,
+    /// ブートローダーへの切り替え(ファームウェア更新)
+    Bootloader,
+    /// ボタン押下などのイベントを自発的に送る
+    Events,
+}
+
+impl Capability {
+    pub const ALL: [Capability; 7] = [
+        Capability::Status,
+        Capability::Set,
+        Capability::Info,
+        Capability::DefaultMask,
+        Capability::Provision,
+        Capability::Bootloader,
+        Capability::Events,
+    ];
+
+    /// 機能ビット (応答のcapabilitiesフィールド)
+    pub fn bit(self) -> u16 {
+        match self {
+            Capability::Status => 0x0001,
+            Capability::Set => 0x0002,
+            Capability::Info => 0x0004,
+            Capability::DefaultMask => 0x0008,
+            Capability::Provision => 0x0010,
+            Capability::Bootloader => 0x0020,
+            Capability::Events => 0x0040,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Capability::Status => "status",
+            Capability::Set => "set",
+            Capability::Info => "info",
+            Capability::DefaultMask => "default-mask",
+            Capability::Provision => "provision",
+            Capability::Bootloader => "bootloader",
+            Capability::Events => "events",
+        }
+    }
+
+    /// この機能を使うCLIのサブコマンド
+    pub fn commands(self) -> &'static [&'static str] {
+        match self {
+            Capability::Status => &["status", "snapshot save"],
+            Capability::Set => &["on", "off", "apply", "snapshot restore", "daemon", "mqtt"],
+            Capability::Info => &["info"],
+            Capability::DefaultMask => &["default get", "default set"],
+            Capability::Provision => &["provision"],
+            Capability::Bootloader => &["update"],
+            Capability::Events => &["listen"],
+        }
+    }
+}
+
+/// 機能ビットの集合
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Capabilities(pub u16);
+
+impl Capabilities {
+    /// バージョン問い合わせに応答しない初期ファームウェアの機能
+    pub const LEGACY: Capabilities = Capabilities(0x0003);
+
+    pub fn contains(self, capability: Capability) -> bool {
+        self.0 & capability.bit() != 0
+    }
+
+    pub fn iter(self) -> impl Iterator<Item = Capability> {
+        Capability::ALL
+            .into_iter()
+            .filter(move |c| self.contains(*c))
+    }
+
+    /// 使えるCLIのサブコマンド
+    pub fn commands(self) -> Vec<&'static str> {
+        self.iter().flat_map(|c| c.commands().iter().copied()).collect()
+    }
+}
+
+impl fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<_> = self.iter().map(Capability::name).collect();
+        write!(f, "{}", names.join(","))
+    }
+}
+
+/// バージョン問い合わせの結果
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FirmwareInfo {
+    /// (major, minor, patch)。問い合わせに応答しないファームウェアはNone
+    pub version: Option<(u8, u8, u8)>,
+    pub capabilities: Capabilities,
+    pub raw: Vec<u8>,
+}
+
+impl FirmwareInfo {
+    /// バージョン問い合わせに対応していないファームウェア
+    pub fn legacy(raw: Vec<u8>) -> Self {
+        Self {
+            version: None,
+            capabilities: Capabilities::LEGACY,
+            raw,
+        }
+    }
+
+    pub fn version_string(&self) -> String {
+        match self.version {
+            Some((major, minor, patch)) => format!("{}.{}.{}", major, minor, patch),
+            None => "unknown".to_string(),
+        }
+    }
+
+    /// 機能に対応していなければエラーにする
+    pub fn require(&self, capability: Capability) -> Result<()> {
+        if !self.capabilities.contains(capability) {
+            bail!(
+                "このlocatorのファームウェア({})は {} に対応していません (対応: {})",
+                self.version_string(),
+                capability.name(),
+                self.capabilities
+            );
+        }
+        Ok(())
+    }
+}
+
+/// locatorごとに問い合わせたファームウェアの機能
+///
+/// `status` や `grpc-serve` の GetStatus のたびに問い合わせると、旧ファームウェアでは毎回タイムアウトまで待つので覚えておく。
+/// ファームウェアを更新するとリリース番号が変わるので、そのときは問い合わせ直す
+#[derive(Clone, Debug, Default)]
+pub struct CapabilityCache {
+    known: HashMap<(String, u16), Capabilities>,
+}
+
+impl CapabilityCache {
+    /// 覚えていればそれを、無ければ `query` で問い合わせる (失敗したときは覚えない)
+    pub fn get_or_query(
+        &mut self,
+        device: &DeviceDescriptor,
+        query: impl FnOnce() -> Result<Capabilities>,
+    ) -> Result<Capabilities> {
+        let key = (device.locator_id(), device.release_number);
+        if let Some(capabilities) = self.known.get(&key) {
+            return Ok(*capabilities);
+        }
+        let capabilities = query()?;
+        self.known.insert(key, capabilities);
+        Ok(capabilities)
+    }
+}
+
+/// USBデバイス記述子のリリース番号(BCD)を "1.02" の形にする
+pub fn format_release(release_number: u16) -> String {
+    format!("{:x}.{:02x}", release_number >> 8, release_number & 0xff)
+}
@@ -1,15 +1,110 @@
+pub mod apply;
+pub mod audit;
+pub mod auth;
+pub mod backend;
 pub mod cli;
 pub mod commands;
+pub mod config;
+pub mod daemon;
+pub mod doctor;
 pub mod env_config;
+pub mod events;
+pub mod firmware;
+#[cfg(feature = "grpc")]
+pub mod grpc;
 pub mod hid;
+pub mod hooks;
+pub mod info;
+pub mod lock;
+pub mod logging;
+pub mod metrics;
+pub mod mqtt;
+pub mod profile;
+pub mod remote;
+pub mod schedule;
+pub mod service;
+pub mod session;
+pub mod snapshot;
+pub mod timer;
+pub mod topology;
 pub mod util;
+pub mod websocket;
 
+pub use apply::{resolve_targets, DesiredMask, DesiredState, Resolution};
+pub use audit::{
+    audit_layer, parse_time_bound, read_audit_log, AuditQuery, AuditRecord, Change, Origin,
+    AUDIT_TARGET,
+};
+pub use auth::{
+    bearer_token, normalize_fingerprint, AccessControl, Caller, Denied, Permission, RateLimiter,
+    DEFAULT_RATE_LIMIT, MAX_RATE_BUCKETS,
+};
+pub use backend::LocatorBackend;
 pub use cli::{
-    Cli, Commands, FilterArgs, ListArgs, ProtocolArgs, SetArgs, StatusArgs,
+    ApplyArgs, AuditAction, AuditArgs, AuditShowArgs, AuthArgs, Cli, Commands, ConfigArgs,
+    DaemonArgs, DefaultAction, DefaultArgs, DefaultSetArgs, DoctorArgs, FilterArgs, InfoArgs,
+    InstallServiceArgs, ListArgs, ListenArgs, LockArgs, MqttArgs, ProfileArgs, ProtocolArgs,
+    ProvisionArgs, RemoteTlsArgs, ScheduleAction, ScheduleArgs, ScheduleListArgs, ServeArgs,
+    SetArgs, SnapshotAction, SnapshotArgs, SnapshotFileArgs, SnapshotSaveArgs, StatusArgs,
+    UpdateArgs,
+};
+#[cfg(feature = "grpc")]
+pub use cli::GrpcServeArgs;
+pub use commands::{
+    handle_apply, handle_audit_show, handle_daemon, handle_default_get, handle_default_set,
+    handle_doctor, handle_info, handle_install_service, handle_list, handle_listen, handle_mqtt,
+    handle_provision, handle_remote, handle_schedule_list, handle_serve, handle_set,
+    handle_snapshot_restore, handle_snapshot_save, handle_status, handle_update,
+};
+#[cfg(feature = "grpc")]
+pub use commands::handle_grpc_serve;
+pub use config::{ClientConfig, Config};
+pub use daemon::{Daemon, DaemonEvent, ExitAction, LocatorState};
+pub use doctor::{
+    find_rules, open_failure, probe, rule_covers, Access, Accounts, Credentials, NodeOwner,
+    UDEV_RULE_DIRS,
 };
-pub use commands::{handle_list, handle_set, handle_status};
 pub use env_config::{load_env_defaults, merge_filter, EnvDefaults};
-pub use util::{format_bytes, format_usage, parse_hex_or_dec_u16, parse_hex_or_dec_u8};
+pub use events::{listen, LocatorEvent, ReceivedEvent};
+pub use firmware::{
+    flash_blocks, FirmwareImage, FlashBlock, FlashError, FlashProgress, FlashReport, FlashTarget,
+    HidBootloader,
+};
+#[cfg(feature = "grpc")]
+pub use grpc::{remote_endpoint, GrpcClient, GrpcServer, RemoteEvents, ServeOptions};
+pub use hooks::{run_command, Hook, HookEvent, HookEventKind, HookRunner};
+pub use info::{format_release, Capabilities, Capability, CapabilityCache, FirmwareInfo};
+pub use lock::{lock_dir, private_lock_dir, DeviceLock, LockedDevice};
+pub use logging::LogFormat;
+pub use metrics::{serve_metrics, Metrics};
+pub use mqtt::{
+    topic_id, DesiredLeds, Message, MqttBridge, MqttClient, MqttOptions, DEFAULT_MAX_PACKET_SIZE,
+};
+pub use profile::{
+    error_kind, validate_identity, BootloaderLayout, CommandLayout, DeviceProfile, IdentityField,
+    ProfileRegistry, ProtocolError, ResponseLayout,
+};
+pub use remote::{
+    is_http_url, DeviceQuery, RemoteBackend, RemoteDevice, RemoteServer, ServeEvent, MAX_BODY,
+    MAX_READ_LEN,
+};
+pub use schedule::{Edge, Pattern, Rule, Scheduler, Transition, Trigger};
+pub use service::{
+    activated_listener, socket_unit, udev_rules, usb_ids, Notifier, ServiceUnit, WATCHDOG_SEC,
+};
+pub use session::{
+    Recorder, RecordingBackend, RecordingDevice, ReplayBackend, ReplayDevice, SessionEvent,
+};
+pub use snapshot::{match_entry, RestoreMatch, Snapshot, SnapshotEntry};
+pub use timer::{hold_then_restore, wait_for, Clock, SystemClock, TimerOutcome};
+pub use util::{
+    format_bytes, format_duration, format_usage, parse_duration, parse_hex_bytes,
+    parse_hex_or_dec_u16, parse_hex_or_dec_u8, parse_port_chain, MAX_DURATION,
+};
+pub use websocket::{
+    Control, ControlAction, WsMessage, WsServer, API_LOCATORS_PATH, MAX_CONNECTIONS, WS_PATH,
+    WS_PROTOCOL, WS_TOKEN_PROTOCOL_PREFIX,
+};
 
 #[cfg(test)]
 mod tests;
@@ -0,0 +1,179 @@
+use std::env;
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result, bail};
+
+use crate::doctor::Credentials;
+use crate::hid::{DeviceDescriptor, HidDeviceIo};
+use crate::util::format_duration;
+
+/// ロック待ちで再試行する間隔
+const RETRY_INTERVAL: Duration = Duration::from_millis(50);
+
+/// ロックファイルを置くディレクトリ
+///
+/// `$XDG_RUNTIME_DIR/cap-locator`。無ければ一時ディレクトリに `cap-locator-<uid>` を作る
+/// (誰でも書ける `/tmp` で他のユーザーに先回りされないよう `private_lock_dir` で確かめる)
+pub fn lock_dir() -> Result<PathBuf> {
+    if let Some(dir) = env::var_os("XDG_RUNTIME_DIR").filter(|dir| !dir.is_empty()) {
+        return Ok(PathBuf::from(dir).join("cap-locator"));
+    }
+    match Credentials::current() {
+        Some(credentials) => private_lock_dir(&env::temp_dir(), credentials.uid),
+        // uidが分からない (/procが無い) OSの一時ディレクトリはユーザーごと
+        None => Ok(env::temp_dir().join("cap-locator")),
+    }
+}
+
+/// `base` に `cap-locator-<uid>` を0700で作り、`uid` の持ち物で他のユーザーが書けないディレクトリであることを確かめる
+///
+/// 既にあるものがシンボリックリンクや他人のディレクトリなら、ロックを横取りされたりファイルを
+/// 書き換えさせられたりするので使わない
+#[cfg(unix)]
+pub fn private_lock_dir(base: &Path, uid: u32) -> Result<PathBuf> {
+    use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+
+    let dir = base.join(format!("cap-locator-{}", uid));
+    match fs::DirBuilder::new().mode(0o700).create(&dir) {
+        Ok(()) => {}
+        Err(err) if err.kind() == ErrorKind::AlreadyExists => {}
+        Err(err) => {
+            return Err(err).with_context(|| format!("ロック用ディレクトリを作成できません: {}", dir.display()));
+        }
+    }
+    let metadata = fs::symlink_metadata(&dir)
+        .with_context(|| format!("ロック用ディレクトリを確認できません: {}", dir.display()))?;
+    if !metadata.is_dir() {
+        bail!("ロック用ディレクトリがディレクトリではありません (シンボリックリンクなど): {}", dir.display());
+    }
+    if metadata.uid() != uid {
+        bail!(
+            "ロック用ディレクトリ {} の所有者が違います (uid {})。削除するか XDG_RUNTIME_DIR を設定してください",
+            dir.display(),
+            metadata.uid()
+        );
+    }
+    if metadata.mode() & 0o022 != 0 {
+        bail!(
+            "ロック用ディレクトリ {} を他のユーザーが書き換えられます (mode {:o})。chmod 700 してください",
+            dir.display(),
+            metadata.mode() & 0o7777
+        );
+    }
+    Ok(dir)
+}
+
+#[cfg(not(unix))]
+pub fn private_lock_dir(base: &Path, uid: u32) -> Result<PathBuf> {
+    Ok(base.join(format!("cap-locator-{}", uid)))
+}
+
+/// locatorごとのロックファイル名。シリアル番号(無ければHIDパス)をファイル名に使える形にする
+pub fn lock_file_name(device: &DeviceDescriptor) -> String {
+    let key: String = device
+        .locator_id()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+        .collect();
+    format!("{}.lock", key)
+}
+
+/// 1台のlocatorに対する排他ロック(advisory)。Dropで解放される
+pub struct DeviceLock {
+    _file: File,
+    path: PathBuf,
+}
+
+impl DeviceLock {
+    /// ロックを取得する。他プロセスが保持していれば `timeout` まで待ち、取れなければ保持PID付きでエラー
+    pub fn acquire(dir: &Path, device: &DeviceDescriptor, timeout: Duration) -> Result<Self> {
+        fs::create_dir_all(dir)
+            .with_context(|| format!("ロック用ディレクトリを作成できません: {}", dir.display()))?;
+        let path = dir.join(lock_file_name(device));
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .with_context(|| format!("ロックファイルを開けません: {}", path.display()))?;
+
+        let deadline = Instant::now() + timeout;
+        loop {
+            match file.try_lock() {
+                Ok(()) => break,
+                Err(TryLockError::WouldBlock) if Instant::now() < deadline => {
+                    thread::sleep(RETRY_INTERVAL);
+                }
+                Err(TryLockError::WouldBlock) => {
+                    let holder = read_holder(&path)
+                        .map(|pid| format!("PID {}", pid))
+                        .unwrap_or_else(|| "PID不明".to_string());
+                    bail!(
+                        "locator {} は別のプロセス ({}) が使用中です。{}待ってもロックを取得できませんでした ({})",
+                        device.locator_id(),
+                        holder,
+                        format_duration(timeout),
+                        path.display()
+                    );
+                }
+                Err(TryLockError::Error(err)) => {
+                    return Err(err)
+                        .with_context(|| format!("ロックを取得できません: {}", path.display()));
+                }
+            }
+        }
+
+        // 待っている側がエラーに表示できるよう、保持者のPIDを書いておく
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        write!(file, "{}", process::id())?;
+        file.flush()?;
+        Ok(Self { _file: file, path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+fn read_holder(path: &Path) -> Option<u32> {
+    let mut text = String::new();
+    File::open(path).ok()?.read_to_string(&mut text).ok()?;
+    text.trim().parse().ok()
+}
+
+/// ロックを保持したままデバイスを使うためのラッパー。Dropでデバイスを閉じてからロックを解放する
+pub struct LockedDevice<D> {
+    device: D,
+    _lock: Option<DeviceLock>,
+}
+
+impl<D> LockedDevice<D> {
+    pub fn new(device: D, lock: DeviceLock) -> Self {
+        Self {
+            device,
+            _lock: Some(lock),
+        }
+    }
+
+    /// 相手側(`serve`)でロックしているリモートのlocator
+    pub fn remote(device: D) -> Self {
+        Self { device, _lock: None }
+    }
+}
+
+impl<D: HidDeviceIo> HidDeviceIo for LockedDevice<D> {
+    fn write(&self, data: &[u8]) -> hidapi::HidResult<usize> {
+        self.device.write(data)
+    }
+
+    fn read_timeout(&self, data: &mut [u8], timeout_ms: i32) -> hidapi::HidResult<usize> {
+        self.device.read_timeout(data, timeout_ms)
+    }
+}
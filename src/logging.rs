@@ -0,0 +1,104 @@
+use std::fs::OpenOptions;
+use std::path::Path;
+use std::sync::Mutex;
+
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use tracing::level_filters::LevelFilter;
+use tracing::Subscriber;
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{Layer, fmt};
+
+use crate::audit::audit_layer;
+
+/// HIDの送受信バイト列を出すイベントのtarget。`--trace-file` にはこのtargetだけを書き出す
+pub const HID_TRACE_TARGET: &str = "cap_locator::hid";
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+/// `-v` の回数から標準エラーへ出すログレベルを決める (なし=warn, -v=debug, -vv=trace)
+pub fn level_for(verbose: u8) -> LevelFilter {
+    match verbose {
+        0 => LevelFilter::WARN,
+        1 => LevelFilter::DEBUG,
+        _ => LevelFilter::TRACE,
+    }
+}
+
+/// ログ出力を初期化する
+///
+/// - 標準エラーへ `-v` に応じたレベルで text/json 出力
+/// - `trace_file` 指定時は、HIDの全送受信をJSON Linesで追記(ログレベルに関係なく)
+/// - `audit_log` 指定時は、LEDの変更をJSON Linesで追記(ログレベルに関係なく)
+pub fn init(verbose: u8, format: LogFormat, trace_file: Option<&Path>, audit_log: Option<&Path>) -> Result<()> {
+    let stderr_filter = Targets::new()
+        .with_target("cap_locator_cli", level_for(verbose))
+        .with_target(HID_TRACE_TARGET, level_for(verbose));
+    let stderr = match format {
+        LogFormat::Text => fmt::layer()
+            .with_writer(std::io::stderr)
+            .with_filter(stderr_filter)
+            .boxed(),
+        LogFormat::Json => fmt::layer()
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .with_writer(std::io::stderr)
+            .with_filter(stderr_filter)
+            .boxed(),
+    };
+
+    let trace = match trace_file {
+        Some(path) => {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("トレースファイルを開けません: {}", path.display()))?;
+            Some(hid_trace_layer(Mutex::new(file)))
+        }
+        None => None,
+    };
+
+    let audit = match audit_log {
+        Some(path) => {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("監査ログを開けません: {}", path.display()))?;
+            Some(audit_layer(Mutex::new(file)))
+        }
+        None => None,
+    };
+
+    tracing_subscriber::registry()
+        .with(stderr)
+        .with(trace)
+        .with(audit)
+        .try_init()
+        .context("ログ出力を初期化できません")
+}
+
+/// HIDの送受信だけをJSON Linesで書き出すレイヤー (`--trace-file` 用)
+pub fn hid_trace_layer<S, W>(writer: W) -> impl Layer<S>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    fmt::layer()
+        .json()
+        .with_current_span(true)
+        .with_span_list(true)
+        .with_writer(writer)
+        .with_filter(Targets::new().with_target(HID_TRACE_TARGET, LevelFilter::TRACE))
+}
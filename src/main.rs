@@ -3,8 +3,16 @@ use clap::Parser;
 use dotenvy::dotenv;
 use hidapi::HidApi;
 
+#[cfg(feature = "grpc")]
+use cap_locator_cli::handle_grpc_serve;
 use cap_locator_cli::{
-    handle_list, handle_set, handle_status, load_env_defaults, Cli, Commands,
+    handle_apply, handle_audit_show, handle_daemon, handle_default_get, handle_default_set,
+    handle_doctor, handle_info, handle_install_service, handle_list, handle_listen, handle_mqtt,
+    handle_provision, handle_remote, handle_schedule_list, handle_serve, handle_set,
+    handle_snapshot_restore, handle_snapshot_save, handle_status, handle_update, is_http_url,
+    load_env_defaults, logging, AuditAction, Cli, Commands, DefaultAction, LocatorBackend,
+    ProfileRegistry, Recorder, RecordingBackend, RemoteBackend, ReplayBackend, ScheduleAction,
+    SnapshotAction,
 };
 
 fn main() -> Result<()> {
@@ -12,13 +20,65 @@ fn main() -> Result<()> {
     dotenv().ok();
 
     let cli = Cli::parse();
+    logging::init(
+        cli.verbose,
+        cli.log_format,
+        cli.trace_file.as_deref(),
+        cli.audit_log.as_deref(),
+    )?;
     let env_defaults = load_env_defaults()?;
-    let api = HidApi::new().context("failed to initialize HID API")?;
-
+    let profiles = ProfileRegistry::load(&cli.profile)?;
+    // gRPCの接続先にはバックエンドを介さずに要求する
+    if let Some(remote) = cli.remote.as_deref().filter(|remote| !is_http_url(remote)) {
+        return handle_remote(remote, cli.remote_token.as_deref(), &cli.remote_tls, &cli.command);
+    }
+    let backend = || -> Result<Box<dyn LocatorBackend>> {
+        Ok(match (&cli.remote, &cli.replay, &cli.record) {
+            (Some(url), _, _) => Box::new(RemoteBackend::new(url, cli.remote_token.as_deref())?),
+            (None, Some(path), _) => Box::new(ReplayBackend::load(path)?),
+            (None, None, record) => {
+                let api = HidApi::new().context("failed to initialize HID API")?;
+                match record {
+                    Some(path) => Box::new(RecordingBackend::new(api, Recorder::create(path)?)),
+                    None => Box::new(api),
+                }
+            }
+        })
+    };
+    // HIDに触れないコマンド (schedule list / audit show / install-service / doctor --print-rule など) は、
+    // hidrawを使えない環境でも動くようにバックエンドを作らない
     match cli.command {
-        Commands::List(args) => handle_list(&api, &args, &env_defaults),
-        Commands::Status(args) => handle_status(&api, &args, &env_defaults),
-        Commands::On(args) => handle_set(&api, &args, &env_defaults, true),
-        Commands::Off(args) => handle_set(&api, &args, &env_defaults, false),
+        Commands::List(args) => handle_list(backend()?.as_mut(), &profiles, &args, &env_defaults),
+        Commands::Status(args) => handle_status(backend()?.as_mut(), &profiles, &args, &env_defaults),
+        Commands::On(args) => handle_set(backend()?.as_mut(), &profiles, &args, &env_defaults, true),
+        Commands::Off(args) => handle_set(backend()?.as_mut(), &profiles, &args, &env_defaults, false),
+        Commands::Apply(args) => handle_apply(backend()?.as_mut(), &profiles, &args, &env_defaults),
+        Commands::Snapshot(args) => match args.action {
+            SnapshotAction::Save(args) => handle_snapshot_save(backend()?.as_mut(), &profiles, &args, &env_defaults),
+            SnapshotAction::Restore(args) => {
+                handle_snapshot_restore(backend()?.as_mut(), &profiles, &args, &env_defaults)
+            }
+        },
+        Commands::Daemon(args) => handle_daemon(backend()?.as_mut(), &profiles, &args, &env_defaults),
+        Commands::Doctor(args) => handle_doctor(backend, &profiles, &args, &env_defaults),
+        Commands::InstallService(args) => handle_install_service(&profiles, &args, &env_defaults),
+        Commands::Schedule(args) => match args.action {
+            ScheduleAction::List(args) => handle_schedule_list(&args),
+        },
+        Commands::Audit(args) => match args.action {
+            AuditAction::Show(args) => handle_audit_show(cli.audit_log.as_deref(), &args),
+        },
+        Commands::Info(args) => handle_info(backend()?.as_mut(), &profiles, &args, &env_defaults),
+        Commands::Update(args) => handle_update(backend()?.as_mut(), &profiles, &args, &env_defaults),
+        Commands::Provision(args) => handle_provision(backend()?.as_mut(), &profiles, &args, &env_defaults),
+        Commands::Mqtt(args) => handle_mqtt(backend()?.as_mut(), &profiles, &args, &env_defaults),
+        Commands::Serve(args) => handle_serve(backend()?.as_mut(), &profiles, &args, &env_defaults),
+        Commands::Listen(args) => handle_listen(backend()?.as_mut(), &profiles, &args, &env_defaults),
+        Commands::Default(args) => match args.action {
+            DefaultAction::Get(args) => handle_default_get(backend()?.as_mut(), &profiles, &args, &env_defaults),
+            DefaultAction::Set(args) => handle_default_set(backend()?.as_mut(), &profiles, &args, &env_defaults),
+        },
+        #[cfg(feature = "grpc")]
+        Commands::GrpcServe(args) => handle_grpc_serve(backend()?.as_mut(), &profiles, &args, &env_defaults),
     }
 }
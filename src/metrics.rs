@@ -0,0 +1,160 @@
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use anyhow::{Context, Result, anyhow};
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use tiny_http::{Header, Response, Server};
+use tracing::{debug, warn};
+
+use crate::hid::DeviceDescriptor;
+use crate::profile::{DeviceProfile, error_kind};
+
+/// `query_status` の往復時間のバケット(秒)。USB HIDは数ms、タイムアウトは既定1s
+const LATENCY_BUCKETS: &[f64] = &[0.001, 0.002, 0.005, 0.01, 0.02, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5];
+
+/// daemonの `/metrics` で出すPrometheusのメトリクス
+pub struct Metrics {
+    registry: Registry,
+    present: IntGaugeVec,
+    led: IntGaugeVec,
+    commands: IntCounterVec,
+    errors: IntCounterVec,
+    status_latency: HistogramVec,
+}
+
+impl Metrics {
+    pub fn new() -> Result<Self> {
+        let registry = Registry::new();
+        let present = IntGaugeVec::new(
+            Opts::new("cap_locator_device_present", "locatorが接続されていれば1、切断されたら0"),
+            &["id", "serial", "vendor_id", "product_id"],
+        )?;
+        let led = IntGaugeVec::new(
+            Opts::new("cap_locator_led_on", "LEDが点灯していれば1"),
+            &["id", "led"],
+        )?;
+        let commands = IntCounterVec::new(
+            Opts::new("cap_locator_commands_total", "locatorへ送ったコマンドの数"),
+            &["command"],
+        )?;
+        let errors = IntCounterVec::new(
+            Opts::new("cap_locator_protocol_errors_total", "locatorとの通信の失敗の数"),
+            &["kind"],
+        )?;
+        let status_latency = HistogramVec::new(
+            HistogramOpts::new("cap_locator_status_latency_seconds", "ステータス取得の往復時間")
+                .buckets(LATENCY_BUCKETS.to_vec()),
+            &["id"],
+        )?;
+        registry.register(Box::new(present.clone()))?;
+        registry.register(Box::new(led.clone()))?;
+        registry.register(Box::new(commands.clone()))?;
+        registry.register(Box::new(errors.clone()))?;
+        registry.register(Box::new(status_latency.clone()))?;
+        Ok(Self {
+            registry,
+            present,
+            led,
+            commands,
+            errors,
+            status_latency,
+        })
+    }
+
+    /// 接続状態。切断されたlocatorも0として残すので、消えたことをアラートにできる
+    pub fn device_present(&self, device: &DeviceDescriptor, present: bool) {
+        let id = device.locator_id();
+        let vendor_id = format!("0x{:04x}", device.vendor_id);
+        let product_id = format!("0x{:04x}", device.product_id);
+        self.present
+            .with_label_values(&[
+                id.as_str(),
+                device.serial_number.as_deref().unwrap_or(""),
+                vendor_id.as_str(),
+                product_id.as_str(),
+            ])
+            .set(i64::from(present));
+        if !present {
+            self.forget_leds(&id);
+        }
+    }
+
+    /// LEDごとの点灯状態
+    pub fn led_mask(&self, id: &str, profile: &DeviceProfile, mask: u8) {
+        for (bit, led) in profile.leds.iter().enumerate() {
+            self.led
+                .with_label_values(&[id, led.as_str()])
+                .set(i64::from(mask & (1 << bit) != 0));
+        }
+    }
+
+    fn forget_leds(&self, id: &str) {
+        let stale: Vec<String> = self
+            .registry
+            .gather()
+            .iter()
+            .filter(|family| family.name() == "cap_locator_led_on")
+            .flat_map(|family| family.get_metric().iter())
+            .filter(|metric| metric.get_label().iter().any(|l| l.name() == "id" && l.value() == id))
+            .filter_map(|metric| {
+                metric
+                    .get_label()
+                    .iter()
+                    .find(|l| l.name() == "led")
+                    .map(|l| l.value().to_string())
+            })
+            .collect();
+        for led in stale {
+            let _ = self.led.remove_label_values(&[id, led.as_str()]);
+        }
+    }
+
+    pub fn command_sent(&self, command: &str) {
+        self.commands.with_label_values(&[command]).inc();
+    }
+
+    pub fn protocol_error(&self, err: &anyhow::Error) {
+        self.errors.with_label_values(&[error_kind(err)]).inc();
+    }
+
+    pub fn observe_status(&self, id: &str, elapsed: Duration) {
+        self.status_latency
+            .with_label_values(&[id])
+            .observe(elapsed.as_secs_f64());
+    }
+
+    /// テキスト形式 (text/plain; version=0.0.4)
+    pub fn render(&self) -> Result<String> {
+        let mut buf = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buf)?;
+        Ok(String::from_utf8(buf)?)
+    }
+}
+
+/// `listen` (`127.0.0.1:9464` など) で `/metrics` を返すHTTPサーバーを別スレッドで動かす
+pub fn serve_metrics(listen: &str, metrics: Arc<Metrics>) -> Result<JoinHandle<()>> {
+    let server = Server::http(listen)
+        .map_err(|e| anyhow!(e))
+        .with_context(|| format!("メトリクス用のポートを開けません: {}", listen))?;
+    let content_type = Header::from_bytes("Content-Type", TextEncoder::new().format_type())
+        .map_err(|_| anyhow!("Content-Typeを作れません"))?;
+    Ok(thread::spawn(move || {
+        for request in server.incoming_requests() {
+            debug!(url = request.url(), "メトリクス要求");
+            let result = if request.url() == "/metrics" {
+                match metrics.render() {
+                    Ok(body) => request.respond(Response::from_string(body).with_header(content_type.clone())),
+                    Err(err) => request.respond(Response::from_string(format!("{:#}", err)).with_status_code(500)),
+                }
+            } else {
+                request.respond(Response::from_string("not found").with_status_code(404))
+            };
+            if let Err(err) = result {
+                warn!("メトリクスの応答に失敗しました: {}", err);
+            }
+        }
+    }))
+}
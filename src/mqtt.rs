@@ -0,0 +1,549 @@
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result, anyhow, bail};
+use chrono::{DateTime, TimeZone};
+use rumqttc::{
+    Client, ClientError, Connection, ConnectionError, Event, Incoming, LastWill, Outgoing, QoS, RecvTimeoutError,
+    SubscribeReasonCode,
+};
+use serde_json::{Value, json};
+use tracing::{debug, warn};
+
+use crate::audit::{self, Change};
+use crate::cli::ProtocolArgs;
+use crate::hid::{DeviceDescriptor, HidDeviceIo};
+use crate::profile::ProfileRegistry;
+use crate::schedule::{DEFAULT_BLINK_INTERVAL, Pattern};
+use crate::util::{parse_duration, parse_hex_or_dec_u8};
+
+/// 接続してCONNACKを受け取るまで、切断でDISCONNECTを送り終えるまでに待つ時間
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// 送信待ちにしておける要求の数。あふれたら受信を進めて空ける
+const REQUEST_CAPACITY: usize = 64;
+
+/// 受信・送信するパケットの最大サイズのデフォルト。`…/set` と状態のJSONには十分な大きさ
+pub const DEFAULT_MAX_PACKET_SIZE: usize = 64 * 1024;
+
+/// MQTT 3.1.1 で長さ2バイトを前に付けて送る値 (トピック・ID・認証情報・last willの本文) の最大長
+const MAX_FIELD_LEN: usize = u16::MAX as usize;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Message {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub retain: bool,
+}
+
+impl Message {
+    pub fn new(topic: impl Into<String>, payload: impl Into<Vec<u8>>, retain: bool) -> Self {
+        Self {
+            topic: topic.into(),
+            payload: payload.into(),
+            retain,
+        }
+    }
+}
+
+/// ブローカーへの接続設定
+#[derive(Clone, Debug)]
+pub struct MqttOptions {
+    /// `host:port`
+    pub broker: String,
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub keep_alive: Duration,
+    /// 切断を検出したらブローカーが代わりに送るメッセージ (last will)
+    pub will: Option<Message>,
+    /// 受信・送信するパケットの最大サイズ。これより大きいパケットを受け取ったら接続をやめる
+    pub max_packet_size: usize,
+}
+
+impl MqttOptions {
+    fn to_rumqttc(&self) -> Result<rumqttc::MqttOptions> {
+        check_field_len("クライアントID", self.client_id.len())?;
+        let (host, port) = split_broker(&self.broker)?;
+        let mut options = rumqttc::MqttOptions::new(&self.client_id, host, port);
+        // rumqttcのkeep aliveは秒単位 (0で無効)。1秒未満は1秒にする
+        let keep_alive = match self.keep_alive.as_secs() {
+            0 if self.keep_alive.is_zero() => Duration::ZERO,
+            secs => Duration::from_secs(secs.clamp(1, u16::MAX as u64)),
+        };
+        options
+            .set_keep_alive(keep_alive)
+            .set_clean_session(true)
+            .set_max_packet_size(self.max_packet_size, self.max_packet_size);
+        if self.username.is_some() || self.password.is_some() {
+            let username = self.username.clone().unwrap_or_default();
+            let password = self.password.clone().unwrap_or_default();
+            check_field_len("ユーザー名", username.len())?;
+            check_field_len("パスワード", password.len())?;
+            options.set_credentials(username, password);
+        }
+        if let Some(will) = &self.will {
+            check_field_len("last willのトピック", will.topic.len())?;
+            check_field_len("last willの本文", will.payload.len())?;
+            options.set_last_will(LastWill::new(
+                will.topic.as_str(),
+                will.payload.clone(),
+                QoS::AtMostOnce,
+                will.retain,
+            ));
+        }
+        Ok(options)
+    }
+}
+
+/// `host:port` (`[::1]:1883` も可) を分ける
+fn split_broker(broker: &str) -> Result<(String, u16)> {
+    let (host, port) = broker
+        .rsplit_once(':')
+        .ok_or_else(|| anyhow!("--broker は host:port の形式で指定してください: {}", broker))?;
+    let port = port
+        .parse()
+        .map_err(|_| anyhow!("--broker のポートが不正です: {}", broker))?;
+    Ok((host.trim_start_matches('[').trim_end_matches(']').to_string(), port))
+}
+
+/// 長さ2バイトで送る値は65535バイトまで。超えると長さが切り詰められてパケットが壊れる
+fn check_field_len(what: &str, len: usize) -> Result<()> {
+    if len > MAX_FIELD_LEN {
+        bail!("{}が長すぎます ({} バイト。MQTTでは{}バイトまで)", what, len, MAX_FIELD_LEN);
+    }
+    Ok(())
+}
+
+/// 同期版のMQTTクライアント (QoS 0のみ)。通信とkeep aliveは `rumqttc` に任せる
+///
+/// 送信は `poll` で受信を進めている間に行われる
+pub struct MqttClient {
+    client: Client,
+    connection: Connection,
+    /// 送信待ちを空けている間に受け取ったPUBLISH
+    received: VecDeque<Message>,
+}
+
+impl MqttClient {
+    /// 接続してCONNACKを待つ
+    pub fn connect(options: &MqttOptions) -> Result<Self> {
+        let (client, mut connection) = Client::new(options.to_rumqttc()?, REQUEST_CAPACITY);
+        let started = Instant::now();
+        loop {
+            let left = HANDSHAKE_TIMEOUT.saturating_sub(started.elapsed());
+            match connection.recv_timeout(left) {
+                Ok(Ok(Event::Incoming(Incoming::ConnAck(_)))) => break,
+                Ok(Ok(_)) => {}
+                Ok(Err(ConnectionError::ConnectionRefused(code))) => {
+                    bail!("MQTTブローカーが接続を拒否しました ({:?})", code)
+                }
+                Ok(Err(err)) => {
+                    return Err(err).with_context(|| format!("MQTTブローカーに接続できません: {}", options.broker));
+                }
+                Err(_) => bail!("MQTTブローカーからCONNACKが届きません: {}", options.broker),
+            }
+        }
+        debug!(broker = %options.broker, client_id = %options.client_id, "MQTT接続");
+        Ok(Self {
+            client,
+            connection,
+            received: VecDeque::new(),
+        })
+    }
+
+    pub fn publish(&mut self, message: &Message) -> Result<()> {
+        check_field_len("トピック", message.topic.len())?;
+        debug!(topic = %message.topic, retain = message.retain, "MQTT publish");
+        loop {
+            match self.client.try_publish(
+                message.topic.as_str(),
+                QoS::AtMostOnce,
+                message.retain,
+                message.payload.clone(),
+            ) {
+                Ok(()) => return Ok(()),
+                // 送信待ちがいっぱいなら受信を進めて送り出す
+                Err(ClientError::TryRequest(_)) => {
+                    if let Some(message) = self.next_message(Duration::from_millis(50))? {
+                        self.received.push_back(message);
+                    }
+                }
+                Err(err) => return Err(err).context("MQTTのpublishに失敗"),
+            }
+        }
+    }
+
+    pub fn subscribe(&mut self, filter: &str) -> Result<()> {
+        check_field_len("トピック", filter.len())?;
+        self.client
+            .subscribe(filter, QoS::AtMostOnce)
+            .context("MQTTの購読に失敗")
+    }
+
+    /// `timeout` まで待ち、届いたPUBLISHを返す。その間に送信待ちの要求とkeep aliveのPINGREQを送る
+    pub fn poll(&mut self, timeout: Duration) -> Result<Option<Message>> {
+        if let Some(message) = self.received.pop_front() {
+            return Ok(Some(message));
+        }
+        self.next_message(timeout)
+    }
+
+    fn next_message(&mut self, timeout: Duration) -> Result<Option<Message>> {
+        match self.connection.recv_timeout(timeout) {
+            Ok(Ok(Event::Incoming(Incoming::Publish(publish)))) => Ok(Some(Message::new(
+                publish.topic,
+                publish.payload.to_vec(),
+                publish.retain,
+            ))),
+            Ok(Ok(Event::Incoming(Incoming::SubAck(suback))))
+                if suback.return_codes.contains(&SubscribeReasonCode::Failure) =>
+            {
+                bail!("MQTTブローカーが購読を拒否しました")
+            }
+            Ok(Ok(event)) => {
+                debug!(?event, "MQTT");
+                Ok(None)
+            }
+            Ok(Err(err)) => Err(err).context("MQTTブローカーとの通信に失敗"),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => bail!("MQTTの接続が閉じられました"),
+        }
+    }
+
+    /// 送信待ちを送り終えてから正常に切断する (last will は送られない)
+    pub fn disconnect(mut self) -> Result<()> {
+        self.client.disconnect().context("MQTTの切断に失敗")?;
+        let started = Instant::now();
+        while started.elapsed() < HANDSHAKE_TIMEOUT {
+            match self.connection.recv_timeout(HANDSHAKE_TIMEOUT.saturating_sub(started.elapsed())) {
+                Ok(Ok(Event::Outgoing(Outgoing::Disconnect))) => return Ok(()),
+                Ok(Ok(_)) => {}
+                Ok(Err(err)) => return Err(err).context("MQTTの切断に失敗"),
+                Err(_) => break,
+            }
+        }
+        bail!("MQTTのDISCONNECTを送れませんでした")
+    }
+}
+
+/// `…/set` で指定されたLEDの状態
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DesiredLeds {
+    pub mask: u8,
+    pub pattern: Pattern,
+}
+
+impl DesiredLeds {
+    /// `on` / `off` / `0x04` / `{"mask": 4, "pattern": "blink", "interval": "500ms"}` を解釈する
+    pub fn parse(payload: &[u8], on_value: u8) -> Result<Self> {
+        let text = std::str::from_utf8(payload)
+            .context("payloadがUTF-8ではありません")?
+            .trim();
+        let steady = |mask| DesiredLeds {
+            mask,
+            pattern: Pattern::Steady,
+        };
+        match text {
+            "on" => return Ok(steady(on_value)),
+            "off" => return Ok(steady(0)),
+            _ if !text.starts_with('{') => {
+                return parse_hex_or_dec_u8(text).map(steady).map_err(|e| anyhow!(e));
+            }
+            _ => {}
+        }
+
+        let value: Value = serde_json::from_str(text).context("payloadのJSONを解釈できません")?;
+        let mask = match value.get("mask") {
+            Some(Value::Number(n)) => n
+                .as_u64()
+                .and_then(|n| u8::try_from(n).ok())
+                .ok_or_else(|| anyhow!("mask は0〜255で指定してください"))?,
+            Some(Value::String(s)) if s == "on" => on_value,
+            Some(Value::String(s)) if s == "off" => 0,
+            Some(Value::String(s)) => parse_hex_or_dec_u8(s).map_err(|e| anyhow!(e))?,
+            _ => bail!("mask がありません"),
+        };
+        let pattern = match value.get("pattern").and_then(Value::as_str).unwrap_or("steady") {
+            "steady" => Pattern::Steady,
+            "blink" => Pattern::Blink {
+                interval: match value.get("interval").and_then(Value::as_str) {
+                    Some(interval) => parse_duration(interval).map_err(|e| anyhow!(e))?,
+                    None => DEFAULT_BLINK_INTERVAL,
+                },
+            },
+            other => bail!("不明なpatternです: {} (steady/blink)", other),
+        };
+        if let Pattern::Blink { interval } = pattern
+            && interval.is_zero()
+        {
+            bail!("blinkの interval は0より大きくしてください");
+        }
+        Ok(Self { mask, pattern })
+    }
+}
+
+/// locator idをトピックの1階層として使える形にする (`/` `+` `#` を `_` に置き換える)
+pub fn topic_id(locator_id: &str) -> String {
+    locator_id
+        .chars()
+        .map(|c| if matches!(c, '/' | '+' | '#') { '_' } else { c })
+        .collect()
+}
+
+/// locatorの状態をMQTTへ出し、`…/set` をLEDへ反映するブリッジの状態
+///
+/// - 状態(`<prefix>/<id>/state`)はretainedで、変化したときだけpublishする
+/// - `…/set` の指定はlocatorが抜かれても覚えておき、挿し直されたら送り直す
+pub struct MqttBridge {
+    prefix: String,
+    profiles: ProfileRegistry,
+    on_value: u8,
+    desired: BTreeMap<String, DesiredLeds>,
+    applied: BTreeMap<String, u8>,
+    published: BTreeMap<String, String>,
+}
+
+impl MqttBridge {
+    pub fn new(prefix: &str, profiles: ProfileRegistry, on_value: u8) -> Self {
+        Self {
+            prefix: prefix.trim_end_matches('/').to_string(),
+            profiles,
+            on_value,
+            desired: BTreeMap::new(),
+            applied: BTreeMap::new(),
+            published: BTreeMap::new(),
+        }
+    }
+
+    /// ブリッジ自体の online/offline (last will で offline になる)
+    pub fn availability_topic(&self) -> String {
+        format!("{}/availability", self.prefix)
+    }
+
+    pub fn set_filter(&self) -> String {
+        format!("{}/+/set", self.prefix)
+    }
+
+    pub fn state_topic(&self, id: &str) -> String {
+        format!("{}/{}/state", self.prefix, id)
+    }
+
+    /// 再接続したら全locatorの状態を出し直す
+    pub fn forget_published(&mut self) {
+        self.published.clear();
+    }
+
+    /// `<prefix>/<id>/set` を受け取って目標状態を覚える。対象のトピックでなければNone
+    pub fn handle_message(&mut self, message: &Message) -> Result<Option<String>> {
+        let Some(id) = message
+            .topic
+            .strip_prefix(&format!("{}/", self.prefix))
+            .and_then(|rest| rest.strip_suffix("/set"))
+            .filter(|id| !id.is_empty() && !id.contains('/'))
+        else {
+            return Ok(None);
+        };
+        let desired = DesiredLeds::parse(&message.payload, self.on_value)
+            .with_context(|| format!("{} を解釈できません", message.topic))?;
+        debug!(id, ?desired, "MQTTで目標状態を受信");
+        self.desired.insert(id.to_string(), desired);
+        // 同じマスクでも受け取ったら送り直す
+        self.applied.remove(id);
+        Ok(Some(id.to_string()))
+    }
+
+    /// `now` から次の周までの時間。点滅中のlocatorがあれば、`interval` より先に来る点灯/消灯の切り替わりまで
+    pub fn next_tick<Tz: TimeZone>(&self, now: &DateTime<Tz>, interval: Duration) -> Duration {
+        let millis = now.timestamp_millis();
+        self.desired
+            .values()
+            .filter_map(|desired| match desired.pattern {
+                Pattern::Blink { interval } => {
+                    let period = interval.as_millis().max(1) as i64;
+                    Some(Duration::from_millis((period - millis.rem_euclid(period)) as u64))
+                }
+                Pattern::Steady => None,
+            })
+            .fold(interval, Duration::min)
+    }
+
+    /// 1周分の処理。目標状態を反映し、状態が変わったlocatorのメッセージを返す
+    pub fn tick<Tz: TimeZone>(
+        &mut self,
+        now: &DateTime<Tz>,
+        devices: &[DeviceDescriptor],
+        protocol: &ProtocolArgs,
+        open: &mut dyn FnMut(&DeviceDescriptor) -> Result<Box<dyn HidDeviceIo>>,
+    ) -> Vec<Message> {
+        let mut states = BTreeMap::new();
+        for device in devices {
+            let id = topic_id(&device.locator_id());
+            match self.sync_device(&id, device, now, protocol, open) {
+                Ok(state) => {
+                    states.insert(id, state);
+                }
+                Err(err) => warn!(id, "locatorの状態を反映できません: {:#}", err),
+            }
+        }
+
+        let ids: BTreeSet<String> = devices.iter().map(|d| topic_id(&d.locator_id())).collect();
+        for id in self.published.keys() {
+            if !ids.contains(id) {
+                states.insert(id.clone(), json!({ "present": false }).to_string());
+            }
+        }
+        self.applied.retain(|id, _| ids.contains(id));
+
+        let mut messages = Vec::new();
+        for (id, state) in states {
+            if self.published.get(&id) == Some(&state) {
+                continue;
+            }
+            messages.push(Message::new(self.state_topic(&id), state.clone(), true));
+            if ids.contains(&id) {
+                self.published.insert(id, state);
+            } else {
+                self.published.remove(&id);
+            }
+        }
+        messages
+    }
+
+    /// 目標状態を送り、現在の状態をJSONで返す
+    fn sync_device<Tz: TimeZone>(
+        &mut self,
+        id: &str,
+        device: &DeviceDescriptor,
+        now: &DateTime<Tz>,
+        protocol: &ProtocolArgs,
+        open: &mut dyn FnMut(&DeviceDescriptor) -> Result<Box<dyn HidDeviceIo>>,
+    ) -> Result<String> {
+        let profile = self.profiles.for_device(device);
+        let handle = open(device)?;
+        let desired = self.desired.get(id).copied();
+        if let Some(desired) = desired {
+            let mask = desired.pattern.mask_at(desired.mask, now);
+            if self.applied.get(id) != Some(&mask) {
+                let change = Change::api("mqtt", None, None).previous(self.applied.get(id).copied());
+                audit::set_mask(profile, handle.as_ref(), protocol, device, mask, &change)?;
+                self.applied.insert(id.to_string(), mask);
+            }
+        }
+
+        // 点滅中は読むたびに変わるので、点灯時のマスクを状態とする
+        let (mask, pattern) = match desired {
+            Some(DesiredLeds {
+                mask,
+                pattern: Pattern::Blink { interval },
+            }) => (mask, json!({ "blink": format!("{}ms", interval.as_millis()) })),
+            _ => (profile.query_status(handle.as_ref(), protocol)?.mask, json!("steady")),
+        };
+        Ok(json!({
+            "present": true,
+            "mask": mask,
+            "leds": profile.lit_leds(mask),
+            "pattern": pattern,
+        })
+        .to_string())
+    }
+}
+
+#[cfg(test)]
+pub mod stand_in {
+    use std::io::{ErrorKind, Read, Write};
+    use std::net::{SocketAddr, TcpListener, TcpStream};
+    use std::sync::mpsc::{self, Sender};
+    use std::sync::{Arc, Mutex};
+    use std::thread::{self, JoinHandle};
+
+    use bytes::BytesMut;
+    use rumqttc::mqttbytes::{self, v4};
+    use rumqttc::{ConnAck, ConnectReturnCode, Packet, PingResp, Publish, SubAck, SubscribeReasonCode};
+
+    use super::*;
+
+    /// テスト用のブローカーの代役。1接続だけ受け付け、受け取ったパケットを記録する
+    ///
+    /// CONNACK/SUBACK/PINGRESPを返し、`publish` で渡したメッセージをクライアントへ送る
+    pub struct StandInBroker {
+        pub addr: SocketAddr,
+        pub received: Arc<Mutex<Vec<Packet>>>,
+        inject: Sender<Message>,
+        handle: Option<JoinHandle<()>>,
+    }
+
+    impl StandInBroker {
+        pub fn spawn() -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            let received = Arc::new(Mutex::new(Vec::new()));
+            let (inject, injected) = mpsc::channel::<Message>();
+            let log = Arc::clone(&received);
+            let handle = thread::spawn(move || {
+                let (mut stream, _) = listener.accept().unwrap();
+                stream.set_read_timeout(Some(Duration::from_millis(20))).unwrap();
+                let mut buffer = BytesMut::new();
+                loop {
+                    while let Ok(message) = injected.try_recv() {
+                        let publish = Publish::new(message.topic, QoS::AtMostOnce, message.payload);
+                        send(&mut stream, |out| publish.write(out));
+                    }
+                    let packet = match v4::read(&mut buffer, DEFAULT_MAX_PACKET_SIZE) {
+                        Ok(packet) => packet,
+                        Err(mqttbytes::Error::InsufficientBytes(_)) => {
+                            let mut chunk = [0u8; 1024];
+                            match stream.read(&mut chunk) {
+                                Ok(0) => return,
+                                Ok(n) => buffer.extend_from_slice(&chunk[..n]),
+                                Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+                                Err(_) => return,
+                            }
+                            continue;
+                        }
+                        Err(err) => panic!("{:?}", err),
+                    };
+                    match &packet {
+                        Packet::Connect(_) => {
+                            send(&mut stream, |out| ConnAck::new(ConnectReturnCode::Success, false).write(out))
+                        }
+                        Packet::Subscribe(subscribe) => send(&mut stream, |out| {
+                            SubAck::new(subscribe.pkid, vec![SubscribeReasonCode::Success(QoS::AtMostOnce)]).write(out)
+                        }),
+                        Packet::PingReq => send(&mut stream, |out| PingResp.write(out)),
+                        _ => {}
+                    }
+                    let done = packet == Packet::Disconnect;
+                    log.lock().unwrap().push(packet);
+                    if done {
+                        return;
+                    }
+                }
+            });
+            Self {
+                addr,
+                received,
+                inject,
+                handle: Some(handle),
+            }
+        }
+
+        pub fn publish(&self, message: Message) {
+            self.inject.send(message).unwrap();
+        }
+
+        /// クライアントが切断するまで待ち、受け取ったパケットを返す
+        pub fn finish(mut self) -> Vec<Packet> {
+            if let Some(handle) = self.handle.take() {
+                handle.join().unwrap();
+            }
+            self.received.lock().unwrap().clone()
+        }
+    }
+
+    fn send(stream: &mut TcpStream, write: impl FnOnce(&mut BytesMut) -> Result<usize, mqttbytes::Error>) {
+        let mut out = BytesMut::new();
+        write(&mut out).unwrap();
+        stream.write_all(&out).unwrap();
+    }
+}
@@ -0,0 +1,740 @@
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result, anyhow, bail};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, debug_span, trace};
+
+use crate::cli::{FilterArgs, ProfileArgs, ProtocolArgs};
+use crate::hid::{DeviceDescriptor, HidDeviceIo, LocatorStatus};
+use crate::info::{Capabilities, FirmwareInfo};
+use crate::logging::HID_TRACE_TARGET;
+use crate::util::format_bytes;
+
+/// locatorとの通信が想定通りでなかった理由 (メトリクスでの分類に使う)
+#[derive(Debug)]
+pub enum ProtocolError {
+    /// タイムアウトまでに応答が無い
+    Timeout { command: String },
+    /// 先頭バイトやコマンドのエコーが想定と違う
+    BadHeader { command: String, expected: u8, response: Vec<u8> },
+    /// 応答が短くて値を取り出せない
+    Short { command: String, response: Vec<u8> },
+}
+
+impl ProtocolError {
+    /// 空の応答はタイムアウト、それ以外は不正な応答
+    fn unexpected(command: &str, expected: u8, response: Vec<u8>) -> Self {
+        let command = command.to_string();
+        if response.is_empty() {
+            ProtocolError::Timeout { command }
+        } else {
+            ProtocolError::BadHeader {
+                command,
+                expected,
+                response,
+            }
+        }
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ProtocolError::Timeout { .. } => "timeout",
+            ProtocolError::BadHeader { .. } => "bad_header",
+            ProtocolError::Short { .. } => "short_response",
+        }
+    }
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::Timeout { command } => write!(f, "{} の応答がありません (タイムアウト)", command),
+            ProtocolError::BadHeader {
+                command,
+                expected,
+                response,
+            } => write!(
+                f,
+                "{} の応答が不正です (先頭0x{:02X}を想定): [{}]",
+                command,
+                expected,
+                format_bytes(response)
+            ),
+            ProtocolError::Short { command, response } => {
+                write!(f, "{} の応答のバイトが不足しています: [{}]", command, format_bytes(response))
+            }
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+/// エラーの種類 (`timeout` / `bad_header` / `short_response` / `io` / `other`)
+pub fn error_kind(err: &anyhow::Error) -> &'static str {
+    for cause in err.chain() {
+        if let Some(protocol) = cause.downcast_ref::<ProtocolError>() {
+            return protocol.kind();
+        }
+        if cause.is::<hidapi::HidError>() {
+            return "io";
+        }
+    }
+    "other"
+}
+
+/// バージョン問い合わせの応答の長さ (header, エコー, 目印, バージョン3B, 機能2B)
+const INFO_RESPONSE_LEN: usize = 8;
+
+/// 組み込みプロファイル名 (PIC16F1455向けCap Locatorファームウェア)
+pub const CAP_LOCATOR_PROFILE: &str = "cap-locator";
+
+/// locatorファームウェアごとのコマンド体系と既定値
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceProfile {
+    pub name: String,
+    /// 自動選択と既定フィルタに使うVID/PID/Usage
+    pub vendor_id: Option<u16>,
+    pub product_id: Option<u16>,
+    pub usage_page: Option<u16>,
+    pub usage: Option<u16>,
+    /// 入出力レポート長 (`--report-len` 未指定時)
+    #[serde(default = "default_report_len")]
+    pub report_len: usize,
+    pub commands: CommandLayout,
+    pub response: ResponseLayout,
+    /// ビット0から順にLEDの名前。個数がLED数になる
+    pub leds: Vec<String>,
+    /// ファームウェア更新用のブートローダー。未指定なら `update` できない
+    #[serde(default)]
+    pub bootloader: Option<BootloaderLayout>,
+}
+
+/// Output Reportのコマンド配置
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CommandLayout {
+    /// ステータス取得コマンド (先頭バイト)
+    pub status: u8,
+    /// LED設定コマンド (先頭バイト)
+    pub set: u8,
+    /// LED設定コマンドでマスクを置く位置
+    #[serde(default = "default_mask_offset")]
+    pub mask_offset: usize,
+    /// バージョン/機能の問い合わせコマンド。未指定なら問い合わせない
+    #[serde(default)]
+    pub info: Option<u8>,
+    /// シリアル番号/名前をEEPROMへ読み書きするコマンド。未指定なら `provision` できない
+    #[serde(default)]
+    pub provision: Option<u8>,
+    /// 電源投入時のマスクをEEPROMへ読み書きするコマンド。未指定なら `default` を使えない
+    #[serde(default)]
+    pub default_mask: Option<u8>,
+}
+
+/// EEPROMに保存する識別情報
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IdentityField {
+    /// USBのシリアル番号文字列として使われる (再接続後に反映)
+    Serial,
+    /// 表示用の名前
+    Name,
+}
+
+impl IdentityField {
+    fn code(self) -> u8 {
+        match self {
+            IdentityField::Serial => 0x01,
+            IdentityField::Name => 0x02,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            IdentityField::Serial => "serial",
+            IdentityField::Name => "name",
+        }
+    }
+}
+
+/// EEPROMに書ける識別情報の最大長
+pub const IDENTITY_MAX_LEN: usize = 24;
+
+/// Input Report(応答)の解釈
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResponseLayout {
+    /// 応答の先頭バイト
+    pub header: u8,
+    /// LEDマスクの位置
+    #[serde(default = "default_mask_offset")]
+    pub mask_offset: usize,
+    /// ボタン押下などで自発的に送られるInput Reportの先頭バイト。未指定ならイベントを扱わない
+    #[serde(default)]
+    pub event_header: Option<u8>,
+    /// バージョン問い合わせの応答でコマンドのエコーの次に置く目印 (既定は `'I'`)
+    ///
+    /// 旧ファームウェアはどのコマンドにもステータスを返すので、マスクがたまたまコマンドと
+    /// 同じ値でもバージョンと取り違えないようにする
+    #[serde(default = "default_info_marker")]
+    pub info_marker: u8,
+}
+
+/// HIDブートローダーの構成
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BootloaderLayout {
+    /// アプリケーションからブートローダーへ切り替えるコマンド (誤動作防止に `'B', 'L'` を続けて送る)
+    pub enter: u8,
+    /// ブートローダーとして再列挙されたときのVID/PID
+    pub vendor_id: u16,
+    pub product_id: u16,
+    /// 1回に書き込むバイト数
+    #[serde(default = "default_block_size")]
+    pub block_size: usize,
+    /// 書き込むアドレス範囲(バイト単位、app_endは含まない)
+    pub app_start: u32,
+    pub app_end: u32,
+}
+
+fn default_block_size() -> usize {
+    32
+}
+
+fn default_report_len() -> usize {
+    64
+}
+
+fn default_mask_offset() -> usize {
+    1
+}
+
+fn default_info_marker() -> u8 {
+    b'I'
+}
+
+impl DeviceProfile {
+    /// 組み込みのCap Locatorプロファイル (RC2〜RC5/RA4の5灯、Interrupt IN/OUT 64B)
+    pub fn cap_locator() -> Self {
+        Self {
+            name: CAP_LOCATOR_PROFILE.to_string(),
+            vendor_id: Some(0x04d8),
+            product_id: Some(0x1455),
+            usage_page: Some(0xff00),
+            usage: Some(0x0001),
+            report_len: default_report_len(),
+            commands: CommandLayout {
+                status: 0x01,
+                set: 0x02,
+                mask_offset: 1,
+                info: Some(0x03),
+                provision: Some(0x05),
+                default_mask: Some(0x06),
+            },
+            response: ResponseLayout {
+                header: 0xff,
+                mask_offset: 1,
+                event_header: Some(0xfe),
+                info_marker: default_info_marker(),
+            },
+            leds: ["RC2", "RC3", "RC4", "RC5", "RA4"]
+                .into_iter()
+                .map(String::from)
+                .collect(),
+            bootloader: Some(BootloaderLayout {
+                enter: 0x04,
+                vendor_id: 0x04d8,
+                product_id: 0x003c,
+                block_size: default_block_size(),
+                app_start: 0x1000,
+                app_end: 0x4000,
+            }),
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        let profile: Self = toml::from_str(text)?;
+        profile.validate()?;
+        Ok(profile)
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("プロファイルを読み込めません: {}", path.display()))?;
+        Self::parse(&text).with_context(|| format!("{} の解析に失敗", path.display()))
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.leds.is_empty() || self.leds.len() > 8 {
+            bail!("leds は1〜8個で指定してください ({}個)", self.leds.len());
+        }
+        if self.commands.mask_offset == 0 || self.response.mask_offset == 0 {
+            bail!("mask_offset は1以上にしてください (先頭はコマンド/ヘッダ)");
+        }
+        if self.response.event_header == Some(self.response.header) {
+            bail!("event_header は応答の header と別の値にしてください");
+        }
+        if let Some(bootloader) = &self.bootloader
+            && (bootloader.block_size == 0 || bootloader.app_start >= bootloader.app_end)
+        {
+            bail!("bootloader の block_size は1以上、app_start は app_end 未満にしてください");
+        }
+        Ok(())
+    }
+
+    /// VID/PIDがこのプロファイルの対象か (未指定の項目は問わない)
+    pub fn matches(&self, device: &DeviceDescriptor) -> bool {
+        self.vendor_id.is_some_and(|id| id == device.vendor_id)
+            && self.product_id.is_none_or(|id| id == device.product_id)
+    }
+
+    /// 有効なLEDビットのマスク
+    pub fn led_bits(&self) -> u8 {
+        (0..self.leds.len()).fold(0u8, |bits, i| bits | (1 << i))
+    }
+
+    /// マスクで点灯しているLEDの名前
+    pub fn lit_leds(&self, mask: u8) -> Vec<&str> {
+        self.leds
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| mask & (1 << i) != 0)
+            .map(|(_, name)| name.as_str())
+            .collect()
+    }
+
+    /// CLI指定を優先したレポート長 (コマンドと応答の配置が収まる長さ以上)
+    pub fn report_len(&self, protocol: &ProtocolArgs) -> usize {
+        let needed = self.commands.mask_offset.max(self.response.mask_offset) + 1;
+        protocol.report_len.unwrap_or(self.report_len).max(needed)
+    }
+
+    /// ステータスコマンドを送り、応答のLEDマスクを読む
+    pub fn query_status(&self, device: &dyn HidDeviceIo, protocol: &ProtocolArgs) -> Result<LocatorStatus> {
+        let _span = debug_span!("query_status", profile = %self.name).entered();
+        let started = Instant::now();
+        let report_len = self.report_len(protocol);
+
+        let mut request = vec![0u8; report_len];
+        request[0] = self.commands.status;
+        trace!(target: HID_TRACE_TARGET, command = "status", direction = "out", bytes = %format_bytes(&request));
+        device
+            .write(&request)
+            .context("output report送信に失敗")?;
+
+        let response = self.read_reply(device, protocol, "status", report_len, started)?;
+
+        if response.first().copied() != Some(self.response.header) {
+            return Err(ProtocolError::unexpected("status", self.response.header, response).into());
+        }
+
+        let Some(mask) = response.get(self.response.mask_offset).copied() else {
+            return Err(ProtocolError::Short {
+                command: "status".to_string(),
+                response,
+            }
+            .into());
+        };
+        let is_on = mask != 0;
+        debug!(mask, elapsed_us = started.elapsed().as_micros() as u64, "ステータス取得");
+
+        Ok(LocatorStatus {
+            is_on,
+            mask,
+            raw: response,
+        })
+    }
+
+    /// バージョンと機能を問い合わせる
+    ///
+    /// 応答は `[header, info, info_marker, major, minor, patch, caps_lo, caps_hi]`。
+    /// タイムアウトや、先頭がheaderでない・コマンドのエコーや目印が無い応答は、問い合わせ未対応の旧ファームウェアとして扱う
+    pub fn query_info(&self, device: &dyn HidDeviceIo, protocol: &ProtocolArgs) -> Result<FirmwareInfo> {
+        let Some(command) = self.commands.info else {
+            return Ok(FirmwareInfo::legacy(Vec::new()));
+        };
+        let _span = debug_span!("query_info", profile = %self.name).entered();
+        let started = Instant::now();
+        let report_len = self.report_len(protocol).max(INFO_RESPONSE_LEN);
+
+        let mut request = vec![0u8; report_len];
+        request[0] = command;
+        trace!(target: HID_TRACE_TARGET, command = "info", direction = "out", bytes = %format_bytes(&request));
+        device
+            .write(&request)
+            .context("output report送信に失敗")?;
+
+        let response = self.read_reply(device, protocol, "info", report_len, started)?;
+
+        let marked = response.len() >= INFO_RESPONSE_LEN
+            && response[..3] == [self.response.header, command, self.response.info_marker];
+        if !marked {
+            debug!(received = response.len(), "バージョン問い合わせに未対応のファームウェア");
+            return Ok(FirmwareInfo::legacy(response));
+        }
+        let info = FirmwareInfo {
+            version: Some((response[3], response[4], response[5])),
+            capabilities: Capabilities(u16::from_le_bytes([response[6], response[7]])),
+            raw: response,
+        };
+        debug!(version = %info.version_string(), capabilities = %info.capabilities, "バージョン取得");
+        Ok(info)
+    }
+
+    fn provision_command(&self) -> Result<u8> {
+        self.commands
+            .provision
+            .ok_or_else(|| anyhow!("プロファイル {} はシリアル番号の書き込みに対応していません", self.name))
+    }
+
+    /// 要求への応答を受け取る。応答待ちの間に届いたイベントは読み捨てる (イベントは `listen` で受け取る)
+    ///
+    /// タイムアウトすると空の応答を返す
+    fn read_reply(
+        &self,
+        device: &dyn HidDeviceIo,
+        protocol: &ProtocolArgs,
+        name: &str,
+        report_len: usize,
+        started: Instant,
+    ) -> Result<Vec<u8>> {
+        let timeout = Duration::from_millis(protocol.read_timeout_ms.max(0) as u64);
+        loop {
+            // 負のタイムアウト(無期限に待つ)はそのまま渡す
+            let timeout_ms = match protocol.read_timeout_ms {
+                ms if ms < 0 => ms,
+                _ => timeout.saturating_sub(started.elapsed()).as_millis() as i32,
+            };
+            let mut response = vec![0u8; report_len];
+            let received = device
+                .read_timeout(&mut response, timeout_ms)
+                .context("input report受信に失敗")?;
+            response.truncate(received);
+            trace!(
+                target: HID_TRACE_TARGET,
+                command = name,
+                direction = "in",
+                bytes = %format_bytes(&response),
+                elapsed_us = started.elapsed().as_micros() as u64
+            );
+            if !self.is_event(&response) {
+                return Ok(response);
+            }
+            debug!(bytes = %format_bytes(&response), "応答待ちの間に届いたイベントを読み捨てました");
+        }
+    }
+
+    /// 自発的に送られたイベントのInput Reportか (先頭バイトで応答と区別する)
+    pub fn is_event(&self, report: &[u8]) -> bool {
+        self.response
+            .event_header
+            .is_some_and(|header| report.first() == Some(&header))
+    }
+
+    /// Output Reportのコマンド名 (先頭バイトで判定。監査ログ用)
+    pub fn command_name(&self, report: &[u8]) -> &'static str {
+        let Some(&command) = report.first() else {
+            return "empty";
+        };
+        if command == self.commands.set {
+            "set"
+        } else if command == self.commands.status {
+            "status"
+        } else if self.commands.info == Some(command) {
+            "info"
+        } else if self.commands.provision == Some(command) {
+            "provision"
+        } else if self.commands.default_mask == Some(command) {
+            "default_mask"
+        } else if self.bootloader.as_ref().is_some_and(|b| b.enter == command) {
+            "bootloader"
+        } else {
+            "unknown"
+        }
+    }
+
+    /// 状態を変えない問い合わせのOutput Reportか (ステータス/情報/EEPROMの読み出し)
+    pub fn is_query(&self, report: &[u8]) -> bool {
+        let read = report.get(1) == Some(&0x00);
+        match self.command_name(report) {
+            "status" | "info" => true,
+            "provision" | "default_mask" => read,
+            _ => false,
+        }
+    }
+
+    /// 要求を送って応答を受け取る。応答の先頭がheader、次がコマンドのエコーでなければエラー
+    fn transact(&self, device: &dyn HidDeviceIo, protocol: &ProtocolArgs, name: &str, request: &[u8]) -> Result<Vec<u8>> {
+        let started = Instant::now();
+        let report_len = self.report_len(protocol).max(request.len());
+        let mut report = vec![0u8; report_len];
+        report[..request.len()].copy_from_slice(request);
+        trace!(target: HID_TRACE_TARGET, command = name, direction = "out", bytes = %format_bytes(&report));
+        device
+            .write(&report)
+            .context("output report送信に失敗")?;
+
+        let response = self.read_reply(device, protocol, name, report_len, started)?;
+        if response.len() < 2 || response[0] != self.response.header || response[1] != request[0] {
+            return Err(ProtocolError::unexpected(name, self.response.header, response).into());
+        }
+        Ok(response)
+    }
+
+    /// EEPROMの識別情報を読む
+    ///
+    /// `[provision, 0x00, field]` → `[header, provision, len, data...]`
+    pub fn read_identity(&self, device: &dyn HidDeviceIo, protocol: &ProtocolArgs, field: IdentityField) -> Result<String> {
+        let command = self.provision_command()?;
+        let _span = debug_span!("read_identity", profile = %self.name, field = field.name()).entered();
+        let response = self.transact(device, protocol, "provision", &[command, 0x00, field.code()])?;
+        let len = response.get(2).copied().unwrap_or(0) as usize;
+        let data = response
+            .get(3..3 + len)
+            .ok_or_else(|| anyhow!("{} のバイトが不足しています: [{}]", field.name(), format_bytes(&response)))?;
+        String::from_utf8(data.to_vec()).with_context(|| format!("{} がASCIIではありません", field.name()))
+    }
+
+    /// EEPROMへ識別情報を書く
+    ///
+    /// `[provision, 0x01, field, len, data...]` → `[header, provision, status]` (status 0 で成功)
+    pub fn write_identity(
+        &self,
+        device: &dyn HidDeviceIo,
+        protocol: &ProtocolArgs,
+        field: IdentityField,
+        value: &str,
+    ) -> Result<()> {
+        let command = self.provision_command()?;
+        validate_identity(field, value)?;
+        let _span = debug_span!("write_identity", profile = %self.name, field = field.name()).entered();
+        let mut request = vec![command, 0x01, field.code(), value.len() as u8];
+        request.extend_from_slice(value.as_bytes());
+        if request.len() > self.report_len(protocol) {
+            bail!("{} がレポートに収まりません ({}バイト)", field.name(), value.len());
+        }
+        let response = self.transact(device, protocol, "provision", &request)?;
+        match response.get(2).copied() {
+            Some(0) => {
+                debug!(value, "EEPROMへ書き込み");
+                Ok(())
+            }
+            Some(status) => bail!("{} の書き込みに失敗しました (status=0x{:02x})", field.name(), status),
+            None => bail!("書き込み結果がありません: [{}]", format_bytes(&response)),
+        }
+    }
+
+    /// ブートローダーへ切り替える。デバイスはUSBから切断され、ブートローダーとして再列挙される
+    pub fn enter_bootloader(&self, device: &dyn HidDeviceIo, protocol: &ProtocolArgs) -> Result<()> {
+        let bootloader = self
+            .bootloader
+            .as_ref()
+            .ok_or_else(|| anyhow!("プロファイル {} はファームウェア更新に対応していません", self.name))?;
+        let mut report = vec![0u8; self.report_len(protocol).max(3)];
+        report[0] = bootloader.enter;
+        report[1] = b'B';
+        report[2] = b'L';
+        trace!(target: HID_TRACE_TARGET, command = "bootloader", direction = "out", bytes = %format_bytes(&report));
+        device
+            .write(&report)
+            .context("output report送信に失敗")?;
+        debug!("ブートローダーへ切り替え");
+        Ok(())
+    }
+
+    /// LED設定コマンドでマスクを送る
+    pub fn set_mask(&self, device: &dyn HidDeviceIo, protocol: &ProtocolArgs, mask: u8) -> Result<()> {
+        let _span = debug_span!("set_light", profile = %self.name, mask).entered();
+        let started = Instant::now();
+        let mut report = vec![0u8; self.report_len(protocol)];
+        report[0] = self.commands.set;
+        report[self.commands.mask_offset] = mask;
+
+        trace!(target: HID_TRACE_TARGET, command = "set", direction = "out", bytes = %format_bytes(&report));
+        device
+            .write(&report)
+            .context("output report送信に失敗")?;
+        debug!(mask, elapsed_us = started.elapsed().as_micros() as u64, "LED制御");
+        Ok(())
+    }
+
+    fn default_mask_command(&self) -> Result<u8> {
+        self.commands
+            .default_mask
+            .ok_or_else(|| anyhow!("プロファイル {} は電源投入時のマスクに対応していません", self.name))
+    }
+
+    /// EEPROMに保存された電源投入時のマスクを読む
+    ///
+    /// `[default_mask, 0x00]` → `[header, default_mask, mask]`
+    pub fn query_default_mask(&self, device: &dyn HidDeviceIo, protocol: &ProtocolArgs) -> Result<u8> {
+        let command = self.default_mask_command()?;
+        let _span = debug_span!("query_default_mask", profile = %self.name).entered();
+        let response = self.transact(device, protocol, "default_mask", &[command, 0x00])?;
+        let mask = response
+            .get(2)
+            .copied()
+            .ok_or_else(|| anyhow!("電源投入時のマスクのバイトが不足しています: [{}]", format_bytes(&response)))?;
+        debug!(mask, "電源投入時のマスク取得");
+        Ok(mask)
+    }
+
+    /// 電源投入時のマスクをEEPROMへ保存し、読み戻して一致することを確かめる
+    ///
+    /// `[default_mask, 0x01, mask]` → `[header, default_mask, status]` (status 0 で成功)
+    pub fn set_default_mask(&self, device: &dyn HidDeviceIo, protocol: &ProtocolArgs, mask: u8) -> Result<()> {
+        let command = self.default_mask_command()?;
+        let _span = debug_span!("set_default_mask", profile = %self.name, mask).entered();
+        let response = self.transact(device, protocol, "default_mask", &[command, 0x01, mask])?;
+        match response.get(2).copied() {
+            Some(0) => {}
+            Some(status) => bail!("電源投入時のマスクを保存できません (status=0x{:02x})", status),
+            None => bail!("保存結果がありません: [{}]", format_bytes(&response)),
+        }
+        let stored = self.query_default_mask(device, protocol)?;
+        if stored != mask {
+            bail!(
+                "電源投入時のマスクの読み戻しが一致しません (書き込み=0x{:02x} 読み戻し=0x{:02x})",
+                mask,
+                stored
+            );
+        }
+        debug!(mask, "電源投入時のマスクを保存");
+        Ok(())
+    }
+}
+
+/// EEPROMへ書く識別情報の形式を確認する (空でない、印字可能なASCII、最大長以内)
+pub fn validate_identity(field: IdentityField, value: &str) -> Result<()> {
+    if value.is_empty() || value.len() > IDENTITY_MAX_LEN {
+        bail!("{} は1〜{}文字で指定してください", field.name(), IDENTITY_MAX_LEN);
+    }
+    if !value.chars().all(|c| c.is_ascii_graphic() || (c == ' ' && field == IdentityField::Name)) {
+        bail!("{} に使えない文字が含まれています: {:?}", field.name(), value);
+    }
+    Ok(())
+}
+
+/// 組み込みとTOMLファイルから読み込んだプロファイルの一覧
+#[derive(Clone, Debug)]
+pub struct ProfileRegistry {
+    profiles: Vec<DeviceProfile>,
+    /// `--profile` で明示されたプロファイル
+    forced: Option<usize>,
+}
+
+impl Default for ProfileRegistry {
+    fn default() -> Self {
+        Self::builtin()
+    }
+}
+
+impl ProfileRegistry {
+    pub fn builtin() -> Self {
+        Self {
+            profiles: vec![DeviceProfile::cap_locator()],
+            forced: None,
+        }
+    }
+
+    /// 組み込み + `--profile-dir` の *.toml を読み込み、`--profile` があればそれに固定する
+    ///
+    /// `--profile` はプロファイル名か、TOMLファイルのパス
+    pub fn load(args: &ProfileArgs) -> Result<Self> {
+        let mut registry = Self::builtin();
+        if let Some(dir) = args.profile_dir.as_deref() {
+            let mut paths: Vec<_> = fs::read_dir(dir)
+                .with_context(|| format!("プロファイルディレクトリを読めません: {}", dir.display()))?
+                .filter_map(|entry| entry.ok().map(|e| e.path()))
+                .filter(|path| path.extension().is_some_and(|ext| ext == "toml"))
+                .collect();
+            paths.sort();
+            for path in paths {
+                registry.add(DeviceProfile::load(&path)?);
+            }
+        }
+
+        if let Some(selected) = args.profile.as_deref() {
+            let index = match registry.profiles.iter().position(|p| p.name == selected) {
+                Some(index) => index,
+                None if Path::new(selected).is_file() => {
+                    registry.add(DeviceProfile::load(Path::new(selected))?)
+                }
+                None => bail!(
+                    "プロファイルが見つかりません: {} (利用可能: {})",
+                    selected,
+                    registry.names().join(", ")
+                ),
+            };
+            registry.forced = Some(index);
+        }
+        Ok(registry)
+    }
+
+    /// 同名のプロファイルは後から読み込んだもので置き換える
+    pub fn add(&mut self, profile: DeviceProfile) -> usize {
+        match self.profiles.iter().position(|p| p.name == profile.name) {
+            Some(index) => {
+                self.profiles[index] = profile;
+                index
+            }
+            None => {
+                self.profiles.push(profile);
+                self.profiles.len() - 1
+            }
+        }
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.profiles.iter().map(|p| p.name.as_str()).collect()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&DeviceProfile> {
+        self.profiles.iter().find(|p| p.name == name)
+    }
+
+    /// locatorに使うプロファイル。明示指定 > VID/PIDが一致する最後のもの > 組み込み
+    pub fn for_device(&self, device: &DeviceDescriptor) -> &DeviceProfile {
+        if let Some(index) = self.forced {
+            return &self.profiles[index];
+        }
+        self.profiles
+            .iter()
+            .rev()
+            .find(|p| p.matches(device))
+            .unwrap_or(&self.profiles[0])
+    }
+
+    /// ブートローダーとして列挙されたデバイスのプロファイル。明示指定 > ブートローダーのVID/PIDが一致するもの
+    pub fn for_bootloader(&self, device: &DeviceDescriptor) -> Option<&DeviceProfile> {
+        let matches = |p: &DeviceProfile| {
+            p.bootloader
+                .as_ref()
+                .is_some_and(|b| b.vendor_id == device.vendor_id && b.product_id == device.product_id)
+        };
+        match self.forced {
+            Some(index) => Some(&self.profiles[index]).filter(|p| matches(p)),
+            None => self.profiles.iter().rev().find(|p| matches(p)),
+        }
+    }
+
+    /// いずれかのプロファイル(またはそのブートローダー)の対象か。明示指定があれば全て対象
+    pub fn is_known(&self, device: &DeviceDescriptor) -> bool {
+        self.forced.is_some()
+            || self.profiles.iter().any(|p| p.matches(device))
+            || self.for_bootloader(device).is_some()
+    }
+
+    /// 明示されたプロファイルのVID/PID/Usageで、CLIと.envで未指定の項目を補う
+    pub fn fill_filter(&self, filter: FilterArgs) -> FilterArgs {
+        let Some(profile) = self.forced.map(|i| &self.profiles[i]) else {
+            return filter;
+        };
+        FilterArgs {
+            vendor_id: filter.vendor_id.or(profile.vendor_id),
+            product_id: filter.product_id.or(profile.product_id),
+            usage_page: filter.usage_page.or(profile.usage_page),
+            usage: filter.usage.or(profile.usage),
+            port: filter.port,
+        }
+    }
+}
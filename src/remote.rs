@@ -0,0 +1,735 @@
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result, anyhow, bail};
+use hidapi::{HidError, HidResult};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tiny_http::{Header, Method, Response, Server};
+use tracing::{debug, warn};
+
+use crate::audit::{AuditRecord, Change};
+use crate::auth::{AccessControl, Caller, Denied, Permission, bearer_token};
+use crate::backend::LocatorBackend;
+use crate::cli::FilterArgs;
+use crate::hid::{DeviceDescriptor, HidDeviceIo, matches_port};
+use crate::lock::{DeviceLock, LockedDevice, lock_dir};
+use crate::profile::{DeviceProfile, ProfileRegistry};
+use crate::session::RecordedDevice;
+use crate::util::{format_bytes, parse_hex_bytes};
+
+/// 要求を待つ1回あたりの時間。この間隔で中断(Ctrl-C)と放置されたハンドルを確認する
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// サーバー側で入力レポートを待つ1回あたりの最大時間
+///
+/// 待っている間は他の要求を処理できないので短くし、それより長く待つときはクライアントが読み直す
+pub const MAX_READ_TIMEOUT_MS: i32 = 250;
+
+/// 1回の読み取りで受け付ける最大バイト数。HIDのレポートはこれより十分小さい
+pub const MAX_READ_LEN: usize = 1024;
+
+/// 要求の本文として受け付ける最大バイト数。超えた要求は読まずに413で断る
+pub const MAX_BODY: u64 = 64 * 1024;
+
+/// 読み取りの待ち時間に加えて、HTTPの往復に許す時間
+const HTTP_MARGIN: Duration = Duration::from_secs(10);
+
+/// `--remote` の値が `serve` のURLか (それ以外は `grpc-serve` の `host:port`)
+///
+/// `https://` もここではURLとみなし、`RemoteBackend::new` でエラーにする
+pub fn is_http_url(remote: &str) -> bool {
+    remote.starts_with("http://") || remote.starts_with("https://")
+}
+
+/// `POST /v1/devices` の要求。クライアント側のフィルタ
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceQuery {
+    pub vendor_id: Option<u16>,
+    pub product_id: Option<u16>,
+    pub usage_page: Option<u16>,
+    pub usage: Option<u16>,
+    pub port: Option<String>,
+}
+
+impl From<&FilterArgs> for DeviceQuery {
+    fn from(filter: &FilterArgs) -> Self {
+        Self {
+            vendor_id: filter.vendor_id,
+            product_id: filter.product_id,
+            usage_page: filter.usage_page,
+            usage: filter.usage,
+            port: filter.port.clone(),
+        }
+    }
+}
+
+impl DeviceQuery {
+    /// HIDの列挙と同じ条件。Usageが分からないデバイス(Linux)はUsageでは絞らない
+    fn matches(&self, device: &DeviceDescriptor) -> bool {
+        let filter = FilterArgs {
+            port: self.port.clone(),
+            ..FilterArgs::default()
+        };
+        self.vendor_id.is_none_or(|id| id == device.vendor_id)
+            && self.product_id.is_none_or(|id| id == device.product_id)
+            && self
+                .usage_page
+                .is_none_or(|page| device.usage_page.is_none_or(|p| p == page))
+            && self.usage.is_none_or(|usage| device.usage.is_none_or(|u| u == usage))
+            && matches_port(device, &filter)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct OpenRequest {
+    path: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct OpenResponse {
+    handle: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct WriteRequest {
+    data: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct WriteResponse {
+    written: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ReadRequest {
+    len: usize,
+    timeout_ms: i32,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ReadResponse {
+    /// 空ならタイムアウト
+    data: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ErrorResponse {
+    error: String,
+}
+
+/// `serve` で起きたこと
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ServeEvent {
+    /// クライアントがlocatorを開いた (ロックを取得した)
+    Opened {
+        handle: u64,
+        id: String,
+        caller: String,
+        client: String,
+    },
+    /// クライアントが閉じた
+    Closed { handle: u64, id: String },
+    /// `--idle-timeout` の間使われなかったので閉じた
+    Expired { handle: u64, id: String },
+    /// トークンが無い・違う、または要求が多すぎる
+    Rejected { client: String, reason: String },
+    /// 状態を変えるOutput Reportを送った (または権限が無く断った)
+    Audit(AuditRecord),
+}
+
+/// 認証済みの要求の送り手
+struct Call<'a> {
+    caller: &'a Caller,
+    /// 接続元のアドレス
+    client: &'a str,
+}
+
+struct OpenHandle {
+    id: String,
+    serial: Option<String>,
+    /// 開いたクライアントの名前。他のクライアントからは使えない
+    caller: String,
+    device: LockedDevice<Box<dyn HidDeviceIo>>,
+    /// Output Reportが問い合わせかどうかの判定に使う。ブートローダーならNone (全て状態を変える操作とみなす)
+    profile: Option<DeviceProfile>,
+    last_used: Instant,
+}
+
+impl OpenHandle {
+    /// 状態を変えるOutput Reportならコマンド名と監査ログ用の内容
+    fn describe_change(&self, data: &[u8]) -> Option<(&'static str, Option<String>)> {
+        let Some(profile) = &self.profile else {
+            return Some(("bootloader", Some(format!("data=[{}]", format_bytes(trim_padding(data))))));
+        };
+        if profile.is_query(data) {
+            return None;
+        }
+        let name = profile.command_name(data);
+        let detail = (name != "set").then(|| format!("data=[{}]", format_bytes(trim_padding(data))));
+        Some((name, detail))
+    }
+
+    /// LEDを設定するOutput Reportならそのマスク
+    fn set_mask(&self, data: &[u8]) -> Option<u8> {
+        let profile = self.profile.as_ref()?;
+        if profile.command_name(data) != "set" {
+            return None;
+        }
+        data.get(profile.commands.mask_offset).copied()
+    }
+}
+
+/// レポート長に合わせて0埋めした末尾を除く
+fn trim_padding(data: &[u8]) -> &[u8] {
+    let len = data.iter().rposition(|b| *b != 0).map_or(0, |i| i + 1);
+    &data[..len]
+}
+
+/// エラー応答 (HTTPステータスとメッセージ)
+struct Failure {
+    status: u16,
+    message: String,
+}
+
+impl Failure {
+    fn new(status: u16, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+        }
+    }
+
+    fn from_error(status: u16, err: &anyhow::Error) -> Self {
+        Self::new(status, format!("{:#}", err))
+    }
+
+    fn denied(denied: &Denied) -> Self {
+        let status = match denied {
+            Denied::Unauthenticated(_) => 401,
+            Denied::Forbidden(_) => 403,
+            Denied::RateLimited(_) => 429,
+        };
+        Self::new(status, denied.to_string())
+    }
+}
+
+/// 別のマシンの `--remote` にlocatorを貸し出すHTTPサーバー
+///
+/// - 列挙・オープン・Output Report送信・Input Report受信をそのまま中継する (クライアント側は `RemoteBackend`)
+/// - 開いている間はlocatorのロックを持つので、サーバー側のマシンのCLIとも排他になる
+/// - 公開するのはフィルタに一致し、いずれかのプロファイルの対象になるデバイスだけ
+/// - クライアントごとに見えるlocatorと、状態を変えるOutput Reportを送れるかを `AccessControl` で決める
+/// - 要求は1つずつ処理する。1回の読み取りで待つのは `MAX_READ_TIMEOUT_MS` まで
+/// - 読み取り専用のクライアントもロックを取る。ステータスの問い合わせも書き込みと読み取りの組なので、他と混ざると応答を取り違える
+pub struct RemoteServer {
+    server: Server,
+    access: AccessControl,
+    filter: FilterArgs,
+    lock_timeout: Duration,
+    idle_timeout: Duration,
+    handles: BTreeMap<u64, OpenHandle>,
+    next_handle: u64,
+}
+
+impl RemoteServer {
+    /// `listen` (`0.0.0.0:7070` など。ポート0なら空いているポート) で待ち受けを始める
+    pub fn bind(listen: &str, access: AccessControl, filter: FilterArgs) -> Result<Self> {
+        let server = Server::http(listen)
+            .map_err(|e| anyhow!(e))
+            .with_context(|| format!("serve用のポートを開けません: {}", listen))?;
+        Ok(Self {
+            server,
+            access,
+            filter,
+            lock_timeout: Duration::from_secs(5),
+            idle_timeout: Duration::from_secs(60),
+            handles: BTreeMap::new(),
+            next_handle: 1,
+        })
+    }
+
+    /// 開くときにロックの解放を待つ最大時間
+    pub fn lock_timeout(mut self, timeout: Duration) -> Self {
+        self.lock_timeout = timeout;
+        self
+    }
+
+    /// この間使われなかったハンドルは閉じてロックを解放する (クライアントが異常終了したとき用)
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.server
+            .server_addr()
+            .to_ip()
+            .ok_or_else(|| anyhow!("TCPで待ち受けていません"))
+    }
+
+    /// `stop` が立つまで要求に答え続ける
+    pub fn run(
+        &mut self,
+        backend: &mut dyn LocatorBackend,
+        profiles: &ProfileRegistry,
+        stop: &AtomicBool,
+        on_event: &mut dyn FnMut(&ServeEvent),
+    ) -> Result<()> {
+        while !stop.load(Ordering::SeqCst) {
+            if let Some(request) = self.server.recv_timeout(POLL_INTERVAL)? {
+                self.answer(request, backend, profiles, on_event);
+            }
+            self.expire(on_event);
+        }
+        for (handle, open) in std::mem::take(&mut self.handles) {
+            on_event(&ServeEvent::Closed { handle, id: open.id });
+        }
+        Ok(())
+    }
+
+    fn expire(&mut self, on_event: &mut dyn FnMut(&ServeEvent)) {
+        let idle: Vec<u64> = self
+            .handles
+            .iter()
+            .filter(|(_, open)| open.last_used.elapsed() >= self.idle_timeout)
+            .map(|(handle, _)| *handle)
+            .collect();
+        for handle in idle {
+            if let Some(open) = self.handles.remove(&handle) {
+                on_event(&ServeEvent::Expired { handle, id: open.id });
+            }
+        }
+    }
+
+    fn answer(
+        &mut self,
+        mut request: tiny_http::Request,
+        backend: &mut dyn LocatorBackend,
+        profiles: &ProfileRegistry,
+        on_event: &mut dyn FnMut(&ServeEvent),
+    ) {
+        let client = request
+            .remote_addr()
+            .map_or_else(|| "-".to_string(), |addr| addr.to_string());
+        debug!(method = %request.method(), url = request.url(), client, "serve要求");
+        let result = match self.authenticate(&request, &client) {
+            Ok(caller) => read_body(&mut request).and_then(|body| {
+                let url = request.url().to_string();
+                let call = Call {
+                    caller: &caller,
+                    client: &client,
+                };
+                self.route(request.method(), &url, &body, &call, backend, profiles, on_event)
+            }),
+            Err(denied) => {
+                on_event(&ServeEvent::Rejected {
+                    client: client.clone(),
+                    reason: denied.to_string(),
+                });
+                Err(Failure::denied(&denied))
+            }
+        };
+        let (status, body) = match result {
+            Ok(body) => (200, body),
+            Err(failure) => (
+                failure.status,
+                json!(ErrorResponse {
+                    error: failure.message
+                }),
+            ),
+        };
+        let header = Header::from_bytes("Content-Type", "application/json").expect("固定のヘッダー");
+        let response = Response::from_string(body.to_string())
+            .with_status_code(status)
+            .with_header(header);
+        if let Err(err) = request.respond(response) {
+            warn!(client, "serveの応答に失敗しました: {}", err);
+        }
+    }
+
+    /// トークンでクライアントを決め、要求数を数える。認証できなければ接続元ごとに数える
+    fn authenticate(&self, request: &tiny_http::Request, client: &str) -> std::result::Result<Caller, Denied> {
+        let presented = request
+            .headers()
+            .iter()
+            .find(|h| h.field.equiv("Authorization"))
+            .and_then(|h| bearer_token(h.value.as_str()));
+        match self.access.authenticate(presented, None) {
+            Ok(caller) => {
+                self.access.admit(Some(caller), client)?;
+                Ok(caller.clone())
+            }
+            Err(denied) => {
+                self.access.admit(None, client)?;
+                Err(denied)
+            }
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn route(
+        &mut self,
+        method: &Method,
+        url: &str,
+        body: &str,
+        call: &Call,
+        backend: &mut dyn LocatorBackend,
+        profiles: &ProfileRegistry,
+        on_event: &mut dyn FnMut(&ServeEvent),
+    ) -> std::result::Result<serde_json::Value, Failure> {
+        let segments: Vec<&str> = url.trim_matches('/').split('/').collect();
+        match (method, segments.as_slice()) {
+            (Method::Post, ["v1", "devices"]) => {
+                let query: DeviceQuery = parse_body(body)?;
+                let mut devices = self.visible(backend, profiles, &query)?;
+                devices.retain(|device| call.caller.can_access(device));
+                Ok(json!(devices.iter().map(RecordedDevice::from).collect::<Vec<_>>()))
+            }
+            (Method::Post, ["v1", "open"]) => {
+                let open: OpenRequest = parse_body(body)?;
+                let handle = self.open(backend, profiles, &open.path, call, on_event)?;
+                Ok(json!(OpenResponse { handle }))
+            }
+            (Method::Post, ["v1", "handles", handle, "write"]) => {
+                let write: WriteRequest = parse_body(body)?;
+                let data = parse_hex_bytes(&write.data).map_err(|e| Failure::new(400, e))?;
+                let open = self.handle(handle, call.caller)?;
+                let Some((action, detail)) = open.describe_change(&data) else {
+                    let written = open
+                        .device
+                        .write(&data)
+                        .map_err(|err| Failure::new(502, err.to_string()))?;
+                    return Ok(json!(WriteResponse { written }));
+                };
+                let result = if call.caller.permission < Permission::Control {
+                    Err(Failure::new(403, format!("{} は読み取り専用です", call.caller.name)))
+                } else {
+                    open.device
+                        .write(&data)
+                        .map(|written| json!(WriteResponse { written }))
+                        .map_err(|err| Failure::new(502, err.to_string()))
+                };
+                let change = Change::api(action, Some(&call.caller.name), Some(call.client));
+                let outcome = result.as_ref().map(|_| ()).map_err(|failure| failure.message.clone());
+                let mask = open.set_mask(&data);
+                let mut record = AuditRecord::new(&open.id, open.serial.as_deref(), mask, &change, outcome);
+                if let Some(detail) = detail {
+                    record = record.detail(detail);
+                }
+                record.emit();
+                on_event(&ServeEvent::Audit(record));
+                result
+            }
+            (Method::Post, ["v1", "handles", handle, "read"]) => {
+                let read: ReadRequest = parse_body(body)?;
+                if read.len > MAX_READ_LEN {
+                    return Err(Failure::new(400, format!("len は {} バイトまでです", MAX_READ_LEN)));
+                }
+                let timeout_ms = if read.timeout_ms < 0 {
+                    MAX_READ_TIMEOUT_MS
+                } else {
+                    read.timeout_ms.min(MAX_READ_TIMEOUT_MS)
+                };
+                let open = self.handle(handle, call.caller)?;
+                let mut data = vec![0u8; read.len];
+                let len = open
+                    .device
+                    .read_timeout(&mut data, timeout_ms)
+                    .map_err(|err| Failure::new(502, err.to_string()))?;
+                data.truncate(len);
+                Ok(json!(ReadResponse {
+                    data: format_bytes(&data)
+                }))
+            }
+            (Method::Delete, ["v1", "handles", handle]) => {
+                let handle = parse_handle(handle)?;
+                // 他のクライアントのハンドルは閉じない
+                let open = self
+                    .handles
+                    .get(&handle)
+                    .is_some_and(|open| open.caller == call.caller.name)
+                    .then(|| self.handles.remove(&handle))
+                    .flatten()
+                    .ok_or_else(|| Failure::new(404, format!("ハンドル {} は開いていません", handle)))?;
+                on_event(&ServeEvent::Closed { handle, id: open.id });
+                Ok(json!({}))
+            }
+            _ => Err(Failure::new(404, format!("不明な要求です: {} {}", method, url))),
+        }
+    }
+
+    /// 公開するデバイス。サーバー側のフィルタとプロファイルで絞り、クライアントのフィルタを当てる
+    fn visible(
+        &self,
+        backend: &mut dyn LocatorBackend,
+        profiles: &ProfileRegistry,
+        query: &DeviceQuery,
+    ) -> std::result::Result<Vec<DeviceDescriptor>, Failure> {
+        backend.refresh().map_err(|err| Failure::from_error(502, &err))?;
+        let mut devices = backend
+            .devices(&self.filter)
+            .map_err(|err| Failure::from_error(502, &err))?;
+        devices.retain(|device| profiles.is_known(device) && query.matches(device));
+        Ok(devices)
+    }
+
+    fn open(
+        &mut self,
+        backend: &mut dyn LocatorBackend,
+        profiles: &ProfileRegistry,
+        path: &str,
+        call: &Call,
+        on_event: &mut dyn FnMut(&ServeEvent),
+    ) -> std::result::Result<u64, Failure> {
+        let device = self
+            .visible(backend, profiles, &DeviceQuery::default())?
+            .into_iter()
+            .find(|device| device.path.to_string_lossy() == path)
+            .ok_or_else(|| Failure::new(404, format!("デバイスが見つかりません: {}", path)))?;
+        call.caller
+            .authorize(&device, Permission::Read)
+            .map_err(|denied| Failure::denied(&denied))?;
+        let id = device.locator_id();
+        let profile = match profiles.for_bootloader(&device) {
+            Some(_) => None,
+            None => Some(profiles.for_device(&device).clone()),
+        };
+        // 同じserveの別のハンドルが持っているロックは、待っても要求を1つずつ処理している間は解放されない
+        if let Some(open) = self.handles.values().find(|open| open.id == id) {
+            return Err(Failure::new(
+                409,
+                format!("locator {} はクライアント {} が使用中です", id, open.caller),
+            ));
+        }
+        let dir = lock_dir().map_err(|err| Failure::from_error(500, &err))?;
+        let guard = DeviceLock::acquire(&dir, &device, self.lock_timeout)
+            .map_err(|err| Failure::from_error(409, &err))?;
+        let handle = backend
+            .open(&device)
+            .map_err(|err| Failure::from_error(502, &err))?;
+        let number = self.next_handle;
+        self.next_handle += 1;
+        self.handles.insert(
+            number,
+            OpenHandle {
+                id: id.clone(),
+                serial: device.serial_number.clone(),
+                caller: call.caller.name.clone(),
+                device: LockedDevice::new(handle, guard),
+                profile,
+                last_used: Instant::now(),
+            },
+        );
+        on_event(&ServeEvent::Opened {
+            handle: number,
+            id,
+            caller: call.caller.name.clone(),
+            client: call.client.to_string(),
+        });
+        Ok(number)
+    }
+
+    /// 開いているハンドル。他のクライアントが開いたものは開いていないものとして扱う
+    fn handle(&mut self, handle: &str, caller: &Caller) -> std::result::Result<&OpenHandle, Failure> {
+        let handle = parse_handle(handle)?;
+        let open = self
+            .handles
+            .get_mut(&handle)
+            .filter(|open| open.caller == caller.name)
+            .ok_or_else(|| Failure::new(404, format!("ハンドル {} は開いていません", handle)))?;
+        open.last_used = Instant::now();
+        Ok(open)
+    }
+}
+
+/// 要求の本文を `MAX_BODY` まで読む。Content-Lengthか実際の長さが超えていれば413
+fn read_body(request: &mut tiny_http::Request) -> std::result::Result<String, Failure> {
+    let too_large = || Failure::new(413, format!("要求の本文は {} バイトまでです", MAX_BODY));
+    if request.body_length().is_some_and(|len| len as u64 > MAX_BODY) {
+        return Err(too_large());
+    }
+    let mut body = String::new();
+    request
+        .as_reader()
+        .take(MAX_BODY + 1)
+        .read_to_string(&mut body)
+        .map_err(|err| Failure::new(400, format!("要求を読めません: {}", err)))?;
+    if body.len() as u64 > MAX_BODY {
+        return Err(too_large());
+    }
+    Ok(body)
+}
+
+fn parse_body<T: DeserializeOwned>(body: &str) -> std::result::Result<T, Failure> {
+    serde_json::from_str(body).map_err(|err| Failure::new(400, format!("要求のJSONが不正です: {}", err)))
+}
+
+fn parse_handle(handle: &str) -> std::result::Result<u64, Failure> {
+    handle
+        .parse()
+        .map_err(|_| Failure::new(400, format!("ハンドルが不正です: {}", handle)))
+}
+
+/// `serve` を動かしている別のマシンのlocatorを使うバックエンド
+///
+/// 各ハンドラからはローカルのHIDと区別できないので、全てのサブコマンドがそのまま動く
+pub struct RemoteBackend {
+    base: String,
+    token: String,
+    agent: ureq::Agent,
+}
+
+impl RemoteBackend {
+    pub fn new(url: &str, token: Option<&str>) -> Result<Self> {
+        // serve は平文のHTTPしか話さない。https:// を受け付けると暗号化されていると誤解させる
+        if url.starts_with("https://") {
+            bail!(
+                "serve はTLSに対応していません。serve をループバックで待ち受けてSSHのポート転送などで接続し、--remote http://host:port を指定してください: {}",
+                url
+            );
+        }
+        if !is_http_url(url) {
+            bail!("--remote は http://host:port の形式で指定してください: {}", url);
+        }
+        let token = token
+            .filter(|token| !token.is_empty())
+            .ok_or_else(|| anyhow!("--remote-token (または CAP_LOCATOR_REMOTE_TOKEN) を指定してください"))?;
+        Ok(Self {
+            base: url.trim_end_matches('/').to_string(),
+            token: token.to_string(),
+            agent: ureq::AgentBuilder::new().timeout(HTTP_MARGIN).build(),
+        })
+    }
+
+    fn post<T: DeserializeOwned>(&self, path: &str, body: serde_json::Value) -> Result<T> {
+        call(&self.agent, &self.base, &self.token, "POST", path, Some(body), HTTP_MARGIN)
+    }
+}
+
+impl LocatorBackend for RemoteBackend {
+    fn devices(&self, filter: &FilterArgs) -> Result<Vec<DeviceDescriptor>> {
+        let devices: Vec<RecordedDevice> = self.post("/v1/devices", json!(DeviceQuery::from(filter)))?;
+        devices.iter().map(DeviceDescriptor::try_from).collect()
+    }
+
+    fn open(&self, device: &DeviceDescriptor) -> Result<Box<dyn HidDeviceIo>> {
+        let path = device.path.to_string_lossy().into_owned();
+        let opened: OpenResponse = self
+            .post("/v1/open", json!(OpenRequest { path }))
+            .with_context(|| format!("open device {}", device.locator_id()))?;
+        Ok(Box::new(RemoteDevice {
+            agent: self.agent.clone(),
+            base: self.base.clone(),
+            token: self.token.clone(),
+            handle: opened.handle,
+        }))
+    }
+
+    fn locks_locally(&self) -> bool {
+        false
+    }
+}
+
+/// `serve` 側で開いているlocator。Dropで閉じる
+pub struct RemoteDevice {
+    agent: ureq::Agent,
+    base: String,
+    token: String,
+    handle: u64,
+}
+
+impl RemoteDevice {
+    fn post<T: DeserializeOwned>(&self, action: &str, body: serde_json::Value, timeout: Duration) -> HidResult<T> {
+        let path = format!("/v1/handles/{}/{}", self.handle, action);
+        call(&self.agent, &self.base, &self.token, "POST", &path, Some(body), timeout).map_err(|err| {
+            HidError::HidApiError {
+                message: format!("{:#}", err),
+            }
+        })
+    }
+}
+
+impl HidDeviceIo for RemoteDevice {
+    fn write(&self, data: &[u8]) -> HidResult<usize> {
+        let response: WriteResponse = self.post(
+            "write",
+            json!(WriteRequest {
+                data: format_bytes(data)
+            }),
+            HTTP_MARGIN,
+        )?;
+        Ok(response.written)
+    }
+
+    /// serve側は1回に `MAX_READ_TIMEOUT_MS` までしか待たないので、届くか `timeout_ms` が経つまで読み直す
+    fn read_timeout(&self, data: &mut [u8], timeout_ms: i32) -> HidResult<usize> {
+        let deadline = u64::try_from(timeout_ms)
+            .ok()
+            .map(|ms| Instant::now() + Duration::from_millis(ms));
+        loop {
+            let wait = deadline.map_or(MAX_READ_TIMEOUT_MS, |deadline| {
+                let left = deadline.saturating_duration_since(Instant::now()).as_millis();
+                left.min(MAX_READ_TIMEOUT_MS as u128) as i32
+            });
+            let response: ReadResponse = self.post(
+                "read",
+                json!(ReadRequest {
+                    len: data.len(),
+                    timeout_ms: wait,
+                }),
+                Duration::from_millis(wait as u64) + HTTP_MARGIN,
+            )?;
+            let received = parse_hex_bytes(&response.data).map_err(|message| HidError::HidApiError { message })?;
+            if !received.is_empty() || deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                let len = received.len().min(data.len());
+                data[..len].copy_from_slice(&received[..len]);
+                return Ok(len);
+            }
+        }
+    }
+}
+
+impl Drop for RemoteDevice {
+    fn drop(&mut self) {
+        let path = format!("/v1/handles/{}", self.handle);
+        if let Err(err) = call::<serde_json::Value>(&self.agent, &self.base, &self.token, "DELETE", &path, None, HTTP_MARGIN) {
+            // 閉じられなくても、serve側が `--idle-timeout` 後に閉じる
+            warn!("リモートのlocatorを閉じられません: {:#}", err);
+        }
+    }
+}
+
+/// `serve` へ要求する。エラー応答はサーバー側のメッセージをそのままエラーにする
+fn call<T: DeserializeOwned>(
+    agent: &ureq::Agent,
+    base: &str,
+    token: &str,
+    method: &str,
+    path: &str,
+    body: Option<serde_json::Value>,
+    timeout: Duration,
+) -> Result<T> {
+    let request = agent
+        .request(method, &format!("{}{}", base, path))
+        .timeout(timeout)
+        .set("Authorization", &format!("Bearer {}", token));
+    let result = match body {
+        Some(body) => request.send_json(body),
+        None => request.call(),
+    };
+    match result {
+        Ok(response) => response
+            .into_json()
+            .with_context(|| format!("{} の応答を読めません", base)),
+        Err(ureq::Error::Status(status, response)) => match response.into_json::<ErrorResponse>() {
+            Ok(error) => Err(anyhow!(error.error)),
+            Err(_) => Err(anyhow!("{} がエラーを返しました (HTTP {})", base, status)),
+        },
+        Err(err) => Err(anyhow!(err)).with_context(|| format!("{} に接続できません", base)),
+    }
+}
@@ -0,0 +1,298 @@
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{Context, Result, anyhow, bail};
+use chrono::{DateTime, Datelike, NaiveTime, TimeDelta, TimeZone, Weekday};
+
+use crate::config::{Config, RuleConfig};
+use crate::hid::{DeviceDescriptor, IdQuery};
+use crate::util::{MAX_DURATION, parse_duration};
+
+pub const DEFAULT_BLINK_INTERVAL: Duration = Duration::from_millis(500);
+
+/// ルールが有効になる条件
+#[derive(Clone, Debug)]
+pub enum Trigger {
+    /// 指定曜日の start〜end (end < start なら日付をまたぐ)
+    Window {
+        start: NaiveTime,
+        end: NaiveTime,
+        days: Vec<Weekday>,
+    },
+    /// cronの発火時刻から duration の間
+    Cron {
+        schedule: Box<cron::Schedule>,
+        duration: Duration,
+    },
+}
+
+/// 有効な間のLEDの出し方
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Pattern {
+    Steady,
+    /// interval ごとに mask と 0 を切り替える
+    Blink { interval: Duration },
+}
+
+impl Pattern {
+    /// now 時点で送るべきマスク (点滅は時刻から位相を決めるので、複数のlocatorが揃って点滅する)
+    pub fn mask_at<Tz: TimeZone>(self, mask: u8, now: &DateTime<Tz>) -> u8 {
+        match self {
+            Pattern::Steady => mask,
+            Pattern::Blink { interval } => {
+                let phase = now.timestamp_millis().div_euclid(interval.as_millis().max(1) as i64);
+                if phase % 2 == 0 { mask } else { 0 }
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Rule {
+    pub name: String,
+    pub target: String,
+    pub trigger: Trigger,
+    pub mask: u8,
+    pub pattern: Pattern,
+    pub priority: i32,
+}
+
+/// ルールの開始/終了
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Edge {
+    Start,
+    End,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Transition<Tz: TimeZone> {
+    pub at: DateTime<Tz>,
+    pub edge: Edge,
+    pub rule: String,
+    pub target: String,
+    pub mask: u8,
+    pub priority: i32,
+}
+
+impl Rule {
+    pub fn from_config(config: &RuleConfig) -> Result<Self> {
+        let name = config.name.clone().unwrap_or_else(|| config.target.clone());
+        let trigger = match (&config.window, &config.cron) {
+            (Some(window), None) => {
+                let (start, end) = parse_window(window)?;
+                let days = config
+                    .days
+                    .iter()
+                    .map(|d| {
+                        Weekday::from_str(d).map_err(|_| anyhow!("曜日を解釈できません: {}", d))
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                Trigger::Window { start, end, days }
+            }
+            (None, Some(expr)) => {
+                let schedule = cron::Schedule::from_str(expr)
+                    .map_err(|e| anyhow!("cron式を解釈できません: {} ({})", expr, e))?;
+                let duration = config
+                    .duration
+                    .as_deref()
+                    .ok_or_else(|| anyhow!("cronルールには duration が必要です"))
+                    .and_then(|d| parse_duration(d).map_err(|e| anyhow!(e)))?;
+                Trigger::Cron {
+                    schedule: Box::new(schedule),
+                    duration,
+                }
+            }
+            _ => bail!("window と cron のどちらか一方を指定してください"),
+        };
+
+        let pattern = match config.pattern.as_deref().unwrap_or("steady") {
+            "steady" => Pattern::Steady,
+            "blink" => Pattern::Blink {
+                interval: match config.interval.as_deref() {
+                    Some(interval) => parse_duration(interval).map_err(|e| anyhow!(e))?,
+                    None => DEFAULT_BLINK_INTERVAL,
+                },
+            },
+            other => bail!("不明なpatternです: {} (steady/blink)", other),
+        };
+        if let Pattern::Blink { interval } = pattern
+            && interval.is_zero()
+        {
+            bail!("blinkの interval は0より大きくしてください");
+        }
+
+        Ok(Self {
+            name,
+            target: config.target.clone(),
+            trigger,
+            mask: config.mask,
+            pattern,
+            priority: config.priority,
+        })
+    }
+
+    pub fn is_active<Tz: TimeZone>(&self, now: &DateTime<Tz>) -> bool {
+        match &self.trigger {
+            Trigger::Window { start, end, days } => {
+                let time = now.time();
+                let day_ok = |day: Weekday| days.is_empty() || days.contains(&day);
+                if start <= end {
+                    day_ok(now.weekday()) && *start <= time && time < *end
+                } else {
+                    (day_ok(now.weekday()) && time >= *start)
+                        || (day_ok(now.weekday().pred()) && time < *end)
+                }
+            }
+            Trigger::Cron { schedule, duration } => {
+                let since = now.clone() - to_delta(*duration);
+                schedule
+                    .after(&since)
+                    .next()
+                    .is_some_and(|fired| fired <= *now)
+            }
+        }
+    }
+
+    /// 有効な間に now 時点で送るべきマスク
+    pub fn mask_at<Tz: TimeZone>(&self, now: &DateTime<Tz>) -> u8 {
+        self.pattern.mask_at(self.mask, now)
+    }
+
+    /// now より後の開始/終了時刻を、近い順に最大 limit 件
+    fn transitions_after<Tz: TimeZone>(&self, now: &DateTime<Tz>, limit: usize) -> Vec<(DateTime<Tz>, Edge)> {
+        let mut edges = Vec::new();
+        match &self.trigger {
+            Trigger::Window { start, end, days } => {
+                // 1週間+1日先まで見れば、曜日指定があっても各エッジが最低1回は現れる
+                for offset in -1..=8 {
+                    let date = now.date_naive() + TimeDelta::days(offset);
+                    if !days.is_empty() && !days.contains(&date.weekday()) {
+                        continue;
+                    }
+                    let end_date = if end <= start { date.succ_opt() } else { Some(date) };
+                    let start_at = now.timezone().from_local_datetime(&date.and_time(*start)).earliest();
+                    let end_at = end_date.and_then(|d| {
+                        now.timezone().from_local_datetime(&d.and_time(*end)).earliest()
+                    });
+                    edges.extend(start_at.map(|at| (at, Edge::Start)));
+                    edges.extend(end_at.map(|at| (at, Edge::End)));
+                }
+            }
+            Trigger::Cron { schedule, duration } => {
+                let since = now.clone() - to_delta(*duration);
+                for fired in schedule.after(&since).take(limit + 1) {
+                    let ended = fired.clone() + to_delta(*duration);
+                    edges.push((fired, Edge::Start));
+                    edges.push((ended, Edge::End));
+                }
+            }
+        }
+        edges.retain(|(at, _)| at > now);
+        edges.sort_by(|a, b| a.0.cmp(&b.0));
+        edges.truncate(limit);
+        edges
+    }
+}
+
+/// ルール一覧とグループ定義から、各locatorの目標マスクを決める
+#[derive(Clone, Debug, Default)]
+pub struct Scheduler {
+    pub rules: Vec<Rule>,
+    config: Config,
+}
+
+impl Scheduler {
+    pub fn from_config(config: &Config) -> Result<Self> {
+        let rules = config
+            .rules
+            .iter()
+            .enumerate()
+            .map(|(i, rule)| {
+                Rule::from_config(rule)
+                    .with_context(|| format!("rules[{}] ({}) が不正です", i, rule.target))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            rules,
+            config: config.clone(),
+        })
+    }
+
+    /// `present` (接続中のlocator) の中で、ルールの対象のidに選ばれるか
+    fn targets(&self, rule: &Rule, device: &DeviceDescriptor, present: &[DeviceDescriptor]) -> bool {
+        self.config
+            .expand_target(&rule.target)
+            .iter()
+            .any(|query| IdQuery::resolve(present, query).selects(device))
+    }
+
+    /// now 時点で device に送るべきマスク
+    ///
+    /// - どのルールの対象でもなければ None (触らない)
+    /// - 対象だが有効なルールが無ければ 0 (消灯)
+    /// - 有効なルールが重なったら priority が大きいもの、同じなら先に書かれたもの
+    /// - ルールの対象のidは `present` (接続中のlocator) に完全に一致するものがあればそれだけに当てる
+    pub fn desired_mask<Tz: TimeZone>(
+        &self,
+        device: &DeviceDescriptor,
+        present: &[DeviceDescriptor],
+        now: &DateTime<Tz>,
+    ) -> Option<u8> {
+        let mut targeted = false;
+        let mut winner: Option<&Rule> = None;
+        for rule in self.rules.iter().filter(|rule| self.targets(rule, device, present)) {
+            targeted = true;
+            if rule.is_active(now) && winner.is_none_or(|w| rule.priority > w.priority) {
+                winner = Some(rule);
+            }
+        }
+        match winner {
+            Some(rule) => Some(rule.mask_at(now)),
+            None if targeted => Some(0),
+            None => None,
+        }
+    }
+
+    /// now より後のルール開始/終了を近い順に最大 limit 件
+    pub fn next_transitions<Tz: TimeZone>(&self, now: &DateTime<Tz>, limit: usize) -> Vec<Transition<Tz>> {
+        let mut transitions: Vec<Transition<Tz>> = self
+            .rules
+            .iter()
+            .flat_map(|rule| {
+                rule.transitions_after(now, limit)
+                    .into_iter()
+                    .map(|(at, edge)| Transition {
+                        at,
+                        edge,
+                        rule: rule.name.clone(),
+                        target: rule.target.clone(),
+                        mask: rule.mask,
+                        priority: rule.priority,
+                    })
+            })
+            .collect();
+        transitions.sort_by(|a, b| a.at.cmp(&b.at));
+        transitions.truncate(limit);
+        transitions
+    }
+}
+
+fn parse_window(window: &str) -> Result<(NaiveTime, NaiveTime)> {
+    let (start, end) = window
+        .split_once('-')
+        .ok_or_else(|| anyhow!("時間帯は HH:MM-HH:MM で指定してください: {}", window))?;
+    let parse = |s: &str| {
+        NaiveTime::parse_from_str(s.trim(), "%H:%M")
+            .with_context(|| format!("時刻を解釈できません: {}", s))
+    };
+    let (start, end) = (parse(start)?, parse(end)?);
+    if start == end {
+        bail!("開始と終了が同じ時間帯は指定できません: {}", window);
+    }
+    Ok((start, end))
+}
+
+/// 日時から足し引きしてもあふれないよう `MAX_DURATION` までに抑える
+fn to_delta(duration: Duration) -> TimeDelta {
+    TimeDelta::from_std(duration.min(MAX_DURATION)).expect("MAX_DURATIONはTimeDeltaに収まる")
+}
@@ -0,0 +1,198 @@
+use std::net::TcpListener;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use listenfd::ListenFd;
+use tracing::debug;
+
+use crate::cli::FilterArgs;
+use crate::profile::ProfileRegistry;
+
+/// `install-service` が書き出すユニットの WatchdogSec
+pub const WATCHDOG_SEC: Duration = Duration::from_secs(30);
+
+/// systemd へ起動完了・watchdog・停止を知らせる (`Type=notify`)
+///
+/// systemd から起動されていなければ (NOTIFY_SOCKET が無ければ) 何も送らない
+pub struct Notifier {
+    /// WatchdogSec。有効でなければNone
+    watchdog: Option<Duration>,
+    last_ping: Instant,
+}
+
+impl Notifier {
+    pub fn from_env() -> Self {
+        Self {
+            watchdog: watchdog_interval(),
+            last_ping: Instant::now(),
+        }
+    }
+
+    pub fn watchdog_interval(&self) -> Option<Duration> {
+        self.watchdog
+    }
+
+    /// 起動が終わったことと、`systemctl status` に出す状態を知らせる
+    pub fn ready(&mut self, status: &str) {
+        self.last_ping = Instant::now();
+        notify(&["READY=1".to_string(), format!("STATUS={}", status)]);
+    }
+
+    pub fn status(&self, status: &str) {
+        notify(&[format!("STATUS={}", status)]);
+    }
+
+    /// watchdogが有効なら、WatchdogSecの半分ごとに生きていることを知らせる
+    ///
+    /// 常駐ループの1周ごとに呼ぶ。HIDの読み書きなどで止まったままならsystemdが再起動する
+    pub fn watchdog(&mut self) {
+        let Some(interval) = self.watchdog else {
+            return;
+        };
+        if self.last_ping.elapsed() >= interval / 2 {
+            self.last_ping = Instant::now();
+            notify(&["WATCHDOG=1".to_string()]);
+        }
+    }
+
+    pub fn stopping(&self) {
+        notify(&["STOPPING=1".to_string()]);
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn watchdog_interval() -> Option<Duration> {
+    let mut usec = 0;
+    sd_notify::watchdog_enabled(false, &mut usec).then(|| Duration::from_micros(usec))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn watchdog_interval() -> Option<Duration> {
+    None
+}
+
+#[cfg(target_os = "linux")]
+fn notify(states: &[String]) {
+    let states: Vec<sd_notify::NotifyState> = states
+        .iter()
+        .map(|state| sd_notify::NotifyState::Custom(state))
+        .collect();
+    // systemd 配下でなければ何もせずOkが返る
+    if let Err(err) = sd_notify::notify(false, &states) {
+        debug!("systemdへ通知できません: {}", err);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn notify(_states: &[String]) {}
+
+/// systemd のソケット活性化 (`<name>.socket`) で渡された待ち受け中のTCPソケット。無ければNone
+pub fn activated_listener() -> Result<Option<TcpListener>> {
+    let mut fds = ListenFd::from_env();
+    if fds.len() == 0 {
+        return Ok(None);
+    }
+    let listener = fds
+        .take_tcp_listener(0)
+        .context("systemdから渡されたソケットがTCPの待ち受けではありません")?;
+    Ok(listener)
+}
+
+/// udevルールやユニットの対象にするUSBの (vendor id, product id)
+///
+/// フィルタ (.env含む) で両方決まっていればそれだけ、そうでなければ各プロファイルとそのブートローダー
+pub fn usb_ids(profiles: &ProfileRegistry, filter: &FilterArgs) -> Vec<(u16, u16)> {
+    if let (Some(vendor_id), Some(product_id)) = (filter.vendor_id, filter.product_id) {
+        return vec![(vendor_id, product_id)];
+    }
+    let mut ids: Vec<(u16, u16)> = profiles
+        .names()
+        .into_iter()
+        .filter_map(|name| profiles.get(name))
+        .flat_map(|profile| {
+            let app = profile.vendor_id.zip(profile.product_id);
+            let bootloader = profile
+                .bootloader
+                .as_ref()
+                .map(|layout| (layout.vendor_id, layout.product_id));
+            [app, bootloader]
+        })
+        .flatten()
+        .filter(|(vendor_id, _)| filter.vendor_id.is_none_or(|filter| filter == *vendor_id))
+        .collect();
+    ids.sort();
+    ids.dedup();
+    ids
+}
+
+/// hidrawを `group` のユーザーが読み書きできるようにするudevルール
+pub fn udev_rules(ids: &[(u16, u16)], group: &str) -> String {
+    let mut rules = String::from("# cap-locator-cli が生成したルール\n");
+    for (vendor_id, product_id) in ids {
+        rules.push_str(&format!(
+            "KERNEL==\"hidraw*\", ATTRS{{idVendor}}==\"{:04x}\", ATTRS{{idProduct}}==\"{:04x}\", MODE=\"0660\", GROUP=\"{}\", TAG+=\"uaccess\"\n",
+            vendor_id, product_id, group
+        ));
+    }
+    rules
+}
+
+/// `install-service` で作る `<name>.service` の内容
+#[derive(Clone, Debug)]
+pub struct ServiceUnit {
+    pub name: String,
+    /// `daemon` を起動するコマンドライン
+    pub exec_start: Vec<String>,
+    pub user: Option<String>,
+    /// hidrawを使うためのグループ (SupplementaryGroups)
+    pub group: String,
+    /// `<name>.socket` から待ち受けソケットを受け取る
+    pub socket: bool,
+}
+
+impl ServiceUnit {
+    pub fn render(&self) -> String {
+        let mut unit = String::from("[Unit]\nDescription=Cap Locator daemon\n");
+        if self.socket {
+            unit.push_str(&format!("Requires={0}.socket\nAfter={0}.socket\n", self.name));
+        } else {
+            unit.push_str("After=network.target\n");
+        }
+        unit.push_str("\n[Service]\nType=notify\nNotifyAccess=main\n");
+        // --ws-token などの秘密はユニットに書かず、ここに置いてもらう
+        unit.push_str(&format!("EnvironmentFile=-/etc/default/{}\n", self.name));
+        unit.push_str(&format!(
+            "ExecStart={}\n",
+            self.exec_start.iter().map(|arg| quote(arg)).collect::<Vec<_>>().join(" ")
+        ));
+        if let Some(user) = &self.user {
+            unit.push_str(&format!("User={}\n", user));
+        }
+        unit.push_str(&format!("SupplementaryGroups={}\n", self.group));
+        unit.push_str(&format!(
+            "Restart=on-failure\nRestartSec=5s\nWatchdogSec={}s\nTimeoutStopSec=30s\n",
+            WATCHDOG_SEC.as_secs()
+        ));
+        unit.push_str("\n[Install]\nWantedBy=multi-user.target\n");
+        if self.socket {
+            unit.push_str(&format!("Also={}.socket\n", self.name));
+        }
+        unit
+    }
+}
+
+/// `<name>.socket` の内容。`listen` で待ち受けたソケットを `daemon` に渡す
+pub fn socket_unit(name: &str, listen: &str) -> String {
+    format!(
+        "[Unit]\nDescription=Cap Locator daemon (Web UI / WebSocket)\n\n[Socket]\nListenStream={}\nService={}.service\n\n[Install]\nWantedBy=sockets.target\n",
+        listen, name
+    )
+}
+
+/// ExecStart の引数。空白や引用符を含むものだけ引用符で囲む
+fn quote(arg: &str) -> String {
+    if !arg.is_empty() && !arg.contains(|c: char| c.is_whitespace() || c == '"' || c == '\\' || c == '\'') {
+        return arg.to_string();
+    }
+    format!("\"{}\"", arg.replace('\\', "\\\\").replace('"', "\\\""))
+}
@@ -0,0 +1,329 @@
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use anyhow::{Context, Result, anyhow, bail};
+use hidapi::{HidError, HidResult};
+use serde::{Deserialize, Serialize};
+
+use crate::backend::LocatorBackend;
+use crate::cli::FilterArgs;
+use crate::hid::{DeviceDescriptor, HidDeviceIo, matches_port};
+use crate::util::{format_bytes, parse_hex_bytes};
+
+/// 記録ファイル(JSON Lines)の1行
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SessionEvent {
+    /// 列挙結果
+    Devices { devices: Vec<RecordedDevice> },
+    /// locatorを開いた
+    Open { path: String },
+    /// Output Report送信
+    Write {
+        path: String,
+        at_ms: u64,
+        data: String,
+    },
+    /// Input Report受信 (`error` があれば失敗)
+    Read {
+        path: String,
+        at_ms: u64,
+        timeout_ms: i32,
+        elapsed_ms: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        data: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+}
+
+/// 記録用のDeviceDescriptor
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecordedDevice {
+    pub path: String,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub serial_number: Option<String>,
+    pub usage_page: Option<u16>,
+    pub usage: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub manufacturer: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub product: Option<String>,
+    #[serde(default)]
+    pub release_number: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<String>,
+}
+
+impl From<&DeviceDescriptor> for RecordedDevice {
+    fn from(device: &DeviceDescriptor) -> Self {
+        Self {
+            path: device.path.to_string_lossy().into_owned(),
+            vendor_id: device.vendor_id,
+            product_id: device.product_id,
+            serial_number: device.serial_number.clone(),
+            usage_page: device.usage_page,
+            usage: device.usage,
+            manufacturer: device.manufacturer.clone(),
+            product: device.product.clone(),
+            release_number: device.release_number,
+            port: device.port.clone(),
+        }
+    }
+}
+
+impl TryFrom<&RecordedDevice> for DeviceDescriptor {
+    type Error = anyhow::Error;
+
+    fn try_from(device: &RecordedDevice) -> Result<Self> {
+        Ok(Self {
+            path: std::ffi::CString::new(device.path.clone())?,
+            vendor_id: device.vendor_id,
+            product_id: device.product_id,
+            serial_number: device.serial_number.clone(),
+            usage_page: device.usage_page,
+            usage: device.usage,
+            manufacturer: device.manufacturer.clone(),
+            product: device.product.clone(),
+            release_number: device.release_number,
+            port: device.port.clone(),
+        })
+    }
+}
+
+/// 記録ファイルへの書き込み。記録中の全デバイスで共有する
+pub struct Recorder {
+    writer: Mutex<Box<dyn Write + Send>>,
+    started: Instant,
+}
+
+impl Recorder {
+    pub fn new(writer: Box<dyn Write + Send>) -> Self {
+        Self {
+            writer: Mutex::new(writer),
+            started: Instant::now(),
+        }
+    }
+
+    pub fn create(path: &Path) -> Result<Self> {
+        let file = File::create(path)
+            .with_context(|| format!("記録ファイルを作成できません: {}", path.display()))?;
+        Ok(Self::new(Box::new(BufWriter::new(file))))
+    }
+
+    fn elapsed_ms(&self) -> u64 {
+        self.started.elapsed().as_millis() as u64
+    }
+
+    /// 1行書き込む。記録の失敗で本来の操作を止めないよう、エラーは警告ログだけにする
+    fn record(&self, event: &SessionEvent) {
+        let result = serde_json::to_string(event)
+            .map_err(anyhow::Error::from)
+            .and_then(|line| {
+                let mut writer = self.writer.lock().map_err(|_| anyhow!("記録ファイルのロックが壊れています"))?;
+                writeln!(writer, "{}", line)?;
+                writer.flush()?;
+                Ok(())
+            });
+        if let Err(err) = result {
+            tracing::warn!("セッションを記録できません: {:#}", err);
+        }
+    }
+}
+
+/// 送受信を記録するHidDeviceIoラッパー
+pub struct RecordingDevice<D> {
+    inner: D,
+    path: String,
+    recorder: Arc<Recorder>,
+}
+
+impl<D: HidDeviceIo> HidDeviceIo for RecordingDevice<D> {
+    fn write(&self, data: &[u8]) -> HidResult<usize> {
+        self.recorder.record(&SessionEvent::Write {
+            path: self.path.clone(),
+            at_ms: self.recorder.elapsed_ms(),
+            data: format_bytes(data),
+        });
+        self.inner.write(data)
+    }
+
+    fn read_timeout(&self, data: &mut [u8], timeout_ms: i32) -> HidResult<usize> {
+        let at_ms = self.recorder.elapsed_ms();
+        let started = Instant::now();
+        let result = self.inner.read_timeout(data, timeout_ms);
+        let (received, error) = match &result {
+            Ok(len) => (Some(format_bytes(&data[..*len])), None),
+            Err(err) => (None, Some(err.to_string())),
+        };
+        self.recorder.record(&SessionEvent::Read {
+            path: self.path.clone(),
+            at_ms,
+            timeout_ms,
+            elapsed_ms: started.elapsed().as_millis() as u64,
+            data: received,
+            error,
+        });
+        result
+    }
+}
+
+/// 列挙とオープン、全送受信を記録するバックエンド (`--record`)
+pub struct RecordingBackend<B> {
+    inner: B,
+    recorder: Arc<Recorder>,
+}
+
+impl<B: LocatorBackend> RecordingBackend<B> {
+    pub fn new(inner: B, recorder: Recorder) -> Self {
+        Self {
+            inner,
+            recorder: Arc::new(recorder),
+        }
+    }
+}
+
+impl<B: LocatorBackend> LocatorBackend for RecordingBackend<B> {
+    fn devices(&self, filter: &FilterArgs) -> Result<Vec<DeviceDescriptor>> {
+        let devices = self.inner.devices(filter)?;
+        self.recorder.record(&SessionEvent::Devices {
+            devices: devices.iter().map(RecordedDevice::from).collect(),
+        });
+        Ok(devices)
+    }
+
+    fn open(&self, device: &DeviceDescriptor) -> Result<Box<dyn HidDeviceIo>> {
+        let inner = self.inner.open(device)?;
+        let path = device.path.to_string_lossy().into_owned();
+        self.recorder.record(&SessionEvent::Open { path: path.clone() });
+        Ok(Box::new(RecordingDevice {
+            inner,
+            path,
+            recorder: Arc::clone(&self.recorder),
+        }))
+    }
+
+    fn refresh(&mut self) -> Result<()> {
+        self.inner.refresh()
+    }
+}
+
+/// 記録ファイルの応答を返すバックエンド (`--replay`)
+///
+/// 記録と異なる順序・内容の要求が来たらエラーにする
+pub struct ReplayBackend {
+    events: Arc<Mutex<VecDeque<SessionEvent>>>,
+}
+
+impl ReplayBackend {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("記録ファイルを読み込めません: {}", path.display()))?;
+        Self::parse(&text).with_context(|| format!("{} の解析に失敗", path.display()))
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        let events = text
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(i, line)| {
+                serde_json::from_str(line).with_context(|| format!("{}行目を解釈できません", i + 1))
+            })
+            .collect::<Result<VecDeque<SessionEvent>>>()?;
+        Ok(Self {
+            events: Arc::new(Mutex::new(events)),
+        })
+    }
+
+    /// まだ再生されていない記録の数
+    pub fn remaining(&self) -> usize {
+        self.events.lock().map(|events| events.len()).unwrap_or(0)
+    }
+}
+
+fn next_event(events: &Mutex<VecDeque<SessionEvent>>) -> Option<SessionEvent> {
+    events.lock().ok()?.pop_front()
+}
+
+impl LocatorBackend for ReplayBackend {
+    fn devices(&self, filter: &FilterArgs) -> Result<Vec<DeviceDescriptor>> {
+        match next_event(&self.events) {
+            Some(SessionEvent::Devices { devices }) => devices
+                .iter()
+                .filter(|d| {
+                    filter.vendor_id.is_none_or(|id| id == d.vendor_id)
+                        && filter.product_id.is_none_or(|id| id == d.product_id)
+                })
+                .map(DeviceDescriptor::try_from)
+                .filter(|d| d.as_ref().map_or(true, |d| matches_port(d, filter)))
+                .collect(),
+            other => bail!("記録と異なります: 列挙を要求しましたが、記録は {:?}", other),
+        }
+    }
+
+    fn open(&self, device: &DeviceDescriptor) -> Result<Box<dyn HidDeviceIo>> {
+        let path = device.path.to_string_lossy().into_owned();
+        match next_event(&self.events) {
+            Some(SessionEvent::Open { path: recorded }) if recorded == path => {
+                Ok(Box::new(ReplayDevice {
+                    path,
+                    events: Arc::clone(&self.events),
+                }))
+            }
+            other => bail!("記録と異なります: {} のオープンを要求しましたが、記録は {:?}", path, other),
+        }
+    }
+}
+
+/// 記録された応答を返すデバイス
+pub struct ReplayDevice {
+    path: String,
+    events: Arc<Mutex<VecDeque<SessionEvent>>>,
+}
+
+fn divergence(message: String) -> HidError {
+    HidError::HidApiError {
+        message: format!("記録と異なります: {}", message),
+    }
+}
+
+impl HidDeviceIo for ReplayDevice {
+    fn write(&self, data: &[u8]) -> HidResult<usize> {
+        let sent = format_bytes(data);
+        match next_event(&self.events) {
+            Some(SessionEvent::Write { path, data: recorded, .. })
+                if path == self.path && recorded == sent =>
+            {
+                Ok(data.len())
+            }
+            other => Err(divergence(format!(
+                "{} へ [{}] を送信しましたが、記録は {:?}",
+                self.path, sent, other
+            ))),
+        }
+    }
+
+    fn read_timeout(&self, data: &mut [u8], _timeout_ms: i32) -> HidResult<usize> {
+        match next_event(&self.events) {
+            Some(SessionEvent::Read { path, data: Some(recorded), .. }) if path == self.path => {
+                let bytes = parse_hex_bytes(&recorded).map_err(divergence)?;
+                let len = data.len().min(bytes.len());
+                data[..len].copy_from_slice(&bytes[..len]);
+                Ok(len)
+            }
+            Some(SessionEvent::Read { path, error: Some(message), .. }) if path == self.path => {
+                Err(HidError::HidApiError { message })
+            }
+            other => Err(divergence(format!(
+                "{} からの受信を要求しましたが、記録は {:?}",
+                self.path, other
+            ))),
+        }
+    }
+}
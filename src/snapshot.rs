@@ -0,0 +1,142 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+
+use crate::hid::{DeviceDescriptor, PORT_ID_PREFIX};
+
+/// 保存時点の全locatorのLEDマスク
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub saved_at: DateTime<Local>,
+    pub devices: Vec<SnapshotEntry>,
+}
+
+/// 1台分の保存内容。復元時はシリアル番号、無ければUSBポートの経路、それも無ければパスで対応付ける
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SnapshotEntry {
+    pub serial: Option<String>,
+    pub path: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<String>,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub mask: u8,
+}
+
+impl SnapshotEntry {
+    pub fn new(device: &DeviceDescriptor, mask: u8) -> Self {
+        Self {
+            serial: device.serial_number.clone(),
+            path: device.path.to_string_lossy().into_owned(),
+            port: device.port.clone(),
+            vendor_id: device.vendor_id,
+            product_id: device.product_id,
+            mask,
+        }
+    }
+
+    /// 表示用のid (DeviceDescriptor::locator_idと同じ規則)
+    pub fn locator_id(&self) -> String {
+        match (&self.serial, &self.port) {
+            (Some(serial), _) => serial.clone(),
+            (None, Some(port)) => format!("{}{}", PORT_ID_PREFIX, port),
+            (None, None) => self.path.clone(),
+        }
+    }
+}
+
+impl Snapshot {
+    pub fn new(devices: Vec<SnapshotEntry>) -> Self {
+        Self {
+            saved_at: Local::now(),
+            devices,
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let json = serde_json::to_string_pretty(self)?;
+        fs::write(path, json + "\n").with_context(|| format!("{} に書き込めません", path.display()))
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("{} を読み込めません", path.display()))?;
+        serde_json::from_str(&text).with_context(|| format!("{} の解析に失敗", path.display()))
+    }
+}
+
+/// 保存内容と現在のlocatorの対応付け結果
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RestoreMatch {
+    /// シリアル番号で一致 (`path_changed` は挿し直しなどでパスが変わったことを示す)
+    BySerial {
+        device: DeviceDescriptor,
+        path_changed: bool,
+    },
+    /// シリアル番号の無いlocatorをUSBポートの経路で一致
+    ByPort {
+        device: DeviceDescriptor,
+        path_changed: bool,
+    },
+    /// シリアル番号の無いlocatorをパスで一致
+    ByPath { device: DeviceDescriptor },
+    /// 同じパスに別のlocator(シリアルやVID/PIDが違う)が居る
+    IdentityChanged { device: DeviceDescriptor },
+    /// 対応するlocatorが見つからない
+    Disappeared,
+}
+
+/// 保存された1台に対応する現在のlocatorを探す
+pub fn match_entry(entry: &SnapshotEntry, devices: &[DeviceDescriptor]) -> RestoreMatch {
+    if let Some(serial) = entry.serial.as_deref() {
+        let found = devices.iter().find(|d| {
+            d.serial_number.as_deref() == Some(serial)
+                && d.vendor_id == entry.vendor_id
+                && d.product_id == entry.product_id
+        });
+        if let Some(device) = found {
+            return RestoreMatch::BySerial {
+                device: device.clone(),
+                path_changed: device.path.to_string_lossy() != entry.path,
+            };
+        }
+    }
+
+    if let Some(port) = entry.port.as_deref().filter(|_| entry.serial.is_none()) {
+        let found = devices.iter().find(|d| {
+            d.serial_number.is_none()
+                && d.port.as_deref() == Some(port)
+                && d.vendor_id == entry.vendor_id
+                && d.product_id == entry.product_id
+        });
+        if let Some(device) = found {
+            return RestoreMatch::ByPort {
+                device: device.clone(),
+                path_changed: device.path.to_string_lossy() != entry.path,
+            };
+        }
+    }
+
+    let same_path = devices
+        .iter()
+        .find(|d| d.path.to_string_lossy() == entry.path);
+    match same_path {
+        Some(device)
+            if entry.serial.is_none()
+                && device.serial_number.is_none()
+                && device.vendor_id == entry.vendor_id
+                && device.product_id == entry.product_id =>
+        {
+            RestoreMatch::ByPath {
+                device: device.clone(),
+            }
+        }
+        Some(device) => RestoreMatch::IdentityChanged {
+            device: device.clone(),
+        },
+        None => RestoreMatch::Disappeared,
+    }
+}
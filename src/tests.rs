@@ -1,7 +1,71 @@
-use crate::cli::{FilterArgs, ProtocolArgs};
+use std::cell::RefCell;
+use std::ffi::CString;
+use std::rc::Rc;
+use std::io::Write;
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::{DateTime, Local, TimeDelta, TimeZone, Timelike, Utc};
+
+use crate::apply::{resolve_targets, DesiredMask, DesiredState};
+use crate::audit::{self, audit_layer, parse_time_bound, AuditQuery, AuditRecord, Change, Origin};
+use crate::auth::{
+    AccessControl, Caller, Denied, Permission, RateLimiter, DEFAULT_RATE_LIMIT, MAX_RATE_BUCKETS,
+};
+use crate::backend::LocatorBackend;
+#[cfg(feature = "grpc")]
+use crate::cli::RemoteTlsArgs;
+use crate::cli::{
+    FilterArgs, LockArgs, ProtocolArgs, ProvisionArgs, SetArgs, SnapshotFileArgs, SnapshotSaveArgs,
+};
+use crate::commands::{
+    format_status, format_status_details, handle_provision, handle_set, handle_snapshot_save,
+    stored_default_mask,
+};
+use crate::config::Config;
+use crate::daemon::{Daemon, DaemonEvent, ExitAction, LocatorState};
+use crate::doctor::{find_rules, rule_covers, Accounts, Credentials, NodeOwner};
 use crate::env_config::{merge_filter, EnvDefaults};
-use crate::hid::{mock::MockDevice, query_status, set_light};
-use crate::util::{format_bytes, format_usage, parse_hex_or_dec_u16, parse_hex_or_dec_u8};
+use crate::events::{listen, LocatorEvent};
+#[cfg(feature = "grpc")]
+use crate::grpc::{
+    proto::locator_event::Kind, remote_endpoint, GrpcClient, GrpcServer, ServeOptions,
+};
+use crate::firmware::{
+    flash_blocks, simulated::SimulatedBootloader, FirmwareImage, FlashProgress, HidBootloader,
+};
+use crate::hid::{
+    mock::{MockBackend, MockDevice, SentLog},
+    pick_single_device, query_default_mask, query_status, retain_id, set_default_mask, set_light,
+    DeviceDescriptor, HidDeviceIo, IdQuery, LocatorStatus,
+};
+use crate::hooks::{run_command, Hook, HookEvent, HookEventKind, HookRunner};
+use crate::info::{format_release, Capabilities, Capability, CapabilityCache};
+use crate::lock::{lock_file_name, private_lock_dir, DeviceLock};
+use crate::logging::{hid_trace_layer, level_for};
+use crate::metrics::Metrics;
+use crate::mqtt::{
+    stand_in::StandInBroker, topic_id, DesiredLeds, Message, MqttBridge, MqttClient, MqttOptions,
+    DEFAULT_MAX_PACKET_SIZE,
+};
+use crate::profile::{
+    error_kind, validate_identity, BootloaderLayout, DeviceProfile, IdentityField, ProfileRegistry,
+};
+use crate::remote::{RemoteBackend, RemoteServer, ServeEvent, MAX_BODY, MAX_READ_LEN};
+use crate::schedule::{Edge, Pattern, Scheduler};
+use crate::service::{socket_unit, udev_rules, usb_ids, ServiceUnit};
+use crate::session::{Recorder, RecordingBackend, ReplayBackend};
+use crate::snapshot::{match_entry, RestoreMatch, Snapshot, SnapshotEntry};
+use crate::timer::{fake::FakeClock, hold_then_restore, wait_for, TimerOutcome};
+use crate::topology::{is_port_chain, port_from_sysfs_path};
+use crate::util::{
+    format_bytes, format_duration, format_usage, parse_duration, parse_hex_or_dec_u16,
+    parse_hex_or_dec_u8, parse_port_chain, MAX_DURATION,
+};
+use crate::websocket::{
+    ControlAction, WsServer, MAX_CONNECTIONS, WS_PROTOCOL, WS_TOKEN_PROTOCOL_PREFIX,
+};
 
 // 数値パーサが16進/10進を正しく受け付けることを確認
 #[test]
@@ -36,6 +100,7 @@ fn merge_filter_prefers_cli_over_env_defaults() {
         product_id: None,
         usage_page: Some(0x01),
         usage: None,
+        port: None,
     };
     let env = EnvDefaults {
         vendor_id: Some(0x9999),
@@ -58,6 +123,7 @@ fn merge_filter_fills_missing_from_env() {
         product_id: None,
         usage_page: None,
         usage: None,
+        port: None,
     };
     let env = EnvDefaults {
         vendor_id: Some(1),
@@ -78,7 +144,7 @@ fn merge_filter_fills_missing_from_env() {
 fn query_status_reads_mask_and_records_command() {
     let device = MockDevice::with_response(vec![0xff, 0x19, 0x00]);
     let protocol = ProtocolArgs {
-        report_len: 4,
+        report_len: Some(4),
         read_timeout_ms: 100,
     };
 
@@ -93,7 +159,7 @@ fn query_status_reads_mask_and_records_command() {
 fn set_light_sends_on_and_off_commands() {
     let device = MockDevice::with_response(vec![0x00]);
     let protocol = ProtocolArgs {
-        report_len: 4,
+        report_len: Some(4),
         read_timeout_ms: 100,
     };
 
@@ -103,3 +169,2325 @@ fn set_light_sends_on_and_off_commands() {
     set_light(&device, &protocol, false, 0x11, 0x22).unwrap();
     assert_eq!(device.last_sent().unwrap(), vec![0x02, 0x22, 0x00, 0x00]);
 }
+
+fn descriptor(serial: Option<&str>, path: &str) -> DeviceDescriptor {
+    DeviceDescriptor {
+        path: CString::new(path).unwrap(),
+        vendor_id: 0x04d8,
+        product_id: 0x1455,
+        serial_number: serial.map(|s| s.to_string()),
+        usage_page: None,
+        usage: None,
+        manufacturer: None,
+        product: None,
+        release_number: 0x0100,
+        port: None,
+    }
+}
+
+// 宣言ファイルが各形式で同じ内容として読めることを確認
+#[test]
+fn desired_state_parses_yaml_toml_and_json() {
+    let yaml = DesiredState::parse("SN-CAP25001: 0x1f\ndesk-left: off\nSN-3: \"0x04\"\n", "yaml").unwrap();
+    let toml = DesiredState::parse("SN-CAP25001 = 0x1f\ndesk-left = \"off\"\nSN-3 = \"0x04\"\n", "toml").unwrap();
+    let json = DesiredState::parse(r#"{"SN-CAP25001": 31, "desk-left": false, "SN-3": "4"}"#, "json").unwrap();
+
+    assert_eq!(yaml.entries.get("SN-CAP25001"), Some(&DesiredMask::Mask(0x1f)));
+    assert_eq!(yaml.entries.get("desk-left"), Some(&DesiredMask::Off));
+    assert_eq!(yaml.entries.get("SN-3"), Some(&DesiredMask::Mask(0x04)));
+    assert_eq!(yaml, toml);
+    assert_eq!(yaml, json);
+}
+
+#[test]
+fn desired_state_rejects_out_of_range_mask() {
+    assert!(DesiredState::parse("SN-1: 300\n", "yaml").is_err());
+    assert!(DesiredState::parse("SN-1: 1\n", "ini").is_err());
+}
+
+// 宣言と接続中locatorの突き合わせで missing/ambiguous/unknown を分けることを確認
+#[test]
+fn resolve_targets_separates_missing_ambiguous_and_unknown() {
+    let devices = vec![
+        descriptor(Some("SN-CAP25001"), "/dev/hidraw0"),
+        descriptor(Some("SN-CAP25002"), "/dev/hidraw1"),
+        descriptor(None, "/dev/hidraw2"),
+    ];
+    let desired = DesiredState::parse(
+        "SN-CAP25001: on\nSN-CAP2500: off\nSN-GONE: 0x01\n",
+        "yaml",
+    )
+    .unwrap();
+
+    let resolution = resolve_targets(&desired, &devices, &Config::default(), 0x1f, 0x00).unwrap();
+    assert_eq!(resolution.targets.len(), 1);
+    assert_eq!(resolution.targets[0].0.locator_id(), "SN-CAP25001");
+    assert_eq!(resolution.targets[0].1, 0x1f);
+    assert_eq!(resolution.missing, vec!["SN-GONE".to_string()]);
+    assert_eq!(resolution.ambiguous.len(), 1);
+    assert_eq!(resolution.ambiguous[0].0, "SN-CAP2500");
+    let unknown: Vec<String> = resolution.unknown.iter().map(|d| d.locator_id()).collect();
+    assert_eq!(unknown, vec!["SN-CAP25002".to_string(), "/dev/hidraw2".to_string()]);
+}
+
+#[test]
+fn resolve_targets_rejects_two_ids_for_same_device() {
+    let devices = vec![descriptor(Some("SN-CAP25001"), "/dev/hidraw0")];
+    let desired = DesiredState::parse("SN-CAP25001: on\nhidraw0: off\n", "yaml").unwrap();
+    assert!(resolve_targets(&desired, &devices, &Config::default(), 0x1f, 0x00).is_err());
+}
+
+// 長いシリアルのlocatorが一緒に挿さっていても完全一致を優先し、グループと別名もキーに書ける
+#[test]
+fn resolve_targets_prefers_exact_ids_and_expands_groups_and_aliases() {
+    let devices = vec![
+        descriptor(Some("SN-CAP2500"), "/dev/hidraw0"),
+        descriptor(Some("SN-CAP25001"), "/dev/hidraw1"),
+        descriptor(Some("SN-CAP25002"), "/dev/hidraw2"),
+        descriptor(Some("SN-CAP25003"), "/dev/hidraw3"),
+    ];
+    let config = Config::parse(
+        "[groups]\ndesk = [\"SN-CAP25001\", \"SN-GONE\"]\n\n[aliases]\n\"SN-CAP25002\" = \"desk-left\"\n",
+    )
+    .unwrap();
+    let desired = DesiredState::parse("SN-CAP2500: on\ndesk: 0x04\ndesk-left: off\n", "yaml").unwrap();
+
+    let resolution = resolve_targets(&desired, &devices, &config, 0x1f, 0x00).unwrap();
+    let targets: Vec<(String, u8)> = resolution
+        .targets
+        .iter()
+        .map(|(device, mask)| (device.locator_id(), *mask))
+        .collect();
+    assert_eq!(
+        targets,
+        vec![
+            ("SN-CAP2500".to_string(), 0x1f),
+            ("SN-CAP25001".to_string(), 0x04),
+            ("SN-CAP25002".to_string(), 0x00),
+        ]
+    );
+    assert!(resolution.ambiguous.is_empty());
+    assert_eq!(resolution.missing, vec!["desk/SN-GONE".to_string()]);
+    let unknown: Vec<String> = resolution.unknown.iter().map(|d| d.locator_id()).collect();
+    assert_eq!(unknown, vec!["SN-CAP25003".to_string()]);
+}
+
+// `--id`・スケジュール・フックも、長いシリアルのlocatorが一緒に挿さっていれば完全一致だけを選ぶ
+#[test]
+fn id_query_prefers_exact_ids_for_commands_schedules_and_hooks() {
+    let short = descriptor(Some("SN-CAP2500"), "/dev/hidraw0");
+    let long = descriptor(Some("SN-CAP25001"), "/dev/hidraw1");
+    let devices = vec![short.clone(), long.clone()];
+    assert!(IdQuery::resolve(&devices, "SN-CAP2500").selects(&short));
+    assert!(!IdQuery::resolve(&devices, "SN-CAP2500").selects(&long));
+    // 完全一致が無ければ部分一致
+    let mut partial = devices.clone();
+    retain_id(&mut partial, "CAP250");
+    assert_eq!(partial.len(), 2);
+
+    let backend = MockBackend::new(devices.clone(), vec![0xff, 0x00]);
+    assert_eq!(pick_single_device(&backend, &no_filter(), Some("SN-CAP2500")).unwrap(), short);
+
+    let config = Config::parse(
+        "[[rules]]\ntarget = \"SN-CAP2500\"\nwindow = \"09:00-18:00\"\nmask = 0x1f\n\n\
+         [[hooks]]\ntarget = \"SN-CAP2500\"\ncommand = \"true\"\n",
+    )
+    .unwrap();
+    let scheduler = Scheduler::from_config(&config).unwrap();
+    assert_eq!(scheduler.desired_mask(&short, &devices, &at(19, 10, 0)), Some(0x1f));
+    assert_eq!(scheduler.desired_mask(&long, &devices, &at(19, 10, 0)), None);
+    assert_eq!(scheduler.desired_mask(&long, std::slice::from_ref(&long), &at(19, 10, 0)), Some(0x1f));
+
+    let hook = Hook::from_config(&config.hooks[0], &config).unwrap();
+    let attached = |id: &str| HookEvent::from_daemon(&DaemonEvent::Attached { id: id.to_string() });
+    assert!(hook.matches(&attached("SN-CAP2500"), &devices));
+    assert!(!hook.matches(&attached("SN-CAP25001"), &devices));
+}
+
+// スナップショットの対応付け: シリアル優先、シリアル無しはパス、それ以外は報告対象
+#[test]
+fn match_entry_prefers_serial_and_reports_moved_path() {
+    let saved = SnapshotEntry::new(&descriptor(Some("SN-CAP25001"), "/dev/hidraw0"), 0x1f);
+    let devices = vec![
+        descriptor(None, "/dev/hidraw0"),
+        descriptor(Some("SN-CAP25001"), "/dev/hidraw3"),
+    ];
+
+    assert_eq!(
+        match_entry(&saved, &devices),
+        RestoreMatch::BySerial {
+            device: devices[1].clone(),
+            path_changed: true,
+        }
+    );
+}
+
+#[test]
+fn match_entry_falls_back_to_path_and_detects_identity_change() {
+    let serial_less = SnapshotEntry::new(&descriptor(None, "/dev/hidraw2"), 0x04);
+    let devices = vec![descriptor(None, "/dev/hidraw2")];
+    assert_eq!(
+        match_entry(&serial_less, &devices),
+        RestoreMatch::ByPath {
+            device: devices[0].clone(),
+        }
+    );
+
+    let replaced = vec![descriptor(Some("SN-OTHER"), "/dev/hidraw2")];
+    assert_eq!(
+        match_entry(&serial_less, &replaced),
+        RestoreMatch::IdentityChanged {
+            device: replaced[0].clone(),
+        }
+    );
+
+    let gone = SnapshotEntry::new(&descriptor(Some("SN-GONE"), "/dev/hidraw9"), 0x01);
+    assert_eq!(match_entry(&gone, &devices), RestoreMatch::Disappeared);
+}
+
+// ---- USBポートの経路 ----
+
+fn on_port(serial: Option<&str>, path: &str, port: &str) -> DeviceDescriptor {
+    DeviceDescriptor {
+        port: Some(port.to_string()),
+        ..descriptor(serial, path)
+    }
+}
+
+#[test]
+fn port_chain_is_read_from_sysfs_path() {
+    let sysfs = std::path::Path::new(
+        "/sys/devices/pci0000:00/0000:00:14.0/usb1/1-2/1-2.3/1-2.3:1.0/0003:04D8:1455.0005",
+    );
+    assert_eq!(port_from_sysfs_path(sysfs).as_deref(), Some("1-2.3"));
+    assert_eq!(port_from_sysfs_path(std::path::Path::new("/sys/devices/virtual/misc/uhid")), None);
+    assert!(is_port_chain("3-1.4.2"));
+    assert!(!is_port_chain("1-2.3:1.0"));
+    assert!(!is_port_chain("usb1"));
+    assert_eq!(parse_port_chain("port:1-2").unwrap(), "1-2");
+    assert!(parse_port_chain("1-").is_err());
+}
+
+#[test]
+fn port_gives_serial_less_locators_a_stable_id() {
+    let device = on_port(None, "/dev/hidraw4", "1-2.3");
+    assert_eq!(device.locator_id(), "port:1-2.3");
+    assert!(device.has_stable_id());
+    assert!(device.matches_id("1-2.3"));
+    assert!(device.matches_id("port:1-2.3"));
+    assert!(!device.matches_id("1-2.4"));
+    assert!(!descriptor(None, "/dev/hidraw4").has_stable_id());
+    // シリアル番号があればそちらを優先
+    assert_eq!(on_port(Some("SN1"), "/dev/hidraw4", "1-2.3").locator_id(), "SN1");
+
+    // 挿し直しでhidrawの番号が変わっても、同じポートなら復元できる
+    let saved = SnapshotEntry::new(&device, 0x02);
+    let replugged = vec![on_port(None, "/dev/hidraw2", "1-2.4"), on_port(None, "/dev/hidraw9", "1-2.3")];
+    assert_eq!(
+        match_entry(&saved, &replugged),
+        RestoreMatch::ByPort {
+            device: replugged[1].clone(),
+            path_changed: true,
+        }
+    );
+
+    // グループ(エイリアス)からポートで指定できる
+    let config = Config::parse("[groups]\ndesk = [\"port:1-2.3\"]\n").unwrap();
+    assert!(config.expand_target("desk").iter().any(|id| device.matches_id(id)));
+}
+
+#[test]
+fn snapshot_round_trips_through_json() {
+    let snapshot = Snapshot::new(vec![SnapshotEntry::new(
+        &descriptor(Some("SN-CAP25001"), "/dev/hidraw0"),
+        0x19,
+    )]);
+    let json = serde_json::to_string(&snapshot).unwrap();
+    let loaded: Snapshot = serde_json::from_str(&json).unwrap();
+    assert_eq!(loaded.saved_at, snapshot.saved_at);
+    assert_eq!(loaded.devices, snapshot.devices);
+}
+
+// 期間指定のパースと表示
+#[test]
+fn parse_duration_accepts_units_and_combinations() {
+    assert_eq!(parse_duration("10m").unwrap(), Duration::from_secs(600));
+    assert_eq!(parse_duration("1h30m").unwrap(), Duration::from_secs(5400));
+    assert_eq!(parse_duration("500ms").unwrap(), Duration::from_millis(500));
+    assert_eq!(parse_duration("45").unwrap(), Duration::from_secs(45));
+    assert!(parse_duration("10x").is_err());
+    assert!(parse_duration("m").is_err());
+    // 桁あふれはパニックせずエラーにする
+    assert!(parse_duration("18446744073709551615d").is_err());
+    assert!(parse_duration("18446744073709551615s1s").is_err());
+    assert!(parse_duration("36501d").is_err() && parse_duration("99999999999").is_err());
+    assert_eq!(parse_duration("36500d").unwrap(), MAX_DURATION);
+    assert_eq!(format_duration(Duration::from_secs(5400)), "1h30m");
+}
+
+// タイマーは注入したクロックで時間が進み、期限か中断で抜けることを確認
+#[test]
+fn wait_for_expires_after_duration() {
+    let clock = FakeClock::new();
+    let cancel = AtomicBool::new(false);
+    let outcome = wait_for(&clock, Duration::from_secs(600), &cancel);
+    assert_eq!(outcome, TimerOutcome::Expired);
+    assert_eq!(clock.elapsed(), Duration::from_secs(600));
+}
+
+#[test]
+fn hold_then_restore_restores_previous_mask_when_cancelled() {
+    let cancel = AtomicBool::new(false);
+    let clock = FakeClock::cancelling_after(3, &cancel);
+    let device = MockDevice::with_response(vec![0xff, 0x04]);
+    let sent = Rc::clone(&device.sent);
+    let protocol = ProtocolArgs {
+        report_len: Some(4),
+        read_timeout_ms: 100,
+    };
+
+    let (outcome, status) = hold_then_restore(
+        move || Ok(device),
+        &DeviceProfile::cap_locator(),
+        &descriptor(Some("SN-CAP25001"), "/dev/hidraw0"),
+        &protocol,
+        0x04,
+        Duration::from_secs(600),
+        &clock,
+        &cancel,
+    )
+    .unwrap();
+    assert_eq!(outcome, TimerOutcome::Cancelled);
+    assert!(clock.elapsed() < Duration::from_secs(1));
+    assert_eq!(status.mask, 0x04);
+    let sent = sent.borrow();
+    assert_eq!(sent[0], vec![0x02, 0x04, 0x00, 0x00]);
+    assert_eq!(sent[1], vec![0x01, 0x00, 0x00, 0x00]);
+}
+
+// ---- スケジューラ/常駐処理 (固定時刻 + モックデバイスで駆動) ----
+
+const SCHEDULE_CONFIG: &str = r#"
+[groups]
+bench-a = ["SN-CAP25001", "SN-CAP25002"]
+
+[[rules]]
+name = "booking"
+target = "bench-a"
+window = "09:00-18:00"
+days = ["mon", "tue", "wed", "thu", "fri"]
+mask = 0x1f
+priority = 10
+
+[[rules]]
+name = "maintenance"
+target = "SN-CAP25001"
+cron = "0 0 12 * * *"
+duration = "30m"
+mask = 0x04
+priority = 20
+
+[[rules]]
+name = "night"
+target = "SN-CAP25003"
+window = "22:00-06:00"
+pattern = "blink"
+interval = "1s"
+mask = 0x01
+"#;
+
+fn scheduler() -> Scheduler {
+    Scheduler::from_config(&Config::parse(SCHEDULE_CONFIG).unwrap()).unwrap()
+}
+
+// 2026-10-19 は月曜日
+fn at(day: u32, hour: u32, min: u32) -> DateTime<Utc> {
+    Utc.with_ymd_and_hms(2026, 10, day, hour, min, 0).unwrap()
+}
+
+#[test]
+fn scheduler_applies_window_and_priority() {
+    let scheduler = scheduler();
+    let first = descriptor(Some("SN-CAP25001"), "/dev/hidraw0");
+    let second = descriptor(Some("SN-CAP25002"), "/dev/hidraw1");
+    let other = descriptor(Some("SN-OTHER"), "/dev/hidraw9");
+    let present = vec![first.clone(), second.clone(), other.clone()];
+
+    assert_eq!(scheduler.desired_mask(&first, &present, &at(19, 8, 59)), Some(0));
+    assert_eq!(scheduler.desired_mask(&first, &present, &at(19, 9, 0)), Some(0x1f));
+    // 12:00〜12:30はpriorityの高いメンテナンスルールが勝つ
+    assert_eq!(scheduler.desired_mask(&first, &present, &at(19, 12, 10)), Some(0x04));
+    assert_eq!(scheduler.desired_mask(&second, &present, &at(19, 12, 10)), Some(0x1f));
+    assert_eq!(scheduler.desired_mask(&first, &present, &at(19, 12, 30)), Some(0x1f));
+    // 土曜日は予約時間外
+    assert_eq!(scheduler.desired_mask(&second, &present, &at(24, 10, 0)), Some(0));
+    assert_eq!(scheduler.desired_mask(&other, &present, &at(19, 10, 0)), None);
+}
+
+#[test]
+fn scheduler_handles_overnight_window_and_blink() {
+    let scheduler = scheduler();
+    let night = descriptor(Some("SN-CAP25003"), "/dev/hidraw2");
+    let present = vec![night.clone()];
+    let t = at(19, 23, 0);
+    assert_eq!(scheduler.desired_mask(&night, &present, &t), Some(0x01));
+    assert_eq!(
+        scheduler.desired_mask(&night, &present, &(t + TimeDelta::seconds(1))),
+        Some(0x00)
+    );
+    assert_eq!(scheduler.desired_mask(&night, &present, &at(20, 5, 0)), Some(0x01));
+    assert_eq!(scheduler.desired_mask(&night, &present, &at(20, 6, 0)), Some(0x00));
+}
+
+#[test]
+fn scheduler_lists_next_transitions_in_order() {
+    let transitions = scheduler().next_transitions(&at(19, 8, 0), 4);
+    let summary: Vec<(u32, &str, Edge)> = transitions
+        .iter()
+        .map(|t| (t.at.hour(), t.rule.as_str(), t.edge))
+        .collect();
+    assert_eq!(
+        summary,
+        vec![
+            (9, "booking", Edge::Start),
+            (12, "maintenance", Edge::Start),
+            (12, "maintenance", Edge::End),
+            (18, "booking", Edge::End),
+        ]
+    );
+}
+
+#[test]
+fn scheduler_rejects_invalid_rules() {
+    let missing_trigger = "[[rules]]\ntarget = \"x\"\nmask = 1\n";
+    assert!(Scheduler::from_config(&Config::parse(missing_trigger).unwrap()).is_err());
+    let cron_without_duration = "[[rules]]\ntarget = \"x\"\ncron = \"0 0 9 * * *\"\nmask = 1\n";
+    assert!(Scheduler::from_config(&Config::parse(cron_without_duration).unwrap()).is_err());
+    let overflowing = "[[rules]]\ntarget = \"x\"\ncron = \"0 0 9 * * *\"\nduration = \"999999999999999d\"\nmask = 1\n";
+    assert!(Scheduler::from_config(&Config::parse(overflowing).unwrap()).is_err());
+
+    // 最長の期間でも日時の計算であふれない
+    let longest = "[[rules]]\ntarget = \"SN-CAP25001\"\ncron = \"0 0 9 * * *\"\nduration = \"36500d\"\nmask = 1\n";
+    let scheduler = Scheduler::from_config(&Config::parse(longest).unwrap()).unwrap();
+    let device = descriptor(Some("SN-CAP25001"), "/dev/hidraw0");
+    let present = vec![device.clone()];
+    assert_eq!(scheduler.desired_mask(&device, &present, &at(19, 10, 0)), Some(1));
+    assert!(!scheduler.next_transitions(&at(19, 10, 0), 4).is_empty());
+}
+
+#[test]
+fn daemon_sends_only_changes_and_reapplies_after_hotplug() {
+    let mut daemon = Daemon::new(scheduler(), ProfileRegistry::builtin());
+    let protocol = ProtocolArgs {
+        report_len: Some(2),
+        read_timeout_ms: 100,
+    };
+    let device = descriptor(Some("SN-CAP25001"), "/dev/hidraw0");
+    let sent = Rc::new(RefCell::new(Vec::new()));
+    let mut open = |_: &DeviceDescriptor| -> anyhow::Result<Box<dyn HidDeviceIo>> {
+        let mock = MockDevice::with_responses(Vec::new());
+        let log = Rc::clone(&mock.sent);
+        sent.borrow_mut().push(log);
+        Ok(Box::new(mock))
+    };
+
+    let events = daemon.tick(&at(19, 9, 0), std::slice::from_ref(&device), &protocol, &mut open);
+    assert_eq!(
+        events,
+        vec![
+            DaemonEvent::Attached {
+                id: "SN-CAP25001".to_string()
+            },
+            DaemonEvent::Applied {
+                id: "SN-CAP25001".to_string(),
+                mask: 0x1f
+            },
+        ]
+    );
+
+    // 目標が変わらなければ送らない
+    let events = daemon.tick(&at(19, 10, 0), std::slice::from_ref(&device), &protocol, &mut open);
+    assert!(events.is_empty());
+
+    // 抜かれて挿し直されたら同じマスクでも送り直す
+    let events = daemon.tick(&at(19, 10, 1), &[], &protocol, &mut open);
+    assert_eq!(
+        events,
+        vec![DaemonEvent::Detached {
+            id: "SN-CAP25001".to_string()
+        }]
+    );
+    let events = daemon.tick(&at(19, 10, 2), std::slice::from_ref(&device), &protocol, &mut open);
+    assert_eq!(events.len(), 2);
+    assert_eq!(daemon.applied_mask("SN-CAP25001"), Some(0x1f));
+
+    let writes: Vec<Vec<u8>> = sent
+        .borrow()
+        .iter()
+        .flat_map(|log| log.borrow().clone())
+        .collect();
+    assert_eq!(writes, vec![vec![0x02, 0x1f], vec![0x02, 0x1f]]);
+}
+
+// WebSocketなどで指定した状態は、releaseするまでスケジュールより優先される
+#[test]
+fn daemon_override_wins_over_schedule_until_released() {
+    let mut daemon = Daemon::new(scheduler(), ProfileRegistry::builtin());
+    let protocol = ProtocolArgs {
+        report_len: Some(2),
+        read_timeout_ms: 100,
+    };
+    let device = descriptor(Some("SN-CAP25001"), "/dev/hidraw0");
+    let mut open = |_: &DeviceDescriptor| -> anyhow::Result<Box<dyn HidDeviceIo>> {
+        Ok(Box::new(MockDevice::with_responses(Vec::new())))
+    };
+    let applied = |mask| DaemonEvent::Applied {
+        id: "SN-CAP25001".to_string(),
+        mask,
+    };
+
+    daemon.tick(&at(19, 9, 0), std::slice::from_ref(&device), &protocol, &mut open);
+    let leds = DesiredLeds {
+        mask: 0x04,
+        pattern: Pattern::Steady,
+    };
+    daemon.override_leds("SN-CAP25001", leds, "desk-bot", "10.0.0.5:41000");
+    let events = daemon.tick(&at(19, 9, 1), std::slice::from_ref(&device), &protocol, &mut open);
+    assert_eq!(events, vec![applied(0x04)]);
+    let states = daemon.states();
+    assert_eq!(states.len(), 1);
+    assert_eq!(states[0].mask, Some(0x04));
+    assert_eq!(states[0].controlled_by.as_deref(), Some("desk-bot"));
+    assert!(!states[0].blinking);
+
+    // スケジュールの対象時間内でも上書きしたまま
+    let events = daemon.tick(&at(19, 10, 0), std::slice::from_ref(&device), &protocol, &mut open);
+    assert!(events.is_empty());
+
+    assert!(daemon.release("SN-CAP25001"));
+    let events = daemon.tick(&at(19, 10, 1), std::slice::from_ref(&device), &protocol, &mut open);
+    assert_eq!(events, vec![applied(0x1f)]);
+    assert_eq!(daemon.states()[0].controlled_by, None);
+}
+
+// --on-exit restore なら最初に送る前のマスクを読んでおき、停止時に戻す
+#[test]
+fn daemon_shutdown_restores_initial_mask() {
+    let mut daemon = Daemon::new(scheduler(), ProfileRegistry::builtin()).on_exit(ExitAction::Restore);
+    let protocol = ProtocolArgs {
+        report_len: Some(2),
+        read_timeout_ms: 100,
+    };
+    let device = descriptor(Some("SN-CAP25001"), "/dev/hidraw0");
+    let sent: SentLog = Rc::new(RefCell::new(Vec::new()));
+    let mut open = |_: &DeviceDescriptor| -> anyhow::Result<Box<dyn HidDeviceIo>> {
+        let mut mock = MockDevice::with_response(vec![0xff, 0x02]);
+        mock.sent = Rc::clone(&sent);
+        Ok(Box::new(mock))
+    };
+    let applied = |mask| DaemonEvent::Applied {
+        id: "SN-CAP25001".to_string(),
+        mask,
+    };
+
+    let events = daemon.tick(&at(19, 9, 0), std::slice::from_ref(&device), &protocol, &mut open);
+    assert_eq!(events[1..], [applied(0x1f)]);
+    assert_eq!(*sent.borrow(), vec![vec![0x01, 0x00], vec![0x02, 0x1f]]);
+
+    let events = daemon.shutdown(&protocol, &mut open);
+    assert_eq!(events, vec![applied(0x02)]);
+    assert_eq!(sent.borrow().last().unwrap(), &vec![0x02, 0x02]);
+    // 戻した後はもう送らない
+    assert!(daemon.shutdown(&protocol, &mut open).is_empty());
+}
+
+// --on-exit off は接続中のlocatorを消灯し、keep は何も送らない
+#[test]
+fn daemon_shutdown_turns_off_or_keeps_leds() {
+    let protocol = ProtocolArgs {
+        report_len: Some(2),
+        read_timeout_ms: 100,
+    };
+    let device = descriptor(Some("SN-CAP25001"), "/dev/hidraw0");
+    let mut open = |_: &DeviceDescriptor| -> anyhow::Result<Box<dyn HidDeviceIo>> {
+        Ok(Box::new(MockDevice::with_responses(Vec::new())))
+    };
+
+    let mut daemon = Daemon::new(scheduler(), ProfileRegistry::builtin()).on_exit(ExitAction::Off);
+    daemon.tick(&at(19, 9, 0), std::slice::from_ref(&device), &protocol, &mut open);
+    let events = daemon.shutdown(&protocol, &mut open);
+    assert_eq!(events, vec![DaemonEvent::Applied {
+        id: "SN-CAP25001".to_string(),
+        mask: 0,
+    }]);
+
+    let mut daemon = Daemon::new(scheduler(), ProfileRegistry::builtin());
+    daemon.tick(&at(19, 9, 0), std::slice::from_ref(&device), &protocol, &mut open);
+    assert!(daemon.shutdown(&protocol, &mut open).is_empty());
+    assert_eq!(daemon.applied_mask("SN-CAP25001"), Some(0x1f));
+}
+
+// install-service が書き出すユニットとudevルール
+#[test]
+fn service_unit_and_udev_rules_render() {
+    let unit = ServiceUnit {
+        name: "cap-locator".to_string(),
+        exec_start: vec![
+            "/usr/local/bin/cap-locator-cli".to_string(),
+            "daemon".to_string(),
+            "--config".to_string(),
+            "/etc/cap locator/cap-locator.toml".to_string(),
+        ],
+        user: Some("locator".to_string()),
+        group: "plugdev".to_string(),
+        socket: true,
+    }
+    .render();
+    assert!(unit.contains("Type=notify\n"), "{}", unit);
+    assert!(unit.contains("WatchdogSec=30s\n"), "{}", unit);
+    assert!(unit.contains("Requires=cap-locator.socket\n"), "{}", unit);
+    assert!(
+        unit.contains("ExecStart=/usr/local/bin/cap-locator-cli daemon --config \"/etc/cap locator/cap-locator.toml\"\n"),
+        "{}",
+        unit
+    );
+    assert!(unit.contains("User=locator\nSupplementaryGroups=plugdev\n"), "{}", unit);
+    assert!(socket_unit("cap-locator", "0.0.0.0:9465").contains("ListenStream=0.0.0.0:9465\n"));
+
+    let rules = udev_rules(&[(0x04d8, 0x1455)], "plugdev");
+    assert!(rules.contains(
+        "KERNEL==\"hidraw*\", ATTRS{idVendor}==\"04d8\", ATTRS{idProduct}==\"1455\", MODE=\"0660\", GROUP=\"plugdev\""
+    ));
+
+    // VID/PIDが両方決まっていればそれだけ、vendor idだけならそのベンダーのプロファイルとブートローダー
+    let profiles = ProfileRegistry::builtin();
+    let filter = FilterArgs {
+        vendor_id: Some(0x1234),
+        product_id: Some(0x5678),
+        ..FilterArgs::default()
+    };
+    assert_eq!(usb_ids(&profiles, &filter), vec![(0x1234, 0x5678)]);
+    assert!(!usb_ids(&profiles, &FilterArgs::default()).is_empty());
+}
+
+// doctor: 生成したルールや配布元の書き方のルールを見つけ、コメントや他のVID/PIDは無視する
+#[test]
+fn doctor_finds_udev_rules_for_vid_pid() {
+    assert!(rule_covers(
+        r#"SUBSYSTEM=="hidraw", ATTRS{idVendor}=="04D8", ATTRS{idProduct}=="1455", MODE="0666""#,
+        0x04d8,
+        0x1455
+    ));
+    assert!(rule_covers(r#"KERNEL=="hidraw*", KERNELS=="*:04D8:1455.*", TAG+="uaccess""#, 0x04d8, 0x1455));
+    assert!(!rule_covers(r#"# ATTRS{idVendor}=="04d8", ATTRS{idProduct}=="1455""#, 0x04d8, 0x1455));
+    assert!(!rule_covers(r#"ATTRS{idVendor}=="04d8", ATTRS{idProduct}=="000b""#, 0x04d8, 0x1455));
+
+    let dir = std::env::temp_dir().join(format!("cap-locator-udev-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("70-cap-locator.rules"), udev_rules(&[(0x04d8, 0x1455)], "plugdev")).unwrap();
+    std::fs::write(dir.join("99-other.rules"), udev_rules(&[(0x1234, 0x5678)], "plugdev")).unwrap();
+    let dirs = vec![dir.clone(), dir.join("missing")];
+    assert_eq!(find_rules(&dirs, 0x04d8, 0x1455), vec![dir.join("70-cap-locator.rules")]);
+    assert!(find_rules(&dirs, 0x04d8, 0x000b).is_empty());
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+// doctor: グループのメンバーと、このプロセスに反映されているグループを区別する
+#[test]
+fn doctor_reads_accounts_and_credentials() {
+    let accounts = Accounts::parse(
+        "root:x:0:0:root:/root:/bin/bash\nalice:x:1000:1000::/home/alice:/bin/bash\n",
+        "root:x:0:\nplugdev:x:46:alice,bob\nalice:x:1000:\n",
+    );
+    assert_eq!(accounts.user_name(1000), "alice");
+    assert_eq!(accounts.user_name(1001), "1001");
+    assert_eq!(accounts.group_name(46), "plugdev");
+    assert_eq!(accounts.group_id("plugdev"), Some(46));
+    assert_eq!(accounts.group_id("dialout"), None);
+    assert!(accounts.is_member("alice", "plugdev"));
+    assert!(!accounts.is_member("alice", "root"));
+
+    let credentials = Credentials::parse(
+        "Name:\tcap-locator-cli\nUid:\t1000\t1000\t1000\t1000\nGid:\t1000\t1000\t1000\t1000\nGroups:\t4 24 27\n",
+    )
+    .unwrap();
+    assert_eq!(credentials.uid, 1000);
+    assert_eq!(credentials.gids, vec![4, 24, 27, 1000]);
+
+    let owner = NodeOwner {
+        mode: 0o660,
+        uid: 0,
+        gid: 46,
+    };
+    assert!(owner.group_can_use());
+    assert!(!NodeOwner { mode: 0o600, ..owner }.group_can_use());
+}
+
+// 同じlocatorのロックは同時に1つしか取れず、エラーに保持者のPIDが出ることを確認
+#[test]
+fn device_lock_is_exclusive_and_names_holder_pid() {
+    let dir = std::env::temp_dir().join(format!("cap-locator-test-{}", std::process::id()));
+    let device = descriptor(Some("SN-CAP25001"), "/dev/hidraw0");
+    let other = descriptor(None, "/dev/hidraw1");
+
+    let held = DeviceLock::acquire(&dir, &device, Duration::ZERO).unwrap();
+    let err = DeviceLock::acquire(&dir, &device, Duration::from_millis(120))
+        .err()
+        .unwrap();
+    let message = err.to_string();
+    assert!(message.contains("SN-CAP25001"), "{}", message);
+    assert!(message.contains(&format!("PID {}", std::process::id())), "{}", message);
+
+    // 別のlocatorは独立してロックできる
+    assert!(DeviceLock::acquire(&dir, &other, Duration::ZERO).is_ok());
+
+    drop(held);
+    assert!(DeviceLock::acquire(&dir, &device, Duration::ZERO).is_ok());
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+// 一時ディレクトリのロック用ディレクトリはuidごとに0700で作り、他人のものやリンクは使わない
+#[cfg(unix)]
+#[test]
+fn private_lock_dir_rejects_foreign_or_open_directories() {
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+    let base = std::env::temp_dir().join(format!("cap-locator-lockdir-{}", std::process::id()));
+    std::fs::create_dir_all(&base).unwrap();
+    let uid = std::fs::metadata(&base).unwrap().uid();
+
+    let dir = private_lock_dir(&base, uid).unwrap();
+    assert_eq!(dir, base.join(format!("cap-locator-{}", uid)));
+    assert_eq!(std::fs::metadata(&dir).unwrap().permissions().mode() & 0o777, 0o700);
+    // 作り直さずにそのまま使える
+    assert_eq!(private_lock_dir(&base, uid).unwrap(), dir);
+
+    std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o777)).unwrap();
+    let err = private_lock_dir(&base, uid).unwrap_err();
+    assert!(err.to_string().contains("他のユーザーが書き換えられます"), "{}", err);
+
+    // 他のユーザーに先に作られていた
+    let other = uid.wrapping_add(1);
+    std::fs::create_dir(base.join(format!("cap-locator-{}", other))).unwrap();
+    let err = private_lock_dir(&base, other).unwrap_err();
+    assert!(err.to_string().contains("所有者が違います"), "{}", err);
+
+    let target = base.join("elsewhere");
+    std::fs::create_dir(&target).unwrap();
+    std::os::unix::fs::symlink(&target, base.join(format!("cap-locator-{}", uid.wrapping_add(2)))).unwrap();
+    assert!(private_lock_dir(&base, uid.wrapping_add(2)).is_err());
+    let _ = std::fs::remove_dir_all(&base);
+}
+
+#[test]
+fn lock_file_name_is_filesystem_safe() {
+    assert_eq!(lock_file_name(&descriptor(Some("SN-CAP25001"), "/dev/hidraw0")), "SN-CAP25001.lock");
+    assert_eq!(lock_file_name(&descriptor(None, "/dev/hidraw0")), "_dev_hidraw0.lock");
+}
+
+// -v の回数とログレベルの対応
+#[test]
+fn level_for_maps_verbosity() {
+    use tracing::level_filters::LevelFilter;
+    assert_eq!(level_for(0), LevelFilter::WARN);
+    assert_eq!(level_for(1), LevelFilter::DEBUG);
+    assert_eq!(level_for(5), LevelFilter::TRACE);
+}
+
+#[derive(Clone, Default)]
+struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> tracing_subscriber::fmt::MakeWriter<'a> for SharedBuffer {
+    type Writer = SharedBuffer;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self.clone()
+    }
+}
+
+// トレースファイル用レイヤーが送受信のバイト列だけをJSON Linesで書き出すことを確認
+#[test]
+fn hid_trace_layer_records_every_transaction() {
+    use tracing_subscriber::layer::SubscriberExt;
+
+    let buffer = SharedBuffer::default();
+    let subscriber = tracing_subscriber::registry().with(hid_trace_layer(buffer.clone()));
+    let device = MockDevice::with_response(vec![0xff, 0x19]);
+    let protocol = ProtocolArgs {
+        report_len: Some(2),
+        read_timeout_ms: 100,
+    };
+
+    tracing::subscriber::with_default(subscriber, || {
+        set_light(&device, &protocol, true, 0x19, 0).unwrap();
+        query_status(&device, &protocol).unwrap();
+    });
+
+    let text = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+    let lines: Vec<serde_json::Value> = text
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    let summary: Vec<(String, String, String)> = lines
+        .iter()
+        .map(|line| {
+            let fields = &line["fields"];
+            (
+                fields["command"].as_str().unwrap().to_string(),
+                fields["direction"].as_str().unwrap().to_string(),
+                fields["bytes"].as_str().unwrap().to_string(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        summary,
+        vec![
+            ("set".to_string(), "out".to_string(), "02 19".to_string()),
+            ("status".to_string(), "out".to_string(), "01 00".to_string()),
+            ("status".to_string(), "in".to_string(), "ff 19".to_string()),
+        ]
+    );
+}
+
+// 監査ログには変更前(呼び出し側が知っていれば)と変更後のマスク、経路、結果が1行ずつ残る
+#[test]
+fn audit_layer_records_previous_and_new_mask() {
+    use tracing_subscriber::layer::SubscriberExt;
+
+    let buffer = SharedBuffer::default();
+    let subscriber = tracing_subscriber::registry().with(audit_layer(buffer.clone()));
+    let device = MockDevice::with_response(vec![0xff, 0x04]);
+    let target = descriptor(Some("SN-AUDIT01"), "/dev/hidraw0");
+    let profile = DeviceProfile::cap_locator();
+    let protocol = ProtocolArgs {
+        report_len: Some(2),
+        read_timeout_ms: 100,
+    };
+
+    tracing::subscriber::with_default(subscriber, || {
+        audit::set_mask(&profile, &device, &protocol, &target, 0x1f, &Change::cli("on")).unwrap();
+        let change = Change::api("SetLeds", Some("desk-bot"), Some("10.0.0.5:4100")).previous(Some(0x1f));
+        audit::set_mask(&profile, &device, &protocol, &target, 0x00, &change).unwrap();
+    });
+
+    let text = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+    let records: Vec<AuditRecord> = text.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[0].serial.as_deref(), Some("SN-AUDIT01"));
+    // 変更前が分からなければ問い合わせずに空のまま残す
+    assert_eq!((records[0].previous, records[0].mask), (None, Some(0x1f)));
+    assert_eq!((records[0].origin, records[0].command.as_str()), (Origin::Cli, "on"));
+    assert_eq!(records[0].result, "ok");
+    assert_eq!((records[1].previous, records[1].mask), (Some(0x1f), Some(0x00)));
+    assert_eq!(records[1].origin, Origin::Api);
+    assert_eq!((records[1].user.as_str(), records[1].host.as_str()), ("desk-bot", "10.0.0.5:4100"));
+    // 監査のためにステータスを問い合わせることはない
+    assert_eq!(device.sent.borrow().len(), 2);
+}
+
+// `audit show` の絞り込み: id/シリアル、--since 以降、--until より前
+#[test]
+fn audit_query_filters_by_device_and_time() {
+    let now = Local.with_ymd_and_hms(2026, 3, 10, 12, 0, 0).unwrap();
+    let record = |id: &str, at: &str| AuditRecord {
+        timestamp: DateTime::parse_from_rfc3339(at).unwrap(),
+        user: "alice".to_string(),
+        host: "lab1".to_string(),
+        id: id.to_string(),
+        serial: Some(id.to_string()),
+        previous: None,
+        mask: Some(0x1f),
+        detail: None,
+        origin: Origin::Cli,
+        command: "on".to_string(),
+        result: "ok".to_string(),
+        error: None,
+    };
+    let morning = record("SN-A", &now.with_hour(9).unwrap().to_rfc3339());
+    let noon = record("SN-B", &now.to_rfc3339());
+
+    let query = AuditQuery {
+        id: Some("SN-A".to_string()),
+        ..AuditQuery::default()
+    };
+    assert!(query.matches(&morning) && !query.matches(&noon));
+
+    let query = AuditQuery {
+        since: Some(parse_time_bound("2h", &now).unwrap()),
+        until: Some(parse_time_bound(&now.to_rfc3339(), &now).unwrap()),
+        ..AuditQuery::default()
+    };
+    // 10:00以降12:00より前
+    assert!(!query.matches(&morning) && !query.matches(&noon));
+
+    let query = AuditQuery {
+        since: Some(parse_time_bound("2026-03-10", &now).unwrap()),
+        ..AuditQuery::default()
+    };
+    assert!(query.matches(&morning) && query.matches(&noon));
+    assert!(parse_time_bound("yesterday", &now).is_err());
+}
+
+// ---- WebSocket ----
+
+fn read_json(socket: &mut tungstenite::WebSocket<std::net::TcpStream>) -> serde_json::Value {
+    loop {
+        if let tungstenite::Message::Text(text) = socket.read().unwrap() {
+            return serde_json::from_str(text.as_str()).unwrap();
+        }
+    }
+}
+
+// トークンが無ければ断り、接続すると状態とイベントが届き、制御メッセージはdaemonのループへ渡る
+#[test]
+fn websocket_streams_events_and_forwards_controls() {
+    let access = AccessControl::new(DEFAULT_RATE_LIMIT).with_token("secret").unwrap();
+    let server = WsServer::bind("127.0.0.1:0", Some(access), 0x1f).unwrap();
+    let addr = server.local_addr();
+    // ブラウザと同じく、トークンはサブプロトコルで送る
+    let connect = |url: String, protocols: Option<&str>| {
+        let stream = std::net::TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut request = tungstenite::client::IntoClientRequest::into_client_request(url).unwrap();
+        if let Some(protocols) = protocols {
+            request.headers_mut().insert("Sec-WebSocket-Protocol", protocols.parse().unwrap());
+        }
+        tungstenite::client(request, stream).ok()
+    };
+    let state = LocatorState {
+        id: "SN-CAP25001".to_string(),
+        alias: Some("3F 会議室".to_string()),
+        mask: Some(0x1f),
+        leds: vec!["red".to_string()],
+        blinking: false,
+        controlled_by: None,
+    };
+    server.publish_state("2026-10-19T09:00:00+09:00", vec![state]);
+
+    let url = format!("ws://{}/ws", addr);
+    let with_token = |token: &str| format!("{}, {}{}", WS_PROTOCOL, WS_TOKEN_PROTOCOL_PREFIX, token);
+    assert!(connect(url.clone(), None).is_none());
+    assert!(connect(url.clone(), Some(&with_token("wrong"))).is_none());
+    // URLのトークンは受け付けない
+    assert!(connect(format!("{}?token=secret", url), None).is_none());
+
+    let (mut socket, response) = connect(url, Some(&with_token("secret"))).unwrap();
+    // 選ぶのはトークンを含まない方
+    assert_eq!(response.headers()["Sec-WebSocket-Protocol"], WS_PROTOCOL);
+    let message = read_json(&mut socket);
+    assert_eq!((message["type"].as_str(), message["control"].as_bool()), (Some("session"), Some(true)));
+    assert_eq!(message["auth"], true);
+    let message = read_json(&mut socket);
+    assert_eq!(message["type"], "state");
+    assert_eq!(message["locators"][0]["id"], "SN-CAP25001");
+    assert_eq!(message["locators"][0]["mask"], 0x1f);
+
+    let event = DaemonEvent::Attached {
+        id: "SN-CAP25002".to_string(),
+    };
+    // 接続スレッドが購読を登録するまで少し待つことがある
+    let mut message = serde_json::Value::Null;
+    for _ in 0..20 {
+        server.publish("2026-10-19T09:00:01+09:00", &event);
+        message = read_json(&mut socket);
+        if message["type"] == "attached" {
+            break;
+        }
+    }
+    assert_eq!(message["id"], "SN-CAP25002");
+
+    let request = r#"{"action": "set", "id": "SN-CAP25001", "leds": {"mask": 4, "pattern": "blink"}, "request_id": 7}"#;
+    socket.send(tungstenite::Message::text(request)).unwrap();
+    let stop = AtomicBool::new(false);
+    let control = server.wait(Duration::from_secs(5), &stop).unwrap();
+    assert_eq!(control.id, "SN-CAP25001");
+    assert_eq!(control.caller.name, "token");
+    assert!(matches!(
+        control.action,
+        ControlAction::Set(DesiredLeds {
+            mask: 0x04,
+            pattern: Pattern::Blink { .. }
+        })
+    ));
+    control.respond(Ok(()));
+    let message = read_json(&mut socket);
+    assert_eq!((message["type"].as_str(), message["request_id"].as_i64()), (Some("result"), Some(7)));
+    assert_eq!(message["ok"], true);
+
+    // 解釈できない制御メッセージはその場で断る
+    socket
+        .send(tungstenite::Message::text(r#"{"action": "set", "id": "SN-CAP25001", "leds": "bright"}"#))
+        .unwrap();
+    let message = read_json(&mut socket);
+    assert_eq!(message["ok"], false);
+    assert!(server.wait(Duration::from_millis(200), &stop).is_none());
+}
+
+fn http_get(addr: std::net::SocketAddr, path: &str) -> String {
+    http_request(addr, path, "")
+}
+
+fn http_request(addr: std::net::SocketAddr, path: &str, headers: &str) -> String {
+    use std::io::Read;
+
+    let mut stream = std::net::TcpStream::connect(addr).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    write!(stream, "GET {} HTTP/1.1\r\nHost: {}\r\n{}\r\n", path, addr, headers).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+// トークンが無ければループバックでだけ読み取り専用で待ち受け、他のサイトからのハンドシェイクは断る
+#[test]
+fn websocket_without_token_is_loopback_only_and_read_only() {
+    assert!(WsServer::bind("0.0.0.0:0", None, 0x1f).is_err());
+    let server = WsServer::bind("127.0.0.1:0", None, 0x1f).unwrap();
+    let addr = server.local_addr();
+    let connect = |origin: Option<&str>| {
+        let stream = std::net::TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut request = tungstenite::client::IntoClientRequest::into_client_request(format!("ws://{}/ws", addr)).unwrap();
+        if let Some(origin) = origin {
+            request.headers_mut().insert("Origin", origin.parse().unwrap());
+        }
+        tungstenite::client(request, stream).ok().map(|(socket, _)| socket)
+    };
+
+    assert!(connect(Some("http://evil.example")).is_none());
+    assert!(connect(Some(&format!("http://{}", addr))).is_some());
+    let mut socket = connect(None).unwrap();
+    // 画面が操作のボタンを止められるよう、読み取り専用であることを最初に知らせる
+    let message = read_json(&mut socket);
+    assert_eq!((message["type"].as_str(), message["control"].as_bool()), (Some("session"), Some(false)));
+    assert_eq!(message["auth"], false);
+    let request = r#"{"action": "set", "id": "SN-CAP25001", "leds": "on", "request_id": 1}"#;
+    socket.send(tungstenite::Message::text(request)).unwrap();
+    let control = server.wait(Duration::from_secs(5), &AtomicBool::new(false)).unwrap();
+    assert_eq!(control.caller.permission, Permission::Read);
+}
+
+// WebSocketと同じポートで、埋め込んだ画面と認証付きの /api/locators を返す
+#[test]
+fn websocket_server_serves_page_and_locators_api() {
+    let access = AccessControl::new(DEFAULT_RATE_LIMIT).with_token("secret").unwrap();
+    let server = WsServer::bind("127.0.0.1:0", Some(access), 0x1f).unwrap();
+    let addr = server.local_addr();
+    let state = LocatorState {
+        id: "SN-CAP25001".to_string(),
+        alias: Some("3F 会議室".to_string()),
+        mask: Some(0x00),
+        leds: Vec::new(),
+        blinking: false,
+        controlled_by: None,
+    };
+    server.publish_state("2026-10-19T09:00:00+09:00", vec![state]);
+
+    let page = http_get(addr, "/");
+    assert!(page.starts_with("HTTP/1.1 200"), "{}", page);
+    assert!(page.contains("text/html") && page.contains("/app.js"));
+    assert!(http_get(addr, "/app.js").contains("/api/locators"));
+    assert!(http_get(addr, "/missing").starts_with("HTTP/1.1 404"));
+
+    assert!(http_get(addr, "/api/locators").starts_with("HTTP/1.1 401"));
+    assert!(http_get(addr, "/api/locators?token=secret").starts_with("HTTP/1.1 401"));
+    let response = http_request(addr, "/api/locators", "Authorization: Bearer secret\r\n");
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    let body = response.split_once("\r\n\r\n").unwrap().1;
+    let body: serde_json::Value = serde_json::from_str(body).unwrap();
+    assert_eq!(body["locators"][0]["alias"], "3F 会議室");
+    assert_eq!(body["locators"][0]["mask"], 0);
+}
+
+// 同時接続数の上限を超えた接続は503で断り、接続が終われば再び受け付ける
+#[test]
+fn websocket_server_caps_concurrent_connections() {
+    let server = WsServer::bind("127.0.0.1:0", None, 0x1f).unwrap();
+    let addr = server.local_addr();
+    // ヘッダーを送らずに接続を占有する
+    let idle: Vec<_> = (0..MAX_CONNECTIONS)
+        .map(|_| std::net::TcpStream::connect(addr).unwrap())
+        .collect();
+    let mut refused = std::net::TcpStream::connect(addr).unwrap();
+    refused.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut response = String::new();
+    std::io::Read::read_to_string(&mut refused, &mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 503"), "{}", response);
+
+    drop(idle);
+    let mut page = String::new();
+    for _ in 0..50 {
+        page = http_get(addr, "/");
+        if page.starts_with("HTTP/1.1 200") {
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    assert!(page.starts_with("HTTP/1.1 200"), "{}", page);
+}
+
+// ---- 記録/再生 ----
+
+fn no_filter() -> FilterArgs {
+    FilterArgs {
+        vendor_id: None,
+        product_id: None,
+        usage_page: None,
+        usage: None,
+        port: None,
+    }
+}
+
+// 記録した内容をそのまま再生でき、実機なしで同じ応答が得られることを確認
+#[test]
+fn recorded_session_replays_without_hardware() {
+    let buffer = SharedBuffer::default();
+    let device = descriptor(Some("SN-REC-1"), "/dev/hidraw0");
+    let recording = RecordingBackend::new(
+        MockBackend::new(vec![device.clone()], vec![0xff, 0x1f, 0x00]),
+        Recorder::new(Box::new(buffer.clone())),
+    );
+    let protocol = ProtocolArgs {
+        report_len: Some(3),
+        read_timeout_ms: 100,
+    };
+
+    let devices = recording.devices(&no_filter()).unwrap();
+    let handle = recording.open(&devices[0]).unwrap();
+    set_light(&handle, &protocol, true, 0x1f, 0).unwrap();
+    let recorded = query_status(&handle, &protocol).unwrap();
+
+    let text = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+    let replay = ReplayBackend::parse(&text).unwrap();
+    let devices = replay.devices(&no_filter()).unwrap();
+    assert_eq!(devices, vec![device]);
+    let handle = replay.open(&devices[0]).unwrap();
+    set_light(&handle, &protocol, true, 0x1f, 0).unwrap();
+    let replayed = query_status(&handle, &protocol).unwrap();
+    assert_eq!(replayed.raw, recorded.raw);
+    assert_eq!(replay.remaining(), 0);
+}
+
+// 記録と異なる要求は再生時にエラーになる
+#[test]
+fn replay_fails_on_divergent_request() {
+    let replay = ReplayBackend::parse(REPLAY_SN_REPLAY_1).unwrap();
+    let devices = replay.devices(&no_filter()).unwrap();
+    let handle = replay.open(&devices[0]).unwrap();
+    let protocol = ProtocolArgs {
+        report_len: Some(4),
+        read_timeout_ms: 100,
+    };
+    // 記録はマスク0x04の送信なので0x1fは不一致
+    let err = set_light(&handle, &protocol, true, 0x1f, 0).unwrap_err();
+    assert!(format!("{:#}", err).contains("記録と異なります"), "{:#}", err);
+}
+
+/// 不具合報告の `--record` ファイルをそのまま回帰テストに使う例
+const REPLAY_SN_REPLAY_1: &str = r#"
+{"type":"devices","devices":[{"path":"/dev/hidraw3","vendor_id":1240,"product_id":5205,"serial_number":"SN-REPLAY-1","usage_page":null,"usage":null}]}
+{"type":"open","path":"/dev/hidraw3"}
+{"type":"write","path":"/dev/hidraw3","at_ms":3,"data":"02 04 00 00"}
+{"type":"write","path":"/dev/hidraw3","at_ms":3,"data":"01 00 00 00"}
+{"type":"read","path":"/dev/hidraw3","at_ms":3,"timeout_ms":100,"elapsed_ms":2,"data":"ff 04 00 00"}
+"#;
+
+#[test]
+fn replayed_bug_report_runs_through_handle_set() {
+    let replay = ReplayBackend::parse(REPLAY_SN_REPLAY_1).unwrap();
+    let args = SetArgs {
+        id: Some("SN-REPLAY-1".to_string()),
+        filter: no_filter(),
+        protocol: ProtocolArgs {
+            report_len: Some(4),
+            read_timeout_ms: 100,
+        },
+        lock: LockArgs {
+            lock_timeout: Duration::from_secs(5),
+        },
+        on_value: 0x04,
+        off_value: 0x00,
+        hold_for: None,
+    };
+    handle_set(&replay, &ProfileRegistry::builtin(), &args, &EnvDefaults::default(), true).unwrap();
+    assert_eq!(replay.remaining(), 0);
+}
+
+/// 2台のうち SN-SNAP-2 だけが応答しない
+const REPLAY_SNAPSHOT_PARTIAL: &str = r#"
+{"type":"devices","devices":[{"path":"/dev/hidraw3","vendor_id":1240,"product_id":5205,"serial_number":"SN-SNAP-1","usage_page":null,"usage":null},{"path":"/dev/hidraw4","vendor_id":1240,"product_id":5205,"serial_number":"SN-SNAP-2","usage_page":null,"usage":null}]}
+{"type":"open","path":"/dev/hidraw3"}
+{"type":"write","path":"/dev/hidraw3","at_ms":1,"data":"01 00 00 00"}
+{"type":"read","path":"/dev/hidraw3","at_ms":1,"timeout_ms":100,"elapsed_ms":1,"data":"ff 04 00 00"}
+{"type":"open","path":"/dev/hidraw4"}
+{"type":"write","path":"/dev/hidraw4","at_ms":2,"data":"01 00 00 00"}
+{"type":"read","path":"/dev/hidraw4","at_ms":2,"timeout_ms":100,"elapsed_ms":100,"data":""}
+"#;
+
+// 読めなかったlocatorがあればスナップショットを書かず、--allow-partial のときだけ読めた分を書く
+#[test]
+fn snapshot_save_skips_writing_incomplete_snapshots() {
+    let file = std::env::temp_dir().join(format!("cap-locator-snapshot-{}.json", std::process::id()));
+    let _ = std::fs::remove_file(&file);
+    let args = |allow_partial: bool| SnapshotSaveArgs {
+        snapshot: SnapshotFileArgs {
+            file: file.clone(),
+            filter: no_filter(),
+            protocol: ProtocolArgs {
+                report_len: Some(4),
+                read_timeout_ms: 100,
+            },
+            lock: LockArgs {
+                lock_timeout: Duration::from_secs(5),
+            },
+        },
+        allow_partial,
+    };
+    let profiles = ProfileRegistry::builtin();
+
+    let replay = ReplayBackend::parse(REPLAY_SNAPSHOT_PARTIAL).unwrap();
+    let err = handle_snapshot_save(&replay, &profiles, &args(false), &EnvDefaults::default()).unwrap_err();
+    assert!(err.to_string().contains("--allow-partial"), "{}", err);
+    assert!(!file.exists());
+
+    let replay = ReplayBackend::parse(REPLAY_SNAPSHOT_PARTIAL).unwrap();
+    assert!(handle_snapshot_save(&replay, &profiles, &args(true), &EnvDefaults::default()).is_err());
+    let saved = Snapshot::load(&file).unwrap();
+    assert_eq!(saved.devices.len(), 1);
+    assert_eq!(saved.devices[0].serial.as_deref(), Some("SN-SNAP-1"));
+    std::fs::remove_file(&file).unwrap();
+
+    // 1台も読めなければ --allow-partial でも書かない
+    let nothing = REPLAY_SNAPSHOT_PARTIAL.replace(r#""data":"ff 04 00 00""#, r#""data":"""#);
+    let replay = ReplayBackend::parse(&nothing).unwrap();
+    let err = handle_snapshot_save(&replay, &profiles, &args(true), &EnvDefaults::default()).unwrap_err();
+    assert!(err.to_string().contains("どのlocatorも読めなかった"), "{}", err);
+    assert!(!file.exists());
+}
+
+// ---- デバイスプロファイル ----
+
+const ALT_PROFILE: &str = r#"
+name = "alt-locator"
+vendor_id = 0x1209
+product_id = 0x0001
+report_len = 8
+leds = ["red", "green", "blue"]
+
+[commands]
+status = 0x10
+set = 0x20
+mask_offset = 2
+
+[response]
+header = 0xA5
+mask_offset = 3
+"#;
+
+#[test]
+fn profile_parses_toml_with_defaults() {
+    let profile = DeviceProfile::parse(ALT_PROFILE).unwrap();
+    assert_eq!(profile.name, "alt-locator");
+    assert_eq!(profile.commands.set, 0x20);
+    assert_eq!(profile.response.mask_offset, 3);
+    assert_eq!(profile.led_bits(), 0x07);
+    assert_eq!(profile.lit_leds(0x05), vec!["red", "blue"]);
+
+    let minimal = DeviceProfile::parse(
+        "name = \"m\"\nleds = [\"a\"]\n[commands]\nstatus = 1\nset = 2\n[response]\nheader = 0xff\n",
+    )
+    .unwrap();
+    assert_eq!(minimal.report_len, 64);
+    assert_eq!(minimal.commands.mask_offset, 1);
+    assert!(DeviceProfile::parse(&ALT_PROFILE.replace("mask_offset = 2", "mask_offset = 0")).is_err());
+}
+
+#[test]
+fn profile_registry_selects_by_vid_pid_unless_forced() {
+    let mut registry = ProfileRegistry::builtin();
+    registry.add(DeviceProfile::parse(ALT_PROFILE).unwrap());
+
+    let alt = DeviceDescriptor {
+        vendor_id: 0x1209,
+        product_id: 0x0001,
+        ..descriptor(Some("ALT-1"), "/dev/hidraw5")
+    };
+    assert_eq!(registry.for_device(&alt).name, "alt-locator");
+    assert_eq!(registry.for_device(&descriptor(Some("SN1"), "/dev/hidraw0")).name, "cap-locator");
+
+    let dir = std::env::temp_dir().join(format!("cap-locator-profiles-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("alt.toml"), ALT_PROFILE).unwrap();
+    let forced = ProfileRegistry::load(&crate::cli::ProfileArgs {
+        profile: Some("alt-locator".to_string()),
+        profile_dir: Some(dir.clone()),
+    })
+    .unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(forced.for_device(&descriptor(Some("SN1"), "/dev/hidraw0")).name, "alt-locator");
+    let filter = forced.fill_filter(FilterArgs {
+        product_id: Some(0x0002),
+        ..no_filter()
+    });
+    assert_eq!(filter.vendor_id, Some(0x1209));
+    assert_eq!(filter.product_id, Some(0x0002));
+}
+
+#[test]
+fn profile_uses_its_own_command_bytes_and_offsets() {
+    let profile = DeviceProfile::parse(ALT_PROFILE).unwrap();
+    let protocol = ProtocolArgs {
+        report_len: None,
+        read_timeout_ms: 100,
+    };
+    let device = MockDevice::with_response(vec![0xa5, 0x00, 0x00, 0x06]);
+
+    profile.set_mask(&device, &protocol, 0x03).unwrap();
+    assert_eq!(device.last_sent().unwrap(), vec![0x20, 0x00, 0x03, 0, 0, 0, 0, 0]);
+
+    let status = profile.query_status(&device, &protocol).unwrap();
+    assert_eq!(device.last_sent().unwrap()[0], 0x10);
+    assert_eq!(status.mask, 0x06);
+
+    let wrong = MockDevice::with_response(vec![0xff, 0x06]);
+    let err = profile.query_status(&wrong, &protocol).unwrap_err();
+    assert!(err.to_string().contains("0xA5"));
+}
+
+// ---- バージョン/機能の問い合わせ ----
+
+#[test]
+fn query_info_parses_version_and_capabilities() {
+    let profile = DeviceProfile::cap_locator();
+    let protocol = ProtocolArgs {
+        report_len: Some(8),
+        read_timeout_ms: 100,
+    };
+    let device = MockDevice::with_response(vec![0xff, 0x03, b'I', 1, 2, 3, 0x0b, 0x00]);
+    let info = profile.query_info(&device, &protocol).unwrap();
+    assert_eq!(device.last_sent().unwrap()[0], 0x03);
+    assert_eq!(info.version, Some((1, 2, 3)));
+    assert_eq!(info.capabilities.to_string(), "status,set,default-mask");
+    assert!(info.require(Capability::DefaultMask).is_ok());
+    let err = info.require(Capability::Provision).unwrap_err();
+    assert!(err.to_string().contains("1.2.3"));
+}
+
+#[test]
+fn query_info_treats_unknown_opcode_as_legacy_firmware() {
+    let profile = DeviceProfile::cap_locator();
+    let protocol = ProtocolArgs {
+        report_len: Some(8),
+        read_timeout_ms: 100,
+    };
+    // タイムアウト(0バイト)、先頭が0xFFでない応答、ステータス応答のいずれも旧ファームウェア扱い。
+    // マスクがたまたまコマンドと同じ0x03のステータス応答も、目印が無いのでバージョンとは読まない
+    let responses = [
+        vec![],
+        vec![0x00, 0x03, b'I', 1, 0, 0, 0xff, 0xff],
+        vec![0xff, 0x1f],
+        vec![0xff, 0x03, 0, 0, 0, 0, 0, 0],
+    ];
+    for response in responses {
+        let device = MockDevice::with_response(response);
+        let info = profile.query_info(&device, &protocol).unwrap();
+        assert_eq!(info.version, None);
+        assert_eq!(info.capabilities, Capabilities::LEGACY);
+        assert!(info.require(Capability::Set).is_ok());
+        assert!(info.require(Capability::Events).is_err());
+    }
+    assert_eq!(Capabilities::LEGACY.commands()[0], "status");
+    assert_eq!(format_release(0x0102), "1.02");
+}
+
+// `status` の電源投入時のマスクは、機能の問い合わせをlocatorごとに1回だけにする
+#[test]
+fn stored_default_mask_queries_capabilities_once_per_device() {
+    let profile = DeviceProfile::cap_locator();
+    let protocol = ProtocolArgs {
+        report_len: Some(8),
+        read_timeout_ms: 100,
+    };
+    let device = descriptor(Some("SN-CAP25001"), "/dev/hidraw0");
+    let mut capabilities = CapabilityCache::default();
+    let handle = MockDevice::with_responses(vec![
+        vec![0xff, 0x03, b'I', 1, 2, 3, 0x0f, 0x00],
+        vec![0xff, 0x06, 0x04],
+        vec![0xff, 0x06, 0x04],
+    ]);
+    assert_eq!(stored_default_mask(&profile, &device, &handle, &protocol, &mut capabilities), Some(0x04));
+    assert_eq!(stored_default_mask(&profile, &device, &handle, &protocol, &mut capabilities), Some(0x04));
+    let commands: Vec<u8> = handle.sent.borrow().iter().map(|report| report[0]).collect();
+    assert_eq!(commands, vec![0x03, 0x06, 0x06]);
+
+    // 旧ファームウェアは2回目からタイムアウトを待たない
+    let legacy = descriptor(Some("SN-LEGACY"), "/dev/hidraw1");
+    let handle = MockDevice::with_response(Vec::new());
+    assert_eq!(stored_default_mask(&profile, &legacy, &handle, &protocol, &mut capabilities), None);
+    assert_eq!(stored_default_mask(&profile, &legacy, &handle, &protocol, &mut capabilities), None);
+    assert_eq!(handle.sent.borrow().len(), 1);
+}
+
+// `status` の既定の行は従来のまま。LEDの名前と電源投入時のマスクは `--details` のときだけ行末に足す
+#[test]
+fn status_line_keeps_the_default_format_and_appends_details() {
+    let status = LocatorStatus {
+        is_on: true,
+        mask: 0x05,
+        raw: vec![0xff, 0x05],
+    };
+    let line = format_status("SN-CAP25001", &status);
+    assert_eq!(line, "id=SN-CAP25001          status=on  mask=0x05 raw=[ff 05]");
+    assert_eq!(
+        format_status_details("SN-CAP25001", &["RC2", "RC4"], &status, Some(0x04)),
+        format!("{} leds=RC2,RC4 default=0x04", line)
+    );
+    assert!(format_status_details("SN-CAP25001", &[], &status, None).ends_with(" leds=- default=-"));
+}
+
+// ---- ファームウェア更新 (Intel HEX + 模擬ブートローダー) ----
+
+const FIRMWARE_HEX: &str = "\
+:020000040000FA
+:10100000000102030405060708090A0B0C0D0E0F68
+:04102000AABBCCDDBE
+:020000040001F9
+:04000E00FF3FFF3F72
+:00000001FF
+";
+
+fn bootloader_layout() -> BootloaderLayout {
+    DeviceProfile::cap_locator().bootloader.unwrap()
+}
+
+#[test]
+fn intel_hex_parser_reads_records_and_extended_addresses() {
+    let image = FirmwareImage::parse(FIRMWARE_HEX).unwrap();
+    assert_eq!(image.data.len(), 16 + 4 + 4);
+    assert_eq!(image.data[&0x1000], 0x00);
+    assert_eq!(image.data[&0x100f], 0x0f);
+    assert_eq!(image.data[&0x1023], 0xdd);
+    assert_eq!(image.data[&0x1000e], 0xff);
+
+    // 0x1000-0x101f と 0x1020-0x103f の2ブロック。コンフィグワード(0x1000e)は範囲外なので含めない
+    let blocks = image.blocks(&bootloader_layout()).unwrap();
+    assert_eq!(blocks.len(), 2);
+    assert_eq!(blocks[0].address, 0x1000);
+    assert_eq!(blocks[0].data[16], 0xff);
+    assert_eq!(blocks[1].data[..5], [0xaa, 0xbb, 0xcc, 0xdd, 0xff]);
+}
+
+#[test]
+fn intel_hex_parser_rejects_broken_files() {
+    let bad_checksum = FIRMWARE_HEX.replace(":04102000AABBCCDDBE", ":04102000AABBCCDDBF");
+    let err = FirmwareImage::parse(&bad_checksum).unwrap_err();
+    assert!(err.to_string().contains("3行目"));
+    assert!(FirmwareImage::parse(":10100000000102030405060708090A0B0C0D0E0F68\n").is_err());
+    assert!(FirmwareImage::parse("10100000\n:00000001FF\n").is_err());
+
+    let into_bootloader = FirmwareImage::parse(":0100000000FF\n:00000001FF\n").unwrap();
+    assert!(into_bootloader.blocks(&bootloader_layout()).is_err());
+}
+
+#[test]
+fn flash_blocks_retries_failed_writes_and_verifies() {
+    let image = FirmwareImage::parse(FIRMWARE_HEX).unwrap();
+    let blocks = image.blocks(&bootloader_layout()).unwrap();
+    let device = SimulatedBootloader::new(0x4000);
+    device.fail_writes.borrow_mut().push(0x1000);
+    device.corrupt_writes.borrow_mut().push(0x1020);
+    let bootloader = HidBootloader::new(&device, 64, 0xff, 100);
+
+    let mut events = Vec::new();
+    let report = flash_blocks(&bootloader, &blocks, 3, &mut |p| events.push(p.clone())).unwrap();
+    assert_eq!(report.written, 2);
+    assert_eq!(report.retries, 2);
+    assert_eq!(*device.writes.borrow(), vec![0x1000, 0x1000, 0x1020, 0x1020]);
+    assert_eq!(device.flash.borrow()[0x1000..0x1010], blocks[0].data[..16]);
+    assert!(matches!(events[0], FlashProgress::Retry { address: 0x1000, attempt: 1, .. }));
+
+    bootloader.reset().unwrap();
+    assert!(*device.reset.borrow());
+}
+
+#[test]
+fn flash_blocks_resumes_after_giving_up() {
+    let image = FirmwareImage::parse(FIRMWARE_HEX).unwrap();
+    let blocks = image.blocks(&bootloader_layout()).unwrap();
+    let device = SimulatedBootloader::new(0x4000);
+    device.fail_writes.borrow_mut().extend([0x1020, 0x1020]);
+    let bootloader = HidBootloader::new(&device, 64, 0xff, 100);
+
+    let err = flash_blocks(&bootloader, &blocks, 1, &mut |_| {}).unwrap_err();
+    assert_eq!(err.address, 0x1020);
+    assert_eq!(err.completed, 1);
+
+    // やり直すと書き込み済みのブロックは飛ばす
+    device.writes.borrow_mut().clear();
+    let report = flash_blocks(&bootloader, &blocks, 1, &mut |_| {}).unwrap();
+    assert_eq!((report.skipped, report.written), (1, 1));
+    assert_eq!(*device.writes.borrow(), vec![0x1020]);
+}
+
+// ---- シリアル番号の書き込み ----
+
+#[test]
+fn validate_identity_rejects_unusable_serials() {
+    assert!(validate_identity(IdentityField::Serial, "SN-CAP25001").is_ok());
+    assert!(validate_identity(IdentityField::Serial, "").is_err());
+    assert!(validate_identity(IdentityField::Serial, "SN 1").is_err());
+    assert!(validate_identity(IdentityField::Serial, "シリアル").is_err());
+    assert!(validate_identity(IdentityField::Serial, &"X".repeat(25)).is_err());
+    assert!(validate_identity(IdentityField::Name, "Rack 3 left").is_ok());
+}
+
+/// シリアル番号の無いlocatorへ書き込み、読み戻すまでの記録
+const REPLAY_PROVISION: &str = r#"
+{"type":"devices","devices":[{"path":"/dev/hidraw7","vendor_id":1240,"product_id":5205,"serial_number":null,"usage_page":null,"usage":null}]}
+{"type":"open","path":"/dev/hidraw7"}
+{"type":"write","path":"/dev/hidraw7","at_ms":1,"data":"03 00 00 00 00 00 00 00"}
+{"type":"read","path":"/dev/hidraw7","at_ms":1,"timeout_ms":100,"elapsed_ms":1,"data":"ff 03 49 01 01 00 13 00"}
+{"type":"write","path":"/dev/hidraw7","at_ms":2,"data":"05 00 01 00 00 00 00 00"}
+{"type":"read","path":"/dev/hidraw7","at_ms":2,"timeout_ms":100,"elapsed_ms":1,"data":"ff 05 00"}
+{"type":"write","path":"/dev/hidraw7","at_ms":3,"data":"05 01 01 04 53 4e 2d 39"}
+{"type":"read","path":"/dev/hidraw7","at_ms":3,"timeout_ms":100,"elapsed_ms":5,"data":"ff 05 00"}
+{"type":"write","path":"/dev/hidraw7","at_ms":9,"data":"05 00 01 00 00 00 00 00"}
+{"type":"read","path":"/dev/hidraw7","at_ms":9,"timeout_ms":100,"elapsed_ms":1,"data":"ff 05 04 53 4e 2d 39"}
+"#;
+
+fn provision_args(serial: &str) -> ProvisionArgs {
+    ProvisionArgs {
+        path: Some("/dev/hidraw7".to_string()),
+        id: None,
+        serial: serial.to_string(),
+        name: None,
+        yes: true,
+        filter: no_filter(),
+        protocol: ProtocolArgs {
+            report_len: Some(8),
+            read_timeout_ms: 100,
+        },
+        lock: LockArgs {
+            lock_timeout: Duration::from_secs(5),
+        },
+    }
+}
+
+#[test]
+fn provision_writes_serial_and_reads_it_back() {
+    let replay = ReplayBackend::parse(REPLAY_PROVISION).unwrap();
+    let profiles = ProfileRegistry::builtin();
+    handle_provision(&replay, &profiles, &provision_args("SN-9"), &EnvDefaults::default()).unwrap();
+    assert_eq!(replay.remaining(), 0);
+}
+
+#[test]
+fn provision_fails_when_read_back_differs() {
+    let replay = ReplayBackend::parse(&REPLAY_PROVISION.replace(
+        r#""data":"ff 05 04 53 4e 2d 39""#,
+        r#""data":"ff 05 04 53 4e 2d 38""#,
+    ))
+    .unwrap();
+    let profiles = ProfileRegistry::builtin();
+    let err = handle_provision(&replay, &profiles, &provision_args("SN-9"), &EnvDefaults::default())
+        .unwrap_err();
+    assert!(err.to_string().contains("読み戻し"), "{:#}", err);
+}
+
+// ---- 電源投入時のマスク ----
+
+#[test]
+fn set_default_mask_reads_back_stored_mask() {
+    let protocol = ProtocolArgs {
+        report_len: Some(4),
+        read_timeout_ms: 100,
+    };
+    let device = MockDevice::with_responses(vec![vec![0xff, 0x06, 0x00], vec![0xff, 0x06, 0x04]]);
+    set_default_mask(&device, &protocol, 0x04).unwrap();
+    assert_eq!(device.last_sent().unwrap(), vec![0x06, 0x00, 0x00, 0x00]);
+
+    let device = MockDevice::with_response(vec![0xff, 0x06, 0x1f]);
+    assert_eq!(query_default_mask(&device, &protocol).unwrap(), 0x1f);
+}
+
+#[test]
+fn set_default_mask_fails_when_read_back_differs() {
+    let protocol = ProtocolArgs {
+        report_len: Some(4),
+        read_timeout_ms: 100,
+    };
+    let device = MockDevice::with_responses(vec![vec![0xff, 0x06, 0x00], vec![0xff, 0x06, 0x00]]);
+    let err = set_default_mask(&device, &protocol, 0x04).unwrap_err();
+    assert!(err.to_string().contains("読み戻し"), "{:#}", err);
+
+    let device = MockDevice::with_response(vec![0xff, 0x06, 0x02]);
+    let err = set_default_mask(&device, &protocol, 0x04).unwrap_err();
+    assert!(err.to_string().contains("status=0x02"), "{:#}", err);
+}
+
+// ---- ボタンのイベント ----
+
+#[test]
+fn events_are_decoded_and_other_reports_ignored() {
+    let profile = DeviceProfile::cap_locator();
+    assert_eq!(
+        LocatorEvent::decode(&profile, &[0xfe, 0x01, 0x02]),
+        Some(LocatorEvent::Pressed { button: 2 })
+    );
+    assert_eq!(
+        LocatorEvent::decode(&profile, &[0xfe, 0x09]),
+        Some(LocatorEvent::Unknown { kind: 0x09 })
+    );
+    assert_eq!(LocatorEvent::decode(&profile, &[0xff, 0x01, 0x02]), None);
+
+    // タイムアウト(0バイト)と他のCLIへのステータス応答を挟んでも、イベントだけを受け取る
+    let device = MockDevice::with_responses(vec![
+        vec![0xfe, 0x01, 0x00],
+        vec![],
+        vec![0xff, 0x04],
+        vec![0xfe, 0x02, 0x00],
+    ]);
+    let cancel = AtomicBool::new(false);
+    let mut events = Vec::new();
+    let count = listen(&profile, &device, 8, &cancel, Some(2), &mut |received| {
+        events.push(received.event.clone())
+    })
+    .unwrap();
+    assert_eq!(count, 2);
+    assert_eq!(
+        events,
+        vec![LocatorEvent::Pressed { button: 0 }, LocatorEvent::Released { button: 0 }]
+    );
+}
+
+#[test]
+fn replies_skip_events_received_while_waiting() {
+    let protocol = ProtocolArgs {
+        report_len: Some(4),
+        read_timeout_ms: 100,
+    };
+    let device = MockDevice::with_responses(vec![vec![0xfe, 0x01, 0x00], vec![0xff, 0x04]]);
+    let status = query_status(&device, &protocol).unwrap();
+    assert_eq!(status.mask, 0x04);
+
+    let mut profile = DeviceProfile::cap_locator();
+    profile.response.event_header = Some(0xff);
+    let text = toml::to_string(&profile).unwrap();
+    assert!(DeviceProfile::parse(&text).is_err());
+}
+
+// ---- フック ----
+
+const HOOK_CONFIG: &str = r#"
+[groups]
+desk = ["SN-CAP25001", "SN-CAP25002"]
+
+[[hooks]]
+on = ["changed"]
+target = "desk"
+command = "notify {id} {event} {mask}"
+timeout = "2s"
+
+[[hooks]]
+on = ["attached", "detached"]
+command = "logger {id}"
+"#;
+
+#[test]
+fn hooks_match_events_and_render_placeholders() {
+    let config = Config::parse(HOOK_CONFIG).unwrap();
+    let runner = HookRunner::from_config(&config, 1).unwrap();
+    assert_eq!(runner.len(), 2);
+    let hook = Hook::from_config(&config.hooks[0], &config).unwrap();
+    assert_eq!(hook.timeout, Duration::from_secs(2));
+
+    let changed = HookEvent::from_daemon(&DaemonEvent::Applied {
+        id: "SN-CAP25001".to_string(),
+        mask: 0x04,
+    });
+    assert_eq!(changed.kind, HookEventKind::Changed);
+    assert!(hook.matches(&changed, &[]));
+    assert_eq!(hook.render(&changed), "notify 'SN-CAP25001' 'changed' '0x04'");
+
+    let other = HookEvent {
+        id: "SN-OTHER".to_string(),
+        ..changed.clone()
+    };
+    assert!(!hook.matches(&other, &[]));
+    let attached = HookEvent::from_daemon(&DaemonEvent::Attached {
+        id: "SN-CAP25001".to_string(),
+    });
+    assert!(!hook.matches(&attached, &[]));
+
+    // 値のシングルクォートはエスケープする
+    let quoted = HookEvent {
+        id: "it's".to_string(),
+        ..changed
+    };
+    assert_eq!(hook.render(&quoted), r"notify 'it'\''s' 'changed' '0x04'");
+
+    let broken = Config::parse("[[hooks]]\non = [\"pressed\"]\ncommand = \"true\"\n").unwrap();
+    assert!(HookRunner::from_config(&broken, 1).is_err());
+}
+
+#[cfg(unix)]
+#[test]
+fn run_command_passes_vars_and_enforces_timeout() {
+    let vars = [("CAP_LOCATOR_ID", "SN-CAP25001".to_string())];
+    run_command(r#"test "$CAP_LOCATOR_ID" = SN-CAP25001"#, &vars, None).unwrap();
+    assert!(run_command("exit 3", &vars, None).is_err());
+
+    let started = std::time::Instant::now();
+    let err = run_command("sleep 5", &vars, Some(Duration::from_millis(100))).unwrap_err();
+    assert!(err.to_string().contains("停止しました"), "{:#}", err);
+    assert!(started.elapsed() < Duration::from_secs(2));
+
+    // タイムアウトしたらコマンドが起動したプロセスもまとめて止める
+    let marker = std::env::temp_dir().join(format!("cap-locator-hook-{}", std::process::id()));
+    let _ = std::fs::remove_file(&marker);
+    let vars = [("MARKER", marker.display().to_string())];
+    let command = r#"(sleep 1; touch "$MARKER") & sleep 5"#;
+    assert!(run_command(command, &vars, Some(Duration::from_millis(100))).is_err());
+    std::thread::sleep(Duration::from_millis(1500));
+    assert!(!marker.exists());
+}
+
+#[test]
+fn daemon_watch_reports_masks_changed_by_others() {
+    let mut daemon = Daemon::new(Scheduler::default(), ProfileRegistry::builtin()).watch_status(true);
+    let protocol = ProtocolArgs {
+        report_len: Some(2),
+        read_timeout_ms: 100,
+    };
+    let device = descriptor(Some("SN-CAP25001"), "/dev/hidraw0");
+    let responses = RefCell::new(vec![vec![0xff, 0x00], vec![0xff, 0x00], vec![0xff, 0x04]]);
+    let mut open = |_: &DeviceDescriptor| -> anyhow::Result<Box<dyn HidDeviceIo>> {
+        let response = responses.borrow_mut().remove(0);
+        Ok(Box::new(MockDevice::with_response(response)))
+    };
+
+    // 接続直後は記録だけ、変化が無ければ何も起きない
+    let events = daemon.tick(&at(19, 9, 0), std::slice::from_ref(&device), &protocol, &mut open);
+    assert_eq!(events.len(), 1);
+    let events = daemon.tick(&at(19, 9, 1), std::slice::from_ref(&device), &protocol, &mut open);
+    assert!(events.is_empty());
+    let events = daemon.tick(&at(19, 9, 2), std::slice::from_ref(&device), &protocol, &mut open);
+    assert_eq!(
+        events,
+        vec![DaemonEvent::Changed {
+            id: "SN-CAP25001".to_string(),
+            mask: 0x04
+        }]
+    );
+}
+
+// ---- MQTTブリッジ ----
+
+#[test]
+fn mqtt_set_payloads_and_state_messages() {
+    assert_eq!(DesiredLeds::parse(b"on", 0x1f).unwrap().mask, 0x1f);
+    assert_eq!(DesiredLeds::parse(b" 0x04 ", 0x1f).unwrap().mask, 0x04);
+    let blink = DesiredLeds::parse(br#"{"mask": "0x02", "pattern": "blink", "interval": "1s"}"#, 0x1f).unwrap();
+    assert_eq!(blink.pattern, Pattern::Blink { interval: Duration::from_secs(1) });
+    assert!(DesiredLeds::parse(br#"{"mask": 300}"#, 0x1f).is_err());
+    assert!(DesiredLeds::parse(b"dim", 0x1f).is_err());
+    assert_eq!(topic_id("/dev/hidraw0"), "_dev_hidraw0");
+
+    let mut bridge = MqttBridge::new("lab/", ProfileRegistry::builtin(), 0x1f);
+    let protocol = ProtocolArgs {
+        report_len: Some(2),
+        read_timeout_ms: 100,
+    };
+    let device = descriptor(Some("SN-CAP25001"), "/dev/hidraw0");
+    let sent = Rc::new(RefCell::new(Vec::new()));
+    let mut open = |_: &DeviceDescriptor| -> anyhow::Result<Box<dyn HidDeviceIo>> {
+        let mock = MockDevice::with_response(vec![0xff, 0x04]);
+        sent.borrow_mut().push(Rc::clone(&mock.sent));
+        Ok(Box::new(mock))
+    };
+
+    let set = Message::new("lab/SN-CAP25001/set", "0x04", false);
+    assert_eq!(bridge.handle_message(&set).unwrap(), Some("SN-CAP25001".to_string()));
+    assert_eq!(bridge.handle_message(&Message::new("lab/x/state", "on", false)).unwrap(), None);
+
+    let messages = bridge.tick(&at(19, 9, 0), std::slice::from_ref(&device), &protocol, &mut open);
+    assert_eq!(messages.len(), 1);
+    assert_eq!(messages[0].topic, "lab/SN-CAP25001/state");
+    assert!(messages[0].retain);
+    let state: serde_json::Value = serde_json::from_slice(&messages[0].payload).unwrap();
+    assert_eq!(state["mask"], 4);
+    assert_eq!(state["leds"], serde_json::json!(["RC4"]));
+    assert_eq!(sent.borrow()[0].borrow()[0], vec![0x02, 0x04]);
+
+    // 変化が無ければpublishしない、抜かれたら present=false
+    assert!(bridge.tick(&at(19, 9, 1), std::slice::from_ref(&device), &protocol, &mut open).is_empty());
+    let messages = bridge.tick(&at(19, 9, 2), &[], &protocol, &mut open);
+    assert_eq!(messages[0].payload, br#"{"present":false}"#.to_vec());
+
+    // 点滅中は --interval より先に来る切り替わりで次の周にする
+    assert_eq!(bridge.next_tick(&at(19, 9, 2), Duration::from_secs(1)), Duration::from_secs(1));
+    let blink = Message::new("lab/SN-CAP25001/set", r#"{"mask": 4, "pattern": "blink", "interval": "500ms"}"#, false);
+    bridge.handle_message(&blink).unwrap();
+    let now = at(19, 9, 2) + TimeDelta::milliseconds(200);
+    assert_eq!(bridge.next_tick(&now, Duration::from_secs(1)), Duration::from_millis(300));
+    assert!(DesiredLeds::parse(br#"{"mask": 4, "pattern": "blink", "interval": "0s"}"#, 0x1f).is_err());
+}
+
+#[test]
+fn mqtt_client_talks_to_stand_in_broker() {
+    use rumqttc::{Packet, Publish, QoS, Subscribe};
+
+    let broker = StandInBroker::spawn();
+    let options = MqttOptions {
+        broker: broker.addr.to_string(),
+        client_id: "test".to_string(),
+        username: Some("user".to_string()),
+        password: Some("secret".to_string()),
+        keep_alive: Duration::from_secs(30),
+        will: Some(Message::new("cap-locator/availability", "offline", true)),
+        max_packet_size: DEFAULT_MAX_PACKET_SIZE,
+    };
+    let mut client = MqttClient::connect(&options).unwrap();
+    client.subscribe("cap-locator/+/set").unwrap();
+    client
+        .publish(&Message::new("cap-locator/availability", "online", true))
+        .unwrap();
+    broker.publish(Message::new("cap-locator/SN-1/set", "off", false));
+    let mut received = None;
+    for _ in 0..50 {
+        if let Some(message) = client.poll(Duration::from_millis(20)).unwrap() {
+            received = Some(message);
+            break;
+        }
+    }
+    assert_eq!(received.unwrap().payload, b"off".to_vec());
+    // 長さ2バイトに収まらない値は切り詰めずにエラーにする
+    let err = client.publish(&Message::new("x".repeat(70_000), "on", false)).unwrap_err();
+    assert!(format!("{:#}", err).contains("長すぎます"), "{:#}", err);
+    client.disconnect().unwrap();
+
+    let packets = broker.finish();
+    let Packet::Connect(connect) = &packets[0] else {
+        panic!("{:?}", packets);
+    };
+    assert_eq!(connect.login.as_ref().map(|login| login.password.as_str()), Some("secret"));
+    assert_eq!(connect.last_will.as_ref().unwrap().message.as_ref(), b"offline");
+    assert!(packets.contains(&Packet::Subscribe(Subscribe {
+        pkid: 1,
+        filters: vec![rumqttc::SubscribeFilter::new("cap-locator/+/set".to_string(), QoS::AtMostOnce)],
+    })));
+    let mut online = Publish::new("cap-locator/availability", QoS::AtMostOnce, "online");
+    online.retain = true;
+    assert!(packets.contains(&Packet::Publish(online)));
+    assert_eq!(packets.last(), Some(&Packet::Disconnect));
+
+    let long_password = MqttOptions {
+        password: Some("p".repeat(70_000)),
+        ..options
+    };
+    let err = MqttClient::connect(&long_password).err().expect("password too long");
+    assert!(format!("{:#}", err).contains("パスワードが長すぎます"), "{:#}", err);
+}
+
+#[test]
+fn mqtt_client_rejects_packets_over_the_size_limit() {
+    let broker = StandInBroker::spawn();
+    let options = MqttOptions {
+        broker: broker.addr.to_string(),
+        client_id: "test".to_string(),
+        username: None,
+        password: None,
+        keep_alive: Duration::from_secs(30),
+        will: None,
+        max_packet_size: 64,
+    };
+    let mut client = MqttClient::connect(&options).unwrap();
+    broker.publish(Message::new("cap-locator/SN-1/set", vec![b'x'; 1024], false));
+    let mut result = Ok(None);
+    for _ in 0..50 {
+        result = client.poll(Duration::from_millis(20));
+        if !matches!(result, Ok(None)) {
+            break;
+        }
+    }
+    assert!(result.is_err(), "{:?}", result);
+    drop(client);
+    broker.finish();
+}
+
+// ---- メトリクス ----
+
+#[test]
+fn daemon_records_metrics_for_status_and_errors() {
+    let metrics = Arc::new(Metrics::new().unwrap());
+    let mut daemon = Daemon::new(Scheduler::default(), ProfileRegistry::builtin())
+        .watch_status(true)
+        .with_metrics(Arc::clone(&metrics));
+    let protocol = ProtocolArgs {
+        report_len: Some(2),
+        read_timeout_ms: 100,
+    };
+    let device = descriptor(Some("SN-CAP25001"), "/dev/hidraw0");
+    // 正常な応答、タイムアウト(0バイト)、先頭が不正な応答
+    let responses = RefCell::new(vec![vec![0xff, 0x05], vec![], vec![0x00, 0x05]]);
+    let mut open = |_: &DeviceDescriptor| -> anyhow::Result<Box<dyn HidDeviceIo>> {
+        let response = responses.borrow_mut().remove(0);
+        Ok(Box::new(MockDevice::with_response(response)))
+    };
+    for second in 0..3 {
+        daemon.tick(&at(19, 9, second), std::slice::from_ref(&device), &protocol, &mut open);
+    }
+    daemon.tick(&at(19, 9, 3), &[], &protocol, &mut open);
+
+    let text = metrics.render().unwrap();
+    for expected in [
+        r#"cap_locator_device_present{id="SN-CAP25001",product_id="0x1455",serial="SN-CAP25001",vendor_id="0x04d8"} 0"#,
+        r#"cap_locator_commands_total{command="status"} 3"#,
+        r#"cap_locator_protocol_errors_total{kind="timeout"} 1"#,
+        r#"cap_locator_protocol_errors_total{kind="bad_header"} 1"#,
+        r#"cap_locator_status_latency_seconds_count{id="SN-CAP25001"} 1"#,
+    ] {
+        assert!(text.contains(expected), "{} が無い:\n{}", expected, text);
+    }
+    // 切断されたlocatorのLEDは出さない
+    assert!(!text.contains("cap_locator_led_on{"), "{}", text);
+
+    let err = anyhow::anyhow!("other");
+    assert_eq!(error_kind(&err), "other");
+}
+
+// `serve` 経由でも既存のハンドラがそのまま動き、開いている間はサーバー側でロックを持つことを確認
+#[test]
+fn remote_backend_runs_handlers_through_serve() {
+    use std::sync::atomic::Ordering;
+    use std::sync::mpsc;
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let (addr_tx, addr_rx) = mpsc::channel();
+    let (event_tx, events) = mpsc::channel();
+    let serving = {
+        let stop = Arc::clone(&stop);
+        std::thread::spawn(move || {
+            let mut other = descriptor(Some("KEYBOARD"), "/dev/hidraw9");
+            other.vendor_id = 0x1234;
+            let mut backend = MockBackend::new(
+                vec![descriptor(Some("SN-REMOTE01"), "/dev/hidraw0"), other],
+                vec![0xff, 0x05],
+            );
+            let access = AccessControl::new(DEFAULT_RATE_LIMIT).with_token("s3cret").unwrap();
+            let mut server = RemoteServer::bind("127.0.0.1:0", access, FilterArgs::default())
+                .unwrap()
+                .lock_timeout(Duration::ZERO);
+            addr_tx.send(server.local_addr().unwrap()).unwrap();
+            server
+                .run(&mut backend, &ProfileRegistry::builtin(), &stop, &mut |event| {
+                    event_tx.send(event.clone()).unwrap()
+                })
+                .unwrap();
+        })
+    };
+    let url = format!("http://{}", addr_rx.recv().unwrap());
+
+    let wrong = RemoteBackend::new(&url, Some("guess")).unwrap();
+    let err = wrong.devices(&no_filter()).unwrap_err();
+    assert!(format!("{:#}", err).contains("トークンが違います"), "{:#}", err);
+    assert!(matches!(events.recv().unwrap(), ServeEvent::Rejected { .. }));
+    assert!(RemoteBackend::new(&url, None).is_err());
+    let https = RemoteBackend::new(&url.replace("http://", "https://"), Some("s3cret"));
+    assert!(https.is_err_and(|err| err.to_string().contains("TLSに対応していません")));
+
+    // 大きすぎる本文は読まずに断る
+    {
+        use std::io::{Read, Write};
+        let mut stream = std::net::TcpStream::connect(url.trim_start_matches("http://")).unwrap();
+        let request = format!(
+            "POST /v1/open HTTP/1.1\r\nHost: x\r\nAuthorization: Bearer s3cret\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            MAX_BODY + 1
+        );
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 413"), "{}", response);
+    }
+
+    let remote = RemoteBackend::new(&url, Some("s3cret")).unwrap();
+    // プロファイルの対象ではないHIDデバイスは見えない
+    let devices = remote.devices(&no_filter()).unwrap();
+    assert_eq!(devices, vec![descriptor(Some("SN-REMOTE01"), "/dev/hidraw0")]);
+
+    let protocol = ProtocolArgs {
+        report_len: Some(2),
+        read_timeout_ms: 100,
+    };
+    {
+        let handle = remote.open(&devices[0]).unwrap();
+        let status = DeviceProfile::cap_locator().query_status(handle.as_ref(), &protocol).unwrap();
+        assert_eq!(status.mask, 0x05);
+        assert_eq!(status.raw, vec![0xff, 0x05]);
+        // クライアントが決める読み取り長は上限を超えると断る
+        let err = handle.read_timeout(&mut vec![0u8; MAX_READ_LEN + 1], 10).unwrap_err();
+        assert!(err.to_string().contains("バイトまで"), "{}", err);
+        assert!(matches!(events.recv().unwrap(), ServeEvent::Opened { ref id, .. } if id == "SN-REMOTE01"));
+        // サーバー側のロックで、同じlocatorは同時に開けない
+        let err = remote.open(&devices[0]).err().unwrap();
+        assert!(format!("{:#}", err).contains("使用中です"), "{:#}", err);
+    }
+    assert!(matches!(events.recv().unwrap(), ServeEvent::Closed { .. }));
+
+    let args = SetArgs {
+        id: Some("SN-REMOTE01".to_string()),
+        filter: no_filter(),
+        protocol,
+        lock: LockArgs {
+            lock_timeout: Duration::from_secs(1),
+        },
+        on_value: 0x05,
+        off_value: 0x00,
+        hold_for: None,
+    };
+    handle_set(&remote, &ProfileRegistry::builtin(), &args, &EnvDefaults::default(), true).unwrap();
+
+    stop.store(true, Ordering::SeqCst);
+    serving.join().unwrap();
+}
+
+const CLIENTS_CONFIG: &str = r#"
+[groups]
+desk = ["SN-REMOTE01"]
+
+[[clients]]
+name = "dashboard"
+token = "view"
+
+[[clients]]
+name = "desk-bot"
+token = "ctl"
+permission = "control"
+targets = ["desk"]
+rate_limit = 100
+
+[[clients]]
+name = "lab-pc"
+cert_sha256 = "AB:CD:EF"
+permission = "control"
+"#;
+
+// 設定ファイルのクライアントがトークン/証明書の指紋で決まり、権限と対象が反映されることを確認
+#[test]
+fn access_control_maps_credentials_to_clients() {
+    let config = Config::parse(CLIENTS_CONFIG).unwrap();
+    let access = AccessControl::load(&config, Some("admin"), 2.0).unwrap();
+    let desk = descriptor(Some("SN-REMOTE01"), "/dev/hidraw0");
+    let other = descriptor(Some("SN-REMOTE02"), "/dev/hidraw1");
+
+    let admin = access.authenticate(Some("admin"), None).unwrap();
+    assert_eq!((admin.name.as_str(), admin.permission), ("token", Permission::Control));
+    assert!(admin.authorize(&other, Permission::Control).is_ok());
+
+    let viewer = access.authenticate(Some("view"), None).unwrap();
+    assert_eq!(viewer.permission, Permission::Read);
+    assert!(viewer.authorize(&desk, Permission::Read).is_ok());
+    assert!(matches!(viewer.authorize(&desk, Permission::Control), Err(Denied::Forbidden(_))));
+
+    let bot = access.authenticate(Some("ctl"), Some("ab:cd:ef")).unwrap();
+    assert_eq!(bot.name, "desk-bot");
+    assert!(bot.authorize(&desk, Permission::Control).is_ok());
+    assert!(!bot.can_access(&other));
+    assert!(bot.can_access_id("SN-REMOTE01") && !bot.can_access_id("SN-REMOTE02"));
+    // 対象は部分一致ではなく完全一致か、明示した `*` のパターン
+    assert!(!bot.can_access_id("SN-REMOTE010") && !bot.can_access_id("REMOTE01"));
+    assert!(!bot.can_access(&descriptor(Some("SN-REMOTE010"), "/dev/hidraw2")));
+    let lab = Caller {
+        targets: Some(vec!["SN-LAB*".to_string(), "port:1-2.*".to_string()]),
+        ..bot.clone()
+    };
+    assert!(lab.can_access_id("SN-LAB07") && !lab.can_access_id("XSN-LAB07"));
+    assert!(lab.can_access_id("port:1-2.3") && !lab.can_access_id("port:1-3"));
+    let mut by_port = descriptor(None, "/dev/hidraw3");
+    by_port.port = Some("1-2.4".to_string());
+    assert!(lab.can_access(&by_port));
+
+    assert_eq!(access.authenticate(None, Some("abcdef")).unwrap().name, "lab-pc");
+    assert!(matches!(access.authenticate(Some("guess"), None), Err(Denied::Unauthenticated(_))));
+    assert!(matches!(access.authenticate(None, Some("0000")), Err(Denied::Unauthenticated(_))));
+    assert!(matches!(access.authenticate(None, None), Err(Denied::Unauthenticated(_))));
+
+    // 要求数はクライアントごとに数え、[[clients]] の rate_limit が `--rate-limit` より優先される
+    assert!(access.admit(Some(viewer), "10.0.0.2:5000").is_ok());
+    assert!(access.admit(Some(viewer), "10.0.0.2:5000").is_ok());
+    assert!(matches!(access.admit(Some(viewer), "10.0.0.2:5000"), Err(Denied::RateLimited(_))));
+    assert!(access.admit(Some(bot), "10.0.0.2:5000").is_ok());
+    assert!(access.admit(Some(bot), "10.0.0.2:5000").is_ok());
+    assert!(access.admit(Some(bot), "10.0.0.2:5000").is_ok());
+    assert!(access.admit(None, "10.0.0.2:5000").is_ok());
+    // 認証できない要求は、接続し直してポートが変わっても同じ接続元として数える
+    assert!(access.admit(None, "10.0.0.3:6001").is_ok());
+    assert!(access.admit(None, "10.0.0.3:6002").is_ok());
+    assert!(matches!(access.admit(None, "10.0.0.3:6003"), Err(Denied::RateLimited(_))));
+
+    assert!(AccessControl::load(&Config::default(), None, 10.0).is_err());
+    let err = AccessControl::load(&config, Some("view"), 10.0).err().unwrap();
+    assert!(format!("{:#}", err).contains("他のクライアントと同じ"), "{:#}", err);
+    let err = AccessControl::load(&Config::parse("[[clients]]\nname = \"x\"\n").unwrap(), None, 10.0)
+        .err()
+        .unwrap();
+    assert!(format!("{:#}", err).contains("いずれかを指定"), "{:#}", err);
+}
+
+#[test]
+fn rate_limiter_refills_per_second() {
+    let limiter = RateLimiter::default();
+    let start = std::time::Instant::now();
+    assert!(limiter.check("a", 2.0, start));
+    assert!(limiter.check("a", 2.0, start));
+    assert!(!limiter.check("a", 2.0, start));
+    assert!(limiter.check("b", 2.0, start));
+    assert!(limiter.check("a", 2.0, start + Duration::from_millis(500)));
+    assert!(!limiter.check("a", 2.0, start + Duration::from_millis(600)));
+    assert!(limiter.check("a", 0.0, start));
+
+    // キーが増え続けても、満タンに戻ったものや古いものから捨てて上限を超えない
+    for i in 0..MAX_RATE_BUCKETS + 100 {
+        assert!(limiter.check(&format!("client:{}", i), 2.0, start));
+    }
+    assert!(limiter.len() <= MAX_RATE_BUCKETS);
+    let later = start + Duration::from_secs(2);
+    assert!(limiter.check("client:new", 2.0, later));
+    assert!(limiter.len() < 10, "{}", limiter.len());
+}
+
+#[test]
+fn profile_tells_queries_from_state_changes() {
+    let profile = DeviceProfile::cap_locator();
+    assert!(profile.is_query(&[0x01, 0x00]));
+    assert!(!profile.is_query(&[0x02, 0x05]));
+    assert_eq!(profile.command_name(&[0x02, 0x05]), "set");
+    assert!(!profile.is_query(&[]));
+    assert!(profile.is_query(&[0x03]));
+    // EEPROMは読み出し(0x00)だけが問い合わせ
+    assert!(profile.is_query(&[0x06, 0x00]));
+    assert!(!profile.is_query(&[0x06, 0x01, 0x05]));
+    assert!(!profile.is_query(&[0x05, 0x01, 0x00, 0x04]));
+    assert_eq!(profile.command_name(&[0x7f]), "unknown");
+}
+
+// `serve` がクライアントごとに見えるlocatorを絞り、読み取り専用のクライアントのLED設定を断って監査ログに残すことを確認
+#[test]
+fn serve_enforces_client_permissions_and_audits_changes() {
+    use std::sync::atomic::Ordering;
+    use std::sync::mpsc;
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let (addr_tx, addr_rx) = mpsc::channel();
+    let (audit_tx, audits) = mpsc::channel::<AuditRecord>();
+    let serving = {
+        let stop = Arc::clone(&stop);
+        std::thread::spawn(move || {
+            let mut backend = MockBackend::new(
+                vec![
+                    descriptor(Some("SN-REMOTE01"), "/dev/hidraw0"),
+                    descriptor(Some("SN-REMOTE02"), "/dev/hidraw1"),
+                ],
+                vec![0xff, 0x05],
+            );
+            let config = Config::parse(CLIENTS_CONFIG).unwrap();
+            let access = AccessControl::load(&config, None, 0.0).unwrap();
+            let mut server = RemoteServer::bind("127.0.0.1:0", access, FilterArgs::default()).unwrap();
+            addr_tx.send(server.local_addr().unwrap()).unwrap();
+            server
+                .run(&mut backend, &ProfileRegistry::builtin(), &stop, &mut |event| {
+                    if let ServeEvent::Audit(entry) = event {
+                        audit_tx.send(entry.clone()).unwrap();
+                    }
+                })
+                .unwrap();
+        })
+    };
+    let url = format!("http://{}", addr_rx.recv().unwrap());
+    let mut args = SetArgs {
+        id: Some("SN-REMOTE01".to_string()),
+        filter: no_filter(),
+        protocol: ProtocolArgs {
+            report_len: Some(2),
+            read_timeout_ms: 100,
+        },
+        lock: LockArgs {
+            lock_timeout: Duration::from_secs(1),
+        },
+        on_value: 0x05,
+        off_value: 0x00,
+        hold_for: None,
+    };
+
+    let viewer = RemoteBackend::new(&url, Some("view")).unwrap();
+    assert_eq!(viewer.devices(&no_filter()).unwrap().len(), 2);
+    // ステータスの問い合わせは読み取り専用でもできる
+    let device = viewer.devices(&no_filter()).unwrap().remove(0);
+    let handle = viewer.open(&device).unwrap();
+    let protocol = args.protocol.clone();
+    assert_eq!(DeviceProfile::cap_locator().query_status(handle.as_ref(), &protocol).unwrap().mask, 0x05);
+    // 読み取り専用のクライアントもロックを持つので、開いている間は状態を変えるクライアントの通信と混ざらない
+    let bot = RemoteBackend::new(&url, Some("ctl")).unwrap();
+    let err = bot.open(&device).err().expect("locked by the viewer");
+    assert!(format!("{:#}", err).contains("dashboard が使用中"), "{:#}", err);
+    drop(handle);
+    let held = bot.open(&device).unwrap();
+    let err = viewer.open(&device).err().expect("locked by the bot");
+    assert!(format!("{:#}", err).contains("desk-bot が使用中"), "{:#}", err);
+    drop(held);
+    let err = handle_set(&viewer, &ProfileRegistry::builtin(), &args, &EnvDefaults::default(), true).unwrap_err();
+    assert!(format!("{:#}", err).contains("読み取り専用"), "{:#}", err);
+    let denied = audits.recv().unwrap();
+    assert_eq!((denied.user.as_str(), denied.id.as_str(), denied.command.as_str()), ("dashboard", "SN-REMOTE01", "set"));
+    assert_eq!((denied.origin, denied.change_text()), (Origin::Api, "mask=0x05".to_string()));
+    assert!(denied.error.as_deref().is_some_and(|error| error.contains("読み取り専用")));
+
+    assert_eq!(bot.devices(&no_filter()).unwrap(), vec![descriptor(Some("SN-REMOTE01"), "/dev/hidraw0")]);
+    handle_set(&bot, &ProfileRegistry::builtin(), &args, &EnvDefaults::default(), true).unwrap();
+    let applied = audits.recv().unwrap();
+    assert_eq!((applied.user.as_str(), applied.result.as_str()), ("desk-bot", "ok"));
+    assert!(applied.line().contains("user=desk-bot"));
+    // 対象外のlocatorは一覧に出ないので選べない
+    args.id = Some("SN-REMOTE02".to_string());
+    assert!(handle_set(&bot, &ProfileRegistry::builtin(), &args, &EnvDefaults::default(), true).is_err());
+
+    stop.store(true, Ordering::SeqCst);
+    serving.join().unwrap();
+    assert!(audits.try_recv().is_err());
+}
+
+#[test]
+#[cfg(feature = "grpc")]
+fn remote_endpoint_accepts_host_port_and_grpc_scheme() {
+    assert_eq!(remote_endpoint("lab-01:50051", false).unwrap(), "http://lab-01:50051");
+    assert_eq!(remote_endpoint("grpc://lab-01:50051/", false).unwrap(), "http://lab-01:50051");
+    assert_eq!(remote_endpoint("lab-01:50051", true).unwrap(), "https://lab-01:50051");
+    assert!(remote_endpoint("https://lab-01", false).is_err());
+    assert!(remote_endpoint("", false).is_err());
+}
+
+/// 抜き差しを再現するため、`present` が落ちている間はlocatorを列挙しないバックエンド
+#[cfg(feature = "grpc")]
+struct HotplugBackend {
+    inner: MockBackend,
+    present: Arc<std::sync::atomic::AtomicBool>,
+}
+
+#[cfg(feature = "grpc")]
+impl LocatorBackend for HotplugBackend {
+    fn devices(&self, filter: &FilterArgs) -> anyhow::Result<Vec<DeviceDescriptor>> {
+        if self.present.load(std::sync::atomic::Ordering::SeqCst) {
+            self.inner.devices(filter)
+        } else {
+            Ok(Vec::new())
+        }
+    }
+
+    fn open(&self, device: &DeviceDescriptor) -> anyhow::Result<Box<dyn HidDeviceIo>> {
+        self.inner.open(device)
+    }
+}
+
+// gRPCのクライアントから一覧/ステータス/LED設定ができ、抜き差しがStreamEventsで届くことを確認
+#[test]
+#[cfg(feature = "grpc")]
+fn grpc_service_controls_locators_and_streams_hotplug() {
+    use std::sync::atomic::Ordering;
+
+    let mut access = AccessControl::new(DEFAULT_RATE_LIMIT).with_token("s3cret").unwrap();
+    let config = Config::parse(CLIENTS_CONFIG).unwrap();
+    access.add_client(&config.clients[0], &config).unwrap();
+    let server = GrpcServer::bind("127.0.0.1:0", access, None).unwrap();
+    let addr = server.local_addr();
+    let stop = Arc::new(AtomicBool::new(false));
+    let present = Arc::new(AtomicBool::new(true));
+    let options = ServeOptions {
+        filter: FilterArgs {
+            vendor_id: None,
+            product_id: None,
+            usage_page: None,
+            usage: None,
+            port: None,
+        },
+        protocol: ProtocolArgs {
+            report_len: Some(2),
+            read_timeout_ms: 100,
+        },
+        lock: LockArgs {
+            lock_timeout: Duration::from_secs(1),
+        },
+        interval: Duration::from_millis(50),
+    };
+    let serving = {
+        let stop = Arc::clone(&stop);
+        let present = Arc::clone(&present);
+        std::thread::spawn(move || {
+            let mut backend = HotplugBackend {
+                inner: MockBackend::new(vec![descriptor(Some("SN-GRPC0001"), "/dev/hidraw0")], vec![0xff, 0x05]),
+                present,
+            };
+            let mut audits = 0;
+            server
+                .run(&mut backend, &ProfileRegistry::builtin(), &options, &stop, &mut |_, _| {}, &mut |_| {
+                    audits += 1
+                })
+                .map(|()| audits)
+        })
+    };
+
+    let tls = RemoteTlsArgs::default();
+    let mut anonymous = GrpcClient::connect(&addr.to_string(), None, &tls).unwrap();
+    let err = anonymous.list().unwrap_err();
+    assert!(format!("{:#}", err).contains("トークンがありません"), "{:#}", err);
+    let mut viewer = GrpcClient::connect(&addr.to_string(), Some("view"), &tls).unwrap();
+    assert_eq!(viewer.list().unwrap().len(), 1);
+    let err = viewer.set_leds(Some("SN-GRPC0001"), 0x05).unwrap_err();
+    assert!(format!("{:#}", err).contains("読み取り専用"), "{:#}", err);
+
+    let mut client = GrpcClient::connect(&addr.to_string(), Some("s3cret"), &tls).unwrap();
+    let locators = client.list().unwrap();
+    assert_eq!(locators.len(), 1);
+    assert_eq!(locators[0].id, "SN-GRPC0001");
+    assert_eq!(locators[0].profile, "cap-locator");
+    assert_eq!(locators[0].descriptor().unwrap().vendor_id, 0x04d8);
+
+    let statuses = client.status(None, true).unwrap();
+    assert_eq!(statuses.len(), 1);
+    assert_eq!(statuses[0].mask, 0x05);
+    assert_eq!(statuses[0].lit, vec!["RC2", "RC4"]);
+    assert_eq!(statuses[0].raw, vec![0xff, 0x05]);
+
+    let status = client.set_leds(Some("SN-GRPC0001"), 0x05).unwrap();
+    assert_eq!(status.locator_status().unwrap().mask, 0x05);
+    let err = client.set_leds(Some("SN-UNKNOWN"), 0x1f).unwrap_err();
+    assert!(format!("{:#}", err).contains("一致するlocatorがありませんでした"), "{:#}", err);
+    let err = client.status(Some("SN-UNKNOWN"), false).unwrap_err();
+    assert!(format!("{:#}", err).contains("見つかりませんでした"), "{:#}", err);
+
+    let mut events = client.events(Some("SN-GRPC0001")).unwrap();
+    present.store(false, Ordering::SeqCst);
+    // 接続中のステータス取得は常に成功するので、changed/failed は来ない
+    let mut next_kind = || {
+        let event = events.next(Duration::from_secs(5)).unwrap().expect("イベントが届かない");
+        assert_eq!(event.id, "SN-GRPC0001");
+        event.kind()
+    };
+    assert_eq!(next_kind(), Kind::Detached);
+    present.store(true, Ordering::SeqCst);
+    assert_eq!(next_kind(), Kind::Attached);
+
+    stop.store(true, Ordering::SeqCst);
+    // 断ったものも含め、対象が見つかったSetLedsは全て記録する
+    assert_eq!(serving.join().unwrap().unwrap(), 2);
+}
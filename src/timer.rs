@@ -0,0 +1,134 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+
+use crate::audit::{self, Change};
+use crate::cli::ProtocolArgs;
+use crate::hid::{DeviceDescriptor, HidDeviceIo, LocatorStatus};
+use crate::profile::DeviceProfile;
+
+/// Ctrl-Cを確認する間隔。これより長くsleepしない
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// 時刻取得と待機を抽象化するトレイト（テストで時間を進められるようにするため）
+pub trait Clock {
+    fn now(&self) -> Instant;
+    fn sleep(&self, duration: Duration);
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimerOutcome {
+    /// 指定時間が経過した
+    Expired,
+    /// Ctrl-Cなどで中断された
+    Cancelled,
+}
+
+/// `duration` が経過するか `cancel` が立つまで待つ
+pub fn wait_for(clock: &dyn Clock, duration: Duration, cancel: &AtomicBool) -> TimerOutcome {
+    let deadline = clock.now() + duration;
+    loop {
+        if cancel.load(Ordering::SeqCst) {
+            return TimerOutcome::Cancelled;
+        }
+        let now = clock.now();
+        if now >= deadline {
+            return TimerOutcome::Expired;
+        }
+        clock.sleep((deadline - now).min(POLL_INTERVAL));
+    }
+}
+
+/// 点灯状態を `duration` だけ維持してから `previous_mask` へ戻す
+///
+/// - 待っている間はデバイスを開かず、戻すときに `open` で開き直す(他のCLIをブロックしないため)
+/// - 中断された場合も待ちを打ち切ってすぐに戻す
+/// - 戻した後のステータスを返す
+#[allow(clippy::too_many_arguments)]
+pub fn hold_then_restore<D: HidDeviceIo>(
+    open: impl FnOnce() -> Result<D>,
+    profile: &DeviceProfile,
+    target: &DeviceDescriptor,
+    protocol: &ProtocolArgs,
+    previous_mask: u8,
+    duration: Duration,
+    clock: &dyn Clock,
+    cancel: &AtomicBool,
+) -> Result<(TimerOutcome, LocatorStatus)> {
+    let outcome = wait_for(clock, duration, cancel);
+    let device = open()?;
+    audit::set_mask(profile, &device, protocol, target, previous_mask, &Change::cli("restore"))?;
+    let status = profile.query_status(&device, protocol)?;
+    Ok((outcome, status))
+}
+
+#[cfg(test)]
+pub mod fake {
+    use std::cell::{Cell, RefCell};
+
+    use super::*;
+
+    /// sleepした分だけ時刻が進むテスト用クロック。指定回数sleepしたらcancelを立てられる
+    pub struct FakeClock<'a> {
+        now: Cell<Instant>,
+        pub sleeps: RefCell<Vec<Duration>>,
+        cancel_after: Option<(usize, &'a AtomicBool)>,
+    }
+
+    impl Default for FakeClock<'_> {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl<'a> FakeClock<'a> {
+        pub fn new() -> Self {
+            Self {
+                now: Cell::new(Instant::now()),
+                sleeps: RefCell::new(Vec::new()),
+                cancel_after: None,
+            }
+        }
+
+        pub fn cancelling_after(sleeps: usize, cancel: &'a AtomicBool) -> Self {
+            Self {
+                cancel_after: Some((sleeps, cancel)),
+                ..Self::new()
+            }
+        }
+
+        pub fn elapsed(&self) -> Duration {
+            self.sleeps.borrow().iter().sum()
+        }
+    }
+
+    impl Clock for FakeClock<'_> {
+        fn now(&self) -> Instant {
+            self.now.get()
+        }
+
+        fn sleep(&self, duration: Duration) {
+            self.now.set(self.now.get() + duration);
+            self.sleeps.borrow_mut().push(duration);
+            if let Some((count, cancel)) = self.cancel_after
+                && self.sleeps.borrow().len() >= count
+            {
+                cancel.store(true, Ordering::SeqCst);
+            }
+        }
+    }
+}
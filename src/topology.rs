@@ -0,0 +1,37 @@
+use std::path::Path;
+
+/// HIDデバイスパスから、locatorが挿さっているUSBポートの経路(`1-2.3` のようなbus-port.port)を求める
+///
+/// 同じハブの同じポートに挿せば再起動や挿し直しでも変わらないので、シリアル番号の無いlocatorのidに使う。
+/// Linuxのhidraw(`/dev/hidrawN`)だけ対応し、それ以外はNone
+#[cfg(target_os = "linux")]
+pub fn usb_port(hid_path: &str) -> Option<String> {
+    let node = Path::new(hid_path).file_name()?;
+    let device = Path::new("/sys/class/hidraw").join(node).join("device");
+    let resolved = std::fs::canonicalize(device).ok()?;
+    port_from_sysfs_path(&resolved)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn usb_port(_hid_path: &str) -> Option<String> {
+    None
+}
+
+/// sysfsのデバイスパス(`/sys/devices/.../usb1/1-2/1-2.3/1-2.3:1.0/0003:04D8:1455.0005`)から
+/// 最も深いUSBポートの経路を取り出す
+pub fn port_from_sysfs_path(path: &Path) -> Option<String> {
+    path.components()
+        .rev()
+        .filter_map(|c| c.as_os_str().to_str())
+        .find(|c| is_port_chain(c))
+        .map(String::from)
+}
+
+/// `1-2` や `3-1.4.2` の形か (bus番号-ポート番号.ポート番号...)
+pub fn is_port_chain(text: &str) -> bool {
+    let all_digits = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+    match text.split_once('-') {
+        Some((bus, ports)) => all_digits(bus) && ports.split('.').all(all_digits),
+        None => false,
+    }
+}
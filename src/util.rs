@@ -1,3 +1,7 @@
+use std::time::Duration;
+
+use crate::hid::PORT_ID_PREFIX;
+
 pub fn parse_hex_or_dec_u16(input: &str) -> std::result::Result<u16, String> {
     if let Some(stripped) = input
         .strip_prefix("0x")
@@ -28,6 +32,24 @@ pub fn format_bytes(bytes: &[u8]) -> String {
         .join(" ")
 }
 
+/// `--port` の値。`1-2.3` の形(bus-port.port)でなければエラー
+pub fn parse_port_chain(input: &str) -> std::result::Result<String, String> {
+    let chain = input.strip_prefix(PORT_ID_PREFIX).unwrap_or(input);
+    if crate::topology::is_port_chain(chain) {
+        Ok(chain.to_string())
+    } else {
+        Err(format!("{} はUSBポートの経路(1-2.3 のような形)ではありません", input))
+    }
+}
+
+/// `format_bytes` の逆。空白区切りの16進バイト列を読む
+pub fn parse_hex_bytes(input: &str) -> std::result::Result<Vec<u8>, String> {
+    input
+        .split_whitespace()
+        .map(|b| u8::from_str_radix(b, 16).map_err(|e| format!("{}: {}", b, e)))
+        .collect()
+}
+
 pub fn format_usage(usage_page: Option<u16>, usage: Option<u16>) -> String {
     match (usage_page, usage) {
         (Some(page), Some(usage)) => format!("0x{page:04x}:0x{usage:04x}"),
@@ -36,3 +58,66 @@ pub fn format_usage(usage_page: Option<u16>, usage: Option<u16>) -> String {
         (None, None) => "-".to_string(),
     }
 }
+
+/// `parse_duration` が受け付ける最長の期間 (36500d)。期限の計算で `Instant` や日時があふれないようにする
+pub const MAX_DURATION: Duration = Duration::from_secs(36500 * 24 * 60 * 60);
+
+/// `10m` / `30s` / `1h30m` / `500ms` のような期間指定を解釈する（単位省略時は秒）
+pub fn parse_duration(input: &str) -> std::result::Result<Duration, String> {
+    let input = input.trim();
+    if input.is_empty() {
+        return Err("期間が空です".to_string());
+    }
+    let too_long = || format!("期間が長すぎます (36500dまで): {}", input);
+    if let Ok(secs) = input.parse::<u64>() {
+        return Some(Duration::from_secs(secs))
+            .filter(|duration| *duration <= MAX_DURATION)
+            .ok_or_else(too_long);
+    }
+
+    let mut total = Duration::ZERO;
+    let mut rest = input;
+    while !rest.is_empty() {
+        let digits = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+        if digits == 0 {
+            return Err(format!("数値がありません: {}", input));
+        }
+        let value: u64 = rest[..digits].parse().map_err(|e| format!("{}", e))?;
+        rest = &rest[digits..];
+        let unit_len = rest.find(|c: char| c.is_ascii_digit()).unwrap_or(rest.len());
+        let unit = &rest[..unit_len];
+        rest = &rest[unit_len..];
+        let part = match unit {
+            "ms" => Some(Duration::from_millis(value)),
+            "s" => Some(Duration::from_secs(value)),
+            "m" => value.checked_mul(60).map(Duration::from_secs),
+            "h" => value.checked_mul(60 * 60).map(Duration::from_secs),
+            "d" => value.checked_mul(60 * 60 * 24).map(Duration::from_secs),
+            other => return Err(format!("不明な単位です: {:?} (ms/s/m/h/d)", other)),
+        };
+        total = part
+            .and_then(|part| total.checked_add(part))
+            .filter(|total| *total <= MAX_DURATION)
+            .ok_or_else(too_long)?;
+    }
+    Ok(total)
+}
+
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs == 0 {
+        return format!("{}ms", duration.as_millis());
+    }
+    let (h, m, s) = (secs / 3600, secs / 60 % 60, secs % 60);
+    let mut out = String::new();
+    if h > 0 {
+        out += &format!("{}h", h);
+    }
+    if m > 0 {
+        out += &format!("{}m", m);
+    }
+    if s > 0 || out.is_empty() {
+        out += &format!("{}s", s);
+    }
+    out
+}
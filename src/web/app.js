@@ -0,0 +1,177 @@
+// daemon の /api/locators と /ws を使って locator の一覧と LED の状態を表示し、操作する
+"use strict";
+
+// トークンはURLに載せず (ログや履歴に残るため)、求められたら入力してもらいタブの間だけ覚える
+const TOKEN_KEY = "cap-locator-token";
+let token = sessionStorage.getItem(TOKEN_KEY);
+
+const list = document.getElementById("locators");
+const template = document.getElementById("locator");
+const search = document.getElementById("search");
+const connection = document.getElementById("connection");
+const message = document.getElementById("message");
+const empty = document.getElementById("empty");
+const login = document.getElementById("login");
+
+let locators = [];
+let socket = null;
+let nextRequest = 1;
+let retryDelay = 1000;
+// 読み取り専用の接続 (トークン無しのループバックなど) ではLEDのボタンを止め、トークンの入力を促す
+let canControl = false;
+
+function showMessage(text) {
+  message.textContent = text;
+  message.hidden = !text;
+}
+
+function setConnection(online, text) {
+  connection.textContent = text;
+  connection.classList.toggle("online", online);
+  connection.classList.toggle("offline", !online);
+}
+
+function describe(locator) {
+  if (locator.mask === null) {
+    return "状態を確認中";
+  }
+  let text = locator.blinking ? "点滅中" : locator.mask === 0 ? "消灯" : "点灯";
+  if (locator.leds.length > 0) {
+    text += ` (${locator.leds.join(", ")})`;
+  }
+  if (locator.controlled_by) {
+    text += ` ・ ${locator.controlled_by} が操作中`;
+  }
+  return text;
+}
+
+function render() {
+  const words = search.value.trim().toLowerCase();
+  const shown = locators.filter((locator) =>
+    [locator.alias ?? "", locator.id].some((text) => text.toLowerCase().includes(words)),
+  );
+  list.replaceChildren(
+    ...shown.map((locator) => {
+      const item = template.content.firstElementChild.cloneNode(true);
+      const lamp = item.querySelector(".lamp");
+      lamp.classList.toggle("on", locator.mask !== null && (locator.mask !== 0 || locator.blinking));
+      lamp.classList.toggle("blink", locator.blinking);
+      item.querySelector(".alias").textContent = locator.alias ?? locator.id;
+      item.querySelector(".id").textContent = locator.alias ? locator.id : "";
+      item.querySelector(".detail").textContent = describe(locator);
+      for (const button of item.querySelectorAll("button[data-leds]")) {
+        button.disabled = !canControl;
+        button.addEventListener("click", () => setLeds(locator.id, button.dataset.leds));
+      }
+      const release = item.querySelector("button[data-release]");
+      release.hidden = !locator.controlled_by;
+      release.disabled = !canControl;
+      release.addEventListener("click", () => send({ action: "release", id: locator.id }));
+      return item;
+    }),
+  );
+  empty.hidden = shown.length > 0;
+}
+
+function setLeds(id, leds) {
+  const value = leds === "blink" ? { mask: "on", pattern: "blink" } : leds;
+  send({ action: "set", id, leds: value });
+}
+
+function send(request) {
+  if (!socket || socket.readyState !== WebSocket.OPEN) {
+    showMessage("daemon に接続していません");
+    return;
+  }
+  socket.send(JSON.stringify({ ...request, request_id: nextRequest++ }));
+}
+
+function receive(event) {
+  const data = JSON.parse(event.data);
+  switch (data.type) {
+    case "session":
+      canControl = data.control;
+      // 認証の無いdaemonではトークンを入れても操作できないので、入力ではなく理由を出す
+      login.hidden = canControl || !data.auth;
+      if (!canControl && !data.auth) {
+        showMessage("読み取り専用です (daemon を --ws-token 付きで起動すると操作できます)");
+      }
+      render();
+      break;
+    case "state":
+      locators = data.locators;
+      render();
+      break;
+    case "result":
+      showMessage(data.ok ? "" : `操作できませんでした: ${data.error}`);
+      break;
+    case "failed":
+      showMessage(`${data.id}: ${data.error}`);
+      break;
+  }
+}
+
+function askToken() {
+  const input = prompt("daemon のトークンを入力してください (--ws-token)");
+  if (!input) {
+    return false;
+  }
+  token = input.trim();
+  sessionStorage.setItem(TOKEN_KEY, token);
+  return true;
+}
+
+async function load() {
+  try {
+    const headers = token ? { Authorization: `Bearer ${token}` } : {};
+    const response = await fetch("/api/locators", { headers });
+    const data = await response.json();
+    if (response.status === 401 && askToken()) {
+      load();
+      socket?.close();
+      return;
+    }
+    if (!response.ok) {
+      showMessage(data.error);
+      return;
+    }
+    locators = data.locators;
+    render();
+  } catch (err) {
+    showMessage(`一覧を取得できません: ${err}`);
+  }
+}
+
+function connect() {
+  const scheme = location.protocol === "https:" ? "wss:" : "ws:";
+  // ブラウザのWebSocketはヘッダーを付けられないので、トークンはサブプロトコルで送る
+  const protocols = token ? ["cap-locator", `cap-locator.bearer.${token}`] : ["cap-locator"];
+  try {
+    socket = new WebSocket(`${scheme}//${location.host}/ws`, protocols);
+  } catch (err) {
+    setConnection(false, "切断");
+    showMessage(`接続できません (トークンは英数字と - _ . だけにしてください): ${err}`);
+    return;
+  }
+  socket.addEventListener("open", () => {
+    retryDelay = 1000;
+    setConnection(true, "接続中");
+    showMessage("");
+  });
+  socket.addEventListener("message", receive);
+  socket.addEventListener("close", () => {
+    setConnection(false, "切断 (再接続します)");
+    setTimeout(connect, retryDelay);
+    retryDelay = Math.min(retryDelay * 2, 30000);
+  });
+}
+
+search.addEventListener("input", render);
+login.addEventListener("click", () => {
+  if (askToken()) {
+    load();
+    socket?.close();
+  }
+});
+load();
+connect();
@@ -0,0 +1,719 @@
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result, anyhow, bail};
+use chrono::Local;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use tracing::{debug, warn};
+use tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tungstenite::http::{HeaderValue, StatusCode};
+use tungstenite::{Message, WebSocket};
+
+use crate::auth::{AccessControl, Caller, Denied, Permission, bearer_token};
+use crate::daemon::{DaemonEvent, LocatorState};
+use crate::mqtt::DesiredLeds;
+
+/// WebSocketを受け付けるパス
+pub const WS_PATH: &str = "/ws";
+
+/// 受信を待つ間隔。この間隔で送るべきイベントが無いか確認する
+const READ_POLL: Duration = Duration::from_millis(100);
+
+/// ハンドシェイクを待つ最大時間
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// ブラウザのWebSocketはヘッダーを付けられないので、`Sec-WebSocket-Protocol` で
+/// `cap-locator` と `cap-locator.bearer.<トークン>` を申し出てもらい、応答では `cap-locator` を選ぶ
+pub const WS_PROTOCOL: &str = "cap-locator";
+
+const PROTOCOL_HEADER: &str = "Sec-WebSocket-Protocol";
+
+/// トークンを載せるサブプロトコルの接頭辞
+pub const WS_TOKEN_PROTOCOL_PREFIX: &str = "cap-locator.bearer.";
+
+/// 同時に扱う接続数 (画面のHTTPも含む)。超えた接続には503を返して切る
+pub const MAX_CONNECTIONS: usize = 64;
+
+/// `--ws-token` も [[clients]] も無いときのクライアント名 (ループバックでだけ読み取り専用で許す)
+const ANONYMOUS: &str = "anonymous";
+
+/// 接続中のlocatorと状態をJSONで返すパス (WebSocketの `state` と同じ形)
+pub const API_LOCATORS_PATH: &str = "/api/locators";
+
+/// 要求のヘッダーの大きさと数の上限
+const MAX_HEAD_LEN: usize = 8192;
+const MAX_HEADERS: usize = 32;
+
+/// ヘッダーが揃うのを待つ間隔
+const HEAD_POLL: Duration = Duration::from_millis(10);
+
+const JSON_CONTENT_TYPE: &str = "application/json";
+
+/// 画面のファイル1つ分
+struct Asset {
+    path: &'static str,
+    content_type: &'static str,
+    body: &'static str,
+}
+
+/// 画面のファイル。実行ファイルに埋め込むので、配るのはバイナリ1つで済む
+const ASSETS: &[Asset] = &[
+    Asset {
+        path: "/",
+        content_type: "text/html; charset=utf-8",
+        body: include_str!("web/index.html"),
+    },
+    Asset {
+        path: "/app.js",
+        content_type: "text/javascript; charset=utf-8",
+        body: include_str!("web/app.js"),
+    },
+    Asset {
+        path: "/style.css",
+        content_type: "text/css; charset=utf-8",
+        body: include_str!("web/style.css"),
+    },
+];
+
+/// daemonからWebSocketのクライアントへ送るメッセージ
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum WsMessage {
+    /// 接続直後に最初に送る、この接続のクライアント名と操作できるか (読み取り専用なら画面のボタンを止める)
+    Session {
+        caller: String,
+        control: bool,
+        /// トークンで認証しているか。falseならトークンを送っても読み取り専用のまま
+        auth: bool,
+    },
+    /// 接続直後と、locatorやLEDの状態が変わったときの全体
+    State {
+        timestamp: String,
+        locators: Vec<LocatorState>,
+    },
+    Attached {
+        timestamp: String,
+        id: String,
+    },
+    Detached {
+        timestamp: String,
+        id: String,
+    },
+    /// daemonがLEDのマスクを送った
+    Applied {
+        timestamp: String,
+        id: String,
+        mask: u8,
+    },
+    /// daemon以外によってLEDのマスクが変わった
+    Changed {
+        timestamp: String,
+        id: String,
+        mask: u8,
+    },
+    Failed {
+        timestamp: String,
+        id: String,
+        error: String,
+    },
+    /// 制御メッセージへの応答
+    Result {
+        request_id: Option<Value>,
+        ok: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+}
+
+impl WsMessage {
+    pub fn from_daemon(timestamp: &str, event: &DaemonEvent) -> Self {
+        let timestamp = timestamp.to_string();
+        match event.clone() {
+            DaemonEvent::Attached { id } => WsMessage::Attached { timestamp, id },
+            DaemonEvent::Detached { id } => WsMessage::Detached { timestamp, id },
+            DaemonEvent::Applied { id, mask } => WsMessage::Applied { timestamp, id, mask },
+            DaemonEvent::Changed { id, mask } => WsMessage::Changed { timestamp, id, mask },
+            DaemonEvent::Failed { id, error } => WsMessage::Failed { timestamp, id, error },
+        }
+    }
+
+    /// 対象のlocator。`Session` と `State` と `Result` はNone
+    fn locator_id(&self) -> Option<&str> {
+        match self {
+            WsMessage::Attached { id, .. }
+            | WsMessage::Detached { id, .. }
+            | WsMessage::Applied { id, .. }
+            | WsMessage::Changed { id, .. }
+            | WsMessage::Failed { id, .. } => Some(id),
+            WsMessage::Session { .. } | WsMessage::State { .. } | WsMessage::Result { .. } => None,
+        }
+    }
+}
+
+/// クライアントから受け取る制御メッセージ
+///
+/// `{"action": "set", "id": "SN-CAP25001", "leds": "on", "request_id": 1}` のように送る
+#[derive(Clone, Debug, Deserialize)]
+struct ControlMessage {
+    #[serde(default)]
+    request_id: Option<Value>,
+    #[serde(flatten)]
+    request: ControlRequest,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+enum ControlRequest {
+    /// MQTTの `…/set` と同じ `on` / `off` / `0x04` / `{"mask": 4, "pattern": "blink"}`
+    Set { id: String, leds: Value },
+    /// スケジュールに戻す
+    Release { id: String },
+}
+
+/// 制御メッセージで求められた操作
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ControlAction {
+    Set(DesiredLeds),
+    Release,
+}
+
+/// daemonのループへ渡す制御メッセージ。`respond` でクライアントへ結果を返す
+pub struct Control {
+    pub caller: Caller,
+    /// 接続元のアドレス
+    pub client: String,
+    /// クライアントが指定したlocator id
+    pub id: String,
+    pub action: ControlAction,
+    request_id: Option<Value>,
+    reply: Sender<String>,
+}
+
+impl Control {
+    /// 切断済みのクライアントには送れないが、それで構わない
+    pub fn respond(&self, result: std::result::Result<(), String>) {
+        let message = WsMessage::Result {
+            request_id: self.request_id.clone(),
+            ok: result.is_ok(),
+            error: result.err(),
+        };
+        let _ = self.reply.send(to_text(&message));
+    }
+}
+
+struct Subscriber {
+    caller: Caller,
+    outbox: Sender<String>,
+}
+
+impl Subscriber {
+    /// 操作できないlocatorのイベントは送らない。切断済みならfalse
+    fn send(&self, message: &WsMessage) -> bool {
+        if message.locator_id().is_some_and(|id| !self.caller.can_access_id(id)) {
+            return true;
+        }
+        self.outbox.send(to_text(&visible_to(&self.caller, message))).is_ok()
+    }
+}
+
+/// `State` から `caller` が操作できないlocatorを除く
+fn visible_to(caller: &Caller, message: &WsMessage) -> WsMessage {
+    match message {
+        WsMessage::State { timestamp, locators } => WsMessage::State {
+            timestamp: timestamp.clone(),
+            locators: locators
+                .iter()
+                .filter(|state| caller.can_access_id(&state.id))
+                .cloned()
+                .collect(),
+        },
+        message => message.clone(),
+    }
+}
+
+#[derive(Default)]
+struct Hub {
+    subscribers: Vec<Subscriber>,
+    /// 最後に送った `State`。接続してきたクライアントに最初に送る
+    state: Option<WsMessage>,
+}
+
+/// daemonのイベントをWebSocketで配信し、制御メッセージを受け付ける (`daemon --ws-listen`)
+///
+/// - 同じポートで画面 (`/`) と `/api/locators` も返す
+/// - 接続ごとにスレッドを立て、`READ_POLL` ごとに受信と送信を切り替える。同時に `MAX_CONNECTIONS` まで
+/// - `access` があれば `Authorization: Bearer` か、ブラウザからは `Sec-WebSocket-Protocol` のトークンで認証する
+///   (URLに載せるとログや履歴に残るので `?token=` は受け付けない)。Noneならループバックでだけ待ち受け、読み取り専用にする
+/// - 他のサイトのページから接続されないよう、`Origin` が自分のホストでないハンドシェイクは断る
+/// - 制御メッセージは `wait` でdaemonのループが受け取る (locatorはループのスレッドだけが触る)
+pub struct WsServer {
+    local_addr: SocketAddr,
+    hub: Arc<Mutex<Hub>>,
+    controls: Receiver<Control>,
+}
+
+impl WsServer {
+    pub fn bind(listen: &str, access: Option<AccessControl>, on_value: u8) -> Result<Self> {
+        let listener =
+            TcpListener::bind(listen).with_context(|| format!("WebSocket用のポートを開けません: {}", listen))?;
+        Self::from_listener(listener, access, on_value)
+    }
+
+    /// 待ち受け済みのソケット (systemdのソケット活性化で渡されたものなど) を使う
+    pub fn from_listener(listener: TcpListener, access: Option<AccessControl>, on_value: u8) -> Result<Self> {
+        listener.set_nonblocking(false)?;
+        let local_addr = listener.local_addr()?;
+        if access.is_none() && !local_addr.ip().is_loopback() {
+            bail!(
+                "{} で待ち受けるには --ws-token か設定ファイルの [[clients]] を指定してください (認証なしで使えるのは127.0.0.1などのループバックだけです)",
+                local_addr
+            );
+        }
+        let hub = Arc::new(Mutex::new(Hub::default()));
+        let (sender, controls) = mpsc::channel();
+        let connection = Connection {
+            access: access.map(Arc::new),
+            on_value,
+            hub: Arc::clone(&hub),
+            controls: sender,
+        };
+        let active = Arc::new(AtomicUsize::new(0));
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(mut stream) => {
+                        // 受け付けはこのスレッドだけなので、数えてから増やしても上限を超えない
+                        if active.load(Ordering::SeqCst) >= MAX_CONNECTIONS {
+                            debug!("同時接続数が上限 ({}) なので断ります", MAX_CONNECTIONS);
+                            refuse_busy(&mut stream);
+                            continue;
+                        }
+                        let slot = ConnectionSlot::take(&active);
+                        let connection = connection.clone();
+                        thread::spawn(move || {
+                            connection.run(stream);
+                            drop(slot);
+                        });
+                    }
+                    Err(err) => warn!("WebSocketの接続を受け付けられません: {}", err),
+                }
+            }
+        });
+        Ok(Self {
+            local_addr,
+            hub,
+            controls,
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// 全てのクライアントへイベントを送る
+    pub fn publish(&self, timestamp: &str, event: &DaemonEvent) {
+        self.broadcast(&WsMessage::from_daemon(timestamp, event));
+    }
+
+    /// locatorやLEDの状態が前回から変わっていれば、全てのクライアントへ送る
+    pub fn publish_state(&self, timestamp: &str, locators: Vec<LocatorState>) {
+        let Ok(mut hub) = self.hub.lock() else {
+            return;
+        };
+        if matches!(&hub.state, Some(WsMessage::State { locators: last, .. }) if *last == locators) {
+            return;
+        }
+        let message = WsMessage::State {
+            timestamp: timestamp.to_string(),
+            locators,
+        };
+        hub.subscribers.retain(|subscriber| subscriber.send(&message));
+        hub.state = Some(message);
+    }
+
+    fn broadcast(&self, message: &WsMessage) {
+        if let Ok(mut hub) = self.hub.lock() {
+            hub.subscribers.retain(|subscriber| subscriber.send(message));
+        }
+    }
+
+    /// 制御メッセージが届くか、`timeout` が経つか、`stop` が立つまで待つ
+    pub fn wait(&self, timeout: Duration, stop: &AtomicBool) -> Option<Control> {
+        let deadline = Instant::now() + timeout;
+        while !stop.load(Ordering::SeqCst) {
+            let now = Instant::now();
+            if now >= deadline {
+                return None;
+            }
+            match self.controls.recv_timeout((deadline - now).min(READ_POLL)) {
+                Ok(control) => return Some(control),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return None,
+            }
+        }
+        None
+    }
+}
+
+/// 接続数の上限を超えたときの応答
+const BUSY_RESPONSE: &str =
+    "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nRetry-After: 1\r\nConnection: close\r\n\r\n";
+
+/// 受け付けのスレッドを止めないよう、届いている分だけ読み捨ててから503を返す
+/// (読まずに閉じると、クライアントには応答より先にRSTが届くことがある)
+fn refuse_busy(stream: &mut TcpStream) {
+    let mut buf = [0u8; MAX_HEAD_LEN];
+    if stream.set_nonblocking(true).is_ok() {
+        while matches!(stream.read(&mut buf), Ok(len) if len > 0) {}
+    }
+    let _ = stream.write_all(BUSY_RESPONSE.as_bytes());
+    let _ = stream.shutdown(Shutdown::Write);
+}
+
+/// 処理中の接続1つ分。接続のスレッドが終わる (パニックも含む) と数を戻す
+struct ConnectionSlot(Arc<AtomicUsize>);
+
+impl ConnectionSlot {
+    fn take(active: &Arc<AtomicUsize>) -> Self {
+        active.fetch_add(1, Ordering::SeqCst);
+        Self(Arc::clone(active))
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// 1接続分の処理に必要なもの
+#[derive(Clone)]
+struct Connection {
+    access: Option<Arc<AccessControl>>,
+    on_value: u8,
+    hub: Arc<Mutex<Hub>>,
+    controls: Sender<Control>,
+}
+
+impl Connection {
+    fn run(self, stream: TcpStream) {
+        let client = stream
+            .peer_addr()
+            .map(|addr| addr.to_string())
+            .unwrap_or_else(|_| "unknown".to_string());
+        match peek_head(&stream) {
+            Ok(head) if head.upgrade => self.run_websocket(stream, &client),
+            Ok(head) => {
+                if let Err(err) = self.serve_http(stream, &head, &client) {
+                    debug!(client, "HTTPの応答に失敗しました: {:#}", err);
+                }
+            }
+            Err(err) => debug!(client, "要求を受け取れません: {:#}", err),
+        }
+    }
+
+    fn run_websocket(&self, stream: TcpStream, client: &str) {
+        let client = client.to_string();
+        let (caller, mut socket) = match self.handshake(stream, &client) {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                debug!(client, "WebSocketのハンドシェイクに失敗しました: {:#}", err);
+                return;
+            }
+        };
+        debug!(client, caller = caller.name, "WebSocketを接続しました");
+
+        let (outbox, inbox) = mpsc::channel();
+        if let Ok(mut hub) = self.hub.lock() {
+            let subscriber = Subscriber {
+                caller: caller.clone(),
+                outbox: outbox.clone(),
+            };
+            subscriber.send(&WsMessage::Session {
+                caller: caller.name.clone(),
+                control: caller.permission == Permission::Control,
+                auth: self.access.is_some(),
+            });
+            if let Some(state) = &hub.state {
+                subscriber.send(state);
+            }
+            hub.subscribers.push(subscriber);
+        }
+
+        if let Err(err) = self.serve(&mut socket, &caller, &client, &outbox, &inbox) {
+            debug!(client, "WebSocketを切断しました: {}", err);
+        }
+        // 購読の解除は次に配信するときに行われる
+    }
+
+    /// 送られたトークンでクライアントを決める
+    #[allow(clippy::result_large_err)]
+    fn handshake(&self, stream: TcpStream, client: &str) -> Result<(Caller, WebSocket<TcpStream>)> {
+        stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        let mut caller = None;
+        let socket = tungstenite::accept_hdr(stream, |request: &Request, response: Response| {
+            if request.uri().path() != WS_PATH {
+                return Err(reject(StatusCode::NOT_FOUND, format!("{} 以外は使えません", WS_PATH)));
+            }
+            let header = |name: &str| request.headers().get(name).and_then(|value| value.to_str().ok());
+            if !same_origin(header("Origin"), header("Host")) {
+                return Err(reject(StatusCode::FORBIDDEN, "他のサイトからは接続できません".to_string()));
+            }
+            let protocols = header(PROTOCOL_HEADER);
+            let token = header("Authorization")
+                .and_then(bearer_token)
+                .or_else(|| protocol_token(protocols?));
+            match self.authenticate(token, client) {
+                Ok(authenticated) => {
+                    caller = Some(authenticated);
+                    let mut response = response;
+                    // 申し出たサブプロトコルを選ばないとブラウザは接続を切る。トークンの方は返さない
+                    if protocols.is_some_and(|protocols| offered(protocols).any(|p| p == WS_PROTOCOL)) {
+                        response
+                            .headers_mut()
+                            .insert(PROTOCOL_HEADER, HeaderValue::from_static(WS_PROTOCOL));
+                    }
+                    Ok(response)
+                }
+                Err(denied) => Err(reject(denied_status(&denied), denied.to_string())),
+            }
+        })
+        .map_err(|err| anyhow!("{}", err))?;
+        socket.get_ref().set_read_timeout(Some(READ_POLL))?;
+        let caller = caller.ok_or_else(|| anyhow!("認証していません"))?;
+        Ok((caller, socket))
+    }
+
+    /// 送られたトークンで認証し、要求数を数える
+    fn authenticate(&self, token: Option<&str>, client: &str) -> std::result::Result<Caller, Denied> {
+        let Some(access) = &self.access else {
+            return Ok(Caller {
+                name: ANONYMOUS.to_string(),
+                permission: Permission::Read,
+                targets: None,
+                rate_limit: None,
+            });
+        };
+        match access.authenticate(token, None) {
+            Ok(caller) => {
+                access.admit(Some(caller), client)?;
+                Ok(caller.clone())
+            }
+            Err(denied) => {
+                access.admit(None, client)?;
+                Err(denied)
+            }
+        }
+    }
+
+    /// WebSocket以外の要求 (画面のファイルと `/api/locators`) に応えて切断する
+    fn serve_http(&self, mut stream: TcpStream, head: &RequestHead, client: &str) -> Result<()> {
+        // GETだけを受け付けるので、本文は無いものとしてヘッダーだけ読み捨てる
+        let mut consumed = vec![0u8; head.len];
+        stream.read_exact(&mut consumed)?;
+        let (status, content_type, body) = self.route(head, client);
+        let header = format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n",
+            status.as_u16(),
+            status.canonical_reason().unwrap_or(""),
+            content_type,
+            body.len()
+        );
+        stream.write_all(header.as_bytes())?;
+        stream.write_all(body.as_bytes())?;
+        Ok(())
+    }
+
+    fn route(&self, head: &RequestHead, client: &str) -> (StatusCode, &'static str, String) {
+        let error = |status: StatusCode, message: String| {
+            (status, JSON_CONTENT_TYPE, json!({ "error": message }).to_string())
+        };
+        if head.method != "GET" {
+            return error(StatusCode::METHOD_NOT_ALLOWED, format!("{} は使えません", head.method));
+        }
+        if let Some(asset) = ASSETS.iter().find(|asset| asset.path == head.path) {
+            return (StatusCode::OK, asset.content_type, asset.body.to_string());
+        }
+        if head.path != API_LOCATORS_PATH {
+            return error(StatusCode::NOT_FOUND, format!("見つかりません: {}", head.path));
+        }
+        let caller = match self.authenticate(head.authorization.as_deref().and_then(bearer_token), client) {
+            Ok(caller) => caller,
+            Err(denied) => return error(denied_status(&denied), denied.to_string()),
+        };
+        let state = self.hub.lock().ok().and_then(|hub| hub.state.clone());
+        let state = match state {
+            Some(state) => visible_to(&caller, &state),
+            None => WsMessage::State {
+                timestamp: Local::now().to_rfc3339(),
+                locators: Vec::new(),
+            },
+        };
+        (StatusCode::OK, JSON_CONTENT_TYPE, to_text(&state))
+    }
+
+    fn serve(
+        &self,
+        socket: &mut WebSocket<TcpStream>,
+        caller: &Caller,
+        client: &str,
+        outbox: &Sender<String>,
+        inbox: &Receiver<String>,
+    ) -> Result<()> {
+        loop {
+            for text in inbox.try_iter() {
+                socket.send(Message::text(text))?;
+            }
+            match socket.read() {
+                Ok(Message::Text(text)) => {
+                    if let Err(message) = self.accept_control(text.as_str(), caller, client, outbox) {
+                        socket.send(Message::text(to_text(&message)))?;
+                    }
+                }
+                // Pingへの応答と切断はtungsteniteに任せる
+                Ok(_) => {}
+                Err(tungstenite::Error::Io(err))
+                    if matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+
+    /// 制御メッセージを解釈してdaemonのループへ渡す。渡せなければクライアントへ返す応答
+    fn accept_control(
+        &self,
+        text: &str,
+        caller: &Caller,
+        client: &str,
+        outbox: &Sender<String>,
+    ) -> std::result::Result<(), WsMessage> {
+        let error = |request_id: Option<Value>, error: String| WsMessage::Result {
+            request_id,
+            ok: false,
+            error: Some(error),
+        };
+        let message: ControlMessage =
+            serde_json::from_str(text).map_err(|err| error(None, format!("制御メッセージを解釈できません: {}", err)))?;
+        if let Some(access) = &self.access {
+            access
+                .admit(Some(caller), client)
+                .map_err(|denied| error(message.request_id.clone(), denied.to_string()))?;
+        }
+        let (id, action) = match message.request {
+            ControlRequest::Set { id, leds } => {
+                let payload = match leds {
+                    Value::String(text) => text,
+                    other => other.to_string(),
+                };
+                let leds = DesiredLeds::parse(payload.as_bytes(), self.on_value)
+                    .map_err(|err| error(message.request_id.clone(), format!("{:#}", err)))?;
+                (id, ControlAction::Set(leds))
+            }
+            ControlRequest::Release { id } => (id, ControlAction::Release),
+        };
+        let control = Control {
+            caller: caller.clone(),
+            client: client.to_string(),
+            id,
+            action,
+            request_id: message.request_id.clone(),
+            reply: outbox.clone(),
+        };
+        self.controls
+            .send(control)
+            .map_err(|_| error(message.request_id, "daemonが停止しています".to_string()))
+    }
+}
+
+/// 要求のヘッダー部分。WebSocketへの切り替えかどうかで扱いを分ける
+struct RequestHead {
+    /// ヘッダーの終わりまでのバイト数
+    len: usize,
+    method: String,
+    path: String,
+    authorization: Option<String>,
+    upgrade: bool,
+}
+
+/// ヘッダーが揃うまで読まずに覗く (WebSocketならtungsteniteが最初から読み直す)
+fn peek_head(stream: &TcpStream) -> Result<RequestHead> {
+    stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    let deadline = Instant::now() + HANDSHAKE_TIMEOUT;
+    let mut buf = vec![0u8; MAX_HEAD_LEN];
+    loop {
+        let len = stream.peek(&mut buf)?;
+        if len == 0 {
+            bail!("要求を受け取る前に切断されました");
+        }
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut request = httparse::Request::new(&mut headers);
+        match request.parse(&buf[..len]).context("HTTPの要求を解釈できません")? {
+            httparse::Status::Complete(head_len) => {
+                let header = |name: &str| {
+                    request
+                        .headers
+                        .iter()
+                        .find(|h| h.name.eq_ignore_ascii_case(name))
+                        .and_then(|h| std::str::from_utf8(h.value).ok())
+                };
+                let target = request.path.unwrap_or("/");
+                let path = target.split_once('?').map_or(target, |(path, _)| path);
+                return Ok(RequestHead {
+                    len: head_len,
+                    method: request.method.unwrap_or_default().to_string(),
+                    path: path.to_string(),
+                    authorization: header("Authorization").map(str::to_string),
+                    upgrade: header("Upgrade").is_some_and(|value| value.eq_ignore_ascii_case("websocket")),
+                });
+            }
+            httparse::Status::Partial if len == buf.len() => bail!("要求のヘッダーが大きすぎます"),
+            httparse::Status::Partial if Instant::now() >= deadline => bail!("要求のヘッダーが届きません"),
+            httparse::Status::Partial => thread::sleep(HEAD_POLL),
+        }
+    }
+}
+
+/// ブラウザが付ける `Origin` が `Host` と同じか。`Origin` を付けないクライアント (ブラウザ以外) は許す
+fn same_origin(origin: Option<&str>, host: Option<&str>) -> bool {
+    let Some(origin) = origin else {
+        return true;
+    };
+    let authority = origin.split_once("://").map_or(origin, |(_, rest)| rest);
+    host.is_some_and(|host| authority.eq_ignore_ascii_case(host))
+}
+
+/// `Sec-WebSocket-Protocol` で申し出たサブプロトコル
+fn offered(protocols: &str) -> impl Iterator<Item = &str> {
+    protocols.split(',').map(str::trim)
+}
+
+/// `cap-locator.bearer.<トークン>` のトークン
+fn protocol_token(protocols: &str) -> Option<&str> {
+    offered(protocols)
+        .find_map(|protocol| protocol.strip_prefix(WS_TOKEN_PROTOCOL_PREFIX))
+        .filter(|token| !token.is_empty())
+}
+
+fn denied_status(denied: &Denied) -> StatusCode {
+    match denied {
+        Denied::Unauthenticated(_) => StatusCode::UNAUTHORIZED,
+        Denied::Forbidden(_) => StatusCode::FORBIDDEN,
+        Denied::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+    }
+}
+
+fn reject(status: StatusCode, message: String) -> ErrorResponse {
+    let mut response = ErrorResponse::new(Some(message));
+    *response.status_mut() = status;
+    response
+}
+
+fn to_text(message: &WsMessage) -> String {
+    serde_json::to_string(message).expect("WsMessageはJSONにできる")
+}